@@ -0,0 +1,422 @@
+//! Api keys and rate limiting
+//!
+//! The queries can be authenticated with an api key, read from a key file, and are rate limited
+//! with token buckets: by key for the queries with an api key, by IP for the others.
+//! The number of queries of each key is counted.
+use anyhow::{Context, Error};
+use openapi_schema::OpenapiSchema;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// above this number of buckets, the full buckets are removed (they are the same as a new bucket)
+const MAX_IDLE_BUCKETS: usize = 10_000;
+/// the full buckets are removed at most once per interval, not to scan the buckets at each query
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+/// maximum number of calls given by the estimated timetable if `max-stop-visits` is not configured
+pub const DEFAULT_MAX_STOP_VISITS: u64 = 1000;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct RateLimit {
+    pub requests_per_minute: u32,
+    /// maximum number of queries in a burst, `requests-per-minute` by default
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+/// Access control of the api, configured in the `access-control` section of the configuration file
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct AccessControlConfig {
+    /// file with one api key by line, optionally followed by a name (separated by a space).
+    /// The empty lines and the lines starting with `#` are ignored
+    #[serde(default)]
+    pub api_keys_file: Option<String>,
+    /// if true, the queries without a valid api key are rejected
+    #[serde(default)]
+    pub require_api_key: bool,
+    /// rate limit of the queries with an api key, by key
+    #[serde(default)]
+    pub key_rate_limit: Option<RateLimit>,
+    /// rate limit of the queries without api key, by IP
+    #[serde(default)]
+    pub ip_rate_limit: Option<RateLimit>,
+    /// use the `X-Forwarded-For` (or `Forwarded`) header to get the IP of the client,
+    /// only to be used behind a reverse proxy setting this header
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+    /// maximum number of calls given by the estimated timetable, whatever the `MaximumStopVisits` of the query.
+    /// `DEFAULT_MAX_STOP_VISITS` if not given, it can be raised or lowered
+    #[serde(default)]
+    pub max_stop_visits: Option<u64>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.burst.unwrap_or(limit.requests_per_minute)),
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let capacity = f64::from(limit.burst.unwrap_or(limit.requests_per_minute));
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        let rate = f64::from(limit.requests_per_minute) / 60.;
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.last_refill = now;
+    }
+
+    fn is_full(&self, limit: &RateLimit) -> bool {
+        self.tokens >= f64::from(limit.burst.unwrap_or(limit.requests_per_minute))
+    }
+
+    /// take a token, or returns the delay before a token is available
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens >= 1. {
+            self.tokens -= 1.;
+            Ok(())
+        } else if limit.requests_per_minute == 0 {
+            Err(Duration::from_secs(60))
+        } else {
+            Err(Duration::from_secs_f64(
+                (1. - self.tokens) * 60. / f64::from(limit.requests_per_minute),
+            ))
+        }
+    }
+}
+
+/// Token buckets of the clients (api keys or IPs) sharing a rate limit
+struct RateLimiter {
+    limit: RateLimit,
+    buckets: HashMap<String, TokenBucket>,
+    last_cleanup: Option<Instant>,
+}
+
+impl RateLimiter {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: HashMap::new(),
+            last_cleanup: None,
+        }
+    }
+
+    fn take(&mut self, client: &str, now: Instant) -> Result<(), Duration> {
+        let cleanup_due = self.last_cleanup.map_or(true, |last| {
+            now.saturating_duration_since(last) >= CLEANUP_INTERVAL
+        });
+        if self.buckets.len() > MAX_IDLE_BUCKETS && cleanup_due {
+            self.last_cleanup = Some(now);
+            let limit = &self.limit;
+            self.buckets.retain(|_, b| {
+                b.refill(limit, now);
+                !b.is_full(limit)
+            });
+        }
+        let limit = &self.limit;
+        self.buckets
+            .entry(client.to_owned())
+            .or_insert_with(|| TokenBucket::new(limit, now))
+            .take(limit, now)
+    }
+}
+
+/// Number of queries made with an api key
+#[derive(Serialize, Debug, Clone, Default, OpenapiSchema, PartialEq)]
+pub struct KeyUsage {
+    /// name of the key (or its first characters if it has no name)
+    pub name: String,
+    /// number of queries accepted
+    pub requests: u64,
+    /// number of queries rejected because of the rate limit
+    pub rate_limited: u64,
+}
+
+/// Decision on a query
+#[derive(Debug, PartialEq)]
+pub enum Access {
+    Allowed,
+    MissingKey,
+    InvalidKey,
+    /// the query is rejected, it can be retried after this delay
+    RateLimited(Duration),
+}
+
+struct AccessState {
+    key_limiter: Option<RateLimiter>,
+    ip_limiter: Option<RateLimiter>,
+    /// usage by api key
+    usage: BTreeMap<String, KeyUsage>,
+}
+
+impl AccessState {
+    /// take a token in the bucket of the IP
+    fn take_ip(&mut self, ip: Option<&str>, now: Instant) -> Result<(), Duration> {
+        match (self.ip_limiter.as_mut(), ip) {
+            (Some(limiter), Some(ip)) => limiter.take(ip, now),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct AccessControl {
+    require_api_key: bool,
+    trust_forwarded_for: bool,
+    max_stop_visits: u64,
+    state: Mutex<AccessState>,
+}
+
+/// read the api keys and their name
+fn parse_api_keys(content: &str) -> BTreeMap<String, KeyUsage> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| {
+            let mut parts = l.splitn(2, char::is_whitespace);
+            let key = parts.next().unwrap_or_default().to_owned();
+            let name = parts
+                .next()
+                .map(|n| n.trim().to_owned())
+                .unwrap_or_else(|| format!("{}...", key.chars().take(4).collect::<String>()));
+            (
+                key,
+                KeyUsage {
+                    name,
+                    ..Default::default()
+                },
+            )
+        })
+        .collect()
+}
+
+impl AccessControl {
+    pub fn new(config: &AccessControlConfig) -> Result<Self, Error> {
+        let usage = match &config.api_keys_file {
+            Some(file) => parse_api_keys(
+                &std::fs::read_to_string(file)
+                    .with_context(|| format!("impossible to read api keys file {}", file))?,
+            ),
+            None => BTreeMap::new(),
+        };
+        if config.require_api_key && usage.is_empty() {
+            anyhow::bail!("the api keys are required, but no api key is configured");
+        }
+        Ok(Self {
+            require_api_key: config.require_api_key,
+            trust_forwarded_for: config.trust_forwarded_for,
+            max_stop_visits: config.max_stop_visits.unwrap_or(DEFAULT_MAX_STOP_VISITS),
+            state: Mutex::new(AccessState {
+                key_limiter: config.key_rate_limit.clone().map(RateLimiter::new),
+                ip_limiter: config.ip_rate_limit.clone().map(RateLimiter::new),
+                usage,
+            }),
+        })
+    }
+
+    pub fn trust_forwarded_for(&self) -> bool {
+        self.trust_forwarded_for
+    }
+
+    pub fn max_stop_visits(&self) -> u64 {
+        self.max_stop_visits
+    }
+
+    /// Check a query made with an optional api key, from an IP
+    pub fn check(&self, api_key: Option<&str>, ip: Option<&str>, now: Instant) -> Access {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        // without configured api keys, the given keys are ignored
+        let api_key = api_key.filter(|_| !state.usage.is_empty());
+        match api_key {
+            Some(key) => {
+                let usage = match state.usage.get_mut(key) {
+                    Some(usage) => usage,
+                    // the queries with an invalid key are limited by IP, not to try many keys
+                    None => {
+                        return match state.take_ip(ip, now) {
+                            Ok(()) => Access::InvalidKey,
+                            Err(delay) => Access::RateLimited(delay),
+                        }
+                    }
+                };
+                match state.key_limiter.as_mut().map(|l| l.take(key, now)) {
+                    Some(Err(delay)) => {
+                        usage.rate_limited += 1;
+                        Access::RateLimited(delay)
+                    }
+                    _ => {
+                        usage.requests += 1;
+                        Access::Allowed
+                    }
+                }
+            }
+            None if self.require_api_key => Access::MissingKey,
+            None => match state.take_ip(ip, now) {
+                Ok(()) => Access::Allowed,
+                Err(delay) => Access::RateLimited(delay),
+            },
+        }
+    }
+
+    /// true if the key is one of the configured api keys
+    pub fn is_valid_key(&self, api_key: &str) -> bool {
+        self.state.lock().unwrap().usage.contains_key(api_key)
+    }
+
+    /// usage of all the api keys
+    pub fn usage(&self) -> Vec<KeyUsage> {
+        self.state.lock().unwrap().usage.values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access_control(config: AccessControlConfig) -> AccessControl {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "# our clients\nkey1 first client\n\nkey2\n").unwrap();
+        AccessControl::new(&AccessControlConfig {
+            api_keys_file: Some(file.path().to_str().unwrap().to_owned()),
+            ..config
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_api_keys() {
+        let access = access_control(AccessControlConfig {
+            require_api_key: true,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        assert_eq!(access.check(Some("key1"), None, now), Access::Allowed);
+        assert_eq!(access.check(Some("key1"), None, now), Access::Allowed);
+        assert_eq!(access.check(Some("key3"), None, now), Access::InvalidKey);
+        assert_eq!(access.check(None, Some("1.2.3.4"), now), Access::MissingKey);
+        assert!(access.is_valid_key("key2"));
+        assert!(!access.is_valid_key("key3"));
+        assert_eq!(
+            access.usage(),
+            vec![
+                KeyUsage {
+                    name: "first client".to_owned(),
+                    requests: 2,
+                    rate_limited: 0,
+                },
+                KeyUsage {
+                    name: "key2...".to_owned(),
+                    requests: 0,
+                    rate_limited: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let access = access_control(AccessControlConfig {
+            key_rate_limit: Some(RateLimit {
+                requests_per_minute: 60,
+                burst: Some(2),
+            }),
+            ip_rate_limit: Some(RateLimit {
+                requests_per_minute: 1,
+                burst: None,
+            }),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        assert_eq!(access.check(Some("key1"), None, now), Access::Allowed);
+        assert_eq!(access.check(Some("key1"), None, now), Access::Allowed);
+        assert_eq!(
+            access.check(Some("key1"), None, now),
+            Access::RateLimited(Duration::from_secs(1))
+        );
+        // each key has its own bucket
+        assert_eq!(access.check(Some("key2"), None, now), Access::Allowed);
+        // one token is added each second
+        let later = now + Duration::from_secs(1);
+        assert_eq!(access.check(Some("key1"), None, later), Access::Allowed);
+        assert_eq!(access.usage()[0].requests, 3);
+        assert_eq!(access.usage()[0].rate_limited, 1);
+
+        // the queries without key are limited by IP
+        assert_eq!(access.check(None, Some("1.2.3.4"), now), Access::Allowed);
+        assert_eq!(
+            access.check(None, Some("1.2.3.4"), now),
+            Access::RateLimited(Duration::from_secs(60))
+        );
+        assert_eq!(access.check(None, Some("5.6.7.8"), now), Access::Allowed);
+
+        // the queries with an invalid key are limited by IP
+        assert_eq!(
+            access.check(Some("key3"), Some("9.9.9.9"), now),
+            Access::InvalidKey
+        );
+        assert_eq!(
+            access.check(Some("key4"), Some("9.9.9.9"), now),
+            Access::RateLimited(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn test_buckets_cleanup() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            requests_per_minute: 60,
+            burst: None,
+        });
+        let now = Instant::now();
+        let fill = |limiter: &mut RateLimiter, now: Instant| {
+            for n in 0..=MAX_IDLE_BUCKETS {
+                limiter.take(&n.to_string(), now).unwrap();
+            }
+        };
+        fill(&mut limiter, now);
+        assert_eq!(limiter.buckets.len(), MAX_IDLE_BUCKETS + 1);
+
+        // once refilled, the buckets are removed
+        let later = now + Duration::from_secs(120);
+        limiter.take("new client", later).unwrap();
+        assert_eq!(limiter.buckets.len(), 1);
+
+        // but not before the next cleanup
+        fill(&mut limiter, later);
+        limiter
+            .take("another client", later + Duration::from_secs(30))
+            .unwrap();
+        assert_eq!(limiter.buckets.len(), MAX_IDLE_BUCKETS + 3);
+    }
+
+    #[test]
+    fn test_max_stop_visits() {
+        let access = AccessControl::new(&AccessControlConfig::default()).unwrap();
+        assert_eq!(access.max_stop_visits(), DEFAULT_MAX_STOP_VISITS);
+        let access = AccessControl::new(&AccessControlConfig {
+            max_stop_visits: Some(5000),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(access.max_stop_visits(), 5000);
+    }
+
+    #[test]
+    fn test_required_keys_without_keys() {
+        assert!(AccessControl::new(&AccessControlConfig {
+            require_api_key: true,
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
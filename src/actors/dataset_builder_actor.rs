@@ -0,0 +1,266 @@
+use crate::actors::DatasetActor;
+use crate::cache::DataCache;
+use crate::datasets::{Dataset, DatasetInfo, Period};
+use crate::schedule_readers;
+use anyhow::anyhow;
+use futures::channel::oneshot;
+use openapi_schema::OpenapiSchema;
+use slog::info;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Shared flag used to cancel the building of a dataset (for example when the dataset is removed)
+///
+/// Note: a step of the building cannot be interrupted, the cancellation is checked between the steps
+#[derive(Clone, Default, Debug)]
+pub struct Cancellation {
+    cancelled: Arc<AtomicBool>,
+    parent: Option<Box<Cancellation>>,
+}
+
+impl Cancellation {
+    /// A cancellation that can be cancelled on its own (for example when a building times out),
+    /// and that is also cancelled with its parent
+    pub fn child(&self) -> Self {
+        Self {
+            cancelled: Arc::default(),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+            || self.parent.as_ref().map_or(false, |p| p.is_cancelled())
+    }
+}
+
+/// Steps of the building of a dataset
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, OpenapiSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildStep {
+    /// waiting for a worker to be available
+    Queued,
+    FetchingSources,
+    /// reading the sources and computing the timetable
+    BuildingDataset,
+}
+
+#[derive(Serialize, Debug, Clone, OpenapiSchema)]
+pub struct BuildProgress {
+    pub step: BuildStep,
+    pub since: chrono::DateTime<chrono::Utc>,
+}
+
+/// Message send to the DatasetActor to report the progress of its building.
+/// `None` means that no building is in progress
+pub struct ReportBuildProgress(pub Option<BuildProgress>);
+
+impl actix::Message for ReportBuildProgress {
+    type Result = ();
+}
+
+impl actix::Handler<ReportBuildProgress> for DatasetActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        params: ReportBuildProgress,
+        _ctx: &mut actix::Context<Self>,
+    ) -> Self::Result {
+        self.build_progress = params.0;
+    }
+}
+
+/// Worker building the datasets.
+///
+/// The building (downloading, parsing and computing the timetable) is blocking,
+/// so it is run in a dedicated pool of threads (with a `SyncArbiter`),
+/// the number of threads limiting the number of datasets built at the same time.
+pub struct DatasetBuilder;
+
+impl actix::Actor for DatasetBuilder {
+    type Context = actix::SyncContext<Self>;
+}
+
+impl DatasetBuilder {
+    /// start a pool of `nb_workers` workers
+    pub fn start_pool(nb_workers: usize) -> actix::Addr<Self> {
+        actix::SyncArbiter::start(nb_workers.max(1), || DatasetBuilder)
+    }
+}
+
+/// Build a dataset.
+/// Returns `None` if the dataset's sources have not changed
+pub struct BuildDataset {
+    pub dataset_info: DatasetInfo,
+    pub generation_period: Period,
+    pub cache: Option<Arc<DataCache>>,
+    /// if the dataset's sources have this version, the dataset is not built
+    pub unchanged_version: Option<String>,
+    /// if true, the prepared dataset of the cache is used without checking its sources
+    pub use_cached_dataset: bool,
+    pub dataset_actor: actix::Addr<DatasetActor>,
+    pub cancellation: Cancellation,
+    /// notified when a worker starts the building, after the waiting in the queue
+    pub started: Option<oneshot::Sender<()>>,
+    pub log: slog::Logger,
+}
+
+impl actix::Message for BuildDataset {
+    type Result = Result<Option<Dataset>, anyhow::Error>;
+}
+
+impl BuildDataset {
+    fn report(&self, step: Option<BuildStep>) {
+        if let Some(step) = step {
+            info!(self.log, "building dataset: {:?}", step);
+        }
+        self.dataset_actor
+            .do_send(ReportBuildProgress(step.map(|step| BuildProgress {
+                step,
+                since: chrono::Utc::now(),
+            })));
+    }
+
+    fn check_cancellation(&self) -> Result<(), anyhow::Error> {
+        if self.cancellation.is_cancelled() {
+            Err(anyhow!("the building of the dataset has been cancelled"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn build(&self) -> Result<Option<Dataset>, anyhow::Error> {
+        let cache = self.cache.as_deref();
+        self.check_cancellation()?;
+        if let (true, Some(cache)) = (self.use_cached_dataset, cache) {
+            match Dataset::try_from_cache(
+                self.dataset_info.clone(),
+                &self.generation_period,
+                cache,
+            ) {
+                Some(Ok(dataset)) => return Ok(Some(dataset)),
+                Some(Err(e)) => log::warn!("impossible to use the cached dataset: {}", e),
+                None => log::info!("dataset not in the cache"),
+            }
+        }
+
+        self.report(Some(BuildStep::FetchingSources));
+        let sources = schedule_readers::fetch_sources(&self.dataset_info, cache)?;
+        if let Some(cache) = cache {
+            cache.purge();
+        }
+        if self.unchanged_version.as_ref() == Some(&sources.version) {
+            return Ok(None);
+        }
+
+        self.check_cancellation()?;
+        self.report(Some(BuildStep::BuildingDataset));
+        let dataset = Dataset::try_from_sources(
+            self.dataset_info.clone(),
+            &sources,
+            &self.generation_period,
+            cache,
+        )?;
+        self.check_cancellation()?;
+        Ok(Some(dataset))
+    }
+}
+
+impl actix::Handler<BuildDataset> for DatasetBuilder {
+    type Result = Result<Option<Dataset>, anyhow::Error>;
+
+    fn handle(
+        &mut self,
+        mut msg: BuildDataset,
+        _ctx: &mut actix::SyncContext<Self>,
+    ) -> Self::Result {
+        if let Some(started) = msg.started.take() {
+            // the receiver may not wait for it anymore
+            let _ = started.send(());
+        }
+        let log = msg.log.clone();
+        slog_scope::scope(&log, || {
+            let begin = chrono::Utc::now();
+            let result = msg.build();
+            msg.report(None);
+            info!(
+                msg.log,
+                "dataset building finished in {}",
+                chrono::Utc::now().signed_duration_since(begin)
+            );
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuildDataset, Cancellation, DatasetBuilder};
+    use crate::actors::{DatasetActor, GetBuildStatus};
+    use crate::datasets::{DatasetInfo, Period, RealTimeDataset};
+    use actix::Actor;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_cancellation() {
+        let cancellation = Cancellation::default();
+        let shared = cancellation.clone();
+        assert!(!shared.is_cancelled());
+        cancellation.cancel();
+        assert!(shared.is_cancelled());
+    }
+
+    #[test]
+    fn test_child_cancellation() {
+        let parent = Cancellation::default();
+        let child = parent.child();
+        let other_child = parent.child();
+        child.cancel();
+        assert!(child.is_cancelled());
+        assert!(!parent.is_cancelled());
+        assert!(!other_child.is_cancelled());
+        parent.cancel();
+        assert!(other_child.is_cancelled());
+    }
+
+    #[actix_rt::test]
+    async fn cancelled_building_returns_early() {
+        let dataset = Arc::new(Err(anyhow::anyhow!("loading")));
+        let rt_dataset = Arc::new(RealTimeDataset::new(dataset.clone(), &[]));
+        let dataset_actor = DatasetActor::new(dataset, rt_dataset).start();
+        let builder = DatasetBuilder::start_pool(1);
+        let (started, started_receiver) = futures::channel::oneshot::channel();
+        let cancellation = Cancellation::default();
+        cancellation.cancel();
+
+        let result = builder
+            .send(BuildDataset {
+                dataset_info: DatasetInfo::new_default("fixtures/gtfs.zip", &[]),
+                generation_period: Period {
+                    begin: chrono::NaiveDate::from_ymd(2018, 12, 15),
+                    horizon: chrono::Duration::days(1),
+                },
+                cache: None,
+                unchanged_version: None,
+                use_cached_dataset: false,
+                dataset_actor: dataset_actor.clone(),
+                cancellation,
+                started: Some(started),
+                log: slog::Logger::root(slog::Discard, slog::o!()),
+            })
+            .await
+            .unwrap();
+
+        assert!(started_receiver.await.is_ok());
+        let error = result.err().expect("a cancelled building must fail");
+        assert!(error.to_string().contains("cancelled"), "{}", error);
+        // the sources have not been fetched
+        let status = dataset_actor.send(GetBuildStatus).await.unwrap();
+        assert!(status.build_progress.is_none());
+    }
+}
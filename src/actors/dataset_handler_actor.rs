@@ -1,13 +1,94 @@
-use crate::datasets::{Dataset, RealTimeDataset};
+use crate::actors::BuildProgress;
+use crate::datasets::{Dataset, RealTimeDataset, ReloadFailure};
+use crate::diagnostics::{Diagnostics, DiagnosticsReport};
+use futures::channel::mpsc;
+use futures::{Stream, StreamExt};
 use log::info;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+
+/// Channel notified each time the `DatasetActor` swaps in a new `RealTimeDataset`.
+/// Only the latest `RealTimeDataset` is kept, a slow listener skips the intermediate ones
+/// instead of keeping them all in memory.
+pub struct RealtimeListener {
+    latest: Arc<Mutex<Option<Arc<RealTimeDataset>>>>,
+    notifier: mpsc::Sender<()>,
+}
+
+impl RealtimeListener {
+    /// returns false if the receiving end has been dropped
+    fn send(&mut self, realtime: Arc<RealTimeDataset>) -> bool {
+        *self.latest.lock().unwrap() = Some(realtime);
+        // if the channel is full, the listener has not yet read the previous notification,
+        // it will read the latest dataset with it
+        match self.notifier.try_send(()) {
+            Ok(()) => true,
+            Err(e) => !e.is_disconnected(),
+        }
+    }
+}
+
+/// Create a `RealtimeListener` and the stream of the `RealTimeDataset` it receives
+pub fn realtime_channel() -> (
+    RealtimeListener,
+    impl Stream<Item = Arc<RealTimeDataset>> + Unpin,
+) {
+    let latest = Arc::new(Mutex::new(None));
+    let (notifier, notifications) = mpsc::channel(0);
+    let listener = RealtimeListener {
+        latest: latest.clone(),
+        notifier,
+    };
+    let receiver =
+        notifications.filter_map(move |()| futures::future::ready(latest.lock().unwrap().take()));
+    (listener, receiver)
+}
 
 /// Actor whose role is to:
 ///  * give a pointer to a Dataset (on the GetDataset Message)
 ///  * update the pointer to a new Dataset (on the UpdateBaseSchedule Message)
+///  * notify the registered listeners when the realtime data are updated (cf. ListenRealtime Message)
+///  * keep track of the failed reloadings of the Dataset (on the MarkStale Message)
+///  * aggregate the diagnostics of the realtime updates (cf. GetDiagnostics Message)
 pub struct DatasetActor {
     pub gtfs: Arc<Result<Dataset, anyhow::Error>>,
     pub realtime: Arc<RealTimeDataset>,
+    pub realtime_listeners: Vec<RealtimeListener>,
+    /// set if the last reloading has failed and the served dataset is stale
+    pub reload_failure: Option<ReloadFailure>,
+    /// set while the dataset is being built
+    pub build_progress: Option<BuildProgress>,
+    /// quality of the matching of the realtime data with the base schedule
+    pub diagnostics: Diagnostics,
+}
+
+impl DatasetActor {
+    pub fn new(gtfs: Arc<Result<Dataset, anyhow::Error>>, realtime: Arc<RealTimeDataset>) -> Self {
+        Self {
+            gtfs,
+            realtime,
+            realtime_listeners: vec![],
+            reload_failure: None,
+            build_progress: None,
+            diagnostics: Diagnostics::default(),
+        }
+    }
+
+    /// send the current realtime dataset to all the listeners
+    /// the listeners whose receiving end has been dropped are removed
+    pub(crate) fn notify_realtime_listeners(&mut self) {
+        let realtime = self.realtime.clone();
+        self.realtime_listeners = self
+            .realtime_listeners
+            .drain(..)
+            .filter_map(|mut l| {
+                if l.send(realtime.clone()) {
+                    Some(l)
+                } else {
+                    None
+                }
+            })
+            .collect();
+    }
 }
 
 impl actix::Actor for DatasetActor {
@@ -45,3 +126,85 @@ impl actix::Handler<GetRealtimeDataset> for DatasetActor {
         self.realtime.clone()
     }
 }
+
+/// Register a listener that will receive all the future `RealTimeDataset`.
+/// The current `RealTimeDataset` is returned, to be used as an initial snapshot.
+#[derive(actix::Message)]
+#[rtype(result = "Arc<RealTimeDataset>")]
+pub struct ListenRealtime(pub RealtimeListener);
+
+impl actix::Handler<ListenRealtime> for DatasetActor {
+    type Result = Arc<RealTimeDataset>;
+
+    fn handle(&mut self, params: ListenRealtime, _ctx: &mut actix::Context<Self>) -> Self::Result {
+        self.realtime_listeners.push(params.0);
+        self.realtime.clone()
+    }
+}
+
+/// State of the building of the dataset
+#[derive(Debug, Clone)]
+pub struct BuildStatus {
+    pub reload_failure: Option<ReloadFailure>,
+    pub build_progress: Option<BuildProgress>,
+}
+
+#[derive(actix::Message)]
+#[rtype(result = "BuildStatus")]
+pub struct GetBuildStatus;
+
+impl actix::Handler<GetBuildStatus> for DatasetActor {
+    type Result = actix::MessageResult<GetBuildStatus>;
+
+    fn handle(&mut self, _params: GetBuildStatus, _ctx: &mut actix::Context<Self>) -> Self::Result {
+        actix::MessageResult(BuildStatus {
+            reload_failure: self.reload_failure.clone(),
+            build_progress: self.build_progress.clone(),
+        })
+    }
+}
+
+#[derive(actix::Message)]
+#[rtype(result = "DiagnosticsReport")]
+pub struct GetDiagnostics;
+
+impl actix::Handler<GetDiagnostics> for DatasetActor {
+    type Result = actix::MessageResult<GetDiagnostics>;
+
+    fn handle(&mut self, _params: GetDiagnostics, _ctx: &mut actix::Context<Self>) -> Self::Result {
+        actix::MessageResult(self.diagnostics.summary())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn rt_dataset(url: &str) -> Arc<RealTimeDataset> {
+        let base = Arc::new(Err(anyhow::anyhow!("no base schedule")));
+        Arc::new(RealTimeDataset::new(base, &[url.to_owned()]))
+    }
+
+    #[test]
+    fn slow_listener_only_gets_the_latest_dataset() {
+        let (mut listener, mut receiver) = realtime_channel();
+        assert!(listener.send(rt_dataset("first")));
+        assert!(listener.send(rt_dataset("second")));
+        assert!(listener.send(rt_dataset("third")));
+
+        let received = block_on(receiver.next()).unwrap();
+        assert_eq!(received.gtfs_rt_provider_urls, vec!["third".to_owned()]);
+
+        assert!(listener.send(rt_dataset("fourth")));
+        let received = block_on(receiver.next()).unwrap();
+        assert_eq!(received.gtfs_rt_provider_urls, vec!["fourth".to_owned()]);
+    }
+
+    #[test]
+    fn dropped_listener_is_detected() {
+        let (mut listener, receiver) = realtime_channel();
+        drop(receiver);
+        assert!(!listener.send(rt_dataset("first")));
+    }
+}
@@ -1,8 +1,22 @@
+mod dataset_builder_actor;
 mod dataset_handler_actor;
 mod realtime_update_actors;
+mod subscription_actor;
 mod update_actors;
 
 // we reexport the actors
-pub use self::dataset_handler_actor::{DatasetActor, GetDataset, GetRealtimeDataset};
+pub use self::dataset_builder_actor::{
+    BuildDataset, BuildProgress, BuildStep, Cancellation, DatasetBuilder, ReportBuildProgress,
+};
+pub use self::dataset_handler_actor::{
+    realtime_channel, BuildStatus, DatasetActor, GetBuildStatus, GetDataset, GetDiagnostics,
+    GetRealtimeDataset, ListenRealtime, RealtimeListener,
+};
+pub(crate) use self::realtime_update_actors::{apply_rt_update, UpdateRealtime};
 pub use self::realtime_update_actors::RealTimeReloader;
-pub use self::update_actors::BaseScheduleReloader;
+pub use self::subscription_actor::{
+    AddSubscription, Subscription, SubscriptionActor, SubscriptionsConfig, TerminateSubscriptions,
+};
+pub use self::update_actors::{
+    BaseScheduleReloader, LoadedDataset, MarkStale, UpdateBaseSchedule,
+};
@@ -1,25 +1,52 @@
-use crate::actors::{DatasetActor, GetDataset};
+use crate::actors::{Cancellation, DatasetActor, GetDataset};
+use crate::alerts::AlertStore;
+use crate::clock::Clock;
 use crate::datasets::{
-    Dataset, GtfsRT, RealTimeConnection, RealTimeDataset, ScheduleRelationship, UpdatedTimetable,
+    Dataset, GtfsRT, IdStripping, RealTimeConnection, RealTimeDataset, RealtimeFormat,
+    ScheduleRelationship, UpdatedTimetable,
 };
-use crate::model_update;
+use crate::diagnostics::{IssueKind, MatchingReport};
+use crate::estimation;
+use crate::gtfs_rt_writer;
+use crate::model_update::{self, IdResolver};
+use crate::overrides::{self, Override, OverrideStore};
+use crate::recorder::Recorder;
+use crate::replay::{self, Replay};
+use crate::siri_reader;
+use crate::stats::PunctualityTracker;
 use crate::transit_realtime;
 use actix::fut::WrapFuture;
 use actix::prelude::ContextFutureSpawner;
-use actix::AsyncContext;
+use actix::{ActorContext, AsyncContext};
 use anyhow::{anyhow, Error};
 use futures::future::join_all;
 use prost::Message;
 use sentry::integrations::anyhow::capture_anyhow;
 use slog::info;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 /// Actor that once in a while reload the BaseSchedule data (GTFS)
 /// and send them to the DatasetActor
+///
+/// If some GTFS-RT urls are snapshot directories, they are replayed:
+/// each reloading moves the dataset's clock to the next snapshot
 #[derive(Clone)]
 pub struct RealTimeReloader {
     pub gtfs_rt_urls: Vec<String>,
     pub dataset_id: String,
+    pub clock: Clock,
+    pub replay: Option<Arc<Replay>>,
+    /// if given, the fetched GTFS-RT are recorded
+    pub recorder: Option<Arc<Recorder>>,
+    /// if given, the passed connections are stored for the punctuality statistics
+    pub punctuality: Option<Arc<PunctualityTracker>>,
+    /// manual overrides, applied on top of the realtime feeds
+    pub overrides: Arc<OverrideStore>,
+    /// alerts published through the api, the expired ones are removed at each update
+    pub alerts: Arc<AlertStore>,
+    /// cancelled when the dataset is removed, the actor then stops
+    pub cancellation: Cancellation,
 
     // Address of the DatasetActor to notify for the data reloading
     // NOte: for the moment it's a single Actor,
@@ -28,8 +55,15 @@ pub struct RealTimeReloader {
     pub log: slog::Logger,
 }
 
-async fn fetch_gtfs_rt(url: &str, log: &slog::Logger) -> Result<GtfsRT, Error> {
+async fn fetch_gtfs_rt(
+    url: &str,
+    now: chrono::DateTime<chrono::Utc>,
+    log: &slog::Logger,
+) -> Result<GtfsRT, Error> {
     info!(log, "fetching a gtfs_rt");
+    if let Some(path) = replay::local_path(url) {
+        return replay::read_file(path, now);
+    }
     let resp = reqwest::get(url)
         .await
         .and_then(reqwest::Response::error_for_status)
@@ -43,48 +77,148 @@ async fn fetch_gtfs_rt(url: &str, log: &slog::Logger) -> Result<GtfsRT, Error> {
 
     Ok(GtfsRT {
         data: gtfs_rt,
-        datetime: chrono::Utc::now(),
+        datetime: now,
     })
 }
 
-fn aggregate_rts(feed_messages: &[transit_realtime::FeedMessage]) -> Result<GtfsRT, Error> {
+fn aggregate_rts(
+    feed_messages: &[(String, transit_realtime::FeedMessage)],
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<GtfsRT, Error> {
     //We may loose a timestamp, other fields are ok
-    let first = feed_messages
+    let (_, first) = feed_messages
         .first()
         .ok_or_else(|| anyhow!("No feed message!"))?;
     let entity = feed_messages
         .iter()
-        .map(|fm| fm.entity.clone())
+        .map(|(_, fm)| fm.entity.clone())
         .flatten()
         .collect();
     let res = transit_realtime::FeedMessage {
         header: first.header.clone(),
         entity,
     };
+    encode_rt(&res, now)
+}
+
+fn encode_rt(
+    feed_message: &transit_realtime::FeedMessage,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<GtfsRT, Error> {
     let mut data = Vec::new();
-    res.encode(&mut data)
+    feed_message
+        .encode(&mut data)
         .map_err(|err| anyhow!("Unable to encode protobuf: {}", err))?;
     Ok(GtfsRT {
         data,
-        datetime: chrono::Utc::now(),
+        datetime: now,
     })
 }
 
 // modify the generated timetable with a given GTFS-RT
 // Since the connection are sorted by scheduled departure time we don't need to reorder the connections, we can update them in place
 // For each trip update, we only have to find the corresponding connection and update it.
-fn apply_rt_update(
+// Each GTFS-RT is given with its url, to match its ids with the base schedule's ids of its feed
+// The manual overrides are applied last, on top of the realtime feeds
+pub(crate) fn apply_rt_update(
     data: &Arc<Result<Dataset, anyhow::Error>>,
-    gtfs_rts: &[transit_realtime::FeedMessage],
+    gtfs_rts: &[(String, transit_realtime::FeedMessage)],
+    siris: &[(String, siri_reader::Siri)],
+    overrides: &[Override],
+    now: chrono::DateTime<chrono::Utc>,
     log: &slog::Logger,
-) -> Result<UpdatedTimetable, Error> {
+) -> Result<(UpdatedTimetable, MatchingReport), Error> {
     let data = match &(**data) {
-        Err(_e) => return Ok(UpdatedTimetable::default()),
+        Err(_e) => return Ok((UpdatedTimetable::default(), MatchingReport::default())),
         Ok(data) => data,
     };
     let mut updated_timetable = UpdatedTimetable::default();
 
-    let parsed_trip_update = model_update::get_model_update(&data.ntm, gtfs_rts, data.timezone)?;
+    let default_id_resolver = IdResolver::default();
+    let mut parsed_trip_update = model_update::ModelUpdate::default();
+    let estimate = data
+        .feed_construction_info
+        .dataset_info
+        .estimate_from_vehicle_positions;
+    let mut estimated_trips = HashMap::new();
+    // the trip update of each trip, if it is given by only one GTFS-RT
+    let mut gtfs_rt_trip_updates = HashMap::new();
+    for (url, feed_message) in gtfs_rts {
+        let id_resolver = data.id_resolvers.get(url).unwrap_or(&default_id_resolver);
+        let model_update = model_update::get_model_update(
+            &data.ntm,
+            id_resolver,
+            std::slice::from_ref(feed_message),
+            data.timezone,
+            now,
+        )?;
+        for (dated_vj, trip_update) in &model_update.trips {
+            let source = (url.clone(), trip_update.entity_id.clone());
+            gtfs_rt_trip_updates
+                .entry(dated_vj.clone())
+                .and_modify(|s| *s = None)
+                .or_insert(Some(source));
+        }
+        parsed_trip_update.merge(model_update);
+        if estimate {
+            let estimated_update = estimation::estimate_model_update(
+                &data.ntm,
+                id_resolver,
+                std::slice::from_ref(feed_message),
+                data.timezone,
+                now,
+            );
+            estimated_trips.extend(estimated_update.trips);
+        }
+    }
+    let default_id_stripping = IdStripping::default();
+    for (url, siri) in siris {
+        let id_resolver = data.id_resolvers.get(url).unwrap_or(&default_id_resolver);
+        let id_stripping = data
+            .feed_construction_info
+            .dataset_info
+            .realtime_source(url)
+            .map(|source| &source.id_stripping)
+            .unwrap_or(&default_id_stripping);
+        let model_update = siri_reader::get_model_update(
+            &data.ntm,
+            id_resolver,
+            id_stripping,
+            std::slice::from_ref(siri),
+            data.timezone,
+            now,
+        );
+        for dated_vj in model_update.trips.keys() {
+            gtfs_rt_trip_updates.remove(dated_vj);
+        }
+        parsed_trip_update.merge(model_update);
+    }
+    updated_timetable.gtfs_rt_trip_updates = gtfs_rt_trip_updates
+        .into_iter()
+        .filter_map(|(dated_vj, source)| source.map(|source| (dated_vj, source)))
+        .collect();
+    // the real trip updates are more accurate than the estimated ones
+    for (dated_vj, trip_update) in estimated_trips {
+        parsed_trip_update
+            .trips
+            .entry(dated_vj)
+            .or_insert(trip_update);
+    }
+    let mut report = parsed_trip_update.matching_report;
+
+    let period = &data.feed_construction_info.generation_period;
+    for (dated_vj, trip_update) in &parsed_trip_update.trips {
+        if dated_vj.date < period.begin || dated_vj.date >= period.begin + period.horizon {
+            report.add_issue(
+                IssueKind::DateOutOfPeriod,
+                &trip_update.entity_id,
+                format!(
+                    "the date {} of trip {} is outside of the timetable period",
+                    dated_vj.date, &data.ntm.vehicle_journeys[dated_vj.vj_idx].id
+                ),
+            );
+        }
+    }
     let mut nb_changes = 0;
     let mut cpt_incoherent_stops_id = 0;
 
@@ -98,12 +232,14 @@ fn apply_rt_update(
                 // integrity check
                 if let Some(stop_idx) = stop_time_update.stop_point_idx {
                     if stop_idx != connection.stop_point_idx {
-                        slog::warn!(log, "for trip {}, invalid stop connection, the stop n.{} '{}' does not correspond to the gtfsrt stop '{}'",
+                        let message = format!("for trip {}, invalid stop connection, the stop n.{} '{}' does not correspond to the gtfsrt stop '{}'",
                     &data.ntm.vehicle_journeys[connection.dated_vj.vj_idx].id,
                     &connection.sequence,
                     &data.ntm.stop_points[connection.stop_point_idx].id,
                     &data.ntm.stop_points[stop_idx].id,
                     );
+                        slog::warn!(log, "{}", message);
+                        report.add_issue(IssueKind::StopMismatch, &trip_update.entity_id, message);
                         cpt_incoherent_stops_id += 1;
                         continue;
                     }
@@ -115,6 +251,7 @@ fn apply_rt_update(
                         arr_time: stop_time_update.updated_arrival,
                         schedule_relationship: ScheduleRelationship::Scheduled,
                         update_time: trip_update.update_dt,
+                        estimated: trip_update.estimated,
                     },
                 );
                 nb_changes += 1;
@@ -137,8 +274,13 @@ fn apply_rt_update(
         log,
         "{} connections have been updated with trip updates", nb_changes
     );
+    let nb_overridden =
+        overrides::apply_overrides(data, overrides, &mut updated_timetable, now);
+    if nb_overridden != 0 {
+        info!(log, "{} connections have been overridden", nb_overridden);
+    }
 
-    Ok(updated_timetable)
+    Ok((updated_timetable, report))
 }
 
 impl RealTimeReloader {
@@ -160,6 +302,13 @@ impl RealTimeReloader {
         sentry::Hub::current().configure_scope(|scope| {
             scope.set_tag("dataset", &self.dataset_id);
         });
+        if let Some(replay) = &self.replay {
+            if !replay.step() {
+                info!(self.log, "all the gtfs-rt snapshots have been replayed");
+                return;
+            }
+            info!(self.log, "replaying the gtfs-rt at {}", self.clock.now());
+        }
         let res = self.update_realtime_data_impl().await;
         match res {
             Ok(()) => {
@@ -173,44 +322,168 @@ impl RealTimeReloader {
     }
 
     async fn apply_rt(&self, dataset: Arc<Result<Dataset, anyhow::Error>>) -> Result<(), Error> {
-        let gtfs_rts = self
-            .gtfs_rt_urls
-            .iter()
-            .map(|url| fetch_gtfs_rt(&url, &self.log));
+        let now = self.clock.now();
+        let gtfs_rts = self.gtfs_rt_urls.iter().map(|url| async move {
+            let rt = match self.replay.as_ref().and_then(|r| r.snapshot(url)) {
+                Some(snapshot) => snapshot,
+                None => fetch_gtfs_rt(&url, now, &self.log).await,
+            };
+            (url.clone(), rt)
+        });
 
         // NOTE: if one of the urls is responding 404, the error is currently logged then ignored
-        let gtfs_rts = join_all(gtfs_rts)
+        let gtfs_rts: Vec<(String, GtfsRT)> = join_all(gtfs_rts)
             .await
             .into_iter()
-            .filter_map(|rt| rt.map_err(|e| slog::warn!(self.log, "{}", e)).ok())
+            .filter_map(|(url, rt)| {
+                rt.map(|rt| (url, rt))
+                    .map_err(|e| slog::warn!(self.log, "{}", e))
+                    .ok()
+            })
             .collect();
 
-        let rt_dataset = self.make_rt_dataset(dataset, gtfs_rts)?;
+        if let Some(recorder) = &self.recorder {
+            let recorder = recorder.clone();
+            let feeds: Vec<(String, GtfsRT, RealtimeFormat)> = gtfs_rts
+                .iter()
+                .map(|(url, rt)| {
+                    let format = match &*dataset {
+                        Ok(data) => data
+                            .feed_construction_info
+                            .dataset_info
+                            .realtime_format(url),
+                        Err(_) => RealtimeFormat::default(),
+                    };
+                    (url.clone(), rt.clone(), format)
+                })
+                .collect();
+            let log = self.log.clone();
+            // the recording is blocking (compression and writes), it is done out of the arbiter
+            let recording = actix_web::web::block(move || -> Result<(), Error> {
+                for (url, gtfs_rt, format) in &feeds {
+                    if let Err(e) = recorder.record(url, gtfs_rt, *format) {
+                        slog::warn!(log, "impossible to record realtime feed {}: {}", url, e);
+                    }
+                }
+                Ok(())
+            });
+            if let Err(e) = recording.await {
+                slog::warn!(self.log, "impossible to record the realtime feeds: {}", e);
+            }
+        }
+
+        if let Ok(data) = &*dataset {
+            let local_now = now.with_timezone(&data.timezone).naive_local();
+            let overrides = self.overrides.clone();
+            // the overrides are written on disk, it is blocking
+            match actix_web::web::block(move || overrides.remove_expired(local_now)).await {
+                Ok(0) => {}
+                Ok(nb) => info!(self.log, "{} expired overrides removed", nb),
+                Err(e) => slog::warn!(self.log, "impossible to remove the expired overrides: {}", e),
+            }
+            let alerts = self.alerts.clone();
+            // the alerts are written on disk, it is blocking
+            match actix_web::web::block(move || alerts.remove_expired(local_now)).await {
+                Ok(0) => {}
+                Ok(nb) => info!(self.log, "{} expired alerts removed", nb),
+                Err(e) => slog::warn!(self.log, "impossible to remove the expired alerts: {}", e),
+            }
+        }
+        let (rt_dataset, report) = self.make_rt_dataset(dataset, gtfs_rts, now)?;
+        if let Some(punctuality) = &self.punctuality {
+            let observations = punctuality.track(&rt_dataset, now);
+            let nb = observations.len();
+            let store = punctuality.store().clone();
+            // writing the observations is blocking, it is done out of the arbiter
+            match actix_web::web::block(move || store.append(&observations)).await {
+                Ok(()) => info!(self.log, "{} passed connections stored", nb),
+                Err(e) => slog::warn!(self.log, "impossible to store the passed connections: {}", e),
+            }
+        }
         // we send those data as a BaseScheduleReloader message, for the DatasetActor to load those new data
         self.dataset_actor
-            .do_send(UpdateRealtime(Arc::new(rt_dataset)));
+            .do_send(UpdateRealtime(Arc::new(rt_dataset), report));
         Ok(())
     }
 
     fn make_rt_dataset(
         &self,
         dataset: Arc<Result<Dataset, anyhow::Error>>,
-        gtfs_rts: Vec<GtfsRT>,
-    ) -> Result<RealTimeDataset, Error> {
-        let feed_messages: Vec<transit_realtime::FeedMessage> = gtfs_rts
-            .iter()
-            .filter_map(GtfsRT::decode_feed_message)
+        gtfs_rts: Vec<(String, GtfsRT)>,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(RealTimeDataset, MatchingReport), Error> {
+        // the format of the feeds is only known once the dataset is loaded
+        let dataset_info = dataset
+            .as_ref()
+            .as_ref()
+            .ok()
+            .map(|d| &d.feed_construction_info.dataset_info);
+        let has_siri_sources = dataset_info
+            .map(|info| info.realtime_sources.iter().any(|s| s.format.is_siri()))
+            .unwrap_or(false);
+        let estimate = dataset_info
+            .map(|info| info.estimate_from_vehicle_positions)
+            .unwrap_or(false);
+        let (siri_feeds, gtfs_rts): (Vec<_>, Vec<_>) =
+            gtfs_rts.into_iter().partition(|(url, _)| {
+                dataset_info
+                    .map(|info| info.realtime_format(url).is_siri())
+                    .unwrap_or(false)
+            });
+        let feed_messages: Vec<(String, transit_realtime::FeedMessage)> = gtfs_rts
+            .into_iter()
+            .filter_map(|(url, rt)| rt.decode_feed_message().map(|fm| (url, fm)))
+            .map(|(url, mut feed_message)| {
+                // only the alerts of the service alerts feeds are used
+                if dataset_info.map(|info| info.realtime_format(&url))
+                    == Some(RealtimeFormat::GtfsRtAlerts)
+                {
+                    feed_message.entity.retain(|e| e.alert.is_some());
+                }
+                (url, feed_message)
+            })
+            .collect();
+        let siris: Vec<(String, siri_reader::Siri)> = siri_feeds
+            .into_iter()
+            .filter_map(|(url, rt)| match siri_reader::read_siri(&rt.data) {
+                Ok(siri) => Some((url, siri)),
+                Err(e) => {
+                    slog::warn!(self.log, "unable to read siri feed {}: {}", url, e);
+                    None
+                }
+            })
             .collect();
 
-        let gtfs_rt = aggregate_rts(&feed_messages)?;
-        let updated_timetable = apply_rt_update(&dataset, &feed_messages, &self.log)?;
+        let overrides = self.overrides.overrides();
+        let (updated_timetable, report) =
+            apply_rt_update(&dataset, &feed_messages, &siris, &overrides, now, &self.log)?;
+        let regenerated = has_siri_sources || estimate || !overrides.is_empty();
+        let gtfs_rt = match (&*dataset, regenerated) {
+            // the GTFS-RT is generated from the realtime timetable, for it to also have the updates
+            // of the SIRI feeds, the overrides and the estimations
+            (Ok(data), true) => Some(encode_rt(
+                &gtfs_rt_writer::create_feed_message(
+                    data,
+                    &updated_timetable,
+                    &feed_messages,
+                    now,
+                ),
+                now,
+            )?),
+            // there is no GTFS-RT to aggregate (only some SIRI feeds, or no feed could be fetched)
+            _ if feed_messages.is_empty() => None,
+            _ => Some(aggregate_rts(&feed_messages, now)?),
+        };
 
-        Ok(RealTimeDataset {
-            base_schedule_dataset: dataset,
-            gtfs_rt: Some(gtfs_rt),
-            gtfs_rt_provider_urls: self.gtfs_rt_urls.clone(),
-            updated_timetable,
-        })
+        Ok((
+            RealTimeDataset {
+                base_schedule_dataset: dataset,
+                gtfs_rt,
+                gtfs_rt_provider_urls: self.gtfs_rt_urls.clone(),
+                updated_timetable,
+            },
+            report,
+        ))
     }
 }
 
@@ -221,6 +494,14 @@ impl actix::Actor for RealTimeReloader {
         info!(self.log, "Realtime updater actor started");
 
         ctx.run_interval(std::time::Duration::from_secs(60), |act, ctx| {
+            if act.cancellation.is_cancelled() {
+                info!(
+                    act.log,
+                    "dataset removed, stopping the realtime updater actor"
+                );
+                ctx.stop();
+                return;
+            }
             info!(act.log, "reloading realtime data");
             // Note: The actor is cloned there because of lifetime issue.
             // There should be a way to avoid this, but at the time of the writing
@@ -233,8 +514,9 @@ impl actix::Actor for RealTimeReloader {
     }
 }
 
-/// Message send to a DatasetActor to update its baseschedule data
-struct UpdateRealtime(Arc<RealTimeDataset>);
+/// Message send to a DatasetActor to update its realtime data,
+/// with the report of the matching of the realtime data with the base schedule
+pub(crate) struct UpdateRealtime(pub Arc<RealTimeDataset>, pub MatchingReport);
 
 impl actix::Message for UpdateRealtime {
     type Result = ();
@@ -245,5 +527,7 @@ impl actix::Handler<UpdateRealtime> for DatasetActor {
 
     fn handle(&mut self, params: UpdateRealtime, _ctx: &mut actix::Context<Self>) -> Self::Result {
         self.realtime = params.0;
+        self.diagnostics.add_report(chrono::Utc::now(), params.1);
+        self.notify_realtime_listeners();
     }
 }
@@ -0,0 +1,735 @@
+use crate::actors::{realtime_channel, Cancellation, DatasetActor, ListenRealtime};
+use crate::clock::Clock;
+use crate::datasets::RealTimeDataset;
+use crate::routes::subscription_estimated_timetable;
+use crate::siri_lite::subscription as siri;
+use actix::{ActorContext, AsyncContext, SpawnHandle, StreamHandler};
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Utc};
+use slog::{info, warn};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Number of times a notification is sent to a consumer before giving up
+const NB_DELIVERY_ATTEMPTS: u32 = 3;
+
+/// Maximum delay between 2 checks of the expired subscriptions and of the heartbeats to send
+const MAX_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+/// Minimum delay between 2 checks, the smaller heartbeat intervals are not honoured
+const MIN_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Limits of the SIRI subscriptions of each dataset,
+/// configured in the `subscriptions` section of the configuration file
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct SubscriptionsConfig {
+    /// hosts of the `ConsumerAddress` allowed. If empty, all the hosts are allowed,
+    /// except `localhost` and the loopback, private and link-local IPs
+    pub consumer_hosts: Vec<String>,
+    /// maximum number of subscriptions of a dataset
+    pub max_subscriptions: usize,
+    /// maximum number of subscriptions of a dataset made with an api key
+    pub max_subscriptions_by_key: usize,
+    /// a later `InitialTerminationTime` is bounded to this number of days
+    pub max_duration_days: i64,
+}
+
+impl Default for SubscriptionsConfig {
+    fn default() -> Self {
+        Self {
+            consumer_hosts: vec![],
+            max_subscriptions: 1000,
+            max_subscriptions_by_key: 10,
+            max_duration_days: 7,
+        }
+    }
+}
+
+impl SubscriptionsConfig {
+    /// check that the notifications can be sent to a consumer address
+    fn check_consumer_address(&self, address: &str) -> Result<(), String> {
+        let url = url::Url::parse(address)
+            .map_err(|e| format!("invalid ConsumerAddress '{}': {}", address, e))?;
+        let host = url
+            .host()
+            .filter(|_| url.scheme() == "http" || url.scheme() == "https")
+            .ok_or_else(|| format!("the ConsumerAddress '{}' is not an http url", address))?;
+        let allowed = if self.consumer_hosts.is_empty() {
+            match host {
+                url::Host::Domain(domain) => !domain.eq_ignore_ascii_case("localhost"),
+                url::Host::Ipv4(ip) => {
+                    !(ip.is_loopback()
+                        || ip.is_private()
+                        || ip.is_link_local()
+                        || ip.is_unspecified())
+                }
+                url::Host::Ipv6(ip) => {
+                    // the unique local (fc00::/7) and link-local (fe80::/10) addresses
+                    let segment = ip.segments()[0];
+                    !(ip.is_loopback()
+                        || ip.is_unspecified()
+                        || segment & 0xfe00 == 0xfc00
+                        || segment & 0xffc0 == 0xfe80)
+                }
+            }
+        } else {
+            let host = host.to_string();
+            self.consumer_hosts
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case(&host))
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(format!("the ConsumerAddress '{}' is not allowed", address))
+        }
+    }
+}
+
+/// A subscription is identified by its subscriber and its subscription_ref,
+/// 2 subscribers can use the same subscription_ref
+pub type SubscriptionKey = (String, String);
+
+/// A SIRI subscription of a consumer to the estimated timetable of a dataset
+#[derive(Clone, Debug)]
+pub struct Subscription {
+    /// api key of the consumer that made the subscription, only this consumer can terminate it
+    pub api_key: String,
+    pub subscriber_ref: String,
+    pub subscription_ref: String,
+    /// url where the ServiceDelivery and the heartbeats are POSTed
+    pub consumer_address: String,
+    pub initial_termination_time: DateTime<Utc>,
+    pub heartbeat_interval: Option<chrono::Duration>,
+    pub line_ref: Option<String>,
+    pub monitoring_ref: Option<String>,
+    /// datetime of the last ServiceDelivery or HeartbeatNotification sent
+    pub last_notification: DateTime<Utc>,
+}
+
+impl Subscription {
+    pub fn key(&self) -> SubscriptionKey {
+        (self.subscriber_ref.clone(), self.subscription_ref.clone())
+    }
+}
+
+/// Actor that handles the SIRI subscriptions of a dataset
+/// Each time the DatasetActor loads new realtime data, a ServiceDelivery is POSTed
+/// to each subscriber
+pub struct SubscriptionActor {
+    pub dataset_id: String,
+    pub dataset_actor: actix::Addr<DatasetActor>,
+    /// current time of the dataset, used for the expirations and the heartbeats
+    pub clock: Clock,
+    /// the subscriptions by subscriber_ref and subscription_ref
+    pub subscriptions: BTreeMap<SubscriptionKey, Subscription>,
+    pub config: SubscriptionsConfig,
+    /// cancelled when the dataset is removed, the actor then stops
+    pub cancellation: Cancellation,
+    pub retry_delay: std::time::Duration,
+    pub client: reqwest::Client,
+    pub log: slog::Logger,
+    /// timer of the checks of the expired subscriptions and of the heartbeats
+    checks: Option<SpawnHandle>,
+}
+
+impl SubscriptionActor {
+    pub fn new(
+        dataset_id: &str,
+        dataset_actor: actix::Addr<DatasetActor>,
+        clock: Clock,
+        config: SubscriptionsConfig,
+        cancellation: Cancellation,
+        log: slog::Logger,
+    ) -> Self {
+        Self {
+            dataset_id: dataset_id.to_owned(),
+            dataset_actor,
+            clock,
+            subscriptions: BTreeMap::new(),
+            config,
+            cancellation,
+            retry_delay: std::time::Duration::from_secs(2),
+            client: reqwest::Client::new(),
+            log,
+            checks: None,
+        }
+    }
+
+    /// check the limits of the subscriptions before adding (or replacing) a subscription,
+    /// and bound its termination time
+    fn check_new_subscription(&self, subscription: &mut Subscription) -> Result<(), String> {
+        self.config
+            .check_consumer_address(&subscription.consumer_address)?;
+        match self.subscriptions.get(&subscription.key()) {
+            Some(existing) if existing.api_key != subscription.api_key => {
+                return Err(format!(
+                    "the subscription '{}' already exists for another consumer",
+                    &subscription.subscription_ref
+                ));
+            }
+            // a subscription is replaced, the number of subscriptions does not change
+            Some(_) => {}
+            None => {
+                if self.subscriptions.len() >= self.config.max_subscriptions {
+                    return Err("too many subscriptions".to_owned());
+                }
+                let nb_subscriptions_of_key = self
+                    .subscriptions
+                    .values()
+                    .filter(|s| s.api_key == subscription.api_key)
+                    .count();
+                if nb_subscriptions_of_key >= self.config.max_subscriptions_by_key {
+                    return Err(format!(
+                        "too many subscriptions, at most {} subscriptions are allowed",
+                        self.config.max_subscriptions_by_key
+                    ));
+                }
+            }
+        }
+        let max_termination_time =
+            self.clock.now() + chrono::Duration::days(self.config.max_duration_days);
+        subscription.initial_termination_time = subscription
+            .initial_termination_time
+            .min(max_termination_time);
+        Ok(())
+    }
+
+    /// stop the actor if the dataset has been removed, returns true if it is stopped
+    fn stop_if_cancelled(&self, ctx: &mut actix::Context<Self>) -> bool {
+        if self.cancellation.is_cancelled() {
+            info!(self.log, "dataset removed, stopping the subscription actor");
+            ctx.stop();
+            return true;
+        }
+        false
+    }
+
+    /// The expired subscriptions and the heartbeats are checked every 10 seconds,
+    /// or more often if a subscriber asked for a smaller heartbeat interval
+    fn check_interval(&self) -> std::time::Duration {
+        self.subscriptions
+            .values()
+            .filter_map(|s| s.heartbeat_interval)
+            .filter_map(|i| i.to_std().ok())
+            .min()
+            .map_or(MAX_CHECK_INTERVAL, |i| {
+                i.max(MIN_CHECK_INTERVAL).min(MAX_CHECK_INTERVAL)
+            })
+    }
+
+    /// (re)schedule the checks of the subscriptions, to be called when the subscriptions change
+    fn schedule_checks(&mut self, ctx: &mut actix::Context<Self>) {
+        if let Some(handle) = self.checks.take() {
+            ctx.cancel_future(handle);
+        }
+        self.checks = Some(ctx.run_interval(self.check_interval(), |act, ctx| {
+            if act.stop_if_cancelled(ctx) {
+                return;
+            }
+            if act.remove_expired_subscriptions() {
+                act.schedule_checks(ctx);
+            }
+            act.send_heartbeats();
+        }));
+    }
+
+    fn send(&self, url: &str, body: String) {
+        let client = self.client.clone();
+        let url = url.to_owned();
+        let retry_delay = self.retry_delay;
+        let log = self.log.clone();
+        actix_rt::spawn(async move {
+            if let Err(e) = post_with_retries(&client, &url, body, retry_delay).await {
+                warn!(log, "impossible to notify subscriber: {}", e);
+            }
+        });
+    }
+
+    /// remove the expired subscriptions, returns true if some have been removed
+    fn remove_expired_subscriptions(&mut self) -> bool {
+        let now = self.clock.now();
+        let expired: Vec<Subscription> = self
+            .subscriptions
+            .values()
+            .filter(|s| s.initial_termination_time <= now)
+            .cloned()
+            .collect();
+        let has_expired = !expired.is_empty();
+        for s in expired {
+            info!(self.log, "subscription {} has expired", &s.subscription_ref);
+            self.subscriptions.remove(&s.key());
+            self.send(
+                &s.consumer_address,
+                siri::subscription_terminated_notification_xml(
+                    &self.dataset_id,
+                    &s.subscriber_ref,
+                    &s.subscription_ref,
+                    now,
+                ),
+            );
+        }
+        has_expired
+    }
+
+    fn send_heartbeats(&mut self) {
+        let now = self.clock.now();
+        let mut consumers = vec![];
+        for s in self.subscriptions.values_mut() {
+            if let Some(heartbeat_interval) = s.heartbeat_interval {
+                if now - s.last_notification >= heartbeat_interval {
+                    s.last_notification = now;
+                    consumers.push(s.consumer_address.clone());
+                }
+            }
+        }
+        for consumer in consumers {
+            self.send(
+                &consumer,
+                siri::heartbeat_notification_xml(&self.dataset_id, now),
+            );
+        }
+    }
+}
+
+/// POST a notification to a consumer
+/// If the consumer does not respond successfully, the notification is sent again
+/// with an exponential backoff
+pub(crate) async fn post_with_retries(
+    client: &reqwest::Client,
+    url: &str,
+    body: String,
+    retry_delay: std::time::Duration,
+) -> Result<(), Error> {
+    let mut delay = retry_delay;
+    let mut last_error = None;
+    for attempt in 0..NB_DELIVERY_ATTEMPTS {
+        if attempt > 0 {
+            actix_rt::time::delay_for(delay).await;
+            delay *= 2;
+        }
+        let res = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/xml")
+            .body(body.clone())
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        match res {
+            Ok(_) => return Ok(()),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(anyhow!(
+        "notification to {} failed after {} attempts: {}",
+        url,
+        NB_DELIVERY_ATTEMPTS,
+        last_error.map(|e| e.to_string()).unwrap_or_default()
+    ))
+}
+
+impl actix::Actor for SubscriptionActor {
+    type Context = actix::Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!(self.log, "Starting the subscription actor");
+        let (listener, receiver) = realtime_channel();
+        self.dataset_actor.do_send(ListenRealtime(listener));
+        ctx.add_stream(receiver);
+        self.schedule_checks(ctx);
+    }
+}
+
+impl StreamHandler<Arc<RealTimeDataset>> for SubscriptionActor {
+    fn handle(&mut self, rt_dataset: Arc<RealTimeDataset>, ctx: &mut Self::Context) {
+        if self.stop_if_cancelled(ctx) {
+            return;
+        }
+        if self.remove_expired_subscriptions() {
+            self.schedule_checks(ctx);
+        }
+        let now = self.clock.now();
+        let mut deliveries = vec![];
+        for s in self.subscriptions.values_mut() {
+            match subscription_estimated_timetable(&rt_dataset, s, now) {
+                Ok(delivery) => {
+                    s.last_notification = now;
+                    deliveries.push((s.consumer_address.clone(), delivery));
+                }
+                Err(e) => warn!(
+                    self.log,
+                    "impossible to create delivery for subscription {}: {}",
+                    &s.subscription_ref,
+                    e
+                ),
+            }
+        }
+        for (consumer, delivery) in deliveries {
+            self.send(&consumer, delivery);
+        }
+    }
+
+    fn finished(&mut self, _ctx: &mut Self::Context) {
+        // the DatasetActor is gone, there will be no more realtime data to send
+        warn!(self.log, "no more realtime data for the subscriptions");
+    }
+}
+
+/// Message send to a SubscriptionActor to add (or replace) a subscription.
+/// The subscription is refused if it exceeds the limits of the subscriptions
+#[derive(actix::Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct AddSubscription(pub Subscription);
+
+impl actix::Handler<AddSubscription> for SubscriptionActor {
+    type Result = Result<(), String>;
+
+    fn handle(
+        &mut self,
+        mut params: AddSubscription,
+        ctx: &mut actix::Context<Self>,
+    ) -> Self::Result {
+        self.check_new_subscription(&mut params.0)?;
+        info!(
+            self.log,
+            "new subscription {} for {}",
+            &params.0.subscription_ref,
+            &params.0.consumer_address
+        );
+        self.subscriptions.insert(params.0.key(), params.0);
+        self.schedule_checks(ctx);
+        Ok(())
+    }
+}
+
+/// Message send to a SubscriptionActor to remove some subscriptions of a subscriber
+/// If no subscription_ref is given, all the subscriber's subscriptions are removed.
+/// Only the subscriptions made with the api key are removed.
+/// The removed subscription_refs are returned
+#[derive(actix::Message)]
+#[rtype(result = "Vec<String>")]
+pub struct TerminateSubscriptions {
+    pub api_key: String,
+    pub subscriber_ref: String,
+    pub subscription_refs: Vec<String>,
+}
+
+impl actix::Handler<TerminateSubscriptions> for SubscriptionActor {
+    type Result = Vec<String>;
+
+    fn handle(
+        &mut self,
+        params: TerminateSubscriptions,
+        ctx: &mut actix::Context<Self>,
+    ) -> Self::Result {
+        let to_remove: Vec<String> = self
+            .subscriptions
+            .values()
+            .filter(|s| s.subscriber_ref == params.subscriber_ref && s.api_key == params.api_key)
+            .filter(|s| {
+                params.subscription_refs.is_empty()
+                    || params.subscription_refs.contains(&s.subscription_ref)
+            })
+            .map(|s| s.subscription_ref.clone())
+            .collect();
+        for subscription_ref in &to_remove {
+            info!(self.log, "subscription {} terminated", subscription_ref);
+            self.subscriptions
+                .remove(&(params.subscriber_ref.clone(), subscription_ref.clone()));
+        }
+        if !to_remove.is_empty() {
+            self.schedule_checks(ctx);
+        }
+        to_remove
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::actors::UpdateRealtime;
+    use crate::datasets::{Dataset, DatasetInfo, Period};
+    use crate::diagnostics::MatchingReport;
+    use actix::Actor;
+    use mockito::{mock, Matcher};
+
+    #[actix_rt::test]
+    async fn notification_is_sent_once_on_success() {
+        let consumer = mock("POST", "/consumer_ok").with_status(200).expect(1).create();
+        let client = reqwest::Client::new();
+        let res = post_with_retries(
+            &client,
+            &format!("{}/consumer_ok", mockito::SERVER_URL),
+            "<Siri/>".to_owned(),
+            std::time::Duration::from_millis(1),
+        )
+        .await;
+        assert!(res.is_ok());
+        consumer.assert();
+    }
+
+    #[actix_rt::test]
+    async fn notification_is_retried_on_failure() {
+        let consumer = mock("POST", "/consumer_ko")
+            .with_status(500)
+            .expect(super::NB_DELIVERY_ATTEMPTS as usize)
+            .create();
+        let client = reqwest::Client::new();
+        let res = post_with_retries(
+            &client,
+            &format!("{}/consumer_ko", mockito::SERVER_URL),
+            "<Siri/>".to_owned(),
+            std::time::Duration::from_millis(1),
+        )
+        .await;
+        assert!(res.is_err());
+        consumer.assert();
+    }
+
+    fn subscription(subscriber_ref: &str, consumer: &str) -> Subscription {
+        Subscription {
+            api_key: format!("key_{}", subscriber_ref),
+            subscriber_ref: subscriber_ref.to_owned(),
+            subscription_ref: "1".to_owned(),
+            consumer_address: format!("{}/{}", mockito::SERVER_URL, consumer),
+            initial_termination_time: Utc::now() + chrono::Duration::hours(1),
+            heartbeat_interval: None,
+            line_ref: None,
+            monitoring_ref: None,
+            last_notification: Utc::now(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn subscribers_are_notified_until_termination() {
+        let period = Period {
+            begin: chrono::NaiveDate::from_ymd(2018, 12, 15),
+            horizon: chrono::Duration::days(1),
+        };
+        let dataset = Arc::new(Dataset::try_from_dataset_info(
+            DatasetInfo::new_default("fixtures/gtfs.zip", &[]),
+            &period,
+            None,
+        ));
+        let rt_dataset = Arc::new(RealTimeDataset::new(dataset.clone(), &[]));
+        let dataset_actor = DatasetActor::new(dataset, rt_dataset.clone()).start();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let config = SubscriptionsConfig {
+            consumer_hosts: vec!["127.0.0.1".to_owned()],
+            ..Default::default()
+        };
+        let subscription_actor = SubscriptionActor::new(
+            "test",
+            dataset_actor.clone(),
+            Clock::default(),
+            config,
+            Cancellation::default(),
+            log,
+        )
+        .start();
+
+        // 2 subscribers use the same subscription_ref
+        let consumer_a = mock("POST", "/subscriber_a")
+            .match_body(Matcher::Regex(
+                "<SubscriptionRef>1</SubscriptionRef>".to_owned(),
+            ))
+            .with_status(200)
+            .expect(1)
+            .create();
+        let consumer_b = mock("POST", "/subscriber_b")
+            .with_status(200)
+            .expect(2)
+            .create();
+        subscription_actor
+            .send(AddSubscription(subscription("A", "subscriber_a")))
+            .await
+            .unwrap()
+            .unwrap();
+        subscription_actor
+            .send(AddSubscription(subscription("B", "subscriber_b")))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // each new realtime dataset is sent to both subscribers
+        dataset_actor
+            .send(UpdateRealtime(
+                rt_dataset.clone(),
+                MatchingReport::default(),
+            ))
+            .await
+            .unwrap();
+        actix_rt::time::delay_for(std::time::Duration::from_millis(300)).await;
+
+        // only the consumer that made the subscription can terminate it
+        let terminated = subscription_actor
+            .send(TerminateSubscriptions {
+                api_key: "key_B".to_owned(),
+                subscriber_ref: "A".to_owned(),
+                subscription_refs: vec![],
+            })
+            .await
+            .unwrap();
+        assert!(terminated.is_empty());
+        let terminated = subscription_actor
+            .send(TerminateSubscriptions {
+                api_key: "key_A".to_owned(),
+                subscriber_ref: "A".to_owned(),
+                subscription_refs: vec!["1".to_owned()],
+            })
+            .await
+            .unwrap();
+        assert_eq!(terminated, vec!["1".to_owned()]);
+
+        // only B is still notified
+        dataset_actor
+            .send(UpdateRealtime(rt_dataset, MatchingReport::default()))
+            .await
+            .unwrap();
+        actix_rt::time::delay_for(std::time::Duration::from_millis(300)).await;
+
+        consumer_a.assert();
+        consumer_b.assert();
+    }
+
+    #[actix_rt::test]
+    async fn subscriptions_stop_with_the_dataset() {
+        let period = Period {
+            begin: chrono::NaiveDate::from_ymd(2018, 12, 15),
+            horizon: chrono::Duration::days(1),
+        };
+        let dataset = Arc::new(Dataset::try_from_dataset_info(
+            DatasetInfo::new_default("fixtures/gtfs.zip", &[]),
+            &period,
+            None,
+        ));
+        let rt_dataset = Arc::new(RealTimeDataset::new(dataset.clone(), &[]));
+        let dataset_actor = DatasetActor::new(dataset, rt_dataset.clone()).start();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let config = SubscriptionsConfig {
+            consumer_hosts: vec!["127.0.0.1".to_owned()],
+            ..Default::default()
+        };
+        let cancellation = Cancellation::default();
+        let subscription_actor = SubscriptionActor::new(
+            "test",
+            dataset_actor.clone(),
+            Clock::default(),
+            config,
+            cancellation.clone(),
+            log,
+        )
+        .start();
+        let consumer = mock("POST", "/removed_dataset_consumer")
+            .with_status(200)
+            .expect(0)
+            .create();
+        subscription_actor
+            .send(AddSubscription(subscription(
+                "A",
+                "removed_dataset_consumer",
+            )))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // once the dataset is removed, the subscribers are no longer notified
+        cancellation.cancel();
+        dataset_actor
+            .send(UpdateRealtime(rt_dataset, MatchingReport::default()))
+            .await
+            .unwrap();
+        actix_rt::time::delay_for(std::time::Duration::from_millis(300)).await;
+
+        assert!(!subscription_actor.connected());
+        consumer.assert();
+    }
+
+    #[test]
+    fn consumer_addresses() {
+        let config = SubscriptionsConfig::default();
+        assert!(config
+            .check_consumer_address("https://consumer.example.com/siri")
+            .is_ok());
+        for address in &[
+            "http://localhost:8080/siri",
+            "http://127.0.0.1/siri",
+            "http://169.254.169.254/latest/meta-data",
+            "http://10.1.2.3/siri",
+            "http://[::1]/siri",
+            "ftp://consumer.example.com/siri",
+            "not an url",
+        ] {
+            assert!(
+                config.check_consumer_address(address).is_err(),
+                "{}",
+                address
+            );
+        }
+
+        let config = SubscriptionsConfig {
+            consumer_hosts: vec!["consumer.example.com".to_owned()],
+            ..Default::default()
+        };
+        assert!(config
+            .check_consumer_address("https://consumer.example.com/siri")
+            .is_ok());
+        assert!(config
+            .check_consumer_address("https://other.example.com/siri")
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn subscriptions_are_limited() {
+        let period = Period {
+            begin: chrono::NaiveDate::from_ymd(2018, 12, 15),
+            horizon: chrono::Duration::days(1),
+        };
+        let dataset = Arc::new(Dataset::try_from_dataset_info(
+            DatasetInfo::new_default("fixtures/gtfs.zip", &[]),
+            &period,
+            None,
+        ));
+        let rt_dataset = Arc::new(RealTimeDataset::new(dataset.clone(), &[]));
+        let dataset_actor = DatasetActor::new(dataset, rt_dataset).start();
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let config = SubscriptionsConfig {
+            consumer_hosts: vec!["127.0.0.1".to_owned()],
+            max_subscriptions: 3,
+            max_subscriptions_by_key: 2,
+            max_duration_days: 1,
+        };
+        let subscription_actor = SubscriptionActor::new(
+            "test",
+            dataset_actor,
+            Clock::default(),
+            config,
+            Cancellation::default(),
+            log,
+        )
+        .start();
+        let add = |subscriber_ref: &str, subscription_ref: &str| {
+            let mut s = subscription(subscriber_ref, "consumer");
+            s.subscription_ref = subscription_ref.to_owned();
+            s.initial_termination_time = Utc::now() + chrono::Duration::days(365);
+            subscription_actor.send(AddSubscription(s))
+        };
+
+        assert_eq!(add("A", "1").await.unwrap(), Ok(()));
+        assert_eq!(add("A", "2").await.unwrap(), Ok(()));
+        // a subscription can be replaced
+        assert_eq!(add("A", "2").await.unwrap(), Ok(()));
+        assert!(add("A", "3").await.unwrap().is_err());
+        assert_eq!(add("B", "1").await.unwrap(), Ok(()));
+        assert!(add("C", "1").await.unwrap().is_err());
+
+        // a consumer cannot replace the subscription of another consumer
+        let mut stolen = subscription("A", "consumer");
+        stolen.api_key = "key_B".to_owned();
+        assert!(subscription_actor
+            .send(AddSubscription(stolen))
+            .await
+            .unwrap()
+            .is_err());
+    }
+}
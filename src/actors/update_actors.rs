@@ -1,11 +1,49 @@
-use crate::actors::DatasetActor;
-use crate::datasets::{Dataset, FeedConstructionInfo};
-use actix::AsyncContext;
+use crate::actors::{
+    BuildDataset, BuildProgress, BuildStep, Cancellation, DatasetActor, DatasetBuilder,
+    ReportBuildProgress,
+};
+use crate::cache::DataCache;
+use crate::clock::Clock;
+use crate::datasets::{
+    is_loading, Dataset, FeedConstructionInfo, RealTimeDataset, ReloadFailure,
+};
+use actix::fut::WrapFuture;
+use actix::{ActorContext, ActorFuture, AsyncContext};
 use slog::info;
 use std::sync::Arc;
 
+/// delay before retrying a failed reloading, doubled after each failure
+const FIRST_RETRY_DELAY: u64 = 5 * 60;
+const MAX_RETRY_DELAY: u64 = 6 * 60 * 60;
+
+/// The dataset currently served
+#[derive(Clone, Debug)]
+pub struct LoadedDataset {
+    /// version of the dataset's sources
+    pub version: String,
+    /// first day of the loaded period
+    pub period_begin: chrono::NaiveDate,
+    pub validity_end: Option<chrono::NaiveDate>,
+    pub timezone: chrono_tz::Tz,
+}
+
+impl LoadedDataset {
+    pub fn new(dataset: &Dataset, period_begin: chrono::NaiveDate) -> Self {
+        Self {
+            version: dataset.version.clone(),
+            period_begin,
+            validity_end: dataset.validity_end,
+            timezone: dataset.timezone,
+        }
+    }
+}
+
 /// Actor that once in a while reload the BaseSchedule data (GTFS)
 /// and send them to the DatasetActor
+///
+/// If the reloading fails, the previous dataset is kept (and marked as stale)
+/// until the dataset's `StaleDatasetPolicy` drops it,
+/// and the reloading is retried with an exponential backoff
 pub struct BaseScheduleReloader {
     pub feed_construction_info: FeedConstructionInfo,
 
@@ -14,36 +52,177 @@ pub struct BaseScheduleReloader {
     // but if we have several instances of DatasetActor we could have a list of recipient here
     pub dataset_actor: actix::Addr<DatasetActor>,
     pub log: slog::Logger,
+    /// if a cache is given, the sources are only reloaded if they have changed
+    pub cache: Option<Arc<DataCache>>,
+    /// the dataset currently served, if any
+    pub loaded: Option<LoadedDataset>,
+    reload_failure: Option<ReloadFailure>,
+    /// pool of workers building the datasets
+    builder: actix::Addr<DatasetBuilder>,
+    /// once cancelled, the actor stops reloading the dataset
+    cancellation: Cancellation,
+    /// current time of the dataset, the reloaded period begins on its day
+    clock: Clock,
+}
+
+fn retry_delay(failed_attempts: u32) -> std::time::Duration {
+    let factor = 2u64.saturating_pow(failed_attempts.saturating_sub(1));
+    std::time::Duration::from_secs(
+        FIRST_RETRY_DELAY
+            .saturating_mul(factor)
+            .min(MAX_RETRY_DELAY),
+    )
 }
 
 impl BaseScheduleReloader {
-    fn update_data(&self, ctx: &mut actix::Context<Self>) {
-        slog_scope::scope(&self.log, || {
-            let new_dataset = Dataset::try_from_dataset_info(
-                self.feed_construction_info.dataset_info.clone(),
-                &crate::datasets::Period {
-                    begin: chrono::Local::today().naive_local(),
-                    horizon: self.feed_construction_info.generation_period.horizon,
-                },
-            );
+    pub fn new(
+        feed_construction_info: FeedConstructionInfo,
+        dataset_actor: actix::Addr<DatasetActor>,
+        log: slog::Logger,
+        cache: Option<Arc<DataCache>>,
+        loaded: Option<LoadedDataset>,
+        builder: actix::Addr<DatasetBuilder>,
+        cancellation: Cancellation,
+        clock: Clock,
+    ) -> Self {
+        Self {
+            feed_construction_info,
+            dataset_actor,
+            log,
+            cache,
+            loaded,
+            reload_failure: None,
+            builder,
+            cancellation,
+            clock,
+        }
+    }
 
-            if let Err(e) = &new_dataset {
-                log::warn!("impossible to update dataset because of: {}", e);
-                log::warn!("rescheduling data loading in 5 mn");
+    /// the current day in the timezone of the dataset
+    /// (before the first loading, the timezone is not known and the local timezone is used)
+    fn today(&self) -> chrono::NaiveDate {
+        let now = self.clock.now();
+        match &self.loaded {
+            Some(loaded) => now.with_timezone(&loaded.timezone).date().naive_local(),
+            None => now.with_timezone(&chrono::Local).date().naive_local(),
+        }
+    }
+
+    /// Build the dataset in the `DatasetBuilder` pool, and send it to the DatasetActor once built.
+    /// The actor waits for the building to be finished before handling other events,
+    /// so there is only one building at a time for a dataset
+    fn update_data(&mut self, ctx: &mut actix::Context<Self>) {
+        if self.cancellation.is_cancelled() {
+            info!(self.log, "dataset removed, stopping the base schedule updater actor");
+            ctx.stop();
+            return;
+        }
+        let period = crate::datasets::Period {
+            begin: self.today(),
+            horizon: self.feed_construction_info.generation_period.horizon,
+        };
+        // if the sources have not changed since the loading of the current period,
+        // there is no need to build the dataset again
+        let unchanged_version = self
+            .loaded
+            .as_ref()
+            .filter(|l| l.period_begin == period.begin)
+            .map(|l| l.version.clone());
+        self.dataset_actor
+            .do_send(ReportBuildProgress(Some(BuildProgress {
+                step: BuildStep::Queued,
+                since: self.clock.now(),
+            })));
+        self.builder
+            .send(BuildDataset {
+                dataset_info: self.feed_construction_info.dataset_info.clone(),
+                generation_period: period.clone(),
+                cache: self.cache.clone(),
+                unchanged_version,
+                use_cached_dataset: false,
+                dataset_actor: self.dataset_actor.clone(),
+                cancellation: self.cancellation.clone(),
+                started: None,
+                log: self.log.clone(),
+            })
+            .into_actor(self)
+            .map(move |res, act, ctx| {
+                let res = res
+                    .map_err(|e| anyhow::anyhow!("dataset builder mailbox error: {}", e))
+                    .and_then(|r| r);
+                let log = act.log.clone();
+                slog_scope::scope(&log, || match res {
+                    Ok(None) => {
+                        log::info!("base schedule has not changed, no need to reload it");
+                        act.reload_failure = None;
+                    }
+                    Ok(Some(dataset)) => {
+                        act.loaded = Some(LoadedDataset::new(&dataset, period.begin));
+                        act.reload_failure = None;
+                        act.dataset_actor
+                            .do_send(UpdateBaseSchedule(Arc::new(Ok(dataset))));
+                    }
+                    Err(_) if act.cancellation.is_cancelled() => ctx.stop(),
+                    Err(e) => act.handle_failure(e, ctx),
+                })
+            })
+            .wait(ctx);
+    }
+
+    fn handle_failure(&mut self, error: anyhow::Error, ctx: &mut actix::Context<Self>) {
+        let now = self.clock.now();
+        let failed_attempts = self
+            .reload_failure
+            .as_ref()
+            .map(|f| f.failed_attempts)
+            .unwrap_or(0)
+            + 1;
+        let delay = retry_delay(failed_attempts);
+        log::warn!("impossible to update dataset because of: {}", error);
+        log::warn!("rescheduling data loading in {}s", delay.as_secs());
+
+        // trace error in sentry
+        sentry::Hub::current().configure_scope(|scope| {
+            scope.set_tag("dataset", &self.feed_construction_info.dataset_info.id);
+        });
+        sentry::integrations::anyhow::capture_anyhow(&error);
+
+        ctx.run_later(delay, |act, ctx| act.update_data(ctx));
 
-                // trace error in sentry
-                sentry::Hub::current().configure_scope(|scope| {
-                    scope.set_tag("dataset", &self.feed_construction_info.dataset_info.id);
-                });
-                sentry::integrations::anyhow::capture_anyhow(&e);
+        let failure = ReloadFailure {
+            stale_since: self
+                .reload_failure
+                .as_ref()
+                .map(|f| f.stale_since)
+                .unwrap_or(now),
+            failed_attempts,
+            last_error: format!("{}", error),
+            next_attempt: now
+                + chrono::Duration::from_std(delay).unwrap_or_else(|_| chrono::Duration::zero()),
+        };
 
-                ctx.run_later(std::time::Duration::from_secs(5 * 60), |act, ctx| {
-                    act.update_data(ctx)
-                });
+        let policy = &self.feed_construction_info.dataset_info.stale_dataset_policy;
+        let keep_dataset = self
+            .loaded
+            .as_ref()
+            .map(|l| !policy.must_drop(l.validity_end, failure.stale_since, now))
+            .unwrap_or(false);
+        if keep_dataset {
+            log::warn!(
+                "keeping the previous dataset, stale since {}",
+                failure.stale_since
+            );
+        } else {
+            if self.loaded.is_some() {
+                log::warn!("the previous dataset is dropped, as per the {:?} policy", policy);
             }
+            self.loaded = None;
             self.dataset_actor
-                .do_send(UpdateBaseSchedule(Arc::new(new_dataset)));
-        });
+                .do_send(UpdateBaseSchedule(Arc::new(Err(error))));
+        }
+        // the failure is reported even if the dataset is dropped, until a successful reloading
+        self.dataset_actor.do_send(MarkStale(failure.clone()));
+        self.reload_failure = Some(failure);
     }
 }
 
@@ -52,6 +231,20 @@ impl actix::Actor for BaseScheduleReloader {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         info!(self.log, "Starting the base schedule updater actor");
+        if self.cache.is_some() {
+            // the dataset may have been loaded from the cache without checking its sources,
+            // so we check them as soon as possible
+            ctx.run_later(std::time::Duration::from_secs(0), |act, ctx| {
+                info!(act.log, "checking if the cached base schedule is up to date");
+                act.update_data(ctx);
+            });
+        } else if self.loaded.is_none() {
+            // the first loading has failed, we retry it
+            ctx.run_later(retry_delay(1), |act, ctx| {
+                info!(act.log, "retrying to load the baseschedule data");
+                act.update_data(ctx);
+            });
+        }
         ctx.run_interval(std::time::Duration::from_secs(60 * 60 * 24), |act, ctx| {
             info!(act.log, "reloading baseschedule data");
             act.update_data(ctx);
@@ -60,7 +253,7 @@ impl actix::Actor for BaseScheduleReloader {
 }
 
 /// Message send to a DatasetActor to update its baseschedule data
-struct UpdateBaseSchedule(Arc<Result<Dataset, anyhow::Error>>);
+pub struct UpdateBaseSchedule(pub Arc<Result<Dataset, anyhow::Error>>);
 
 impl actix::Message for UpdateBaseSchedule {
     type Result = ();
@@ -75,5 +268,119 @@ impl actix::Handler<UpdateBaseSchedule> for DatasetActor {
         _ctx: &mut actix::Context<Self>,
     ) -> Self::Result {
         self.gtfs = params.0;
+        // a dropped dataset keeps its reload failure, it is only cleared by a successful reloading
+        if self.gtfs.is_ok() {
+            self.reload_failure = None;
+        }
+        // while the dataset was loading there was no realtime data to keep,
+        // the realtime dataset is only based on the loaded dataset
+        if is_loading(&self.realtime.base_schedule_dataset) {
+            self.realtime = Arc::new(RealTimeDataset::new(
+                self.gtfs.clone(),
+                &self.realtime.gtfs_rt_provider_urls,
+            ));
+            self.notify_realtime_listeners();
+        }
+    }
+}
+
+/// Message send to a DatasetActor when its baseschedule data cannot be reloaded
+/// (the previous data being still served or dropped, depending on the `StaleDatasetPolicy`)
+pub struct MarkStale(pub ReloadFailure);
+
+impl actix::Message for MarkStale {
+    type Result = ();
+}
+
+impl actix::Handler<MarkStale> for DatasetActor {
+    type Result = ();
+
+    fn handle(&mut self, params: MarkStale, _ctx: &mut actix::Context<Self>) -> Self::Result {
+        self.reload_failure = Some(params.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{retry_delay, BaseScheduleReloader, LoadedDataset, UpdateBaseSchedule};
+    use crate::actors::{Cancellation, DatasetActor, DatasetBuilder, GetBuildStatus, GetDataset};
+    use crate::clock::Clock;
+    use crate::datasets::{Dataset, DatasetInfo, FeedConstructionInfo, Period, RealTimeDataset};
+    use actix::Actor;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1).as_secs(), 5 * 60);
+        assert_eq!(retry_delay(2).as_secs(), 10 * 60);
+        assert_eq!(retry_delay(3).as_secs(), 20 * 60);
+        assert_eq!(retry_delay(10).as_secs(), 6 * 60 * 60);
+        assert_eq!(retry_delay(100).as_secs(), 6 * 60 * 60);
+    }
+
+    #[actix_rt::test]
+    async fn failed_reload_keeps_the_previous_dataset() {
+        let period = Period {
+            begin: chrono::NaiveDate::from_ymd(2018, 12, 15),
+            horizon: chrono::Duration::days(1),
+        };
+        let dataset = Arc::new(Dataset::try_from_dataset_info(
+            DatasetInfo::new_default("fixtures/gtfs.zip", &[]),
+            &period,
+            None,
+        ));
+        let rt_dataset = Arc::new(RealTimeDataset::new(dataset.clone(), &[]));
+        let dataset_actor = DatasetActor::new(dataset, rt_dataset).start();
+
+        // the sources of the dataset are not available anymore
+        let feed_construction_info = FeedConstructionInfo {
+            dataset_info: DatasetInfo::new_default("fixtures/missing.zip", &[]),
+            generation_period: period,
+        };
+        let loaded = LoadedDataset {
+            version: "previous".to_owned(),
+            period_begin: chrono::NaiveDate::from_ymd(2018, 12, 15),
+            validity_end: Some(chrono::Local::today().naive_local() + chrono::Duration::days(30)),
+            timezone: chrono_tz::UTC,
+        };
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let builder = DatasetBuilder::start_pool(1);
+        let _reloader = BaseScheduleReloader::create(|ctx| {
+            let mut reloader = BaseScheduleReloader::new(
+                feed_construction_info,
+                dataset_actor.clone(),
+                log,
+                None,
+                Some(loaded),
+                builder,
+                Cancellation::default(),
+                Clock::default(),
+            );
+            reloader.update_data(ctx);
+            reloader
+        });
+
+        let mut reload_failure = None;
+        for _ in 0..100 {
+            let status = dataset_actor.send(GetBuildStatus).await.unwrap();
+            if status.reload_failure.is_some() {
+                reload_failure = status.reload_failure;
+                break;
+            }
+            actix_rt::time::delay_for(std::time::Duration::from_millis(50)).await;
+        }
+        // the dataset is marked as stale, but still served
+        let reload_failure = reload_failure.expect("the dataset has not been marked as stale");
+        assert_eq!(reload_failure.failed_attempts, 1);
+        assert!(dataset_actor.send(GetDataset).await.unwrap().is_ok());
+
+        // if the dataset is dropped, the failure is kept until a successful reloading
+        let dropped = Arc::new(Err(anyhow::anyhow!("dropped")));
+        dataset_actor
+            .send(UpdateBaseSchedule(dropped))
+            .await
+            .unwrap();
+        let status = dataset_actor.send(GetBuildStatus).await.unwrap();
+        assert!(status.reload_failure.is_some());
     }
 }
@@ -0,0 +1,517 @@
+//! Alerts published through the API
+//!
+//! Besides the alerts of the realtime feeds, a control room can publish its own alerts on a dataset.
+//! They are kept in an `AlertStore` by dataset, persisted in a json file (if a directory is given)
+//! to survive a restart, and are added to the alerts of the feeds in the `/gtfs-rt` and the general message.
+//! The alerts whose active periods have all ended are removed at each realtime update.
+use crate::siri_lite::DateTime;
+use crate::transit_realtime;
+use anyhow::Context;
+use chrono::{NaiveDateTime, TimeZone};
+use openapi_schema::OpenapiSchema;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Debug, Clone, OpenapiSchema, PartialEq)]
+pub struct Translation {
+    pub text: String,
+    /// BCP-47 language code, if not given the text is in the language of the dataset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+/// Period during which an alert is displayed.
+/// The datetimes are in the timezone of the dataset, formated as YYYY-MM-DDTHH:MM:SS
+#[derive(Serialize, Deserialize, Debug, Clone, OpenapiSchema, PartialEq)]
+pub struct ActivePeriod {
+    /// if not given, the alert is displayed from now
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<DateTime>,
+    /// if not given, the alert is displayed until it is deleted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<DateTime>,
+}
+
+/// Object impacted by an alert, referenced by its id in the base schedule.
+/// If several ids are given, only the combination is impacted (like a line at a stop)
+#[derive(Serialize, Deserialize, Debug, Clone, OpenapiSchema, PartialEq, Default)]
+pub struct InformedEntity {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line_id: Option<String>,
+    /// id of a stop point or a stop area
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_id: Option<String>,
+    /// id of a vehicle journey
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trip_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, OpenapiSchema, PartialEq)]
+pub struct Alert {
+    /// id of the alert, generated at its creation if not given
+    #[serde(default)]
+    pub id: String,
+    /// if empty, the alert is always displayed
+    #[serde(default)]
+    pub active_periods: Vec<ActivePeriod>,
+    pub informed_entities: Vec<InformedEntity>,
+    /// short summary of the alert, in one or several languages
+    pub header_text: Vec<Translation>,
+    #[serde(default)]
+    pub description_text: Vec<Translation>,
+    /// url giving more information about the alert
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+fn translated_string(translations: &[Translation]) -> Option<transit_realtime::TranslatedString> {
+    if translations.is_empty() {
+        return None;
+    }
+    Some(transit_realtime::TranslatedString {
+        translation: translations
+            .iter()
+            .map(|t| transit_realtime::translated_string::Translation {
+                text: t.text.clone(),
+                language: t.language.clone(),
+            })
+            .collect(),
+    })
+}
+
+fn pbf_dt(dt: &Option<DateTime>, timezone: chrono_tz::Tz) -> Option<u64> {
+    dt.as_ref()
+        .and_then(|dt| timezone.from_local_datetime(&dt.0).earliest())
+        .map(|dt| dt.timestamp() as u64)
+}
+
+impl Alert {
+    /// true if all the active periods of the alert have ended at `now` (in the dataset's timezone)
+    pub fn is_expired(&self, now: NaiveDateTime) -> bool {
+        !self.active_periods.is_empty()
+            && self
+                .active_periods
+                .iter()
+                .all(|p| p.end.as_ref().map_or(false, |end| end.0 <= now))
+    }
+
+    /// Check the alert, its informed entities should be objects of the base schedule,
+    /// and its datetimes should exist in the dataset's `timezone`.
+    /// Returns the list of the problems
+    pub fn check(&self, model: &transit_model::Model, timezone: chrono_tz::Tz) -> Vec<String> {
+        let mut errors = vec![];
+        if self.header_text.is_empty() {
+            errors.push("the alert should have a header text".to_owned());
+        }
+        if self.informed_entities.is_empty() {
+            errors.push("the alert should have at least one informed entity".to_owned());
+        }
+        for entity in &self.informed_entities {
+            if entity.line_id.is_none() && entity.stop_id.is_none() && entity.trip_id.is_none() {
+                errors.push("an informed entity should have a line, a stop or a trip".to_owned());
+            }
+            if let Some(line_id) = &entity.line_id {
+                if model.lines.get(line_id).is_none() {
+                    errors.push(format!("unknown line '{}'", line_id));
+                }
+            }
+            if let Some(stop_id) = &entity.stop_id {
+                if model.stop_points.get(stop_id).is_none()
+                    && model.stop_areas.get(stop_id).is_none()
+                {
+                    errors.push(format!("unknown stop '{}'", stop_id));
+                }
+            }
+            if let Some(trip_id) = &entity.trip_id {
+                if model.vehicle_journeys.get(trip_id).is_none() {
+                    errors.push(format!("unknown trip '{}'", trip_id));
+                }
+            }
+        }
+        for period in &self.active_periods {
+            // the datetimes skipped by a daylight saving time change cannot be converted
+            for dt in period.start.iter().chain(period.end.iter()) {
+                if timezone.from_local_datetime(&dt.0).earliest().is_none() {
+                    errors.push(format!(
+                        "the datetime {} does not exist in the timezone {}",
+                        dt.to_string(),
+                        timezone.name()
+                    ));
+                }
+            }
+            if let (Some(start), Some(end)) = (&period.start, &period.end) {
+                if start.0 > end.0 {
+                    errors.push(format!(
+                        "the active period starting at {} ends before its start",
+                        start.to_string()
+                    ));
+                }
+            }
+        }
+        errors
+    }
+
+    /// The GTFS-RT entity of the alert, its datetimes being in the dataset's `timezone`
+    pub fn to_feed_entity(&self, timezone: chrono_tz::Tz) -> transit_realtime::FeedEntity {
+        transit_realtime::FeedEntity {
+            id: self.id.clone(),
+            alert: Some(transit_realtime::Alert {
+                active_period: self
+                    .active_periods
+                    .iter()
+                    .map(|p| transit_realtime::TimeRange {
+                        start: pbf_dt(&p.start, timezone),
+                        end: pbf_dt(&p.end, timezone),
+                    })
+                    .collect(),
+                informed_entity: self
+                    .informed_entities
+                    .iter()
+                    .map(|e| transit_realtime::EntitySelector {
+                        // the GTFS routes are the lines of the base schedule
+                        route_id: e.line_id.clone(),
+                        stop_id: e.stop_id.clone(),
+                        trip: e
+                            .trip_id
+                            .as_ref()
+                            .map(|trip_id| transit_realtime::TripDescriptor {
+                                trip_id: Some(trip_id.clone()),
+                                ..Default::default()
+                            }),
+                        ..Default::default()
+                    })
+                    .collect(),
+                url: self
+                    .url
+                    .as_ref()
+                    .map(|url| transit_realtime::TranslatedString {
+                        translation: vec![transit_realtime::translated_string::Translation {
+                            text: url.clone(),
+                            language: None,
+                        }],
+                    }),
+                header_text: translated_string(&self.header_text),
+                description_text: translated_string(&self.description_text),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AlertError {
+    AlreadyExists(String),
+    UnknownAlert(String),
+    /// the alerts cannot be written
+    Storage(anyhow::Error),
+}
+
+impl std::fmt::Display for AlertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AlertError::AlreadyExists(id) => write!(f, "the alert '{}' already exists", id),
+            AlertError::UnknownAlert(id) => write!(f, "unknown alert '{}'", id),
+            AlertError::Storage(e) => write!(f, "impossible to store the alerts: {}", e),
+        }
+    }
+}
+
+/// Alerts published on a dataset
+///
+/// All the alerts are written in a json file at each change
+pub struct AlertStore {
+    file: Option<PathBuf>,
+    alerts: Mutex<BTreeMap<String, Alert>>,
+}
+
+fn read_alerts(file: &Path) -> anyhow::Result<BTreeMap<String, Alert>> {
+    let content = std::fs::File::open(file)?;
+    let alerts: Vec<Alert> = serde_json::from_reader(std::io::BufReader::new(content))?;
+    Ok(alerts.into_iter().map(|a| (a.id.clone(), a)).collect())
+}
+
+impl AlertStore {
+    /// Create a store, with the alerts previously written in `file`.
+    /// If no file is given, the alerts are only kept in memory
+    pub fn new(file: Option<&Path>) -> Self {
+        let alerts = match file {
+            Some(file) if file.exists() => read_alerts(file)
+                .map_err(|e| log::error!("impossible to read alerts {}: {}", file.display(), e))
+                .unwrap_or_default(),
+            _ => BTreeMap::new(),
+        };
+        Self {
+            file: file.map(Path::to_owned),
+            alerts: Mutex::new(alerts),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.alerts.lock().unwrap().is_empty()
+    }
+
+    pub fn alerts(&self) -> Vec<Alert> {
+        self.alerts.lock().unwrap().values().cloned().collect()
+    }
+
+    /// the GTFS-RT entities of all the alerts
+    pub fn feed_entities(&self, timezone: chrono_tz::Tz) -> Vec<transit_realtime::FeedEntity> {
+        self.alerts
+            .lock()
+            .unwrap()
+            .values()
+            .map(|a| a.to_feed_entity(timezone))
+            .collect()
+    }
+
+    fn write(&self, alerts: &BTreeMap<String, Alert>) -> anyhow::Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let dir = file.parent().unwrap_or_else(|| Path::new("."));
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("impossible to create directory {}", dir.display()))?;
+        // the alerts are written in a temporary file, not to leave a partial file in case of error
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer_pretty(tmp.as_file_mut(), &alerts.values().collect::<Vec<_>>())?;
+        tmp.persist(file)
+            .with_context(|| format!("impossible to write {}", file.display()))?;
+        Ok(())
+    }
+
+    /// Apply a change on the alerts, only once the changed alerts have been written
+    fn change<T>(
+        &self,
+        f: impl FnOnce(&mut BTreeMap<String, Alert>) -> Result<T, AlertError>,
+    ) -> Result<T, AlertError> {
+        let mut alerts = self.alerts.lock().unwrap();
+        let mut changed = alerts.clone();
+        let res = f(&mut changed)?;
+        self.write(&changed).map_err(AlertError::Storage)?;
+        *alerts = changed;
+        Ok(res)
+    }
+
+    /// Add a new alert, returning it with its id
+    pub fn create(&self, mut alert: Alert) -> Result<Alert, AlertError> {
+        self.change(|alerts| {
+            if alert.id.is_empty() {
+                alert.id = (1..)
+                    .map(|n| format!("alert:{}", n))
+                    .find(|id| !alerts.contains_key(id))
+                    .unwrap();
+            } else if alerts.contains_key(&alert.id) {
+                return Err(AlertError::AlreadyExists(alert.id));
+            }
+            alerts.insert(alert.id.clone(), alert.clone());
+            Ok(alert)
+        })
+    }
+
+    /// Replace the alert `id`
+    pub fn update(&self, id: &str, mut alert: Alert) -> Result<Alert, AlertError> {
+        self.change(|alerts| {
+            let existing = alerts
+                .get_mut(id)
+                .ok_or_else(|| AlertError::UnknownAlert(id.to_owned()))?;
+            alert.id = id.to_owned();
+            *existing = alert.clone();
+            Ok(alert)
+        })
+    }
+
+    pub fn delete(&self, id: &str) -> Result<(), AlertError> {
+        self.change(|alerts| {
+            alerts
+                .remove(id)
+                .map(|_| ())
+                .ok_or_else(|| AlertError::UnknownAlert(id.to_owned()))
+        })
+    }
+
+    /// Remove the alerts expired at `now` (in the dataset's timezone).
+    /// Returns the number of removed alerts
+    pub fn remove_expired(&self, now: NaiveDateTime) -> Result<usize, AlertError> {
+        if !self
+            .alerts
+            .lock()
+            .unwrap()
+            .values()
+            .any(|a| a.is_expired(now))
+        {
+            return Ok(0);
+        }
+        self.change(|alerts| {
+            let nb_alerts = alerts.len();
+            alerts.retain(|_, a| !a.is_expired(now));
+            Ok(nb_alerts - alerts.len())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transit_model_builder::ModelBuilder;
+
+    fn alert(entity: InformedEntity) -> Alert {
+        Alert {
+            id: String::new(),
+            active_periods: vec![ActivePeriod {
+                start: Some(DateTime("2020-03-10T08:00:00".parse().unwrap())),
+                end: Some(DateTime("2020-03-10T20:00:00".parse().unwrap())),
+            }],
+            informed_entities: vec![entity],
+            header_text: vec![Translation {
+                text: "strike".to_owned(),
+                language: Some("en".to_owned()),
+            }],
+            description_text: vec![],
+            url: None,
+        }
+    }
+
+    #[test]
+    fn test_check_alert() {
+        let model = ModelBuilder::default()
+            .vj("vj1", |vj_builder| {
+                vj_builder
+                    .st("A", "10:00:00", "10:00:00")
+                    .st("B", "10:10:00", "10:10:00");
+            })
+            .build();
+        let line_id = model.lines.values().next().unwrap().id.clone();
+        let valid = alert(InformedEntity {
+            line_id: Some(line_id),
+            stop_id: Some("A".to_owned()),
+            trip_id: Some("vj1".to_owned()),
+        });
+        assert!(valid.check(&model, chrono_tz::UTC).is_empty());
+
+        let invalid = alert(InformedEntity {
+            line_id: Some("unknown_line".to_owned()),
+            stop_id: Some("Z".to_owned()),
+            trip_id: None,
+        });
+        assert_eq!(
+            invalid.check(&model, chrono_tz::UTC),
+            vec!["unknown line 'unknown_line'", "unknown stop 'Z'"]
+        );
+        let no_entity = alert(InformedEntity::default());
+        assert_eq!(
+            no_entity.check(&model, chrono_tz::UTC),
+            vec!["an informed entity should have a line, a stop or a trip"]
+        );
+        // 2020-03-29T02:30:00 is skipped by the daylight saving time change in Paris
+        let dst_gap = Alert {
+            active_periods: vec![ActivePeriod {
+                start: Some(DateTime("2020-03-29T02:30:00".parse().unwrap())),
+                end: None,
+            }],
+            ..valid
+        };
+        assert!(dst_gap.check(&model, chrono_tz::UTC).is_empty());
+        assert_eq!(
+            dst_gap.check(&model, chrono_tz::Europe::Paris),
+            vec!["the datetime 2020-03-29T02:30:00 does not exist in the timezone Europe/Paris"]
+        );
+    }
+
+    #[test]
+    fn test_alert_to_feed_entity() {
+        let alert = alert(InformedEntity {
+            stop_id: Some("A".to_owned()),
+            ..Default::default()
+        });
+        let entity = alert.to_feed_entity(chrono_tz::Europe::Paris);
+        let gtfs_rt_alert = entity.alert.unwrap();
+        assert_eq!(
+            gtfs_rt_alert.active_period[0].start,
+            Some(
+                chrono_tz::Europe::Paris
+                    .ymd(2020, 3, 10)
+                    .and_hms(8, 0, 0)
+                    .timestamp() as u64
+            )
+        );
+        assert_eq!(gtfs_rt_alert.informed_entity[0].stop_id(), "A");
+        let header = gtfs_rt_alert.header_text.unwrap();
+        assert_eq!(header.translation[0].text, "strike");
+        assert!(gtfs_rt_alert.description_text.is_none());
+    }
+
+    #[test]
+    fn test_alert_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("alerts").join("dataset.json");
+        let store = AlertStore::new(Some(&file));
+        let stop_alert = alert(InformedEntity {
+            stop_id: Some("A".to_owned()),
+            ..Default::default()
+        });
+
+        let created = store.create(stop_alert.clone()).unwrap();
+        assert_eq!(created.id, "alert:1");
+        let named = store
+            .create(Alert {
+                id: "works".to_owned(),
+                ..stop_alert.clone()
+            })
+            .unwrap();
+        assert!(matches!(
+            store.create(named),
+            Err(AlertError::AlreadyExists(_))
+        ));
+        store
+            .update(
+                "alert:1",
+                Alert {
+                    url: Some("http://disruptions".to_owned()),
+                    ..stop_alert.clone()
+                },
+            )
+            .unwrap();
+        assert!(matches!(
+            store.delete("unknown"),
+            Err(AlertError::UnknownAlert(_))
+        ));
+        store.delete("works").unwrap();
+
+        // the alerts are read back from the file
+        let reloaded = AlertStore::new(Some(&file));
+        let alerts = reloaded.alerts();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].id, "alert:1");
+        assert_eq!(alerts[0].url.as_deref(), Some("http://disruptions"));
+    }
+
+    #[test]
+    fn test_remove_expired_alerts() {
+        let store = AlertStore::new(None);
+        let stop_alert = alert(InformedEntity {
+            stop_id: Some("A".to_owned()),
+            ..Default::default()
+        });
+        store.create(stop_alert.clone()).unwrap();
+        // an alert without end is never expired
+        store
+            .create(Alert {
+                active_periods: vec![ActivePeriod {
+                    start: None,
+                    end: None,
+                }],
+                ..stop_alert
+            })
+            .unwrap();
+
+        let ndt = |s: &str| s.parse::<NaiveDateTime>().unwrap();
+        assert_eq!(store.remove_expired(ndt("2020-03-10T19:00:00")).unwrap(), 0);
+        assert_eq!(store.remove_expired(ndt("2020-03-10T21:00:00")).unwrap(), 1);
+        let alerts = store.alerts();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].id, "alert:2");
+    }
+}
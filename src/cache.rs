@@ -0,0 +1,401 @@
+//! On disk cache of the base schedules, for fast restarts
+//!
+//! The downloaded sources are kept with their ETag and the hash of their content,
+//! and are only downloaded again when they have changed.
+//! The prepared models (the sources read and merged) can also be kept, serialized as NTFS,
+//! so that a restart does not need to download nor read the sources again.
+use anyhow::{anyhow, Context, Error};
+use chrono::{DateTime, Utc};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+const METADATA_FILE: &str = "metadata.json";
+const ARCHIVE_FILE: &str = "archive";
+const PREPARED_MODEL_DIR: &str = "ntfs";
+
+pub struct DataCache {
+    dir: PathBuf,
+    /// the entries not used since this duration are removed
+    retention: chrono::Duration,
+    /// if true, the prepared models are also kept
+    keep_prepared_models: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SourceMetadata {
+    url: String,
+    etag: Option<String>,
+    hash: String,
+    last_used: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PreparedModelMetadata {
+    /// hash of the dataset's configuration
+    configuration: String,
+    /// version of the sources used to prepare the model
+    version: String,
+    last_used: DateTime<Utc>,
+}
+
+/// common part of the metadata of the entries, used to purge the cache
+#[derive(Deserialize)]
+struct EntryUsage {
+    last_used: DateTime<Utc>,
+}
+
+/// A source available in the cache
+pub struct CachedSource {
+    pub path: PathBuf,
+    /// hash of the source's content
+    pub hash: String,
+}
+
+/// hash used to identify some content in memory
+///
+/// Note: the hash is only stable for a given build, `stable_hash` must be used for what is kept
+/// between the restarts
+pub fn hash<T: Hash + ?Sized>(value: &T) -> String {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    value.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// hash of some bytes that does not depend on the build (64 bits FNV-1a),
+/// used to name the files and directories kept between the restarts
+pub fn stable_hash(bytes: &[u8]) -> String {
+    let hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// name of the cache entry (or of the recording directory) of an url or a dataset id,
+/// readable but without collisions
+pub(crate) fn entry_name(key: &str) -> String {
+    let readable: String = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .take(64)
+        .collect();
+    format!("{}-{}", readable, stable_hash(key.as_bytes()))
+}
+
+fn read_metadata<T: serde::de::DeserializeOwned>(entry: &Path) -> Option<T> {
+    let file = std::fs::File::open(entry.join(METADATA_FILE)).ok()?;
+    serde_json::from_reader(file)
+        .map_err(|e| log::warn!("invalid cache metadata in {}: {}", entry.display(), e))
+        .ok()
+}
+
+fn write_metadata<T: serde::Serialize>(entry: &Path, metadata: &T) -> Result<(), Error> {
+    let file = std::fs::File::create(entry.join(METADATA_FILE))?;
+    serde_json::to_writer(file, metadata)?;
+    Ok(())
+}
+
+impl DataCache {
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        retention: chrono::Duration,
+        keep_prepared_models: bool,
+    ) -> Result<Self, Error> {
+        let cache = Self {
+            dir: dir.into(),
+            retention,
+            keep_prepared_models,
+        };
+        for dir in &[cache.sources_dir(), cache.datasets_dir()] {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("impossible to create cache directory {}", dir.display()))?;
+        }
+        Ok(cache)
+    }
+
+    fn sources_dir(&self) -> PathBuf {
+        self.dir.join("sources")
+    }
+
+    fn datasets_dir(&self) -> PathBuf {
+        self.dir.join("datasets")
+    }
+
+    /// Get a source from its url.
+    ///
+    /// If the source is in the cache, it is only downloaded again if it has changed
+    /// (with a conditional request on its ETag).
+    /// If the source cannot be downloaded, the cached version is used.
+    pub fn fetch(&self, url: &str) -> Result<CachedSource, Error> {
+        let entry = self.sources_dir().join(entry_name(url));
+        let archive = entry.join(ARCHIVE_FILE);
+        let metadata = read_metadata::<SourceMetadata>(&entry).filter(|_| archive.exists());
+
+        let mut request = reqwest::blocking::Client::new().get(url);
+        if let Some(etag) = metadata.as_ref().and_then(|m| m.etag.as_ref()) {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag.as_str());
+        }
+        let response = request
+            .send()
+            .and_then(reqwest::blocking::Response::error_for_status);
+
+        let metadata = match (response, metadata) {
+            (Ok(response), Some(metadata))
+                if response.status() == reqwest::StatusCode::NOT_MODIFIED =>
+            {
+                log::info!("{} not modified, using the cached version", url);
+                metadata
+            }
+            (Ok(response), previous) => {
+                let etag = response
+                    .headers()
+                    .get(reqwest::header::ETAG)
+                    .and_then(|etag| etag.to_str().ok())
+                    .map(str::to_owned);
+                let content = response
+                    .bytes()
+                    .with_context(|| format!("impossible to download {}", url))?;
+                let hash = stable_hash(content.as_ref());
+                if previous.as_ref().map(|m| &m.hash) == Some(&hash) {
+                    log::info!("{} has the same content as the cached version", url);
+                } else {
+                    std::fs::create_dir_all(&entry)?;
+                    // the archive is written in a temporary file first, not to leave a partial archive
+                    let tmp = entry.join(format!("{}.tmp", ARCHIVE_FILE));
+                    std::fs::write(&tmp, content.as_ref())?;
+                    std::fs::rename(&tmp, &archive)?;
+                }
+                SourceMetadata {
+                    url: url.to_owned(),
+                    etag,
+                    hash,
+                    last_used: Utc::now(),
+                }
+            }
+            (Err(e), Some(metadata)) => {
+                log::warn!(
+                    "impossible to download {}, using the cached version: {}",
+                    url,
+                    e
+                );
+                metadata
+            }
+            (Err(e), None) => return Err(anyhow!("impossible to download {}: {}", url, e)),
+        };
+
+        let metadata = SourceMetadata {
+            last_used: Utc::now(),
+            ..metadata
+        };
+        write_metadata(&entry, &metadata)?;
+        Ok(CachedSource {
+            path: archive,
+            hash: metadata.hash,
+        })
+    }
+
+    /// Get the prepared model of a dataset, if it has been prepared with this version of the sources.
+    /// If no version is given, the last prepared model is returned (without checking the sources).
+    ///
+    /// The model is only given if the dataset's configuration has not changed.
+    /// Returns the version of the model's sources with the model.
+    pub fn prepared_model(
+        &self,
+        dataset_id: &str,
+        configuration: &str,
+        version: Option<&str>,
+    ) -> Option<(String, transit_model::Model)> {
+        if !self.keep_prepared_models {
+            return None;
+        }
+        let entry = self.datasets_dir().join(entry_name(dataset_id));
+        let metadata = read_metadata::<PreparedModelMetadata>(&entry)
+            .filter(|m| m.configuration == configuration)
+            .filter(|m| version.map(|v| v == m.version).unwrap_or(true))?;
+
+        log::info!("reading prepared model of {} from the cache", dataset_id);
+        let model = transit_model::ntfs::read(entry.join(PREPARED_MODEL_DIR))
+            .map_err(|e| log::warn!("impossible to read the cached model: {}", e))
+            .ok()?;
+
+        let metadata = PreparedModelMetadata {
+            last_used: Utc::now(),
+            ..metadata
+        };
+        let _ = write_metadata(&entry, &metadata)
+            .map_err(|e| log::warn!("impossible to update the cache metadata: {}", e));
+        Some((metadata.version, model))
+    }
+
+    /// Store the prepared model of a dataset.
+    /// The errors are only logged, as the cache is not necessary to serve the dataset
+    pub fn store_prepared_model(
+        &self,
+        dataset_id: &str,
+        configuration: &str,
+        version: &str,
+        model: &transit_model::Model,
+    ) {
+        if !self.keep_prepared_models {
+            return;
+        }
+        let entry = self.datasets_dir().join(entry_name(dataset_id));
+        let store = || -> Result<(), Error> {
+            // the new model is written next to the old one, and replaces it once complete
+            let tmp = entry.join(format!("{}.tmp", PREPARED_MODEL_DIR));
+            if tmp.exists() {
+                std::fs::remove_dir_all(&tmp)?;
+            }
+            std::fs::create_dir_all(&tmp)?;
+            transit_model::ntfs::write(model, &tmp, Utc::now().naive_utc())
+                .map_err(|e| anyhow!("impossible to write ntfs: {}", e))?;
+            let prepared_model = entry.join(PREPARED_MODEL_DIR);
+            if prepared_model.exists() {
+                std::fs::remove_dir_all(&prepared_model)?;
+            }
+            std::fs::rename(&tmp, &prepared_model)?;
+            write_metadata(
+                &entry,
+                &PreparedModelMetadata {
+                    configuration: configuration.to_owned(),
+                    version: version.to_owned(),
+                    last_used: Utc::now(),
+                },
+            )
+        };
+        match store() {
+            Ok(()) => log::info!("prepared model of {} stored in the cache", dataset_id),
+            Err(e) => log::warn!("impossible to store the prepared model in the cache: {}", e),
+        }
+    }
+
+    /// Remove the entries that have not been used during the retention period
+    pub fn purge(&self) {
+        let limit = Utc::now() - self.retention;
+        for dir in &[self.sources_dir(), self.datasets_dir()] {
+            let entries = match std::fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    log::warn!("impossible to read cache directory {}: {}", dir.display(), e);
+                    continue;
+                }
+            };
+            for entry in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                // the entries without valid metadata are also removed
+                let is_used = read_metadata::<EntryUsage>(&entry)
+                    .map(|m| m.last_used >= limit)
+                    .unwrap_or(false);
+                if !is_used {
+                    log::info!("removing {} from the cache", entry.display());
+                    let _ = std::fs::remove_dir_all(&entry)
+                        .map_err(|e| log::warn!("impossible to remove cache entry: {}", e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{entry_name, stable_hash, DataCache};
+
+    fn serve_archive(path: &str, etag: &str) -> mockito::Mock {
+        mockito::mock("GET", path)
+            .match_header("if-none-match", mockito::Matcher::Missing)
+            .with_header("etag", etag)
+            .with_body(std::fs::read("fixtures/gtfs.zip").unwrap())
+            .create()
+    }
+
+    #[test]
+    fn entry_names_are_stable() {
+        assert_eq!(stable_hash(b""), "cbf29ce484222325");
+        assert_eq!(
+            entry_name("https://example.com/gtfs-rt"),
+            "https___example_com_gtfs_rt-770ff9dc0a199051"
+        );
+    }
+
+    #[test]
+    fn test_fetch_with_etag() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DataCache::new(dir.path(), chrono::Duration::days(1), false).unwrap();
+        let url = format!("{}/cached/gtfs.zip", mockito::server_url());
+
+        let download = serve_archive("/cached/gtfs.zip", "\"v1\"");
+        let first = cache.fetch(&url).unwrap();
+        download.assert();
+        drop(download);
+        assert_eq!(
+            std::fs::read(&first.path).unwrap(),
+            std::fs::read("fixtures/gtfs.zip").unwrap()
+        );
+
+        // the second time, the archive is not downloaded again
+        let not_modified = mockito::mock("GET", "/cached/gtfs.zip")
+            .match_header("if-none-match", "\"v1\"")
+            .with_status(304)
+            .create();
+        let second = cache.fetch(&url).unwrap();
+        not_modified.assert();
+        drop(not_modified);
+        assert_eq!(first.path, second.path);
+        assert_eq!(first.hash, second.hash);
+
+        // if the server is not available, the cached version is used
+        let unavailable = mockito::mock("GET", "/cached/gtfs.zip")
+            .with_status(500)
+            .create();
+        let third = cache.fetch(&url).unwrap();
+        unavailable.assert();
+        assert_eq!(first.hash, third.hash);
+    }
+
+    #[test]
+    fn test_purge() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = format!("{}/purged/gtfs.zip", mockito::server_url());
+        let _download = serve_archive("/purged/gtfs.zip", "\"v1\"");
+
+        let cache = DataCache::new(dir.path(), chrono::Duration::days(1), false).unwrap();
+        let source = cache.fetch(&url).unwrap();
+        cache.purge();
+        assert!(source.path.exists());
+
+        let cache = DataCache::new(dir.path(), chrono::Duration::zero(), false).unwrap();
+        cache.purge();
+        assert!(!source.path.exists());
+    }
+
+    #[test]
+    fn test_prepared_dataset() {
+        use crate::datasets::{Dataset, DatasetInfo, Period};
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DataCache::new(dir.path(), chrono::Duration::days(1), true).unwrap();
+        let dataset_info = DatasetInfo::new_default("fixtures/gtfs.zip", &[]);
+        let period = Period {
+            begin: chrono::NaiveDate::from_ymd(2018, 12, 15),
+            horizon: chrono::Duration::days(1),
+        };
+
+        assert!(Dataset::try_from_cache(dataset_info.clone(), &period, &cache).is_none());
+        let dataset = Dataset::try_from_dataset_info(dataset_info.clone(), &period, Some(&cache))
+            .unwrap();
+
+        let cached_dataset = Dataset::try_from_cache(dataset_info.clone(), &period, &cache)
+            .unwrap()
+            .unwrap();
+        assert_eq!(dataset.version, cached_dataset.version);
+        assert_eq!(dataset.ntm.stop_points.len(), cached_dataset.ntm.stop_points.len());
+        assert_eq!(
+            dataset.timetable.connections.len(),
+            cached_dataset.timetable.connections.len()
+        );
+
+        // a change in the configuration invalidates the prepared dataset
+        let other_dataset_info = DatasetInfo {
+            gtfs_rt_urls: vec!["http://gtfs-rt".to_owned()],
+            ..dataset_info
+        };
+        assert!(Dataset::try_from_cache(other_dataset_info, &period, &cache).is_none());
+    }
+}
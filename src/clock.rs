@@ -0,0 +1,40 @@
+use chrono::{DateTime, Utc};
+use std::sync::{Arc, RwLock};
+
+/// Source of the current time of a dataset.
+///
+/// The time is the system time, except when recorded GTFS-RT are replayed,
+/// where it is the time of the replayed snapshot
+#[derive(Clone, Debug)]
+pub enum Clock {
+    System,
+    Simulated(Arc<RwLock<DateTime<Utc>>>),
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::System
+    }
+}
+
+impl Clock {
+    pub fn simulated(start: DateTime<Utc>) -> Self {
+        Clock::Simulated(Arc::new(RwLock::new(start)))
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        match self {
+            Clock::System => Utc::now(),
+            Clock::Simulated(dt) => *dt.read().unwrap(),
+        }
+    }
+
+    /// Set the time of a simulated clock.
+    /// Note: the system clock cannot be changed, the call is ignored
+    pub fn set(&self, now: DateTime<Utc>) {
+        match self {
+            Clock::System => log::warn!("impossible to change the time of the system clock"),
+            Clock::Simulated(dt) => *dt.write().unwrap() = now,
+        }
+    }
+}
@@ -6,7 +6,17 @@ use openapi_schema::OpenapiSchema;
 use std::collections::HashMap;
 use std::sync::Arc;
 use transit_model::collection::Idx;
-
+use transit_model::objects::{StopArea, StopPoint};
+use transit_model::relations::IdxSet;
+
+use crate::access_control::AccessControlConfig;
+use crate::actors::SubscriptionsConfig;
+use crate::cache::DataCache;
+use crate::model_update::IdResolver;
+use crate::recorder::RecordingConfig;
+use crate::schedule_readers::{self, Sources};
+use crate::search::StopPointsIndex;
+use crate::spatial_index::StopPointsGrid;
 use crate::transit_realtime;
 
 #[derive(Clone)]
@@ -29,6 +39,8 @@ pub struct RealTimeConnection {
     pub schedule_relationship: ScheduleRelationship,
     //TODO handle uncertainty
     pub update_time: chrono::DateTime<chrono::Utc>, //TODO move it to have one update_time for a trip, not one by stop_time
+    /// true if the times have been estimated from a vehicle position, and not given by a trip update
+    pub estimated: bool,
 }
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
@@ -55,6 +67,9 @@ pub struct UpdatedTimetable {
     /// the key is the index in the BaseSchedule connections Vector
     /// TODO: could we stronger type this index ?
     pub realtime_connections: HashMap<usize, RealTimeConnection>,
+    /// the trips whose realtime data come as is from a GTFS-RT trip update,
+    /// with the url and the entity id of this trip update
+    pub gtfs_rt_trip_updates: HashMap<DatedVehicleJourney, (String, String)>,
 }
 
 #[derive(Clone)]
@@ -69,6 +84,16 @@ pub struct Dataset {
     pub timezone: Tz,
     pub loaded_at: chrono::DateTime<chrono::Utc>,
     pub feed_construction_info: FeedConstructionInfo,
+    /// search index on the stop points, used by the stoppoints discovery
+    pub stop_points_index: StopPointsIndex,
+    /// spatial index on the stop points, used by the bounding box and proximity queries
+    pub stop_points_grid: StopPointsGrid,
+    /// used to find the objects corresponding to the GTFS-RT ids, by GTFS-RT url
+    pub id_resolvers: HashMap<String, IdResolver>,
+    /// version of the base schedule sources and configuration, to know if they have changed
+    pub version: String,
+    /// last day with some service in the base schedule
+    pub validity_end: Option<NaiveDate>,
 }
 
 pub struct RealTimeDataset {
@@ -90,6 +115,26 @@ impl RealTimeDataset {
     }
 }
 
+/// Error given for a dataset until its first loading is finished
+#[derive(Debug)]
+pub struct DatasetLoading;
+
+impl std::fmt::Display for DatasetLoading {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the dataset is being loaded")
+    }
+}
+
+impl std::error::Error for DatasetLoading {}
+
+/// true if the dataset has not been loaded yet
+pub fn is_loading(dataset: &Result<Dataset, anyhow::Error>) -> bool {
+    match dataset {
+        Err(e) => e.is::<DatasetLoading>(),
+        Ok(_) => false,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Period {
     pub begin: NaiveDate,
@@ -98,7 +143,206 @@ pub struct Period {
 
 #[derive(Deserialize, Clone, Default)]
 pub struct Datasets {
+    #[serde(deserialize_with = "deserialize_datasets")]
     pub datasets: Vec<DatasetInfo>,
+    /// api keys and rate limiting of the api
+    #[serde(default, rename = "access-control")]
+    pub access_control: AccessControlConfig,
+    /// limits of the siri subscriptions
+    #[serde(default)]
+    pub subscriptions: SubscriptionsConfig,
+}
+
+/// each dataset needs a base schedule, either a `gtfs` or some `feeds`
+fn deserialize_datasets<'de, D>(deserializer: D) -> Result<Vec<DatasetInfo>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    let datasets: Vec<DatasetInfo> = serde::Deserialize::deserialize(deserializer)?;
+    match datasets
+        .iter()
+        .find(|d| d.gtfs.is_empty() && d.feeds.is_empty())
+    {
+        Some(dataset) => Err(D::Error::custom(format!(
+            "the dataset {} has no base schedule, it needs a gtfs or some feeds",
+            dataset.id
+        ))),
+        None => Ok(datasets),
+    }
+}
+
+/// Format of the base schedule data
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, OpenapiSchema, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum DataFormat {
+    Gtfs,
+    Ntfs,
+    Netex,
+}
+
+/// How to find the base schedule objects from the ids given in the GTFS-RT
+///
+/// By default the GTFS-RT ids are the base schedule ids, but it's not the case
+/// if the base schedule is not the GTFS used by the GTFS-RT producer (eg. an NTFS)
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Default, OpenapiSchema, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "kebab-case")]
+pub struct IdMapping {
+    /// prefix of the base schedule ids, added to the GTFS-RT ids with a `:` separator
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /// type of the objects' codes holding the GTFS-RT ids (like the NTFS `object_codes`)
+    #[serde(default)]
+    pub code_type: Option<String>,
+}
+
+/// How to get the base schedule ids from the references of a SIRI feed,
+/// those references often being longer ids, like `IT:ITC1:ScheduledStopPoint:busATS:1234`
+///
+/// The prefix and the suffix are removed first, then only the part after the last separator is kept
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Default, OpenapiSchema, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "kebab-case")]
+pub struct IdStripping {
+    /// prefix removed from the references
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /// suffix removed from the references (like the trailing `:` of some references)
+    #[serde(default)]
+    pub suffix: Option<String>,
+    /// if given, only the part of the references after the last occurrence of this separator is kept
+    #[serde(default)]
+    pub separator: Option<String>,
+}
+
+impl IdStripping {
+    pub fn strip<'a>(&self, reference: &'a str) -> &'a str {
+        let mut id = reference.trim();
+        if let Some(prefix) = &self.prefix {
+            id = id.strip_prefix(prefix.as_str()).unwrap_or(id);
+        }
+        if let Some(suffix) = &self.suffix {
+            id = id.strip_suffix(suffix.as_str()).unwrap_or(id);
+        }
+        if let Some(separator) = &self.separator {
+            id = id.rsplit(separator.as_str()).next().unwrap_or(id);
+        }
+        id
+    }
+}
+
+/// Format of a realtime feed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, OpenapiSchema, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum RealtimeFormat {
+    GtfsRt,
+    /// GTFS-RT service alerts, only the alerts of the feed are used
+    GtfsRtAlerts,
+    /// SIRI EstimatedTimetable xml
+    SiriEt,
+    /// SIRI StopMonitoring xml
+    SiriSm,
+}
+
+impl Default for RealtimeFormat {
+    fn default() -> Self {
+        RealtimeFormat::GtfsRt
+    }
+}
+
+impl RealtimeFormat {
+    pub fn is_siri(self) -> bool {
+        matches!(self, RealtimeFormat::SiriEt | RealtimeFormat::SiriSm)
+    }
+
+    /// extension of the files of this format
+    pub fn extension(self) -> &'static str {
+        if self.is_siri() {
+            "xml"
+        } else {
+            "pb"
+        }
+    }
+}
+
+/// A realtime feed of a dataset, with its format
+#[derive(Serialize, Deserialize, Debug, Clone, OpenapiSchema, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub struct RealtimeSource {
+    pub url: String,
+    #[serde(default)]
+    pub format: RealtimeFormat,
+    /// how to get the base schedule ids from the SIRI references, not used for the GTFS-RT
+    #[serde(default)]
+    pub id_stripping: IdStripping,
+}
+
+/// What to do with the last valid dataset when its reloading fails
+///
+/// The dataset is kept (and marked as stale) until the policy drops it
+#[derive(Serialize, Deserialize, Debug, Clone, OpenapiSchema, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum StaleDatasetPolicy {
+    /// the dataset is always kept
+    Keep,
+    /// the dataset is dropped once its calendar validity has passed
+    DropWhenExpired,
+    /// the dataset is dropped once it has been stale for this number of days
+    DropAfterDays(u32),
+}
+
+impl Default for StaleDatasetPolicy {
+    fn default() -> Self {
+        StaleDatasetPolicy::DropWhenExpired
+    }
+}
+
+impl StaleDatasetPolicy {
+    /// true if a dataset valid until `validity_end` and stale since `stale_since` must be dropped
+    pub fn must_drop(
+        &self,
+        validity_end: Option<NaiveDate>,
+        stale_since: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> bool {
+        match self {
+            StaleDatasetPolicy::Keep => false,
+            StaleDatasetPolicy::DropWhenExpired => validity_end
+                .map(|end| end < now.naive_utc().date())
+                .unwrap_or(true),
+            StaleDatasetPolicy::DropAfterDays(days) => {
+                now - stale_since > chrono::Duration::days(i64::from(*days))
+            }
+        }
+    }
+}
+
+/// Information about the failed reloadings of a dataset, while the previous dataset is still served
+#[derive(Serialize, Debug, Clone, OpenapiSchema)]
+pub struct ReloadFailure {
+    /// date of the first failed reloading
+    pub stale_since: DateTime<Utc>,
+    pub failed_attempts: u32,
+    pub last_error: String,
+    pub next_attempt: DateTime<Utc>,
+}
+
+/// One of the feeds merged to build the base schedule of a dataset
+#[derive(Serialize, Deserialize, Debug, Clone, OpenapiSchema, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub struct FeedInfo {
+    /// prefix added to all the ids of the feed, to avoid conflicts between the feeds
+    pub prefix: String,
+    /// path or url of the feed
+    pub gtfs: String,
+    #[serde(default)]
+    pub format: Option<DataFormat>,
+    /// urls of the GTFS-RT using the (non prefixed) ids of this feed
+    #[serde(default)]
+    pub gtfs_rt_urls: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, OpenapiSchema, PartialEq, Eq, PartialOrd, Ord)]
@@ -106,8 +350,32 @@ pub struct Datasets {
 pub struct DatasetInfo {
     pub name: String,
     pub id: String,
+    /// path or url of the base schedule data (a zip archive or a directory)
+    /// Note: the name is kept for compatibility, but it can be any of the `DataFormat`
+    #[serde(default)]
     pub gtfs: String,
+    /// format of the base schedule data, detected from its content if not given
+    #[serde(default)]
+    pub format: Option<DataFormat>,
+    /// if some feeds are given, the base schedule is the merge of those feeds
+    /// (and the `gtfs` field is not used)
+    #[serde(default)]
+    pub feeds: Vec<FeedInfo>,
+    #[serde(default)]
     pub gtfs_rt_urls: Vec<String>,
+    /// realtime feeds in other formats than GTFS-RT (or GTFS-RT too)
+    #[serde(default)]
+    pub realtime_sources: Vec<RealtimeSource>,
+    #[serde(default)]
+    pub id_mapping: IdMapping,
+    #[serde(default)]
+    pub stale_dataset_policy: StaleDatasetPolicy,
+    /// if given, all the fetched GTFS-RT are recorded
+    #[serde(default)]
+    pub recording: Option<RecordingConfig>,
+    /// if true, the trips without trip update are estimated from the vehicle positions of the GTFS-RT
+    #[serde(default)]
+    pub estimate_from_vehicle_positions: bool,
     #[serde(default)]
     pub extras: std::collections::BTreeMap<String, String>,
 }
@@ -118,16 +386,60 @@ impl DatasetInfo {
             id: "default".into(),
             name: "default name".into(),
             gtfs: gtfs.to_owned(),
+            format: None,
+            feeds: vec![],
             gtfs_rt_urls: gtfs_rt_urls.to_vec(),
+            realtime_sources: vec![],
+            id_mapping: IdMapping::default(),
+            stale_dataset_policy: StaleDatasetPolicy::default(),
+            recording: None,
+            estimate_from_vehicle_positions: false,
             extras: std::collections::BTreeMap::default(),
         }
     }
+
+    /// all the GTFS-RT urls of the dataset, with the id mapping to use for each of them
+    pub fn realtime_feeds(&self) -> Vec<(String, IdMapping)> {
+        let dataset_feeds = self
+            .gtfs_rt_urls
+            .iter()
+            .chain(self.realtime_sources.iter().map(|source| &source.url))
+            .map(|url| (url.clone(), self.id_mapping.clone()));
+        let merged_feeds = self.feeds.iter().flat_map(|feed| {
+            feed.gtfs_rt_urls.iter().map(move |url| {
+                (
+                    url.clone(),
+                    IdMapping {
+                        prefix: Some(feed.prefix.clone()),
+                        code_type: None,
+                    },
+                )
+            })
+        });
+        dataset_feeds.chain(merged_feeds).collect()
+    }
+
+    pub fn all_gtfs_rt_urls(&self) -> Vec<String> {
+        self.realtime_feeds().into_iter().map(|(url, _)| url).collect()
+    }
+
+    /// the realtime source of an url, `None` for the urls of `gtfs_rt_urls`
+    pub fn realtime_source(&self, url: &str) -> Option<&RealtimeSource> {
+        self.realtime_sources.iter().find(|source| source.url == url)
+    }
+
+    /// format of a realtime url of the dataset
+    pub fn realtime_format(&self, url: &str) -> RealtimeFormat {
+        self.realtime_source(url)
+            .map(|source| source.format)
+            .unwrap_or_default()
+    }
 }
 
 // create a dt from a Date and a StopTime's time
 // Note: the time might be on the next day, for example "26:00:00"
 // is the next day at 2 in the morning
-fn create_dt(date: NaiveDate, time: transit_model::objects::Time) -> NaiveDateTime {
+pub(crate) fn create_dt(date: NaiveDate, time: transit_model::objects::Time) -> NaiveDateTime {
     date.and_time(chrono::NaiveTime::from_hms(0, 0, 0))
         + chrono::Duration::seconds(i64::from(time.total_seconds()))
 }
@@ -210,6 +522,7 @@ impl Dataset {
         dataset_info: DatasetInfo,
         ntm: transit_model::Model,
         generation_period: &Period,
+        version: String,
     ) -> Result<Self, anyhow::Error> {
         // To correctly handle GTFS-RT stream we need the dataset's timezone,
         // as all the time in the dataset are in local time and the GTFS-RT gives its time
@@ -222,8 +535,22 @@ impl Dataset {
 
         Ok(Self {
             timetable: create_timetable(&ntm, generation_period),
+            stop_points_index: StopPointsIndex::new(&ntm),
+            stop_points_grid: StopPointsGrid::new(&ntm),
+            id_resolvers: dataset_info
+                .realtime_feeds()
+                .into_iter()
+                .map(|(url, mapping)| (url, IdResolver::new(&ntm, &mapping)))
+                .collect(),
+            validity_end: ntm
+                .calendars
+                .values()
+                .filter_map(|c| c.dates.iter().next_back())
+                .max()
+                .copied(),
             ntm,
             timezone,
+            version,
             loaded_at: chrono::Utc::now(),
             feed_construction_info: FeedConstructionInfo {
                 dataset_info,
@@ -232,28 +559,103 @@ impl Dataset {
         })
     }
 
+    /// Stop points corresponding to a stop id.
+    /// The id can be a stop point id or a stop area id, in which case all
+    /// the stop points of the stop area are returned
+    pub fn stop_points(&self, stop_id: &str) -> Option<IdxSet<StopPoint>> {
+        if let Some(idx) = self.ntm.stop_points.get_idx(stop_id) {
+            return Some(std::iter::once(idx).collect());
+        }
+        self.ntm
+            .stop_areas
+            .get_idx(stop_id)
+            .map(|sa_idx| self.ntm.get_corresponding_from_idx::<StopArea, StopPoint>(sa_idx))
+    }
+
     pub fn try_from_dataset_info(
         dataset_info: DatasetInfo,
         generation_period: &Period,
+        cache: Option<&DataCache>,
     ) -> Result<Self, anyhow::Error> {
-        log::info!("reading from path");
-        let gtfs = dataset_info.gtfs.as_str();
-        let nav_data = if gtfs.starts_with("http") {
-            transit_model::gtfs::read_from_url(gtfs, None::<&str>, None)
-        } else {
-            transit_model::gtfs::read_from_zip(gtfs, None::<&str>, None)
-        }
-        .map_err(|e| anyhow!("impossible to read GTFS {} because {}", gtfs, e))?;
-        log::info!("gtfs read");
-        Self::new(dataset_info, nav_data, &generation_period)
+        let sources = schedule_readers::fetch_sources(&dataset_info, cache)?;
+        Self::try_from_sources(dataset_info, &sources, generation_period, cache)
+    }
+
+    /// Create the dataset from its fetched sources,
+    /// using the cached prepared model if the sources have not changed
+    pub fn try_from_sources(
+        dataset_info: DatasetInfo,
+        sources: &Sources,
+        generation_period: &Period,
+        cache: Option<&DataCache>,
+    ) -> Result<Self, anyhow::Error> {
+        let configuration = schedule_readers::configuration_version(&dataset_info);
+        let cached_model = cache.and_then(|c| {
+            c.prepared_model(&dataset_info.id, &configuration, Some(&sources.version))
+        });
+        let nav_data = match cached_model {
+            Some((_, model)) => model,
+            None => {
+                log::info!("reading from path");
+                let model = schedule_readers::read_sources(&dataset_info, sources)?;
+                if let Some(cache) = cache {
+                    cache.store_prepared_model(
+                        &dataset_info.id,
+                        &configuration,
+                        &sources.version,
+                        &model,
+                    );
+                }
+                model
+            }
+        };
+        log::info!("base schedule read");
+        Self::new(
+            dataset_info,
+            nav_data,
+            &generation_period,
+            sources.version.clone(),
+        )
+    }
+
+    /// Create the dataset from the last prepared model of the cache, without checking its sources.
+    /// Used at startup, the sources being checked afterward in the background
+    pub fn try_from_cache(
+        dataset_info: DatasetInfo,
+        generation_period: &Period,
+        cache: &DataCache,
+    ) -> Option<Result<Self, anyhow::Error>> {
+        let configuration = schedule_readers::configuration_version(&dataset_info);
+        let (version, model) = cache.prepared_model(&dataset_info.id, &configuration, None)?;
+        Some(Self::new(dataset_info, model, &generation_period, version))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::datasets::{Connection, DatedVehicleJourney, Period};
+    use crate::datasets::{Connection, Datasets, DatedVehicleJourney, Period};
     use transit_model_builder::ModelBuilder;
 
+    #[test]
+    fn dataset_without_base_schedule_is_rejected() {
+        let read = |dataset: &str| {
+            serde_yaml::from_str::<Datasets>(&format!("datasets:\n  - {}", dataset))
+                .map(|datasets| datasets.datasets.len())
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(read("{id: a, name: a, gtfs: fixtures/gtfs.zip}"), Ok(1));
+        assert_eq!(
+            read("{id: a, name: a, feeds: [{prefix: p, gtfs: fixtures/gtfs.zip}]}"),
+            Ok(1)
+        );
+        let error = read("{id: a, name: a, gtfs-rt-urls: [http://a/gtfs-rt]}").unwrap_err();
+        assert!(
+            error.contains("the dataset a has no base schedule"),
+            "{}",
+            error
+        );
+    }
+
     #[test]
     fn test_timetable_creation() {
         let model = ModelBuilder::default()
@@ -353,4 +755,23 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_stale_dataset_policy() {
+        use crate::datasets::StaleDatasetPolicy;
+        use chrono::TimeZone;
+        let now = chrono::Utc.ymd(2020, 3, 10).and_hms(12, 0, 0);
+        let stale_since = chrono::Utc.ymd(2020, 3, 7).and_hms(12, 0, 0);
+        let valid_until = |d| Some(chrono::NaiveDate::from_ymd(2020, 3, d));
+
+        assert!(!StaleDatasetPolicy::Keep.must_drop(valid_until(1), stale_since, now));
+
+        let policy = StaleDatasetPolicy::DropWhenExpired;
+        assert!(!policy.must_drop(valid_until(10), stale_since, now));
+        assert!(policy.must_drop(valid_until(9), stale_since, now));
+        assert!(policy.must_drop(None, stale_since, now));
+
+        assert!(!StaleDatasetPolicy::DropAfterDays(3).must_drop(None, stale_since, now));
+        assert!(StaleDatasetPolicy::DropAfterDays(2).must_drop(None, stale_since, now));
+    }
 }
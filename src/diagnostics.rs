@@ -0,0 +1,292 @@
+//! Quality of the matching between the GTFS-RT feeds and the base schedule
+//!
+//! Each realtime update gives a `MatchingReport`, listing the entities that could not be
+//! (or could only partially be) applied on the base schedule.
+//! The `Diagnostics` aggregate those reports over a rolling window.
+use chrono::{DateTime, Utc};
+use openapi_schema::OpenapiSchema;
+use std::collections::{BTreeMap, VecDeque};
+
+/// Maximum number of sample entities kept for each kind of issue
+const MAX_SAMPLES: usize = 10;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, OpenapiSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// the trip of the trip update is not in the base schedule
+    UnknownTrip,
+    /// a stop of the trip update is not in the base schedule
+    UnknownStop,
+    /// the stop of a stop time update is not the base schedule's stop at this sequence
+    StopMismatch,
+    /// the trip has no id, and several trips of the base schedule match its route and start time
+    AmbiguousMatch,
+    /// the trip is not valid (invalid date, time or direction)
+    InvalidTrip,
+    /// the date of the trip is outside of the period of the timetable
+    DateOutOfPeriod,
+    /// the timestamp of the trip update is in the future
+    FutureTimestamp,
+    /// the timestamp of the trip update is too old
+    PastTimestamp,
+}
+
+impl IssueKind {
+    /// true if the trip update could not be matched with a trip of the base schedule
+    pub fn is_matching_failure(self) -> bool {
+        matches!(
+            self,
+            IssueKind::UnknownTrip | IssueKind::AmbiguousMatch | IssueKind::InvalidTrip
+        )
+    }
+}
+
+/// the name of an issue kind is its serialized name, as in the diagnostics
+impl std::fmt::Display for IssueKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(name)) => f.write_str(&name),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// Error in the matching of a realtime entity with the base schedule
+#[derive(Debug)]
+pub struct MatchingError {
+    pub kind: IssueKind,
+    pub message: String,
+}
+
+impl MatchingError {
+    pub fn new(kind: IssueKind, message: String) -> Self {
+        Self { kind, message }
+    }
+}
+
+impl std::fmt::Display for MatchingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for MatchingError {}
+
+#[derive(Debug, Clone)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub entity_id: String,
+    pub message: String,
+}
+
+/// Issues found while applying one realtime update
+#[derive(Debug, Clone, Default)]
+pub struct MatchingReport {
+    pub nb_trip_updates: usize,
+    pub nb_matched_trip_updates: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl MatchingReport {
+    pub fn add_issue(&mut self, kind: IssueKind, entity_id: &str, message: String) {
+        self.issues.push(Issue {
+            kind,
+            entity_id: entity_id.to_owned(),
+            message,
+        });
+    }
+
+    /// add an issue from an error, the error being an `InvalidTrip` if it's not a `MatchingError`
+    pub fn add_error(&mut self, entity_id: &str, error: &anyhow::Error) {
+        let kind = error
+            .downcast_ref::<MatchingError>()
+            .map(|e| e.kind)
+            .unwrap_or(IssueKind::InvalidTrip);
+        self.add_issue(kind, entity_id, format!("{}", error));
+    }
+
+    pub fn merge(&mut self, other: MatchingReport) {
+        self.nb_trip_updates += other.nb_trip_updates;
+        self.nb_matched_trip_updates += other.nb_matched_trip_updates;
+        self.issues.extend(other.issues);
+    }
+}
+
+#[derive(Serialize, Debug, Clone, OpenapiSchema)]
+pub struct SampleEntity {
+    pub entity_id: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug, Clone, OpenapiSchema)]
+pub struct IssueSummary {
+    pub kind: IssueKind,
+    pub count: usize,
+    /// some of the last entities with this issue
+    pub samples: Vec<SampleEntity>,
+}
+
+/// Summary of the matching issues over the rolling window
+#[derive(Serialize, Debug, Clone, OpenapiSchema)]
+pub struct DiagnosticsReport {
+    /// begin of the window (the date of the oldest realtime update considered)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window_begin: Option<DateTime<Utc>>,
+    pub window_duration_minutes: i64,
+    pub nb_realtime_updates: usize,
+    pub nb_trip_updates: usize,
+    pub nb_matched_trip_updates: usize,
+    /// the issues, sorted by kind
+    pub issues: Vec<IssueSummary>,
+}
+
+/// Count the issues by kind, keeping the first ones as samples
+pub fn summarize_issues<'a>(issues: impl Iterator<Item = &'a Issue>) -> Vec<IssueSummary> {
+    let mut summaries = BTreeMap::<IssueKind, IssueSummary>::new();
+    for issue in issues {
+        let summary = summaries.entry(issue.kind).or_insert_with(|| IssueSummary {
+            kind: issue.kind,
+            count: 0,
+            samples: vec![],
+        });
+        summary.count += 1;
+        if summary.samples.len() < MAX_SAMPLES {
+            summary.samples.push(SampleEntity {
+                entity_id: issue.entity_id.clone(),
+                message: issue.message.clone(),
+            });
+        }
+    }
+    summaries.into_iter().map(|(_, summary)| summary).collect()
+}
+
+/// Matching reports of the realtime updates over a rolling window
+pub struct Diagnostics {
+    window: chrono::Duration,
+    reports: VecDeque<(DateTime<Utc>, MatchingReport)>,
+}
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Self::new(chrono::Duration::hours(1))
+    }
+}
+
+impl Diagnostics {
+    pub fn new(window: chrono::Duration) -> Self {
+        Self {
+            window,
+            reports: VecDeque::new(),
+        }
+    }
+
+    /// add the report of a realtime update, and remove the reports outside the window
+    pub fn add_report(&mut self, date: DateTime<Utc>, report: MatchingReport) {
+        self.reports.push_back((date, report));
+        let limit = date - self.window;
+        while self
+            .reports
+            .front()
+            .map(|(d, _)| *d < limit)
+            .unwrap_or(false)
+        {
+            self.reports.pop_front();
+        }
+    }
+
+    pub fn summary(&self) -> DiagnosticsReport {
+        // we iterate from the most recent report, to keep the most recent samples
+        let issues = summarize_issues(
+            self.reports
+                .iter()
+                .rev()
+                .flat_map(|(_, report)| report.issues.iter().rev()),
+        );
+        DiagnosticsReport {
+            window_begin: self.reports.front().map(|(d, _)| *d),
+            window_duration_minutes: self.window.num_minutes(),
+            nb_realtime_updates: self.reports.len(),
+            nb_trip_updates: self.reports.iter().map(|(_, r)| r.nb_trip_updates).sum(),
+            nb_matched_trip_updates: self
+                .reports
+                .iter()
+                .map(|(_, r)| r.nb_matched_trip_updates)
+                .sum(),
+            issues,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn issue(summary: &DiagnosticsReport, kind: IssueKind) -> &IssueSummary {
+        summary.issues.iter().find(|i| i.kind == kind).unwrap()
+    }
+
+    fn report(issues: &[(IssueKind, &str)]) -> MatchingReport {
+        let mut report = MatchingReport {
+            nb_trip_updates: issues.len() + 1,
+            nb_matched_trip_updates: 1,
+            ..Default::default()
+        };
+        for (kind, entity_id) in issues {
+            report.add_issue(*kind, entity_id, "an issue".to_owned());
+        }
+        report
+    }
+
+    #[test]
+    fn issue_kind_name_is_its_serialized_name() {
+        assert_eq!(IssueKind::UnknownTrip.to_string(), "unknown_trip");
+        assert_eq!(IssueKind::DateOutOfPeriod.to_string(), "date_out_of_period");
+    }
+
+    #[test]
+    fn test_rolling_window() {
+        let mut diagnostics = Diagnostics::new(chrono::Duration::minutes(10));
+        let t0 = Utc.ymd(2020, 3, 10).and_hms(12, 0, 0);
+        diagnostics.add_report(t0, report(&[(IssueKind::UnknownTrip, "e1")]));
+        diagnostics.add_report(
+            t0 + chrono::Duration::minutes(5),
+            report(&[
+                (IssueKind::UnknownTrip, "e2"),
+                (IssueKind::UnknownStop, "e3"),
+            ]),
+        );
+
+        let summary = diagnostics.summary();
+        assert_eq!(summary.nb_realtime_updates, 2);
+        assert_eq!(summary.nb_trip_updates, 5);
+        assert_eq!(summary.nb_matched_trip_updates, 2);
+        let unknown_trips = issue(&summary, IssueKind::UnknownTrip);
+        assert_eq!(unknown_trips.count, 2);
+        // the most recent samples are first
+        assert_eq!(unknown_trips.samples[0].entity_id, "e2");
+        assert_eq!(issue(&summary, IssueKind::UnknownStop).count, 1);
+
+        // the first report is now out of the window
+        diagnostics.add_report(t0 + chrono::Duration::minutes(12), report(&[]));
+        let summary = diagnostics.summary();
+        assert_eq!(summary.nb_realtime_updates, 2);
+        assert_eq!(issue(&summary, IssueKind::UnknownTrip).count, 1);
+        assert_eq!(
+            summary.window_begin,
+            Some(t0 + chrono::Duration::minutes(5))
+        );
+    }
+
+    #[test]
+    fn test_error_kind() {
+        let mut report = MatchingReport::default();
+        report.add_error(
+            "e1",
+            &MatchingError::new(IssueKind::AmbiguousMatch, "2 trips".to_owned()).into(),
+        );
+        report.add_error("e2", &anyhow::anyhow!("invalid date"));
+        assert_eq!(report.issues[0].kind, IssueKind::AmbiguousMatch);
+        assert_eq!(report.issues[1].kind, IssueKind::InvalidTrip);
+    }
+}
@@ -0,0 +1,555 @@
+//! Estimation of the trip updates from the vehicle positions
+//!
+//! Some producers only publish the positions of their vehicles.
+//! The position of a vehicle is projected on the path of its trip (its GTFS shape if there is one,
+//! else the straight lines between its stops), the delay is the difference between the position's timestamp
+//! and the scheduled time at this point of the path, and this delay is propagated to the next stops of the trip.
+//!
+//! The estimated trip updates are marked as `estimated`, and they are only used for the trips without a real trip update.
+use crate::datasets::{create_dt, DatedVehicleJourney};
+use crate::model_update::{get_dated_vj, IdResolver, ModelUpdate, StopTimeUpdate, TripUpdate};
+use crate::spatial_index::METERS_BY_DEGREE;
+use crate::transit_realtime;
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use log::{debug, warn};
+use std::collections::HashMap;
+use transit_model::objects::{Coord, VehicleJourney};
+
+/// a vehicle further than this (in meters) from the path of its trip is not used
+const MAX_DISTANCE_TO_PATH: f64 = 500.;
+/// a vehicle closer than this (in meters) to a stop is considered at the stop
+const STOP_RADIUS: f64 = 50.;
+
+/// A path, in a local plane (in meters) precise enough at the scale of a trip
+struct Path {
+    cos_lat: f64,
+    points: Vec<(f64, f64)>,
+    /// distance from the beginning of the path of each point
+    distances: Vec<f64>,
+}
+
+/// Projection of a coordinate on a path
+#[derive(Debug, Clone, Copy)]
+struct Projection {
+    /// index of the segment of the path
+    segment: usize,
+    /// distance from the beginning of the path
+    along: f64,
+    /// distance between the coordinate and the path
+    offset: f64,
+}
+
+fn plane_distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2)).sqrt()
+}
+
+impl Path {
+    /// `None` if there are less than 2 points
+    fn new(coords: &[Coord]) -> Option<Self> {
+        if coords.len() < 2 {
+            return None;
+        }
+        let mut path = Self {
+            cos_lat: coords[0].lat.to_radians().cos(),
+            points: Vec::with_capacity(coords.len()),
+            distances: Vec::with_capacity(coords.len()),
+        };
+        for coord in coords {
+            let point = path.to_plane(coord);
+            let distance = match (path.points.last(), path.distances.last()) {
+                (Some(last), Some(d)) => d + plane_distance(*last, point),
+                _ => 0.,
+            };
+            path.points.push(point);
+            path.distances.push(distance);
+        }
+        Some(path)
+    }
+
+    fn to_plane(&self, coord: &Coord) -> (f64, f64) {
+        (
+            coord.lon * self.cos_lat * METERS_BY_DEGREE,
+            coord.lat * METERS_BY_DEGREE,
+        )
+    }
+
+    /// closest projection of `coord` on the path, only looking at the segments from `first_segment`
+    fn project(&self, coord: &Coord, first_segment: usize) -> Option<Projection> {
+        let p = self.to_plane(coord);
+        (first_segment..self.points.len() - 1)
+            .map(|i| {
+                let (a, b) = (self.points[i], self.points[i + 1]);
+                let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+                let sq_len = dx * dx + dy * dy;
+                let t = if sq_len > 0. {
+                    (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / sq_len)
+                        .max(0.)
+                        .min(1.)
+                } else {
+                    0.
+                };
+                Projection {
+                    segment: i,
+                    along: self.distances[i] + t * sq_len.sqrt(),
+                    offset: plane_distance(p, (a.0 + t * dx, a.1 + t * dy)),
+                }
+            })
+            .fold(None, |best: Option<Projection>, p| match best {
+                Some(best) if best.offset <= p.offset => Some(best),
+                _ => Some(p),
+            })
+    }
+}
+
+/// The path of a trip, with the position of its stops on it
+struct TripPath {
+    path: Path,
+    /// segment of the path of each stop
+    stop_segments: Vec<usize>,
+    /// distance from the beginning of the path of each stop
+    stop_distances: Vec<f64>,
+}
+
+impl TripPath {
+    fn new(model: &transit_model::Model, vj: &VehicleJourney) -> Option<Self> {
+        let stops: Vec<Coord> = vj
+            .stop_times
+            .iter()
+            .map(|st| {
+                let coord = &model.stop_points[st.stop_point_idx].coord;
+                Coord {
+                    lon: coord.lon,
+                    lat: coord.lat,
+                }
+            })
+            .collect();
+        Self::from_shape(model, vj, &stops).or_else(|| Self::from_stops(&stops))
+    }
+
+    fn from_stops(stops: &[Coord]) -> Option<Self> {
+        let path = Path::new(stops)?;
+        let last_segment = stops.len() - 2;
+        Some(Self {
+            stop_segments: (0..stops.len()).map(|i| i.min(last_segment)).collect(),
+            stop_distances: path.distances.clone(),
+            path,
+        })
+    }
+
+    /// the path of the GTFS shape of the trip, if it goes by all the stops
+    fn from_shape(
+        model: &transit_model::Model,
+        vj: &VehicleJourney,
+        stops: &[Coord],
+    ) -> Option<Self> {
+        let geometry = model.geometries.get(vj.geometry_id.as_ref()?)?;
+        let line_string = geometry.geometry.clone().into_line_string()?;
+        let coords: Vec<Coord> = line_string
+            .0
+            .iter()
+            .map(|c| Coord { lon: c.x, lat: c.y })
+            .collect();
+        let path = Path::new(&coords)?;
+        let mut stop_segments = Vec::with_capacity(stops.len());
+        let mut stop_distances: Vec<f64> = Vec::with_capacity(stops.len());
+        for stop in stops {
+            // the stops are projected in order, for the trips going several times by the same place
+            let first_segment = stop_segments.last().copied().unwrap_or(0);
+            let projection = path.project(stop, first_segment)?;
+            if projection.offset > MAX_DISTANCE_TO_PATH {
+                debug!(
+                    "the shape of trip {} does not go by its stops, they are used as path",
+                    vj.id
+                );
+                return None;
+            }
+            let previous_distance = stop_distances.last().copied().unwrap_or(0.);
+            stop_segments.push(projection.segment);
+            stop_distances.push(projection.along.max(previous_distance));
+        }
+        Some(Self {
+            path,
+            stop_segments,
+            stop_distances,
+        })
+    }
+}
+
+/// Estimate the stop time updates of a trip whose vehicle is at `position` at `timestamp`
+/// (in the local time of the dataset).
+///
+/// `current_stop_sequence`, if given by the producer, is used to project the vehicle only on the path
+/// after the previous stop, for the trips going several times by the same place
+pub fn estimate_stop_time_updates(
+    model: &transit_model::Model,
+    dated_vj: &DatedVehicleJourney,
+    position: &Coord,
+    current_stop_sequence: Option<u32>,
+    timestamp: NaiveDateTime,
+) -> Result<HashMap<u32, StopTimeUpdate>, Error> {
+    let vj = &model.vehicle_journeys[dated_vj.vj_idx];
+    let trip_path =
+        TripPath::new(model, vj).ok_or_else(|| anyhow!("trip {} has less than 2 stops", vj.id))?;
+    let first_segment = current_stop_sequence
+        .and_then(|seq| vj.stop_times.iter().position(|st| st.sequence >= seq))
+        .map(|pos| trip_path.stop_segments[pos.saturating_sub(1)])
+        .unwrap_or(0);
+    let projection = trip_path
+        .path
+        .project(position, first_segment)
+        .ok_or_else(|| anyhow!("impossible to project the vehicle on trip {}", vj.id))?;
+    if projection.offset > MAX_DISTANCE_TO_PATH {
+        return Err(anyhow!(
+            "the vehicle is {:.0}m away from the path of trip {}",
+            projection.offset,
+            vj.id
+        ));
+    }
+
+    let along = projection.along;
+    let distances = &trip_path.stop_distances;
+    // the last stop reached by the vehicle, the vehicle waiting at the first stop if it has not reached it
+    let (stop, at_stop) = match distances.iter().rposition(|d| *d <= along + STOP_RADIUS) {
+        Some(stop) => (stop, distances[stop] >= along - STOP_RADIUS),
+        None => (0, true),
+    };
+    let last_stop = vj.stop_times.len() - 1;
+    if stop == last_stop && !at_stop {
+        return Err(anyhow!("the vehicle has finished trip {}", vj.id));
+    }
+
+    let scheduled = |i: usize| {
+        let st = &vj.stop_times[i];
+        (
+            create_dt(dated_vj.date, st.arrival_time),
+            create_dt(dated_vj.date, st.departure_time),
+        )
+    };
+    let delay = if at_stop {
+        let (arrival, departure) = scheduled(stop);
+        if timestamp > departure {
+            timestamp - departure
+        } else if timestamp < arrival && stop != 0 {
+            // the vehicle is early at this stop
+            timestamp - arrival
+        } else {
+            // the vehicle is on time, or waits at the first stop until its departure, it's not early
+            chrono::Duration::zero()
+        }
+    } else {
+        // between 2 stops, the scheduled time is interpolated with the distance
+        let (_, departure) = scheduled(stop);
+        let (arrival, _) = scheduled(stop + 1);
+        let (from, to) = (distances[stop], distances[stop + 1]);
+        let ratio = if to > from {
+            ((along - from) / (to - from)).max(0.).min(1.)
+        } else {
+            0.
+        };
+        let travel_time = (arrival - departure).num_seconds() as f64;
+        timestamp - (departure + chrono::Duration::seconds((travel_time * ratio).round() as i64))
+    };
+
+    let first_updated_stop = if at_stop { stop } else { stop + 1 };
+    Ok((first_updated_stop..=last_stop)
+        .map(|i| {
+            let st = &vj.stop_times[i];
+            let (arrival, departure) = scheduled(i);
+            (
+                st.sequence,
+                StopTimeUpdate {
+                    stop_point_idx: Some(st.stop_point_idx),
+                    updated_arrival: Some(arrival + delay),
+                    updated_departure: Some(departure + delay),
+                },
+            )
+        })
+        .collect())
+}
+
+/// Estimate the trip updates from the vehicle positions of some GTFS-RT.
+///
+/// `now` is used for the trips without date and the positions without timestamp
+pub fn estimate_model_update(
+    model: &transit_model::Model,
+    id_resolver: &IdResolver,
+    gtfs_rts: &[transit_realtime::FeedMessage],
+    timezone: chrono_tz::Tz,
+    now: DateTime<Utc>,
+) -> ModelUpdate {
+    let mut model_update = ModelUpdate::default();
+    for gtfs_rt in gtfs_rts {
+        for entity in &gtfs_rt.entity {
+            let vehicle_position = match &entity.vehicle {
+                Some(vehicle_position) => vehicle_position,
+                None => continue,
+            };
+            let (trip, position) = match (&vehicle_position.trip, &vehicle_position.position) {
+                (Some(trip), Some(position)) => (trip, position),
+                _ => {
+                    debug!(
+                        "vehicle position {} has no trip or no position, it cannot be used",
+                        entity.id
+                    );
+                    continue;
+                }
+            };
+            let dated_vj = match get_dated_vj(model, id_resolver, trip, &entity.id, timezone, now) {
+                Ok(dated_vj) => dated_vj,
+                Err(e) => {
+                    warn!("{}", e);
+                    continue;
+                }
+            };
+            let update_dt = vehicle_position
+                .timestamp
+                .or(gtfs_rt.header.timestamp)
+                .map(|t| DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(t as i64, 0), Utc))
+                .unwrap_or(now);
+            let position = Coord {
+                lon: f64::from(position.longitude),
+                lat: f64::from(position.latitude),
+            };
+            let stop_time_update_by_sequence = match estimate_stop_time_updates(
+                model,
+                &dated_vj,
+                &position,
+                vehicle_position.current_stop_sequence,
+                update_dt.with_timezone(&timezone).naive_local(),
+            ) {
+                Ok(stus) => stus,
+                Err(e) => {
+                    warn!("for vehicle position {}, {}", entity.id, e);
+                    continue;
+                }
+            };
+            model_update.trips.insert(
+                dated_vj,
+                TripUpdate {
+                    stop_time_update_by_sequence,
+                    update_dt,
+                    entity_id: entity.id.clone(),
+                    estimated: true,
+                },
+            );
+        }
+    }
+    debug!(
+        "{} trip updates estimated from the vehicle positions",
+        model_update.trips.len()
+    );
+    model_update
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_utils::create_feed_message;
+    use crate::transit_realtime as tr;
+    use transit_model_builder::ModelBuilder;
+
+    fn ndt(d: &str) -> NaiveDateTime {
+        d.parse().unwrap()
+    }
+
+    // a trip going north, its stops being about 1.1km away from each other
+    fn make_model() -> transit_model::Model {
+        let model = ModelBuilder::default()
+            .calendar("c", |c| {
+                c.dates.insert(chrono::NaiveDate::from_ymd(2020, 3, 10));
+            })
+            .vj("vj1", |vj_builder| {
+                vj_builder
+                    .calendar("c")
+                    .st("A", "10:00:00", "10:00:00")
+                    .st("B", "10:10:00", "10:11:00")
+                    .st("C", "10:20:00", "10:20:00");
+            })
+            .build();
+        let mut collections = model.into_collections();
+        for (id, lat) in &[("A", 48.85), ("B", 48.86), ("C", 48.87)] {
+            collections.stop_points.get_mut(*id).unwrap().coord = Coord {
+                lon: 2.35,
+                lat: *lat,
+            };
+        }
+        transit_model::Model::new(collections).unwrap()
+    }
+
+    fn dated_vj(model: &transit_model::Model) -> DatedVehicleJourney {
+        DatedVehicleJourney {
+            vj_idx: model.vehicle_journeys.get_idx("vj1").unwrap(),
+            date: chrono::NaiveDate::from_ymd(2020, 3, 10),
+        }
+    }
+
+    fn coord(lat: f64) -> Coord {
+        Coord { lon: 2.35, lat }
+    }
+
+    // the update of a stop
+    fn stu<'a>(
+        stus: &'a HashMap<u32, StopTimeUpdate>,
+        model: &transit_model::Model,
+        stop: &str,
+    ) -> &'a StopTimeUpdate {
+        stus.values()
+            .find(|stu| stu.stop_point_idx == model.stop_points.get_idx(stop))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_path_projection() {
+        let path = Path::new(&[coord(48.85), coord(48.86), coord(48.87)]).unwrap();
+        let projection = path
+            .project(
+                &Coord {
+                    lon: 2.3501,
+                    lat: 48.855,
+                },
+                0,
+            )
+            .unwrap();
+        assert_eq!(projection.segment, 0);
+        assert!((projection.along - 556.6).abs() < 1., "{:?}", projection);
+        assert!(projection.offset < 10., "{:?}", projection);
+
+        // only the segments after the first segment are looked at
+        let projection = path.project(&coord(48.855), 1).unwrap();
+        assert_eq!(projection.segment, 1);
+        assert!((projection.along - 1113.2).abs() < 1., "{:?}", projection);
+    }
+
+    #[test]
+    fn test_estimation_between_stops() {
+        let model = make_model();
+        // half way between B and C, 3 minutes late
+        let stus = estimate_stop_time_updates(
+            &model,
+            &dated_vj(&model),
+            &coord(48.865),
+            None,
+            ndt("2020-03-10T10:18:30"),
+        )
+        .unwrap();
+        assert_eq!(stus.len(), 1);
+        let c = stu(&stus, &model, "C");
+        assert_eq!(c.updated_arrival, Some(ndt("2020-03-10T10:23:00")));
+        assert_eq!(c.updated_departure, Some(ndt("2020-03-10T10:23:00")));
+    }
+
+    #[test]
+    fn test_estimation_at_stop() {
+        let model = make_model();
+        // at B, during its scheduled stop: no delay
+        let stus = estimate_stop_time_updates(
+            &model,
+            &dated_vj(&model),
+            &coord(48.8601),
+            None,
+            ndt("2020-03-10T10:10:30"),
+        )
+        .unwrap();
+        assert_eq!(stus.len(), 2);
+        assert_eq!(
+            stu(&stus, &model, "B").updated_departure,
+            Some(ndt("2020-03-10T10:11:00"))
+        );
+        assert_eq!(
+            stu(&stus, &model, "C").updated_arrival,
+            Some(ndt("2020-03-10T10:20:00"))
+        );
+
+        // still at B after its scheduled departure: the delay is propagated
+        let stus = estimate_stop_time_updates(
+            &model,
+            &dated_vj(&model),
+            &coord(48.8601),
+            None,
+            ndt("2020-03-10T10:13:00"),
+        )
+        .unwrap();
+        assert_eq!(
+            stu(&stus, &model, "B").updated_departure,
+            Some(ndt("2020-03-10T10:13:00"))
+        );
+        assert_eq!(
+            stu(&stus, &model, "C").updated_arrival,
+            Some(ndt("2020-03-10T10:22:00"))
+        );
+
+        // waiting at the first stop before its departure, the vehicle is not early
+        let stus = estimate_stop_time_updates(
+            &model,
+            &dated_vj(&model),
+            &coord(48.8499),
+            None,
+            ndt("2020-03-10T09:55:00"),
+        )
+        .unwrap();
+        assert_eq!(stus.len(), 3);
+        assert_eq!(
+            stu(&stus, &model, "A").updated_departure,
+            Some(ndt("2020-03-10T10:00:00"))
+        );
+    }
+
+    #[test]
+    fn test_estimation_far_from_trip() {
+        let model = make_model();
+        assert!(estimate_stop_time_updates(
+            &model,
+            &dated_vj(&model),
+            &Coord {
+                lon: 2.45,
+                lat: 48.86
+            },
+            None,
+            ndt("2020-03-10T10:10:00"),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_estimate_model_update() {
+        let model = make_model();
+        let position = |id: &str, trip_id: &str| tr::FeedEntity {
+            id: id.to_owned(),
+            vehicle: Some(tr::VehiclePosition {
+                trip: Some(tr::TripDescriptor {
+                    trip_id: Some(trip_id.to_owned()),
+                    start_date: Some("20200310".to_owned()),
+                    ..Default::default()
+                }),
+                position: Some(tr::Position {
+                    latitude: 48.855,
+                    longitude: 2.35,
+                    ..Default::default()
+                }),
+                // 10:06:00 UTC
+                timestamp: Some(1_583_834_760),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let gtfs_rt = create_feed_message(&[position("v1", "vj1"), position("v2", "unknown")]);
+
+        let model_update = estimate_model_update(
+            &model,
+            &IdResolver::default(),
+            &[gtfs_rt],
+            chrono_tz::UTC,
+            Utc::now(),
+        );
+        assert_eq!(model_update.trips.len(), 1);
+        let trip_update = &model_update.trips[&dated_vj(&model)];
+        assert!(trip_update.estimated);
+        assert_eq!(trip_update.entity_id, "v1");
+        // half way between A and B, 1 minute late
+        assert_eq!(
+            stu(&trip_update.stop_time_update_by_sequence, &model, "B").updated_arrival,
+            Some(ndt("2020-03-10T10:11:00"))
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use actix_web::error::InternalError;
+use actix_web::http::header;
+use actix_web::{dev::Payload, web::Data, FromRequest, HttpRequest, HttpResponse};
+use futures::future::{ready, Ready};
+
+/// Token giving access to the admin routes (to publish alerts, ...).
+/// If no token is configured, the admin routes are disabled
+#[derive(Debug, Clone, Default)]
+pub struct AdminToken(pub Option<String>);
+
+/// Guard of the admin routes, the query should be authenticated
+/// with the admin token, given as an `Authorization: Bearer <token>` header.
+///
+/// ```
+/// use transpo_rt::extractors::Admin;
+/// pub async fn an_admin_route(_admin: Admin) -> actix_web::Result<()> {
+///    Ok(())
+///}
+/// ```
+#[derive(Debug)]
+pub struct Admin;
+
+/// comparison taking the same time whatever the position of the first difference,
+/// not to give hints on the token
+fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (g, e)| acc | (g ^ e))
+            == 0
+}
+
+impl Admin {
+    pub fn from_request(req: &HttpRequest) -> Result<Self, actix_web::Error> {
+        let expected = req
+            .app_data::<Data<AdminToken>>()
+            .and_then(|token| token.0.clone())
+            .ok_or_else(|| {
+                actix_web::error::ErrorForbidden("the admin api is disabled, no token configured")
+            })?;
+        let given = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(str::trim);
+        match given {
+            Some(given) if same_token(given, &expected) => Ok(Admin),
+            _ => {
+                let msg = "a valid admin token should be given as a bearer token";
+                let response = HttpResponse::Unauthorized()
+                    .header(header::WWW_AUTHENTICATE, "Bearer")
+                    .body(msg);
+                Err(InternalError::from_response(msg, response).into())
+            }
+        }
+    }
+}
+
+impl FromRequest for Admin {
+    type Config = ();
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Admin, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Admin::from_request(req))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Admin, AdminToken};
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    fn status(req: &actix_web::HttpRequest) -> Option<StatusCode> {
+        Admin::from_request(req)
+            .err()
+            .map(|e| e.as_response_error().status_code())
+    }
+
+    #[test]
+    fn admin_authentication() {
+        let token = AdminToken(Some("secret".to_owned()));
+        let req = TestRequest::with_uri("/d/alerts/")
+            .data(token.clone())
+            .header("Authorization", "Bearer secret")
+            .to_http_request();
+        assert_eq!(status(&req), None);
+        let req = TestRequest::with_uri("/d/alerts/")
+            .data(token.clone())
+            .header("Authorization", "Bearer secreT")
+            .to_http_request();
+        assert_eq!(status(&req), Some(StatusCode::UNAUTHORIZED));
+        let req = TestRequest::with_uri("/d/alerts/")
+            .data(token)
+            .to_http_request();
+        assert_eq!(status(&req), Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn admin_disabled() {
+        let req = TestRequest::with_uri("/d/alerts/")
+            .data(AdminToken(None))
+            .header("Authorization", "Bearer secret")
+            .to_http_request();
+        assert_eq!(status(&req), Some(StatusCode::FORBIDDEN));
+    }
+}
@@ -0,0 +1,70 @@
+use crate::access_control::AccessControl;
+use crate::middlewares::access_control::{api_key, API_KEY_HEADER};
+use actix_web::{dev::Payload, web::Data, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use std::sync::Arc;
+
+/// Guard of the routes only available to the known consumers, the query should be
+/// authenticated with one of the configured api keys.
+///
+/// The api key is given to the route, to identify the consumer
+#[derive(Debug)]
+pub struct ApiKey(pub String);
+
+impl ApiKey {
+    pub fn from_request(req: &HttpRequest) -> Result<Self, actix_web::Error> {
+        let access_control = req.app_data::<Data<Arc<AccessControl>>>();
+        match api_key(req.headers(), req.query_string()) {
+            Some(key) if access_control.map_or(false, |a| a.is_valid_key(&key)) => Ok(ApiKey(key)),
+            _ => Err(actix_web::error::ErrorUnauthorized(format!(
+                "a valid api key should be given, in a '{}' header",
+                API_KEY_HEADER
+            ))),
+        }
+    }
+}
+
+impl FromRequest for ApiKey {
+    type Config = ();
+    type Error = actix_web::Error;
+    type Future = Ready<Result<ApiKey, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(ApiKey::from_request(req))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ApiKey;
+    use crate::access_control::{AccessControl, AccessControlConfig};
+    use actix_web::test::TestRequest;
+    use std::sync::Arc;
+
+    #[test]
+    fn api_key_authentication() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "key1 a consumer\n").unwrap();
+        let access_control = Arc::new(
+            AccessControl::new(&AccessControlConfig {
+                api_keys_file: Some(file.path().to_str().unwrap().to_owned()),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let req = TestRequest::with_uri("/d/siri/2.0/subscriptions")
+            .data(access_control.clone())
+            .header("X-Api-Key", "key1")
+            .to_http_request();
+        assert_eq!(ApiKey::from_request(&req).unwrap().0, "key1");
+        let req = TestRequest::with_uri("/d/siri/2.0/subscriptions")
+            .data(access_control.clone())
+            .header("X-Api-Key", "key2")
+            .to_http_request();
+        assert!(ApiKey::from_request(&req).is_err());
+        let req = TestRequest::with_uri("/d/siri/2.0/subscriptions")
+            .data(access_control)
+            .to_http_request();
+        assert!(ApiKey::from_request(&req).is_err());
+    }
+}
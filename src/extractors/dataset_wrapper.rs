@@ -1,7 +1,9 @@
 use crate::actors::{DatasetActor, GetDataset, GetRealtimeDataset};
-use crate::datasets::{Dataset, RealTimeDataset};
+use crate::datasets::{Dataset, DatasetLoading, RealTimeDataset};
 use actix::Addr;
-use actix_web::{dev::Payload, web::Data, FromRequest, HttpRequest};
+use actix_web::error::InternalError;
+use actix_web::http::header;
+use actix_web::{dev::Payload, web::Data, FromRequest, HttpRequest, HttpResponse};
 use futures::future::{err, FutureExt, LocalBoxFuture};
 use std::sync::Arc;
 
@@ -53,12 +55,22 @@ impl RealTimeDatasetWrapper {
     }
 }
 
+/// delay (in seconds) after which the clients can retry a query on a loading dataset
+const LOADING_RETRY_AFTER: u32 = 30;
+
 fn get_dataset(d: &Arc<Result<Dataset, anyhow::Error>>) -> Result<&Dataset, actix_web::Error> {
     d.as_ref().as_ref().map_err(|e| {
-        actix_web::error::ErrorBadGateway(format!(
-            "theoretical dataset temporarily unavailable: {}",
-            e
-        ))
+        if e.is::<DatasetLoading>() {
+            let response = HttpResponse::ServiceUnavailable()
+                .header(header::RETRY_AFTER, LOADING_RETRY_AFTER.to_string())
+                .body(e.to_string());
+            InternalError::from_response(e.to_string(), response).into()
+        } else {
+            actix_web::error::ErrorBadGateway(format!(
+                "theoretical dataset temporarily unavailable: {}",
+                e
+            ))
+        }
     })
 }
 
@@ -121,3 +133,27 @@ impl FromRequest for RealTimeDatasetWrapper {
             .boxed_local()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::get_dataset;
+    use crate::datasets::{Dataset, DatasetLoading};
+    use actix_web::http::{header, StatusCode};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_loading_dataset() {
+        let loading: Arc<Result<Dataset, anyhow::Error>> = Arc::new(Err(DatasetLoading.into()));
+        let response = get_dataset(&loading).err().unwrap().as_response_error().error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers().get(header::RETRY_AFTER).unwrap(),
+            &super::LOADING_RETRY_AFTER.to_string()
+        );
+
+        let failed: Arc<Result<Dataset, anyhow::Error>> =
+            Arc::new(Err(anyhow::anyhow!("invalid dataset")));
+        let response = get_dataset(&failed).err().unwrap().as_response_error().error_response();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+}
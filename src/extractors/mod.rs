@@ -1,3 +1,9 @@
+mod admin;
+mod api_key;
 mod dataset_wrapper;
+mod response_format;
 
+pub use admin::{Admin, AdminToken};
+pub use api_key::ApiKey;
 pub use dataset_wrapper::{DatasetWrapper, RealTimeDatasetWrapper};
+pub use response_format::ResponseFormat;
@@ -0,0 +1,62 @@
+use actix_web::{dev::Payload, FromRequest, HttpRequest};
+use futures::future::{ok, Ready};
+
+/// Format of the response, given by the `.json` or `.xml` suffix of the route.
+/// The default format is json.
+///
+/// ```
+/// use transpo_rt::extractors::ResponseFormat;
+/// pub async fn a_route(format: ResponseFormat) -> actix_web::Result<()> {
+///    let is_xml = format == ResponseFormat::Xml;
+///    Ok(())
+///}
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Json,
+    Xml,
+}
+
+impl ResponseFormat {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        if req.path().trim_end_matches('/').ends_with(".xml") {
+            ResponseFormat::Xml
+        } else {
+            ResponseFormat::Json
+        }
+    }
+}
+
+impl FromRequest for ResponseFormat {
+    type Config = ();
+    type Error = actix_web::Error;
+    type Future = Ready<Result<ResponseFormat, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ok(ResponseFormat::from_request(req))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ResponseFormat;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn format_from_suffix() {
+        let req = TestRequest::with_uri("/d/siri/2.0/stop-monitoring.xml/").to_http_request();
+        assert_eq!(ResponseFormat::from_request(&req), ResponseFormat::Xml);
+        let req = TestRequest::with_uri("/d/siri/2.0/stop-monitoring.xml").to_http_request();
+        assert_eq!(ResponseFormat::from_request(&req), ResponseFormat::Xml);
+        let req = TestRequest::with_uri("/d/siri/2.0/stop-monitoring.json/").to_http_request();
+        assert_eq!(ResponseFormat::from_request(&req), ResponseFormat::Json);
+    }
+
+    #[test]
+    fn accept_header_does_not_change_the_format() {
+        let req = TestRequest::with_uri("/d/siri/2.0/stop-monitoring.json/")
+            .header("Accept", "application/xml")
+            .to_http_request();
+        assert_eq!(ResponseFormat::from_request(&req), ResponseFormat::Json);
+    }
+}
@@ -0,0 +1,313 @@
+//! Creation of GTFS-RT TripUpdates from the realtime timetable of a dataset
+//!
+//! Used when some realtime feeds of a dataset are not GTFS-RT (like the SIRI feeds),
+//! for the dataset's `/gtfs-rt` to also give their updates.
+//! The TripUpdates use the base schedule ids (of the vehicle journeys, lines and stop points).
+//! The trips only updated by a GTFS-RT trip update keep this trip update as is,
+//! and the trip updates estimated from the vehicle positions have an entity id ending with `:estimated`.
+use crate::datasets::{Dataset, RealTimeConnection, ScheduleRelationship, UpdatedTimetable};
+use crate::transit_realtime;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use std::collections::{BTreeMap, HashSet};
+use transit_realtime::trip_descriptor;
+use transit_realtime::trip_update::{stop_time_update, StopTimeEvent, StopTimeUpdate};
+
+/// suffix of the entity ids of the trip updates estimated from the vehicle positions
+pub const ESTIMATED_SUFFIX: &str = ":estimated";
+
+fn stop_time_event(
+    updated: Option<NaiveDateTime>,
+    scheduled: NaiveDateTime,
+    timezone: chrono_tz::Tz,
+) -> Option<StopTimeEvent> {
+    let updated = updated?;
+    Some(StopTimeEvent {
+        delay: Some((updated - scheduled).num_seconds() as i32),
+        time: timezone
+            .from_local_datetime(&updated)
+            .earliest()
+            .map(|dt| dt.timestamp()),
+        uncertainty: None,
+    })
+}
+
+/// The TripUpdates of the vehicle journeys of the realtime timetable not coming as is from a GTFS-RT,
+/// ordered by vehicle journey and date
+pub fn trip_update_entities(
+    data: &Dataset,
+    updated_timetable: &UpdatedTimetable,
+) -> Vec<transit_realtime::FeedEntity> {
+    let mut connections_by_vj = BTreeMap::<_, Vec<(usize, &RealTimeConnection)>>::new();
+    for (idx, rt_connection) in &updated_timetable.realtime_connections {
+        let dated_vj = &data.timetable.connections[*idx].dated_vj;
+        if updated_timetable
+            .gtfs_rt_trip_updates
+            .contains_key(dated_vj)
+        {
+            continue;
+        }
+        let vj_id = &data.ntm.vehicle_journeys[dated_vj.vj_idx].id;
+        connections_by_vj
+            .entry((vj_id, dated_vj.date))
+            .or_default()
+            .push((*idx, rt_connection));
+    }
+
+    connections_by_vj
+        .into_iter()
+        .map(|((vj_id, date), mut rt_connections)| {
+            rt_connections.sort_by_key(|(idx, _)| data.timetable.connections[*idx].sequence);
+            // the GTFS route is the line of the base schedule
+            let route_id = data
+                .ntm
+                .vehicle_journeys
+                .get(vj_id)
+                .and_then(|vj| data.ntm.routes.get(&vj.route_id))
+                .map(|route| route.line_id.clone());
+            let timestamp = rt_connections
+                .iter()
+                .map(|(_, c)| c.update_time.timestamp() as u64)
+                .max();
+            let estimated = rt_connections.iter().all(|(_, c)| c.estimated);
+            // a trip with all its stops skipped is cancelled
+            let nb_stops = data
+                .ntm
+                .vehicle_journeys
+                .get(vj_id)
+                .map_or(0, |vj| vj.stop_times.len());
+            let cancelled = rt_connections.len() == nb_stops
+                && rt_connections
+                    .iter()
+                    .all(|(_, c)| matches!(c.schedule_relationship, ScheduleRelationship::Skipped));
+            if cancelled {
+                rt_connections.clear();
+            }
+            let stop_time_update = rt_connections
+                .iter()
+                .map(|(idx, rt_connection)| {
+                    let connection = &data.timetable.connections[*idx];
+                    let (schedule_relationship, arrival, departure) =
+                        match rt_connection.schedule_relationship {
+                            ScheduleRelationship::Skipped => {
+                                (stop_time_update::ScheduleRelationship::Skipped, None, None)
+                            }
+                            ScheduleRelationship::NoData => {
+                                (stop_time_update::ScheduleRelationship::NoData, None, None)
+                            }
+                            ScheduleRelationship::Scheduled => (
+                                stop_time_update::ScheduleRelationship::Scheduled,
+                                stop_time_event(
+                                    rt_connection.arr_time,
+                                    connection.arr_time,
+                                    data.timezone,
+                                ),
+                                stop_time_event(
+                                    rt_connection.dep_time,
+                                    connection.dep_time,
+                                    data.timezone,
+                                ),
+                            ),
+                        };
+                    StopTimeUpdate {
+                        stop_sequence: Some(connection.sequence),
+                        stop_id: Some(data.ntm.stop_points[connection.stop_point_idx].id.clone()),
+                        arrival,
+                        departure,
+                        schedule_relationship: Some(schedule_relationship as i32),
+                    }
+                })
+                .collect();
+
+            let mut id = format!("{}:{}", vj_id, date.format("%Y%m%d"));
+            if estimated {
+                id.push_str(ESTIMATED_SUFFIX);
+            }
+            transit_realtime::FeedEntity {
+                id,
+                trip_update: Some(transit_realtime::TripUpdate {
+                    trip: transit_realtime::TripDescriptor {
+                        trip_id: Some(vj_id.clone()),
+                        route_id,
+                        start_date: Some(date.format("%Y%m%d").to_string()),
+                        schedule_relationship: if cancelled {
+                            Some(trip_descriptor::ScheduleRelationship::Canceled as i32)
+                        } else {
+                            None
+                        },
+                        ..Default::default()
+                    },
+                    stop_time_update,
+                    timestamp,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+/// A GTFS-RT with the TripUpdates of the realtime timetable,
+/// and the other entities (vehicle positions, alerts) of some GTFS-RT
+pub fn create_feed_message(
+    data: &Dataset,
+    updated_timetable: &UpdatedTimetable,
+    gtfs_rts: &[(String, transit_realtime::FeedMessage)],
+    now: DateTime<Utc>,
+) -> transit_realtime::FeedMessage {
+    let passed_through: HashSet<(&str, &str)> = updated_timetable
+        .gtfs_rt_trip_updates
+        .values()
+        .map(|(url, entity_id)| (url.as_str(), entity_id.as_str()))
+        .collect();
+    // the other trip updates of the GTFS-RT are generated from the realtime timetable
+    let other_entities = gtfs_rts
+        .iter()
+        .flat_map(|(url, feed_message)| feed_message.entity.iter().map(move |e| (url, e)))
+        .filter(|(url, entity)| {
+            entity.trip_update.is_none()
+                || passed_through.contains(&(url.as_str(), entity.id.as_str()))
+        })
+        .map(|(_, entity)| entity.clone());
+    transit_realtime::FeedMessage {
+        header: transit_realtime::FeedHeader {
+            gtfs_realtime_version: "2.0".to_owned(),
+            incrementality: Some(transit_realtime::feed_header::Incrementality::FullDataset as i32),
+            timestamp: Some(now.timestamp() as u64),
+        },
+        entity: trip_update_entities(data, updated_timetable)
+            .into_iter()
+            .chain(other_entities)
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasets::{DatasetInfo, DatedVehicleJourney, Period};
+
+    #[test]
+    fn test_trip_updates_from_timetable() {
+        let period = Period {
+            begin: chrono::NaiveDate::from_ymd(2018, 12, 15),
+            horizon: chrono::Duration::days(1),
+        };
+        let data = Dataset::try_from_dataset_info(
+            DatasetInfo::new_default("fixtures/gtfs.zip", &[]),
+            &period,
+            None,
+        )
+        .unwrap();
+        let stba = data.ntm.vehicle_journeys.get_idx("STBA").unwrap();
+        let update_time = Utc.ymd(2018, 12, 15).and_hms(13, 55, 0);
+        let mut updated_timetable = UpdatedTimetable::default();
+        for (idx, connection) in data.timetable.connections.iter().enumerate() {
+            if connection.dated_vj.vj_idx == stba {
+                updated_timetable.realtime_connections.insert(
+                    idx,
+                    RealTimeConnection {
+                        dep_time: Some(connection.dep_time + chrono::Duration::minutes(2)),
+                        arr_time: Some(connection.arr_time + chrono::Duration::minutes(2)),
+                        schedule_relationship: ScheduleRelationship::Scheduled,
+                        update_time,
+                        estimated: false,
+                    },
+                );
+            }
+        }
+        let vehicle = transit_realtime::FeedEntity {
+            id: "vehicle".to_owned(),
+            vehicle: Some(Default::default()),
+            ..Default::default()
+        };
+        let trip_update = transit_realtime::FeedEntity {
+            id: "trip_update".to_owned(),
+            trip_update: Some(Default::default()),
+            ..Default::default()
+        };
+        // the trip update of AB1 only comes from the GTFS-RT, it is given as is
+        let ab1_trip_update = transit_realtime::FeedEntity {
+            id: "ab1".to_owned(),
+            trip_update: Some(transit_realtime::TripUpdate {
+                delay: Some(60),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let ab1 = data.ntm.vehicle_journeys.get_idx("AB1").unwrap();
+        updated_timetable.gtfs_rt_trip_updates.insert(
+            DatedVehicleJourney {
+                vj_idx: ab1,
+                date: chrono::NaiveDate::from_ymd(2018, 12, 15),
+            },
+            ("http://gtfs-rt".to_owned(), "ab1".to_owned()),
+        );
+        let gtfs_rt = transit_realtime::FeedMessage {
+            header: Default::default(),
+            entity: vec![trip_update, ab1_trip_update.clone(), vehicle],
+        };
+
+        let feed_message = create_feed_message(
+            &data,
+            &updated_timetable,
+            &[("http://gtfs-rt".to_owned(), gtfs_rt)],
+            update_time,
+        );
+
+        assert_eq!(feed_message.header.gtfs_realtime_version, "2.0");
+        let ids: Vec<_> = feed_message.entity.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, vec!["STBA:20181215", "ab1", "vehicle"]);
+        assert_eq!(feed_message.entity[1], ab1_trip_update);
+        let trip_update = feed_message.entity[0].trip_update.as_ref().unwrap();
+        assert_eq!(trip_update.trip.trip_id(), "STBA");
+        assert_eq!(trip_update.trip.route_id(), "STBA");
+        assert_eq!(trip_update.trip.start_date(), "20181215");
+        assert_eq!(trip_update.timestamp, Some(update_time.timestamp() as u64));
+        let stop_ids: Vec<_> = trip_update
+            .stop_time_update
+            .iter()
+            .map(|stu| stu.stop_id())
+            .collect();
+        assert_eq!(stop_ids, vec!["STAGECOACH", "BEATTY_AIRPORT"]);
+        let arrival = trip_update.stop_time_update[1].arrival.as_ref().unwrap();
+        assert_eq!(arrival.delay, Some(120));
+        assert_eq!(
+            arrival.time,
+            Some(
+                chrono_tz::America::Los_Angeles
+                    .ymd(2018, 12, 15)
+                    .and_hms(6, 22, 0)
+                    .timestamp()
+            )
+        );
+
+        // a trip with all its stops skipped is cancelled
+        let mut cancelled_timetable = UpdatedTimetable::default();
+        for (idx, rt_connection) in &updated_timetable.realtime_connections {
+            cancelled_timetable.realtime_connections.insert(
+                *idx,
+                RealTimeConnection {
+                    dep_time: None,
+                    arr_time: None,
+                    schedule_relationship: ScheduleRelationship::Skipped,
+                    ..rt_connection.clone()
+                },
+            );
+        }
+        let trip_updates = trip_update_entities(&data, &cancelled_timetable);
+        let cancelled = trip_updates[0].trip_update.as_ref().unwrap();
+        assert_eq!(
+            cancelled.trip.schedule_relationship,
+            Some(trip_descriptor::ScheduleRelationship::Canceled as i32)
+        );
+        assert!(cancelled.stop_time_update.is_empty());
+
+        // the trip updates estimated from the vehicle positions are marked
+        for rt_connection in updated_timetable.realtime_connections.values_mut() {
+            rt_connection.estimated = true;
+        }
+        let trip_updates = trip_update_entities(&data, &updated_timetable);
+        assert_eq!(trip_updates.len(), 1);
+        assert_eq!(trip_updates[0].id, "STBA:20181215:estimated");
+    }
+}
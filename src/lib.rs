@@ -9,14 +9,30 @@ pub mod transit_realtime {
 #[macro_use]
 pub mod utils;
 
+pub mod access_control;
 pub mod actors;
+pub mod alerts;
+pub mod cache;
+pub mod clock;
 pub mod datasets;
+pub mod diagnostics;
+pub(crate) mod estimation;
 pub mod extractors;
+pub(crate) mod gtfs_rt_writer;
 pub mod middlewares;
 pub(crate) mod model_update;
+pub mod overrides;
 pub(crate) mod routes;
+pub mod schedule_readers;
+pub mod recorder;
+pub mod replay;
+pub mod search;
 pub mod server;
 pub mod siri_lite;
+pub(crate) mod siri_reader;
+pub mod spatial_index;
+pub mod stats;
+pub mod validation;
 
 #[cfg(test)]
 mod tests;
@@ -1,7 +1,13 @@
 use anyhow::{anyhow, Context};
+use std::sync::Arc;
 use structopt::StructOpt;
+use transpo_rt::access_control::AccessControl;
+use transpo_rt::cache::DataCache;
 use transpo_rt::datasets::{DatasetInfo, Datasets};
+use transpo_rt::extractors::AdminToken;
 use transpo_rt::middlewares;
+use transpo_rt::server::{LoadingOptions, StorageOptions};
+use transpo_rt::validation::Thresholds;
 
 #[derive(StructOpt, Debug, Clone)]
 #[structopt(name = "transpo-rt")]
@@ -45,18 +51,165 @@ struct Params {
     bind: String,
     #[structopt(long = "sentry", help = "sentry dsn", env = "TRANSPO_RT_SENTRY")]
     sentry: Option<String>,
+    #[structopt(
+        long = "loading-parallelism",
+        help = "maximum number of datasets built at the same time (size of the pool of workers building the datasets)",
+        env = "TRANSPO_RT_LOADING_PARALLELISM",
+        default_value = "4"
+    )]
+    loading_parallelism: usize,
+    #[structopt(
+        long = "loading-timeout",
+        help = "number of seconds after which a dataset still building is considered as failed (its building is then cancelled)",
+        env = "TRANSPO_RT_LOADING_TIMEOUT",
+        default_value = "600"
+    )]
+    loading_timeout: u64,
+    #[structopt(
+        long = "cache-dir",
+        help = "directory where the base schedules are cached, for fast restarts. If not given, nothing is cached",
+        env = "TRANSPO_RT_CACHE_DIR"
+    )]
+    cache_dir: Option<std::path::PathBuf>,
+    #[structopt(
+        long = "cache-retention",
+        help = "number of days a base schedule is kept in the cache after its last use",
+        env = "TRANSPO_RT_CACHE_RETENTION",
+        default_value = "7"
+    )]
+    cache_retention: i64,
+    #[structopt(
+        long = "cache-prepared-datasets",
+        help = "also cache the prepared datasets (serialized as NTFS), to avoid reading the sources on restart",
+        env = "TRANSPO_RT_CACHE_PREPARED_DATASETS"
+    )]
+    cache_prepared_datasets: bool,
+    #[structopt(
+        long = "stats-dir",
+        help = "directory where the passed connections are stored, for the punctuality statistics. If not given, no statistics are computed",
+        env = "TRANSPO_RT_STATS_DIR"
+    )]
+    stats_dir: Option<std::path::PathBuf>,
+    #[structopt(
+        long = "alerts-dir",
+        help = "directory where the alerts published through the api are stored. If not given, they are lost on restart",
+        env = "TRANSPO_RT_ALERTS_DIR"
+    )]
+    alerts_dir: Option<std::path::PathBuf>,
+    #[structopt(
+        long = "overrides-dir",
+        help = "directory where the manual realtime overrides are stored. If not given, they are lost on restart",
+        env = "TRANSPO_RT_OVERRIDES_DIR"
+    )]
+    overrides_dir: Option<std::path::PathBuf>,
+    #[structopt(
+        long = "admin-token",
+        help = "token to give as a bearer token to use the admin api (to publish alerts and overrides). If not given, the admin api is disabled",
+        env = "TRANSPO_RT_ADMIN_TOKEN",
+        hide_env_values = true
+    )]
+    admin_token: Option<String>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+enum Command {
+    /// Check a GTFS and some GTFS-RT offline, without running the server.
+    /// The exit code is non-zero if the thresholds are exceeded
+    #[structopt(name = "validate")]
+    Validate(ValidateParams),
+}
+
+#[derive(StructOpt, Debug, Clone)]
+struct ValidateParams {
+    #[structopt(long = "gtfs", help = "path or url to the GTFS")]
+    gtfs: String,
+    #[structopt(
+        long = "gtfs-rt",
+        help = "path or url to a GTFS-RT, can be given several times",
+        required = true
+    )]
+    gtfs_rts: Vec<String>,
+    #[structopt(
+        long = "date",
+        help = "first day of the period on which the GTFS-RT are matched (formated as YYYY-MM-DD), today by default"
+    )]
+    date: Option<chrono::NaiveDate>,
+    #[structopt(long = "json", help = "print the report as json")]
+    json: bool,
+    #[structopt(
+        long = "max-unmatched-ratio",
+        help = "maximum ratio (between 0 and 1) of trip updates not matching a trip of the GTFS",
+        default_value = "0.1"
+    )]
+    max_unmatched_ratio: f64,
+    #[structopt(
+        long = "max-inconsistencies",
+        help = "maximum number of inconsistencies (unknown stops, stop mismatches, ...) in the matched trip updates",
+        default_value = "0"
+    )]
+    max_inconsistencies: usize,
+}
+
+/// Run the validation and print its report, returning the exit code
+fn validate(params: &ValidateParams) -> i32 {
+    use chrono::TimeZone;
+    let period = transpo_rt::datasets::Period {
+        begin: params
+            .date
+            .unwrap_or_else(|| chrono::Local::today().naive_local()),
+        horizon: chrono::Duration::days(2),
+    };
+    // the trips without date are considered on the first day of the period
+    let now = params
+        .date
+        .map(|d| chrono::Utc.from_utc_datetime(&d.and_hms(12, 0, 0)))
+        .unwrap_or_else(chrono::Utc::now);
+    let thresholds = Thresholds {
+        max_unmatched_ratio: params.max_unmatched_ratio,
+        max_inconsistencies: params.max_inconsistencies,
+    };
+    match transpo_rt::validation::validate(
+        &params.gtfs,
+        &params.gtfs_rts,
+        &period,
+        now,
+        &thresholds,
+    ) {
+        Ok(report) => {
+            if params.json {
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            } else {
+                print!("{}", report);
+            }
+            if report.is_valid() {
+                0
+            } else {
+                1
+            }
+        }
+        Err(e) => {
+            eprintln!("impossible to validate the dataset: {:?}", e);
+            2
+        }
+    }
 }
 
 /// Load datasets from the configuration
 /// if a config file has been given, we get the dataset from here,
 /// else we read the gtfs/url cli parameter to create a 'default' dataset with them
-fn get_datasets(params: &Params) -> Result<Datasets, anyhow::Error> {
+async fn get_datasets(params: &Params) -> Result<Datasets, anyhow::Error> {
     if let Some(config) = &params.config_file {
         let yaml = if config.starts_with("http") {
-            serde_yaml::from_reader(
-                reqwest::blocking::get(config)
-                    .with_context(|| "impossible to read config url".to_string())?,
-            )
+            let content = reqwest::get(config)
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .with_context(|| "impossible to read config url".to_string())?
+                .bytes()
+                .await
+                .with_context(|| "impossible to read config url".to_string())?;
+            serde_yaml::from_slice(&content)
         } else {
             serde_yaml::from_reader(
                 std::fs::File::open(config)
@@ -68,6 +221,7 @@ fn get_datasets(params: &Params) -> Result<Datasets, anyhow::Error> {
     } else if let (Some(gtfs), Some(url)) = (&params.gtfs, &params.url) {
         Ok(Datasets {
             datasets: vec![DatasetInfo::new_default(gtfs, &[url.clone()])],
+            ..Default::default()
         })
     } else {
         Err(anyhow!(
@@ -76,12 +230,19 @@ fn get_datasets(params: &Params) -> Result<Datasets, anyhow::Error> {
     }
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
+fn main() -> std::io::Result<()> {
     let log_guard = transpo_rt::utils::init_logger();
     log_guard.cancel_reset();
 
     let params = Params::from_args();
+    match &params.command {
+        // the validation is blocking, it is run outside of the actix system
+        Some(Command::Validate(validate_params)) => std::process::exit(validate(validate_params)),
+        None => actix_web::rt::System::new("transpo-rt").block_on(serve(params)),
+    }
+}
+
+async fn serve(params: Params) -> std::io::Result<()> {
     let sentry = sentry::init(params.sentry.clone().unwrap_or_else(|| "".to_owned()));
     if sentry.is_enabled() {
         log::info!("sentry activated");
@@ -93,24 +254,60 @@ async fn main() -> std::io::Result<()> {
         begin: today.naive_local(),
         horizon: chrono::Duration::days(2),
     };
-    let datasets_infos = get_datasets(&params).unwrap();
-    // we create all the actors
-    // this is an async function as we need to wait for all data (and realtime data too) to be read
-    // we wait for this to be finished before spawning the webserver
-    let actors = transpo_rt::server::create_all_actors(datasets_infos, &period).await;
+    let datasets_infos = get_datasets(&params).await.unwrap();
+    let cache = params.cache_dir.as_ref().map(|dir| {
+        Arc::new(
+            DataCache::new(
+                dir,
+                chrono::Duration::days(params.cache_retention),
+                params.cache_prepared_datasets,
+            )
+            .unwrap(),
+        )
+    });
+    // we create all the actors, the datasets are loaded in the background
+    // and can be queried once loaded (they are in a 'loading' state until then)
+    let storage = StorageOptions {
+        stats_dir: params.stats_dir.clone(),
+        alerts_dir: params.alerts_dir.clone(),
+        overrides_dir: params.overrides_dir.clone(),
+    };
+    let actors = transpo_rt::server::create_all_actors(&datasets_infos, &storage);
+    actix_rt::spawn(transpo_rt::server::load_all_datasets(
+        actors.clone(),
+        period,
+        LoadingOptions {
+            parallelism: params.loading_parallelism,
+            timeout: std::time::Duration::from_secs(params.loading_timeout),
+            cache,
+        },
+    ));
 
+    let admin_token = AdminToken(params.admin_token.clone());
+    let access_control = Arc::new(AccessControl::new(&datasets_infos.access_control).unwrap());
     actix_web::HttpServer::new(move || {
+        let checked_access_control = access_control.clone();
         actix_web::App::new()
             .wrap(actix_web::middleware::normalize::NormalizePath::default())
+            .wrap_fn(move |req, srv| {
+                middlewares::access_control::access_control_middleware(
+                    &checked_access_control,
+                    req,
+                    srv,
+                )
+            })
             .wrap(
                 actix_cors::Cors::default()
                     .allow_any_origin()
                     .send_wildcard()
-                    .allowed_methods(vec!["GET"]),
+                    .allowed_methods(vec!["GET"])
+                    .allowed_header(middlewares::access_control::API_KEY_HEADER),
             )
             .wrap_fn(middlewares::sentry::sentry_middleware)
             .wrap(actix_web::middleware::Logger::default())
-            .configure(|cfg| transpo_rt::server::init_routes(cfg, &actors))
+            .configure(|cfg| {
+                transpo_rt::server::init_routes(cfg, &actors, &admin_token, &access_control)
+            })
             .default_service(actix_web::web::get().to(|req: actix_web::HttpRequest| {
                 actix_web::HttpResponse::NotFound()
                     .body(format!("impossible to find route '{}'", &req.path()))
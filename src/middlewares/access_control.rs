@@ -0,0 +1,157 @@
+use crate::access_control::{Access, AccessControl};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, HeaderMap};
+use actix_web::HttpResponse;
+use futures::future::{ready, Either};
+use std::future::Future;
+
+/// header giving the api key
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+/// query parameter giving the api key, if it is not given in a header
+const API_KEY_PARAM: &str = "apikey";
+
+/// api key of a query, given in a header or a query parameter
+pub fn api_key(headers: &HeaderMap, query_string: &str) -> Option<String> {
+    headers
+        .get(API_KEY_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_owned)
+        .or_else(|| {
+            url::form_urlencoded::parse(query_string.as_bytes())
+                .find(|(k, _)| *k == API_KEY_PARAM)
+                .map(|(_, v)| v.into_owned())
+        })
+}
+
+/// IP of the client given by the reverse proxy, in the last entry of the `Forwarded`
+/// (or `X-Forwarded-For`) header. The previous entries are given by the client
+/// and cannot be trusted
+fn forwarded_ip(request: &ServiceRequest) -> Option<String> {
+    let last_entry = |name: &str| {
+        request
+            .headers()
+            .get_all(name)
+            .last()
+            .and_then(|h| h.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .map(str::trim)
+    };
+    last_entry(header::FORWARDED.as_str())
+        .and_then(|entry| {
+            entry.split(';').find_map(|pair| {
+                let mut pair = pair.trim().splitn(2, '=');
+                match (pair.next(), pair.next()) {
+                    (Some(name), Some(value)) if name.eq_ignore_ascii_case("for") => {
+                        Some(value.trim_matches('"'))
+                    }
+                    _ => None,
+                }
+            })
+        })
+        .or_else(|| last_entry("X-Forwarded-For"))
+        .filter(|ip| !ip.is_empty())
+        .map(str::to_owned)
+}
+
+fn client_ip(request: &ServiceRequest, trust_forwarded_for: bool) -> Option<String> {
+    let forwarded_ip = if trust_forwarded_for {
+        forwarded_ip(request)
+    } else {
+        None
+    };
+    forwarded_ip.or_else(|| request.peer_addr().map(|addr| addr.ip().to_string()))
+}
+
+fn refused_response(access: Access) -> HttpResponse {
+    match access {
+        Access::Allowed => unreachable!(),
+        Access::MissingKey => HttpResponse::Unauthorized().body(format!(
+            "an api key should be given, in a '{}' header or an '{}' parameter",
+            API_KEY_HEADER, API_KEY_PARAM
+        )),
+        Access::InvalidKey => HttpResponse::Unauthorized().body("invalid api key"),
+        Access::RateLimited(delay) => {
+            // the delay is rounded up, not to retry too early
+            let retry_after = delay.as_secs() + u64::from(delay.subsec_nanos() > 0);
+            HttpResponse::TooManyRequests()
+                .header(header::RETRY_AFTER, retry_after.max(1).to_string())
+                .body("too many requests")
+        }
+    }
+}
+
+/// Middleware checking the api key of the queries and their rate limit
+pub fn access_control_middleware(
+    access_control: &AccessControl,
+    request: ServiceRequest,
+    service: &mut impl Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+    >,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>> {
+    let key = api_key(request.headers(), request.query_string());
+    let ip = client_ip(&request, access_control.trust_forwarded_for());
+    match access_control.check(key.as_deref(), ip.as_deref(), std::time::Instant::now()) {
+        Access::Allowed => Either::Left(service.call(request)),
+        refused => Either::Right(ready(Ok(request.into_response(refused_response(refused))))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn api_key_from_request() {
+        let req = TestRequest::with_uri("/d/gtfs-rt/")
+            .header(API_KEY_HEADER, "key1")
+            .to_srv_request();
+        assert_eq!(
+            api_key(req.headers(), req.query_string()).as_deref(),
+            Some("key1")
+        );
+        let req = TestRequest::with_uri("/d/gtfs-rt/?apikey=key%202").to_srv_request();
+        assert_eq!(
+            api_key(req.headers(), req.query_string()).as_deref(),
+            Some("key 2")
+        );
+        let req = TestRequest::with_uri("/d/gtfs-rt/").to_srv_request();
+        assert_eq!(api_key(req.headers(), req.query_string()), None);
+    }
+
+    #[test]
+    fn client_ip_from_request() {
+        let peer = "10.0.0.1:4242".parse().unwrap();
+        // only the entry added by the proxy is used
+        let req = TestRequest::default()
+            .header("X-Forwarded-For", "6.6.6.6, 1.2.3.4")
+            .peer_addr(peer)
+            .to_srv_request();
+        assert_eq!(client_ip(&req, true).as_deref(), Some("1.2.3.4"));
+        assert_eq!(client_ip(&req, false).as_deref(), Some("10.0.0.1"));
+        let req = TestRequest::default()
+            .header(
+                header::FORWARDED,
+                "for=6.6.6.6, for=\"1.2.3.4\";proto=https",
+            )
+            .header("X-Forwarded-For", "5.5.5.5")
+            .peer_addr(peer)
+            .to_srv_request();
+        assert_eq!(client_ip(&req, true).as_deref(), Some("1.2.3.4"));
+        let req = TestRequest::default().peer_addr(peer).to_srv_request();
+        assert_eq!(client_ip(&req, true).as_deref(), Some("10.0.0.1"));
+    }
+
+    #[test]
+    fn rate_limited_response() {
+        let response =
+            refused_response(Access::RateLimited(std::time::Duration::from_millis(1500)));
+        assert_eq!(
+            response.status(),
+            actix_web::http::StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "2");
+    }
+}
@@ -1,12 +1,14 @@
-use crate::datasets::DatedVehicleJourney;
+use crate::datasets::{DatedVehicleJourney, IdMapping};
+use crate::diagnostics::{IssueKind, MatchingError, MatchingReport};
+use crate::schedule_readers::{prefixed_id, PREFIX_SEPARATOR};
 use crate::transit_realtime;
 use anyhow::anyhow;
 use anyhow::Error;
 use chrono::{DateTime, NaiveDateTime, Utc};
 use log::{debug, trace, warn};
 use std::collections::HashMap;
-use transit_model::collection::Idx;
-use transit_model::objects::StopPoint;
+use transit_model::collection::{CollectionWithId, Id, Idx};
+use transit_model::objects::{Codes, Route, StopPoint, VehicleJourney};
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct StopTimeUpdate {
@@ -18,11 +20,145 @@ pub struct StopTimeUpdate {
 pub struct TripUpdate {
     pub stop_time_update_by_sequence: HashMap<u32, StopTimeUpdate>,
     pub update_dt: chrono::DateTime<chrono::Utc>,
+    /// id of the GTFS-RT entity of the trip update
+    pub entity_id: String,
+    /// true if the trip update has been estimated from a vehicle position
+    pub estimated: bool,
 }
 
+/// a trip update whose timestamp is more than this in the future is reported in the diagnostics
+const MAX_FUTURE_TIMESTAMP_MINUTES: i64 = 5;
+/// a trip update whose timestamp is older than this is reported in the diagnostics
+const MAX_PAST_TIMESTAMP_MINUTES: i64 = 60;
+
 #[derive(Default)]
 pub struct ModelUpdate {
     pub trips: HashMap<DatedVehicleJourney, TripUpdate>,
+    /// issues found while matching the GTFS-RT with the base schedule
+    pub matching_report: MatchingReport,
+    /// timestamp of the trip update each stop time update comes from, only filled by `merge`
+    stop_update_dts: HashMap<(DatedVehicleJourney, u32), DateTime<Utc>>,
+}
+
+impl ModelUpdate {
+    /// Add the trip updates of another feed.
+    ///
+    /// If both feeds update the same trip, their stop time updates are merged,
+    /// each stop keeping the update of the most recent trip update.
+    pub fn merge(&mut self, other: ModelUpdate) {
+        for (dated_vj, trip_update) in other.trips {
+            let trip = match self.trips.get_mut(&dated_vj) {
+                Some(trip) => trip,
+                None => {
+                    self.trips.insert(dated_vj, trip_update);
+                    continue;
+                }
+            };
+            // the stops not merged yet come from the trip update itself
+            for sequence in trip.stop_time_update_by_sequence.keys() {
+                self.stop_update_dts
+                    .entry((dated_vj.clone(), *sequence))
+                    .or_insert(trip.update_dt);
+            }
+            for (sequence, stop_time_update) in trip_update.stop_time_update_by_sequence {
+                let key = (dated_vj.clone(), sequence);
+                let is_newer = self
+                    .stop_update_dts
+                    .get(&key)
+                    .map_or(true, |update_dt| trip_update.update_dt >= *update_dt);
+                if is_newer {
+                    self.stop_update_dts.insert(key, trip_update.update_dt);
+                    trip.stop_time_update_by_sequence
+                        .insert(sequence, stop_time_update);
+                }
+            }
+            if trip_update.update_dt >= trip.update_dt {
+                trip.update_dt = trip_update.update_dt;
+                trip.entity_id = trip_update.entity_id;
+            }
+            trip.estimated = trip.estimated && trip_update.estimated;
+        }
+        self.matching_report.merge(other.matching_report);
+    }
+}
+
+fn matching_error(kind: IssueKind, message: String) -> Error {
+    MatchingError::new(kind, message).into()
+}
+
+/// Find the base schedule objects corresponding to the GTFS-RT ids, following the dataset's `IdMapping`
+#[derive(Default)]
+pub struct IdResolver {
+    prefix: Option<String>,
+    /// objects by code, only filled if the mapping is done with a code type
+    vehicle_journeys: HashMap<String, Idx<VehicleJourney>>,
+    stop_points: HashMap<String, Idx<StopPoint>>,
+    routes: HashMap<String, Idx<Route>>,
+}
+
+fn index_by_code<T: Codes>(
+    collection: &CollectionWithId<T>,
+    code_type: &str,
+) -> HashMap<String, Idx<T>> {
+    collection
+        .iter()
+        .flat_map(|(idx, obj)| {
+            obj.codes()
+                .iter()
+                .filter(|(key, _)| key == code_type)
+                .map(move |(_, value)| (value.clone(), idx))
+        })
+        .collect()
+}
+
+impl IdResolver {
+    pub fn new(model: &transit_model::Model, mapping: &IdMapping) -> Self {
+        // the separator is added by the resolver, a prefix already ending with it is accepted
+        let prefix = mapping
+            .prefix
+            .as_ref()
+            .map(|p| p.trim_end_matches(PREFIX_SEPARATOR).to_owned());
+        match &mapping.code_type {
+            Some(code_type) => Self {
+                prefix,
+                vehicle_journeys: index_by_code(&model.vehicle_journeys, code_type),
+                stop_points: index_by_code(&model.stop_points, code_type),
+                routes: index_by_code(&model.routes, code_type),
+            },
+            None => Self {
+                prefix,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn resolve<T: Id<T>>(
+        &self,
+        by_code: &HashMap<String, Idx<T>>,
+        collection: &CollectionWithId<T>,
+        id: &str,
+    ) -> Option<Idx<T>> {
+        by_code.get(id).copied().or_else(|| match &self.prefix {
+            Some(prefix) => collection.get_idx(&prefixed_id(prefix, id)),
+            None => collection.get_idx(id),
+        })
+    }
+
+    pub fn vehicle_journey(
+        &self,
+        model: &transit_model::Model,
+        id: &str,
+    ) -> Option<Idx<VehicleJourney>> {
+        self.resolve(&self.vehicle_journeys, &model.vehicle_journeys, id)
+    }
+
+    pub fn stop_point(&self, model: &transit_model::Model, id: &str) -> Option<Idx<StopPoint>> {
+        self.resolve(&self.stop_points, &model.stop_points, id)
+    }
+
+    pub fn route(&self, model: &transit_model::Model, id: &str) -> Option<Idx<Route>> {
+        self.resolve(&self.routes, &model.routes, id)
+    }
 }
 
 fn get_date_time(
@@ -44,7 +180,10 @@ fn get_date_time(
 fn create_stop_time_updates(
     trip_update: &transit_realtime::TripUpdate,
     model: &transit_model::Model,
+    id_resolver: &IdResolver,
     timezone: chrono_tz::Tz,
+    entity_id: &str,
+    report: &mut MatchingReport,
 ) -> Result<HashMap<u32, StopTimeUpdate>, Error> {
     let mut res = HashMap::default();
     for stop_time_update in &trip_update.stop_time_update {
@@ -55,14 +194,16 @@ fn create_stop_time_updates(
 
         let stop_idx = match stop_id
             .as_ref()
-            .map(|stop_id| model.stop_points.get_idx(&stop_id))
+            .map(|stop_id| id_resolver.stop_point(model, &stop_id))
         {
             Some(None) => {
-                warn!(
+                let message = format!(
                     "impossible to find stop {:?} for vj {}",
                     &stop_id,
                     &trip_update.trip.trip_id()
                 );
+                warn!("{}", message);
+                report.add_issue(IssueKind::UnknownStop, entity_id, message);
                 continue;
             }
             Some(Some(v)) => Some(v),
@@ -92,19 +233,17 @@ fn create_stop_time_updates(
     Ok(res)
 }
 
-fn default_date(timezone: chrono_tz::Tz) -> chrono::NaiveDate {
-    chrono::Utc::now()
-        .with_timezone(&timezone)
-        .date()
-        .naive_local()
+fn default_date(timezone: chrono_tz::Tz, now: chrono::DateTime<chrono::Utc>) -> chrono::NaiveDate {
+    now.with_timezone(&timezone).date().naive_local()
 }
 
 fn get_date(
     trip: &transit_realtime::TripDescriptor,
     timezone: chrono_tz::Tz,
+    now: chrono::DateTime<chrono::Utc>,
 ) -> Result<chrono::NaiveDate, anyhow::Error> {
     trip.start_date.as_ref().map_or_else(
-        || Ok(default_date(timezone)),
+        || Ok(default_date(timezone, now)),
         |s| {
             chrono::NaiveDate::parse_from_str(s, "%Y%m%d")
                 .map_err(|e| anyhow!("Impossible to parse date: {}", e))
@@ -124,6 +263,7 @@ fn make_navitia_route_id(gtfs_route_id: &str, direction_id: u32) -> Result<Strin
 // TODO move this in transit_model ?
 fn find_corresponging_vjs(
     model: &transit_model::Model,
+    id_resolver: &IdResolver,
     gtfs_route_id: &str,
     direction_id: u32,
     start_date: chrono::NaiveDate,
@@ -131,10 +271,12 @@ fn find_corresponging_vjs(
 ) -> Result<Vec<Idx<transit_model::objects::VehicleJourney>>, anyhow::Error> {
     let route_id = make_navitia_route_id(gtfs_route_id, direction_id)?;
 
-    let route_idx = model
-        .routes
-        .get_idx(&route_id)
-        .ok_or_else(|| anyhow!("impossible to find route {}", route_id))?;
+    let route_idx = id_resolver.route(model, &route_id).ok_or_else(|| {
+        matching_error(
+            IssueKind::UnknownTrip,
+            format!("impossible to find route {}", route_id),
+        )
+    })?;
 
     Ok(model
         .get_corresponding_from_idx(route_idx)
@@ -159,13 +301,15 @@ fn find_corresponging_vjs(
         .collect())
 }
 
-fn get_dated_vj(
+pub(crate) fn get_dated_vj(
     model: &transit_model::Model,
+    id_resolver: &IdResolver,
     trip: &transit_realtime::TripDescriptor,
     entity_id: &str,
     timezone: chrono_tz::Tz,
+    now: chrono::DateTime<chrono::Utc>,
 ) -> Result<DatedVehicleJourney, anyhow::Error> {
-    let vj_idx = model.vehicle_journeys.get_idx(trip.trip_id());
+    let vj_idx = id_resolver.vehicle_journey(model, trip.trip_id());
 
     let vj_idx = if let Some(vj_idx) = vj_idx {
         vj_idx
@@ -175,42 +319,56 @@ fn get_dated_vj(
             (&trip.route_id, trip.direction_id, &trip.start_time)
         {
             use std::str::FromStr;
-            let date = get_date(&trip, timezone)?;
+            let date = get_date(&trip, timezone, now)?;
             let time = transit_model::objects::Time::from_str(start_time)?;
-            let vjs = find_corresponging_vjs(model, &route_id, direction_id, date, time)?;
+            let vjs =
+                find_corresponging_vjs(model, id_resolver, &route_id, direction_id, date, time)?;
 
             match vjs.len() {
                 1 => Ok(vjs[0]),
-                0 => Err(anyhow!(
-                    "for entity {}, impossible to find a matching trip",
-                    &entity_id
+                0 => Err(matching_error(
+                    IssueKind::UnknownTrip,
+                    format!(
+                        "for entity {}, impossible to find a matching trip",
+                        &entity_id
+                    ),
                 )),
-                l => Err(anyhow!(
-                    "for entity {}, there is no trip id, and {} matching trips, we can't choose one",
-                    &entity_id,
-                    l
+                l => Err(matching_error(
+                    IssueKind::AmbiguousMatch,
+                    format!(
+                        "for entity {}, there is no trip id, and {} matching trips, we can't choose one",
+                        &entity_id,
+                        l
+                    ),
                 )),
             }
         } else {
-            Err(anyhow!(
-                "impossible to find trip {} for entity {} and no route_id was provided",
-                &trip.trip_id(),
-                &entity_id
+            Err(matching_error(
+                IssueKind::UnknownTrip,
+                format!(
+                    "impossible to find trip {} for entity {} and no route_id was provided",
+                    &trip.trip_id(),
+                    &entity_id
+                ),
             ))
         }?
     };
 
-    let date = get_date(trip, timezone)?;
+    let date = get_date(trip, timezone, now)?;
 
     Ok(DatedVehicleJourney { vj_idx, date })
 }
 
 /// read a gtfs-rt FeedMessage to create a ModelUpdate,
 /// a temporary structure used to
+///
+/// `now` is the current time of the dataset, used for the trips without date
 pub fn get_model_update(
     model: &transit_model::Model,
+    id_resolver: &IdResolver,
     gtfs_rts: &[transit_realtime::FeedMessage],
     timezone: chrono_tz::Tz,
+    now: chrono::DateTime<chrono::Utc>,
 ) -> Result<ModelUpdate, Error> {
     debug!("applying a trip update");
     let mut model_update = ModelUpdate::default();
@@ -219,20 +377,48 @@ pub fn get_model_update(
         for entity in &gtfs_rt.entity {
             let entity_id = &entity.id;
             if let Some(tu) = &entity.trip_update {
-                let dated_vj = skip_fail!(get_dated_vj(&model, &tu.trip, entity_id, timezone));
+                let report = &mut model_update.matching_report;
+                report.nb_trip_updates += 1;
+                let dated_vj =
+                    match get_dated_vj(&model, id_resolver, &tu.trip, entity_id, timezone, now) {
+                        Ok(dated_vj) => dated_vj,
+                        Err(e) => {
+                            warn!("{}", e);
+                            report.add_error(entity_id, &e);
+                            continue;
+                        }
+                    };
+                report.nb_matched_trip_updates += 1;
+                let update_dt = chrono::DateTime::<chrono::Utc>::from_utc(
+                    chrono::NaiveDateTime::from_timestamp(tu.timestamp.unwrap_or(0) as i64, 0),
+                    chrono::Utc,
+                );
+                if tu.timestamp.is_some() {
+                    if update_dt > now + chrono::Duration::minutes(MAX_FUTURE_TIMESTAMP_MINUTES) {
+                        report.add_issue(
+                            IssueKind::FutureTimestamp,
+                            entity_id,
+                            format!("the trip update timestamp {} is in the future", update_dt),
+                        );
+                    } else if update_dt
+                        < now - chrono::Duration::minutes(MAX_PAST_TIMESTAMP_MINUTES)
+                    {
+                        report.add_issue(
+                            IssueKind::PastTimestamp,
+                            entity_id,
+                            format!("the trip update timestamp {} is too old", update_dt),
+                        );
+                    }
+                }
+                let stop_time_update_by_sequence =
+                    create_stop_time_updates(tu, model, id_resolver, timezone, entity_id, report)?;
                 model_update.trips.insert(
                     dated_vj,
                     TripUpdate {
-                        stop_time_update_by_sequence: create_stop_time_updates(
-                            tu, model, timezone,
-                        )?,
-                        update_dt: chrono::DateTime::<chrono::Utc>::from_utc(
-                            chrono::NaiveDateTime::from_timestamp(
-                                tu.timestamp.unwrap_or(0) as i64,
-                                0,
-                            ),
-                            chrono::Utc,
-                        ),
+                        stop_time_update_by_sequence,
+                        update_dt,
+                        entity_id: entity_id.clone(),
+                        estimated: false,
                     },
                 );
             } else {
@@ -252,6 +438,8 @@ pub fn get_model_update(
 #[cfg(test)]
 mod test {
     use crate::transit_realtime as tr;
+    use chrono::Utc;
+    use std::collections::HashMap;
 
     fn make_fake_model() -> transit_model::Model {
         transit_model_builder::ModelBuilder::default()
@@ -292,7 +480,14 @@ mod test {
 
         let model = make_fake_model();
 
-        let dated_vj = super::get_dated_vj(&model, &trip_descriptor, "entity_id", chrono_tz::UTC);
+        let dated_vj = super::get_dated_vj(
+            &model,
+            &super::IdResolver::default(),
+            &trip_descriptor,
+            "entity_id",
+            chrono_tz::UTC,
+            chrono::Utc::now(),
+        );
 
         // we should be able to find the vj since the id is valid
         let vj_idx = dated_vj.unwrap().vj_idx;
@@ -300,6 +495,62 @@ mod test {
         assert_eq!(&vj.id, "vj1");
     }
 
+    #[test]
+    fn corresponding_vj_with_prefixed_id() {
+        // the base schedule ids are prefixed (like in a NTFS), the GTFS-RT ids are not
+        let model = transit_model_builder::ModelBuilder::default()
+            .calendar("c", |c| {
+                c.dates.insert(chrono::NaiveDate::from_ymd(2019, 2, 6));
+            })
+            .route("l1", |_| {})
+            .vj("OCE:1", |vj_builder| {
+                vj_builder
+                    .route("l1")
+                    .calendar("c")
+                    .st("A", "10:00:00", "10:01:00")
+                    .st("B", "11:00:00", "11:01:00");
+            })
+            .vj("OCE1", |vj_builder| {
+                vj_builder
+                    .route("l1")
+                    .calendar("c")
+                    .st("A", "12:00:00", "12:01:00")
+                    .st("B", "13:00:00", "13:01:00");
+            })
+            .build();
+        let find_vj = |prefix: &str, trip_id: &str| {
+            let trip_descriptor = tr::TripDescriptor {
+                trip_id: Some(trip_id.to_owned()),
+                start_date: Some("20190206".to_owned()),
+                ..Default::default()
+            };
+            let id_resolver = super::IdResolver::new(
+                &model,
+                &crate::datasets::IdMapping {
+                    prefix: Some(prefix.to_owned()),
+                    code_type: None,
+                },
+            );
+            super::get_dated_vj(
+                &model,
+                &id_resolver,
+                &trip_descriptor,
+                "entity_id",
+                chrono_tz::UTC,
+                chrono::Utc::now(),
+            )
+            .ok()
+            .map(|dated_vj| model.vehicle_journeys[dated_vj.vj_idx].id.clone())
+        };
+
+        // the prefix and the id are separated, with or without the separator in the configuration
+        assert_eq!(find_vj("OCE", "1").as_deref(), Some("OCE:1"));
+        assert_eq!(find_vj("OCE:", "1").as_deref(), Some("OCE:1"));
+        // the prefix is not just glued to the id
+        assert_eq!(find_vj("OC", "E1"), None);
+        assert_eq!(find_vj("OCE1", ""), None);
+    }
+
     #[test]
     fn corresponding_vj_without_id() {
         let trip_descriptor = tr::TripDescriptor {
@@ -307,7 +558,14 @@ mod test {
             ..Default::default()
         };
         let model = make_fake_model();
-        let dated_vj = super::get_dated_vj(&model, &trip_descriptor, "entity_id", chrono_tz::UTC);
+        let dated_vj = super::get_dated_vj(
+            &model,
+            &super::IdResolver::default(),
+            &trip_descriptor,
+            "entity_id",
+            chrono_tz::UTC,
+            chrono::Utc::now(),
+        );
         // we shouldn't be able to find a vj
         assert_eq!(
             &format!("{}", dated_vj.unwrap_err()),
@@ -323,7 +581,14 @@ mod test {
             ..Default::default()
         };
         let model = make_fake_model();
-        let dated_vj = super::get_dated_vj(&model, &trip_descriptor, "entity_id", chrono_tz::UTC);
+        let dated_vj = super::get_dated_vj(
+            &model,
+            &super::IdResolver::default(),
+            &trip_descriptor,
+            "entity_id",
+            chrono_tz::UTC,
+            chrono::Utc::now(),
+        );
         // we shouldn't be able to find a vj
         assert_eq!(&format!("{}", dated_vj.unwrap_err()),
         "impossible to find trip id_that_does_not_exist for entity entity_id and no route_id was provided");
@@ -344,7 +609,14 @@ mod test {
             ..Default::default()
         };
         let model = make_fake_model();
-        let dated_vj = super::get_dated_vj(&model, &trip_descriptor, "entity_id", chrono_tz::UTC);
+        let dated_vj = super::get_dated_vj(
+            &model,
+            &super::IdResolver::default(),
+            &trip_descriptor,
+            "entity_id",
+            chrono_tz::UTC,
+            chrono::Utc::now(),
+        );
 
         // we should be able to find the vj since the id is valid
         let vj_idx = dated_vj.unwrap().vj_idx;
@@ -366,7 +638,14 @@ mod test {
             ..Default::default()
         };
         let model = make_fake_model();
-        let dated_vj = super::get_dated_vj(&model, &trip_descriptor, "entity_id", chrono_tz::UTC);
+        let dated_vj = super::get_dated_vj(
+            &model,
+            &super::IdResolver::default(),
+            &trip_descriptor,
+            "entity_id",
+            chrono_tz::UTC,
+            chrono::Utc::now(),
+        );
         assert_eq!(
             &format!("{}", dated_vj.unwrap_err()),
             "for entity entity_id, impossible to find a matching trip"
@@ -412,11 +691,79 @@ mod test {
                     .st("C", "12:00:00", "12:01:00");
             })
             .build();
-        let dated_vj = super::get_dated_vj(&model, &trip_descriptor, "entity_id", chrono_tz::UTC);
+        let dated_vj = super::get_dated_vj(
+            &model,
+            &super::IdResolver::default(),
+            &trip_descriptor,
+            "entity_id",
+            chrono_tz::UTC,
+            chrono::Utc::now(),
+        );
         // vj1 and vj2 are eligible, there is an error
         assert_eq!(
             &format!("{}", dated_vj.unwrap_err()),
             "for entity entity_id, there is no trip id, and 2 matching trips, we can\'t choose one"
         );
     }
+
+    #[test]
+    fn merged_feeds_keep_the_most_recent_stop_updates() {
+        let model = make_fake_model();
+        let dated_vj = crate::datasets::DatedVehicleJourney {
+            vj_idx: model.vehicle_journeys.get_idx("vj1").unwrap(),
+            date: chrono::NaiveDate::from_ymd(2019, 2, 6),
+        };
+        let time = |h: u32, m: u32| Some(chrono::NaiveDate::from_ymd(2019, 2, 6).and_hms(h, m, 0));
+        let model_update = |entity_id: &str, minute: u32, stops: &[(u32, u32)]| {
+            let update_dt = chrono::DateTime::<Utc>::from_utc(
+                chrono::NaiveDate::from_ymd(2019, 2, 6).and_hms(9, minute, 0),
+                Utc,
+            );
+            let mut trips = HashMap::new();
+            trips.insert(
+                dated_vj.clone(),
+                super::TripUpdate {
+                    stop_time_update_by_sequence: stops
+                        .iter()
+                        .map(|(sequence, minute)| {
+                            (
+                                *sequence,
+                                super::StopTimeUpdate {
+                                    stop_point_idx: None,
+                                    updated_departure: time(10, *minute),
+                                    updated_arrival: time(10, *minute),
+                                },
+                            )
+                        })
+                        .collect(),
+                    update_dt,
+                    entity_id: entity_id.to_owned(),
+                    estimated: false,
+                },
+            );
+            super::ModelUpdate {
+                trips,
+                ..Default::default()
+            }
+        };
+
+        let mut merged = super::ModelUpdate::default();
+        // the second feed is the most recent for the stop 1, the first one for the stop 2
+        merged.merge(model_update("first", 30, &[(0, 1), (1, 2), (2, 3)]));
+        merged.merge(model_update("second", 40, &[(1, 12)]));
+        merged.merge(model_update("third", 20, &[(1, 22), (2, 23), (3, 24)]));
+
+        let trip = &merged.trips[&dated_vj];
+        let departure = |sequence: u32| {
+            trip.stop_time_update_by_sequence[&sequence]
+                .updated_departure
+                .map(|dt| dt.time().format("%H:%M").to_string())
+        };
+        assert_eq!(departure(0).as_deref(), Some("10:01"));
+        assert_eq!(departure(1).as_deref(), Some("10:12"));
+        assert_eq!(departure(2).as_deref(), Some("10:03"));
+        // a stop only updated by an older feed is still updated
+        assert_eq!(departure(3).as_deref(), Some("10:24"));
+        assert_eq!(trip.entity_id, "second");
+    }
 }
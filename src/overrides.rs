@@ -0,0 +1,480 @@
+//! Manual realtime overrides
+//!
+//! When the realtime feeds are wrong or down, an operator can cancel a trip (or one of its stops)
+//! or delay it by hand through the admin API.
+//! The overrides are applied on top of the realtime updates of the feeds, until their expiration.
+//! Like the published alerts, they are kept in a store by dataset, persisted in a json file if a directory is given.
+use crate::datasets::{
+    Connection, Dataset, DatedVehicleJourney, RealTimeConnection, ScheduleRelationship,
+    UpdatedTimetable,
+};
+use crate::siri_lite::DateTime;
+use anyhow::Context;
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// without an explicit expiration, an override expires 2 days after the date of its trip
+/// (the trips of a day can end after midnight)
+const DEFAULT_EXPIRATION_DAYS: i64 = 2;
+
+/// Manual change of a dated trip.
+/// Either the trip is cancelled, or it is delayed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Override {
+    /// id of the override, generated at its creation
+    #[serde(default)]
+    pub id: String,
+    /// id of the vehicle journey
+    pub trip_id: String,
+    /// date of the trip, formated as YYYY-MM-DD
+    pub date: NaiveDate,
+    /// if given, only this stop is cancelled, or the trip is only delayed from this stop
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_sequence: Option<u32>,
+    #[serde(default)]
+    pub cancelled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay_seconds: Option<i32>,
+    /// the override is not applied after this datetime, in the timezone of the dataset,
+    /// formated as YYYY-MM-DDTHH:MM:SS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime>,
+}
+
+impl Override {
+    pub fn expiration(&self) -> NaiveDateTime {
+        self.expires_at.as_ref().map(|dt| dt.0).unwrap_or_else(|| {
+            (self.date + chrono::Duration::days(DEFAULT_EXPIRATION_DAYS)).and_hms(0, 0, 0)
+        })
+    }
+
+    /// Check the override, its trip should run on its date in the base schedule.
+    /// Returns the list of the problems
+    pub fn check(&self, model: &transit_model::Model) -> Vec<String> {
+        let mut errors = vec![];
+        match (self.cancelled, self.delay_seconds) {
+            (true, Some(_)) => {
+                errors.push("an override cannot both cancel and delay a trip".to_owned())
+            }
+            (false, None) => errors
+                .push("an override should either cancel a trip or give a delay_seconds".to_owned()),
+            _ => {}
+        }
+        let vj = match model.vehicle_journeys.get(&self.trip_id) {
+            Some(vj) => vj,
+            None => {
+                errors.push(format!("unknown trip '{}'", self.trip_id));
+                return errors;
+            }
+        };
+        let runs = model
+            .calendars
+            .get(&vj.service_id)
+            .map(|c| c.dates.contains(&self.date))
+            .unwrap_or(false);
+        if !runs {
+            errors.push(format!(
+                "the trip '{}' does not run on {}",
+                self.trip_id, self.date
+            ));
+        }
+        if let Some(stop_sequence) = self.stop_sequence {
+            if !vj.stop_times.iter().any(|st| st.sequence == stop_sequence) {
+                errors.push(format!(
+                    "the trip '{}' has no stop sequence {}",
+                    self.trip_id, stop_sequence
+                ));
+            }
+        }
+        errors
+    }
+
+    /// the change of a connection of the trip, `None` if the connection is not impacted
+    fn realtime_connection(
+        &self,
+        connection: &Connection,
+        update_time: chrono::DateTime<Utc>,
+    ) -> Option<RealTimeConnection> {
+        match (self.stop_sequence, self.delay_seconds) {
+            // a cancellation only impacts its stop, a delay is propagated to the next stops
+            (Some(sequence), None) if connection.sequence != sequence => return None,
+            (Some(sequence), Some(_)) if connection.sequence < sequence => return None,
+            _ => {}
+        }
+        Some(match self.delay_seconds {
+            Some(delay) if !self.cancelled => {
+                let delay = chrono::Duration::seconds(i64::from(delay));
+                RealTimeConnection {
+                    dep_time: Some(connection.dep_time + delay),
+                    arr_time: Some(connection.arr_time + delay),
+                    schedule_relationship: ScheduleRelationship::Scheduled,
+                    update_time,
+                    estimated: false,
+                }
+            }
+            _ => RealTimeConnection {
+                dep_time: None,
+                arr_time: None,
+                schedule_relationship: ScheduleRelationship::Skipped,
+                update_time,
+                estimated: false,
+            },
+        })
+    }
+}
+
+/// Apply the overrides not expired at `now` on the realtime timetable, replacing the updates of the feeds.
+/// Returns the number of updated connections
+pub fn apply_overrides(
+    data: &Dataset,
+    overrides: &[Override],
+    updated_timetable: &mut UpdatedTimetable,
+    now: chrono::DateTime<Utc>,
+) -> usize {
+    let local_now = now.with_timezone(&data.timezone).naive_local();
+    let mut overrides_by_vj = HashMap::<DatedVehicleJourney, Vec<&Override>>::new();
+    for o in overrides.iter().filter(|o| local_now < o.expiration()) {
+        match data.ntm.vehicle_journeys.get_idx(&o.trip_id) {
+            Some(vj_idx) => overrides_by_vj
+                .entry(DatedVehicleJourney {
+                    vj_idx,
+                    date: o.date,
+                })
+                .or_default()
+                .push(o),
+            None => log::warn!(
+                "the trip '{}' of the override {} is not in the dataset anymore",
+                o.trip_id,
+                o.id
+            ),
+        }
+    }
+    if overrides_by_vj.is_empty() {
+        return 0;
+    }
+    // the overridden trips do not come as is from a GTFS-RT anymore
+    for dated_vj in overrides_by_vj.keys() {
+        updated_timetable.gtfs_rt_trip_updates.remove(dated_vj);
+    }
+
+    // a connection changed by several overrides is only counted once
+    let mut changed = HashSet::new();
+    for (idx, connection) in data.timetable.connections.iter().enumerate() {
+        let overrides = match overrides_by_vj.get(&connection.dated_vj) {
+            Some(overrides) => overrides,
+            None => continue,
+        };
+        // the overrides are sorted by id, the last created one wins
+        for o in overrides {
+            if let Some(rt_connection) = o.realtime_connection(connection, now) {
+                updated_timetable
+                    .realtime_connections
+                    .insert(idx, rt_connection);
+                changed.insert(idx);
+            }
+        }
+    }
+    changed.len()
+}
+
+#[derive(Debug)]
+pub enum OverrideError {
+    UnknownOverride(String),
+    /// the overrides cannot be written
+    Storage(anyhow::Error),
+}
+
+impl std::fmt::Display for OverrideError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            OverrideError::UnknownOverride(id) => write!(f, "unknown override '{}'", id),
+            OverrideError::Storage(e) => write!(f, "impossible to store the overrides: {}", e),
+        }
+    }
+}
+
+/// Overrides of a dataset
+///
+/// All the overrides are written in a json file at each change
+pub struct OverrideStore {
+    file: Option<PathBuf>,
+    overrides: Mutex<BTreeMap<u64, Override>>,
+}
+
+/// the overrides' ids are `override:<number>`, the number giving their creation order
+fn override_number(id: &str) -> Option<u64> {
+    id.strip_prefix("override:").and_then(|n| n.parse().ok())
+}
+
+fn read_overrides(file: &Path) -> anyhow::Result<BTreeMap<u64, Override>> {
+    let content = std::fs::File::open(file)?;
+    let overrides: Vec<Override> = serde_json::from_reader(std::io::BufReader::new(content))?;
+    Ok(overrides
+        .into_iter()
+        .filter_map(|o| override_number(&o.id).map(|n| (n, o)))
+        .collect())
+}
+
+impl OverrideStore {
+    /// Create a store, with the overrides previously written in `file`.
+    /// If no file is given, the overrides are only kept in memory
+    pub fn new(file: Option<&Path>) -> Self {
+        let overrides = match file {
+            Some(file) if file.exists() => read_overrides(file)
+                .map_err(|e| log::error!("impossible to read overrides {}: {}", file.display(), e))
+                .unwrap_or_default(),
+            _ => BTreeMap::new(),
+        };
+        Self {
+            file: file.map(Path::to_owned),
+            overrides: Mutex::new(overrides),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.overrides.lock().unwrap().is_empty()
+    }
+
+    /// all the overrides, sorted by creation
+    pub fn overrides(&self) -> Vec<Override> {
+        self.overrides.lock().unwrap().values().cloned().collect()
+    }
+
+    fn write(&self, overrides: &BTreeMap<u64, Override>) -> anyhow::Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let dir = file.parent().unwrap_or_else(|| Path::new("."));
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("impossible to create directory {}", dir.display()))?;
+        // the overrides are written in a temporary file, not to leave a partial file in case of error
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer_pretty(tmp.as_file_mut(), &overrides.values().collect::<Vec<_>>())?;
+        tmp.persist(file)
+            .with_context(|| format!("impossible to write {}", file.display()))?;
+        Ok(())
+    }
+
+    /// Apply a change on the overrides, only once the changed overrides have been written
+    fn change<T>(
+        &self,
+        f: impl FnOnce(&mut BTreeMap<u64, Override>) -> Result<T, OverrideError>,
+    ) -> Result<T, OverrideError> {
+        let mut overrides = self.overrides.lock().unwrap();
+        let mut changed = overrides.clone();
+        let res = f(&mut changed)?;
+        self.write(&changed).map_err(OverrideError::Storage)?;
+        *overrides = changed;
+        Ok(res)
+    }
+
+    /// Add a new override, returning it with its id
+    pub fn create(&self, mut o: Override) -> Result<Override, OverrideError> {
+        self.change(|overrides| {
+            let number = overrides.keys().next_back().map_or(1, |n| n + 1);
+            o.id = format!("override:{}", number);
+            overrides.insert(number, o.clone());
+            Ok(o)
+        })
+    }
+
+    pub fn delete(&self, id: &str) -> Result<(), OverrideError> {
+        self.change(|overrides| {
+            override_number(id)
+                .and_then(|n| overrides.remove(&n))
+                .map(|_| ())
+                .ok_or_else(|| OverrideError::UnknownOverride(id.to_owned()))
+        })
+    }
+
+    /// Remove the overrides expired at `now` (in the dataset's timezone).
+    /// Returns the number of removed overrides
+    pub fn remove_expired(&self, now: NaiveDateTime) -> Result<usize, OverrideError> {
+        if self
+            .overrides
+            .lock()
+            .unwrap()
+            .values()
+            .all(|o| now < o.expiration())
+        {
+            return Ok(0);
+        }
+        self.change(|overrides| {
+            let nb_overrides = overrides.len();
+            overrides.retain(|_, o| now < o.expiration());
+            Ok(nb_overrides - overrides.len())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasets::{DatasetInfo, Period};
+    use chrono::TimeZone;
+
+    fn make_dataset() -> Dataset {
+        let period = Period {
+            begin: NaiveDate::from_ymd(2018, 12, 15),
+            horizon: chrono::Duration::days(1),
+        };
+        Dataset::try_from_dataset_info(
+            DatasetInfo::new_default("fixtures/gtfs.zip", &[]),
+            &period,
+            None,
+        )
+        .unwrap()
+    }
+
+    fn stba_override() -> Override {
+        Override {
+            id: String::new(),
+            trip_id: "STBA".to_owned(),
+            date: NaiveDate::from_ymd(2018, 12, 15),
+            stop_sequence: None,
+            cancelled: false,
+            delay_seconds: Some(300),
+            expires_at: None,
+        }
+    }
+
+    // realtime connections of the STBA trip, ordered by sequence
+    fn stba_connections(
+        data: &Dataset,
+        updated_timetable: &UpdatedTimetable,
+    ) -> Vec<(u32, Option<RealTimeConnection>)> {
+        let stba = data.ntm.vehicle_journeys.get_idx("STBA").unwrap();
+        data.timetable
+            .connections
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.dated_vj.vj_idx == stba)
+            .map(|(idx, c)| {
+                (
+                    c.sequence,
+                    updated_timetable.realtime_connections.get(&idx).cloned(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_check_override() {
+        let data = make_dataset();
+        assert!(stba_override().check(&data.ntm).is_empty());
+        let invalid = Override {
+            date: NaiveDate::from_ymd(2000, 1, 1),
+            stop_sequence: Some(42),
+            cancelled: true,
+            ..stba_override()
+        };
+        assert_eq!(
+            invalid.check(&data.ntm),
+            vec![
+                "an override cannot both cancel and delay a trip",
+                "the trip 'STBA' does not run on 2000-01-01",
+                "the trip 'STBA' has no stop sequence 42",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let data = make_dataset();
+        let now = Utc.ymd(2018, 12, 15).and_hms(14, 0, 0);
+        let mut updated_timetable = UpdatedTimetable::default();
+        let nb = apply_overrides(&data, &[stba_override()], &mut updated_timetable, now);
+        assert_eq!(nb, 2);
+        let connections = stba_connections(&data, &updated_timetable);
+        let stba = data.ntm.vehicle_journeys.get_idx("STBA").unwrap();
+        for (idx, connection) in data.timetable.connections.iter().enumerate() {
+            if connection.dated_vj.vj_idx == stba {
+                let rt = &updated_timetable.realtime_connections[&idx];
+                assert_eq!(
+                    rt.dep_time,
+                    Some(connection.dep_time + chrono::Duration::minutes(5))
+                );
+            }
+        }
+
+        // only the last stop is cancelled
+        let last_sequence = connections.last().unwrap().0;
+        let cancellation = Override {
+            stop_sequence: Some(last_sequence),
+            cancelled: true,
+            delay_seconds: None,
+            ..stba_override()
+        };
+        let mut updated_timetable = UpdatedTimetable::default();
+        apply_overrides(&data, &[cancellation], &mut updated_timetable, now);
+        let connections = stba_connections(&data, &updated_timetable);
+        assert!(connections[0].1.is_none());
+        assert!(matches!(
+            connections[1].1.as_ref().unwrap().schedule_relationship,
+            ScheduleRelationship::Skipped
+        ));
+
+        // an expired override is not applied
+        let expired = Override {
+            expires_at: Some(DateTime("2018-12-15T05:00:00".parse().unwrap())),
+            ..stba_override()
+        };
+        let mut updated_timetable = UpdatedTimetable::default();
+        assert_eq!(
+            apply_overrides(&data, &[expired], &mut updated_timetable, now),
+            0
+        );
+
+        // a connection changed by several overrides is counted once
+        let cancellation = Override {
+            cancelled: true,
+            delay_seconds: None,
+            ..stba_override()
+        };
+        let mut updated_timetable = UpdatedTimetable::default();
+        assert_eq!(
+            apply_overrides(
+                &data,
+                &[stba_override(), cancellation],
+                &mut updated_timetable,
+                now
+            ),
+            2
+        );
+    }
+
+    #[test]
+    fn test_override_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("dataset.json");
+        let store = OverrideStore::new(Some(&file));
+        let first = store.create(stba_override()).unwrap();
+        assert_eq!(first.id, "override:1");
+        let second = store
+            .create(Override {
+                expires_at: Some(DateTime("2018-12-15T12:00:00".parse().unwrap())),
+                ..stba_override()
+            })
+            .unwrap();
+        assert_eq!(second.id, "override:2");
+        assert!(matches!(
+            store.delete("override:42"),
+            Err(OverrideError::UnknownOverride(_))
+        ));
+        assert_eq!(
+            store
+                .remove_expired("2018-12-15T13:00:00".parse().unwrap())
+                .unwrap(),
+            1
+        );
+
+        // the overrides are read back from the file
+        let reloaded = OverrideStore::new(Some(&file));
+        assert_eq!(reloaded.overrides(), vec![first]);
+        reloaded.delete("override:1").unwrap();
+        assert!(reloaded.is_empty());
+    }
+}
@@ -0,0 +1,232 @@
+//! Recording of the fetched GTFS-RT, for later analysis or replay
+//!
+//! Each GTFS-RT url is recorded in its own directory, with one sub directory per day (`YYYY-MM-DD`),
+//! each fetched feed being compressed in a `<UTC datetime>.pb.gz` file (`.xml.gz` for the SIRI feeds).
+//! A feed identical to the previous one of the url is not recorded.
+//! The day directories can be replayed directly (cf. the `replay` module).
+use crate::cache::{entry_name, hash};
+use crate::datasets::{GtfsRT, RealtimeFormat};
+use anyhow::{Context, Error};
+use chrono::{DateTime, NaiveDate, Utc};
+use openapi_schema::OpenapiSchema;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// file of each url directory, holding the recorded url
+const URL_FILE: &str = "url.txt";
+const DAY_FORMAT: &str = "%Y-%m-%d";
+
+#[derive(Serialize, Deserialize, Debug, Clone, OpenapiSchema, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub struct RecordingConfig {
+    /// directory where the GTFS-RT are recorded
+    pub directory: String,
+    /// number of days the recorded GTFS-RT are kept, they are never removed if not given
+    #[serde(default)]
+    pub retention_days: Option<u32>,
+}
+
+#[derive(Default)]
+struct RecorderState {
+    /// hash of the last recorded feed of each url
+    last_hashes: HashMap<String, String>,
+    /// day of the last removal of the expired recordings
+    last_purge: Option<NaiveDate>,
+}
+
+pub struct Recorder {
+    directory: PathBuf,
+    retention: Option<chrono::Duration>,
+    state: Mutex<RecorderState>,
+}
+
+/// name of the file of a feed fetched at `datetime`
+pub fn record_file_name(datetime: DateTime<Utc>, format: RealtimeFormat) -> String {
+    format!(
+        "{}.{}.gz",
+        datetime.format("%Y%m%dT%H%M%SZ"),
+        format.extension()
+    )
+}
+
+impl Recorder {
+    pub fn new(config: &RecordingConfig) -> Self {
+        Self {
+            directory: PathBuf::from(&config.directory),
+            retention: config
+                .retention_days
+                .map(|days| chrono::Duration::days(i64::from(days))),
+            state: Mutex::new(RecorderState::default()),
+        }
+    }
+
+    fn url_dir(&self, url: &str) -> PathBuf {
+        self.directory.join(entry_name(url))
+    }
+
+    /// Record a feed fetched from `url`.
+    /// Returns the path of the recorded file, or `None` if the feed has not changed
+    ///
+    /// Note: the writes are blocking, it should not be called from an arbiter
+    pub fn record(
+        &self,
+        url: &str,
+        gtfs_rt: &GtfsRT,
+        format: RealtimeFormat,
+    ) -> Result<Option<PathBuf>, Error> {
+        let content_hash = hash(&gtfs_rt.data);
+        let mut state = self.state.lock().unwrap();
+        if state.last_hashes.get(url) == Some(&content_hash) {
+            return Ok(None);
+        }
+
+        let url_dir = self.url_dir(url);
+        let day_dir = url_dir.join(gtfs_rt.datetime.format(DAY_FORMAT).to_string());
+        std::fs::create_dir_all(&day_dir)
+            .with_context(|| format!("impossible to create directory {}", day_dir.display()))?;
+        std::fs::write(url_dir.join(URL_FILE), url)?;
+
+        // the feed is written in a temporary file, not to leave a partial record in case of error
+        let mut file = tempfile::NamedTempFile::new_in(&day_dir)?;
+        let mut encoder =
+            flate2::write::GzEncoder::new(file.as_file_mut(), flate2::Compression::default());
+        encoder.write_all(&gtfs_rt.data)?;
+        encoder.finish()?;
+        let path = day_dir.join(record_file_name(gtfs_rt.datetime, format));
+        file.persist(&path)
+            .with_context(|| format!("impossible to write {}", path.display()))?;
+
+        state.last_hashes.insert(url.to_owned(), content_hash);
+        let today = gtfs_rt.datetime.date().naive_utc();
+        if state.last_purge != Some(today) {
+            state.last_purge = Some(today);
+            self.purge(today);
+        }
+        Ok(Some(path))
+    }
+
+    /// Remove the day directories older than the retention
+    pub fn purge(&self, today: NaiveDate) {
+        let limit = match self.retention {
+            Some(retention) => today - retention,
+            None => return,
+        };
+        let url_dirs = match std::fs::read_dir(&self.directory) {
+            Ok(dirs) => dirs,
+            Err(e) => {
+                log::warn!("impossible to read recording directory: {}", e);
+                return;
+            }
+        };
+        for url_dir in url_dirs.filter_map(|e| e.ok()).map(|e| e.path()) {
+            let day_dirs = match std::fs::read_dir(&url_dir) {
+                Ok(dirs) => dirs,
+                Err(_) => continue,
+            };
+            for day_dir in day_dirs.filter_map(|e| e.ok()).map(|e| e.path()) {
+                if is_expired(&day_dir, limit) {
+                    log::info!("removing expired recording {}", day_dir.display());
+                    let _ = std::fs::remove_dir_all(&day_dir)
+                        .map_err(|e| log::warn!("impossible to remove recording: {}", e));
+                }
+            }
+        }
+    }
+}
+
+fn is_expired(day_dir: &Path, limit: NaiveDate) -> bool {
+    day_dir
+        .file_name()
+        .and_then(|n| n.to_str())
+        .and_then(|n| NaiveDate::parse_from_str(n, DAY_FORMAT).ok())
+        .map(|day| day < limit)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn gtfs_rt(data: &str, datetime: &str) -> GtfsRT {
+        GtfsRT {
+            data: data.as_bytes().to_vec(),
+            datetime: Utc.from_utc_datetime(&datetime.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = Recorder::new(&RecordingConfig {
+            directory: dir.path().to_str().unwrap().to_owned(),
+            retention_days: Some(2),
+        });
+        let url = "http://localhost/gtfs-rt";
+
+        let first = recorder
+            .record(
+                url,
+                &gtfs_rt("feed 1", "2020-03-10T12:00:00"),
+                RealtimeFormat::GtfsRt,
+            )
+            .unwrap()
+            .unwrap();
+        assert!(first.ends_with("2020-03-10/20200310T120000Z.pb.gz"));
+        // the same feed is not recorded again
+        assert!(recorder
+            .record(
+                url,
+                &gtfs_rt("feed 1", "2020-03-10T12:01:00"),
+                RealtimeFormat::GtfsRt,
+            )
+            .unwrap()
+            .is_none());
+        // the recorded feeds can be replayed
+        let replayed = crate::replay::read_file(first.as_path(), Utc::now()).unwrap();
+        assert_eq!(replayed.data, b"feed 1");
+        let url_dir = first.parent().unwrap().parent().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(url_dir.join(URL_FILE)).unwrap(),
+            url
+        );
+
+        // 3 days later, the first day is removed
+        let last = recorder
+            .record(
+                url,
+                &gtfs_rt("feed 2", "2020-03-13T00:00:00"),
+                RealtimeFormat::GtfsRt,
+            )
+            .unwrap()
+            .unwrap();
+        assert!(!first.exists());
+        assert!(last.exists());
+    }
+
+    #[test]
+    fn siri_feeds_are_recorded_as_xml() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = Recorder::new(&RecordingConfig {
+            directory: dir.path().to_str().unwrap().to_owned(),
+            retention_days: None,
+        });
+        let path = recorder
+            .record(
+                "http://localhost/siri",
+                &gtfs_rt("<Siri/>", "2020-03-10T12:00:00"),
+                RealtimeFormat::SiriEt,
+            )
+            .unwrap()
+            .unwrap();
+        assert!(path.ends_with("2020-03-10/20200310T120000Z.xml.gz"));
+        assert_eq!(
+            crate::replay::snapshot_datetime(&path),
+            Some(Utc.ymd(2020, 3, 10).and_hms(12, 0, 0))
+        );
+        let replayed = crate::replay::read_file(path.as_path(), Utc::now()).unwrap();
+        assert_eq!(replayed.data, b"<Siri/>");
+    }
+}
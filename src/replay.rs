@@ -0,0 +1,246 @@
+//! Replay of recorded GTFS-RT
+//!
+//! A GTFS-RT url can be a `file://` path to a directory of snapshots,
+//! each snapshot being named after its UTC datetime (`20200310T120000Z.pb`, or a unix timestamp `1583841600.pb`),
+//! and possibly gzipped (`20200310T120000Z.pb.gz`, like the recorded GTFS-RT).
+//! The SIRI snapshots are named the same way, with a `.xml` extension.
+//! The dataset then runs on a simulated clock: each realtime update moves the clock to the next snapshot datetime,
+//! and serves, for each replayed url, its last snapshot at this datetime.
+use crate::clock::Clock;
+use crate::datasets::GtfsRT;
+use anyhow::{anyhow, Context, Error};
+use chrono::{DateTime, TimeZone, Utc};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub const FILE_SCHEME: &str = "file://";
+
+/// Path of a `file://` url
+pub fn local_path(url: &str) -> Option<&Path> {
+    if url.starts_with(FILE_SCHEME) {
+        Some(Path::new(&url[FILE_SCHEME.len()..]))
+    } else {
+        None
+    }
+}
+
+/// Read a GTFS-RT file, decompressing it if it is gzipped
+pub fn read_file(path: &Path, datetime: DateTime<Utc>) -> Result<GtfsRT, Error> {
+    let mut data = std::fs::read(path)
+        .with_context(|| format!("impossible to read gtfs-rt {}", path.display()))?;
+    if path.extension().map(|e| e == "gz").unwrap_or(false) {
+        use std::io::Read;
+        let mut decompressed = vec![];
+        flate2::read::GzDecoder::new(data.as_slice())
+            .read_to_end(&mut decompressed)
+            .with_context(|| format!("impossible to decompress gtfs-rt {}", path.display()))?;
+        data = decompressed;
+    }
+    Ok(GtfsRT { data, datetime })
+}
+
+/// Datetime of a snapshot, from its file name
+pub fn snapshot_datetime(path: &Path) -> Option<DateTime<Utc>> {
+    let file_name = path.file_name()?.to_str()?;
+    let stem = file_name.strip_suffix(".gz").unwrap_or(file_name);
+    let stem = stem
+        .strip_suffix(".pb")
+        .or_else(|| stem.strip_suffix(".xml"))?;
+    if let Ok(timestamp) = stem.parse::<i64>() {
+        return Some(Utc.timestamp(timestamp, 0));
+    }
+    chrono::NaiveDateTime::parse_from_str(stem.trim_end_matches('Z'), "%Y%m%dT%H%M%S")
+        .ok()
+        .map(|dt| Utc.from_utc_datetime(&dt))
+}
+
+/// Snapshots of a directory, sorted by datetime
+#[derive(Debug, Clone)]
+pub struct Snapshots {
+    snapshots: Vec<(DateTime<Utc>, PathBuf)>,
+}
+
+impl Snapshots {
+    pub fn read_dir(dir: &Path) -> Result<Self, Error> {
+        let mut snapshots = std::fs::read_dir(dir)
+            .with_context(|| format!("impossible to read snapshot directory {}", dir.display()))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter_map(|path| match snapshot_datetime(&path) {
+                Some(dt) => Some((dt, path)),
+                None => {
+                    log::warn!("ignoring {}, not a gtfs-rt snapshot", path.display());
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        snapshots.sort();
+        if snapshots.is_empty() {
+            return Err(anyhow!("no gtfs-rt snapshot in {}", dir.display()));
+        }
+        Ok(Self { snapshots })
+    }
+
+    pub fn first(&self) -> Option<DateTime<Utc>> {
+        self.snapshots.first().map(|(dt, _)| *dt)
+    }
+
+    /// last snapshot at `dt`
+    pub fn at(&self, dt: DateTime<Utc>) -> Option<&(DateTime<Utc>, PathBuf)> {
+        self.snapshots.iter().rev().find(|(d, _)| *d <= dt)
+    }
+
+    /// datetime of the first snapshot after `dt`
+    pub fn next_after(&self, dt: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.snapshots.iter().map(|(d, _)| *d).find(|d| *d > dt)
+    }
+}
+
+/// Replay of the snapshot directories of a dataset
+pub struct Replay {
+    sources: BTreeMap<String, Snapshots>,
+    clock: Clock,
+    started: AtomicBool,
+}
+
+impl Replay {
+    /// Create the replay of the urls that are snapshot directories.
+    /// `None` if none of them is a directory
+    pub fn new(urls: &[String]) -> Result<Option<Self>, Error> {
+        let sources = urls
+            .iter()
+            .filter_map(|url| local_path(url).filter(|p| p.is_dir()).map(|p| (url, p)))
+            .map(|(url, dir)| Snapshots::read_dir(dir).map(|s| (url.clone(), s)))
+            .collect::<Result<BTreeMap<_, _>, _>>()?;
+        let start = match sources.values().filter_map(Snapshots::first).min() {
+            Some(start) => start,
+            None => return Ok(None),
+        };
+        Ok(Some(Self {
+            sources,
+            clock: Clock::simulated(start),
+            started: AtomicBool::new(false),
+        }))
+    }
+
+    /// the simulated clock of the replay
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// Move the clock to the next snapshot datetime.
+    /// The first step stays on the first snapshot.
+    /// Returns false when all the snapshots have been replayed
+    pub fn step(&self) -> bool {
+        if !self.started.swap(true, Ordering::SeqCst) {
+            return true;
+        }
+        let now = self.clock.now();
+        match self
+            .sources
+            .values()
+            .filter_map(|s| s.next_after(now))
+            .min()
+        {
+            Some(next) => {
+                self.clock.set(next);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The snapshot of a replayed url at the current time of the clock.
+    /// `None` if the url is not replayed
+    pub fn snapshot(&self, url: &str) -> Option<Result<GtfsRT, Error>> {
+        let snapshots = self.sources.get(url)?;
+        let now = self.clock.now();
+        Some(
+            snapshots
+                .at(now)
+                .ok_or_else(|| anyhow!("no snapshot of {} at {}", url, now))
+                .and_then(|(dt, path)| read_file(path, *dt)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dt(s: &str) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&s.parse().unwrap())
+    }
+
+    #[test]
+    fn test_snapshot_datetime() {
+        assert_eq!(
+            snapshot_datetime(Path::new("/a/20200310T120000Z.pb")),
+            Some(dt("2020-03-10T12:00:00"))
+        );
+        assert_eq!(
+            snapshot_datetime(Path::new("20200310T120000.pb")),
+            Some(dt("2020-03-10T12:00:00"))
+        );
+        assert_eq!(
+            snapshot_datetime(Path::new("1583841600.pb")),
+            Some(dt("2020-03-10T12:00:00"))
+        );
+        assert_eq!(
+            snapshot_datetime(Path::new("20200310T120000Z.pb.gz")),
+            Some(dt("2020-03-10T12:00:00"))
+        );
+        assert_eq!(snapshot_datetime(Path::new("20200310T120000Z.json")), None);
+        assert_eq!(snapshot_datetime(Path::new("notes.pb")), None);
+    }
+
+    #[test]
+    fn test_replay() {
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+        for (dir, name) in &[
+            (&first, "20200310T120000Z.pb"),
+            (&first, "20200310T120200Z.pb"),
+            (&second, "20200310T120100Z.pb"),
+            (&second, "README.md"),
+        ] {
+            std::fs::write(dir.path().join(name), name.as_bytes()).unwrap();
+        }
+        let first_url = format!("file://{}", first.path().display());
+        let second_url = format!("file://{}", second.path().display());
+        let urls = vec![
+            first_url.clone(),
+            second_url.clone(),
+            "http://localhost/gtfs-rt".to_owned(),
+        ];
+        let replay = Replay::new(&urls).unwrap().unwrap();
+        let content =
+            |url: &str| String::from_utf8(replay.snapshot(url).unwrap().unwrap().data).unwrap();
+
+        assert!(replay.snapshot("http://localhost/gtfs-rt").is_none());
+        assert!(replay.step());
+        assert_eq!(replay.clock().now(), dt("2020-03-10T12:00:00"));
+        assert_eq!(content(&first_url), "20200310T120000Z.pb");
+        assert!(replay.snapshot(&second_url).unwrap().is_err());
+
+        assert!(replay.step());
+        assert_eq!(replay.clock().now(), dt("2020-03-10T12:01:00"));
+        assert_eq!(content(&first_url), "20200310T120000Z.pb");
+        assert_eq!(content(&second_url), "20200310T120100Z.pb");
+
+        assert!(replay.step());
+        assert_eq!(replay.clock().now(), dt("2020-03-10T12:02:00"));
+        assert_eq!(content(&first_url), "20200310T120200Z.pb");
+
+        // all the snapshots have been replayed, the clock does not move anymore
+        assert!(!replay.step());
+        assert_eq!(replay.clock().now(), dt("2020-03-10T12:02:00"));
+    }
+
+    #[test]
+    fn test_no_replay() {
+        let urls = vec!["http://localhost/gtfs-rt".to_owned()];
+        assert!(Replay::new(&urls).unwrap().is_none());
+    }
+}
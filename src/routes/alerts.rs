@@ -0,0 +1,79 @@
+use crate::alerts::{Alert, AlertError, AlertStore};
+use crate::extractors::{Admin, DatasetWrapper};
+use actix_web::error::BlockingError;
+use actix_web::{error, web, HttpResponse};
+use std::sync::Arc;
+
+fn check_alert(alert: &Alert, dataset_wrapper: &DatasetWrapper) -> actix_web::Result<()> {
+    let dataset = dataset_wrapper.get_dataset()?;
+    let errors = alert.check(&dataset.ntm, dataset.timezone);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(error::ErrorBadRequest(format!(
+            "invalid alert: {}",
+            errors.join(", ")
+        )))
+    }
+}
+
+fn alert_error(e: BlockingError<AlertError>) -> actix_web::Error {
+    match e {
+        BlockingError::Error(e @ AlertError::AlreadyExists(_)) => {
+            error::ErrorConflict(e.to_string())
+        }
+        BlockingError::Error(e @ AlertError::UnknownAlert(_)) => {
+            error::ErrorNotFound(e.to_string())
+        }
+        e => {
+            log::error!("impossible to change the alerts: {}", e);
+            error::ErrorInternalServerError("impossible to store the alert")
+        }
+    }
+}
+
+pub async fn alerts_query(store: web::Data<Arc<AlertStore>>) -> web::Json<Vec<Alert>> {
+    web::Json(store.alerts())
+}
+
+pub async fn create_alert_query(
+    _admin: Admin,
+    web::Json(alert): web::Json<Alert>,
+    dataset_wrapper: DatasetWrapper,
+    store: web::Data<Arc<AlertStore>>,
+) -> actix_web::Result<HttpResponse> {
+    check_alert(&alert, &dataset_wrapper)?;
+    let store = store.get_ref().clone();
+    // the alerts are written on disk, it is blocking
+    let alert = web::block(move || store.create(alert))
+        .await
+        .map_err(alert_error)?;
+    Ok(HttpResponse::Created().json(alert))
+}
+
+pub async fn update_alert_query(
+    _admin: Admin,
+    alert_id: web::Path<String>,
+    web::Json(alert): web::Json<Alert>,
+    dataset_wrapper: DatasetWrapper,
+    store: web::Data<Arc<AlertStore>>,
+) -> actix_web::Result<web::Json<Alert>> {
+    check_alert(&alert, &dataset_wrapper)?;
+    let store = store.get_ref().clone();
+    let alert = web::block(move || store.update(&alert_id, alert))
+        .await
+        .map_err(alert_error)?;
+    Ok(web::Json(alert))
+}
+
+pub async fn delete_alert_query(
+    _admin: Admin,
+    alert_id: web::Path<String>,
+    store: web::Data<Arc<AlertStore>>,
+) -> actix_web::Result<HttpResponse> {
+    let store = store.get_ref().clone();
+    web::block(move || store.delete(&alert_id))
+        .await
+        .map_err(alert_error)?;
+    Ok(HttpResponse::NoContent().finish())
+}
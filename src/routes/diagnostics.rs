@@ -0,0 +1,15 @@
+use crate::actors::{DatasetActor, GetDiagnostics};
+use crate::diagnostics::DiagnosticsReport;
+use actix::Addr;
+use actix_web::web;
+
+/// Quality of the matching of the realtime feeds with the base schedule, over the last hour
+pub async fn diagnostics_query(
+    dataset_actor: web::Data<Addr<DatasetActor>>,
+) -> actix_web::Result<web::Json<DiagnosticsReport>> {
+    let report = dataset_actor.send(GetDiagnostics).await.map_err(|e| {
+        log::error!("error while querying actor for data: {:?}", e);
+        actix_web::error::ErrorInternalServerError("impossible to get data".to_string())
+    })?;
+    Ok(web::Json(report))
+}
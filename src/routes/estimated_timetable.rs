@@ -1,16 +1,22 @@
 use std::u64;
 
 use super::open_api::make_param;
-use crate::datasets::{Connection, Dataset, RealTimeConnection, UpdatedTimetable};
-use crate::extractors::RealTimeDatasetWrapper;
-use crate::routes::estimated_timetable;
-use crate::siri_lite::{self, service_delivery as model, SiriResponse};
+use crate::access_control::{AccessControl, DEFAULT_MAX_STOP_VISITS};
+use crate::actors::Subscription;
+use crate::clock::Clock;
+use crate::datasets::{
+    Connection, Dataset, DatedVehicleJourney, RealTimeConnection, RealTimeDataset,
+    UpdatedTimetable,
+};
+use crate::extractors::{RealTimeDatasetWrapper, ResponseFormat};
+use crate::siri_lite::{self, service_delivery as model, shared::CommonDelivery, SiriResponse};
 use crate::utils;
 use actix_web::{error, web, HttpResponse};
 use openapi_schema::OpenapiSchema;
-use transit_model::collection::Idx;
+use std::collections::HashMap;
+use std::sync::Arc;
 use transit_model::objects::StopPoint;
-use quick_xml::se::to_string;
+use transit_model::relations::IdxSet;
 
 #[derive(Debug, Deserialize, PartialEq, Eq, OpenapiSchema)]
 enum DataFreshness {
@@ -62,6 +68,22 @@ pub struct Params {
 }
 
 impl Params {
+    /// Params used to build the deliveries sent to a subscriber
+    fn from_subscription(subscription: &Subscription) -> Self {
+        Self {
+            _requestor_ref: Some(subscription.subscriber_ref.clone()),
+            monitoring_ref: subscription.monitoring_ref.clone(),
+            only_realtime: default_only_realtime(),
+            operator_ref: None,
+            line_ref: subscription.line_ref.clone(),
+            _destination_ref: None,
+            start_time: None,
+            preview_interval: None,
+            data_freshness: DataFreshness::default(),
+            maximum_stop_visits: default_stop_visits(),
+        }
+    }
+
     // TODO: generate this via derive macro
     pub fn openapi_description(spec: &mut openapi::v3_0::Spec) -> Vec<openapi::v3_0::Parameter> {
         vec![
@@ -77,58 +99,54 @@ impl Params {
     }
 }
 
-fn create_estimated_timetable_visit(
+fn create_estimated_call(
     data: &Dataset,
     connection: &Connection,
     updated_connection: Option<&RealTimeConnection>,
-) -> siri_lite::service_delivery::EstimatedVehicleJourney {
+) -> model::EstimatedCall {
     let stop = &data.ntm.stop_points[connection.stop_point_idx];
-    let vj = &data.ntm.vehicle_journeys[connection.dated_vj.vj_idx];
-    let route = &data.ntm.routes.get(&vj.route_id);
-    // we consider that the siri's operator in transmodel's company
-    let operator_ref = data
-        .ntm
-        .get_corresponding_from_idx(connection.dated_vj.vj_idx)
-        .into_iter()
-        .next()
-        .map(|idx| data.ntm.companies[idx].id.clone());
-    let line_ref = route
-        .map(|r| r.line_id.clone())
-        .unwrap_or_else(|| "".to_owned());
-    let update_time = updated_connection
-        .map(|c| c.update_time)
-        // if we have no realtime data, we consider the update time to be the time of the base schedule loading
-        // (it's not that great, but we don't have something better)
-        .unwrap_or_else(|| data.loaded_at);
-    let call = model::EstimatedCall {
-        StopPointRef: model::StopPointRefWrapper{ StopPointRef: format!("IT:ITC1:ScheduledStopPoint:busATS:{}", stop.id.clone())}, //TODO: hardcoded prefix
-        VisitNumber: None, //TODO: find value
-        Order: model::OrderWrapper{ Order: connection.sequence as u16},
-        StopPointName: model::StopPointNameWrapper{ StopPointName: stop.name.clone()},
-        AimedArrivalTime: Some( model::AimedArrivalTimeWrapper{ AimedArrivalTime: siri_lite::DateTime(connection.arr_time)}),
-        ExpectedArrivalTime: updated_connection
+    model::EstimatedCall {
+        stop_point_ref: format!("IT:ITC1:ScheduledStopPoint:busATS:{}", stop.id), //TODO: hardcoded prefix
+        visit_number: None, //TODO: find value
+        order: connection.sequence as u16,
+        stop_point_name: stop.name.clone(),
+        aimed_arrival_time: Some(siri_lite::DateTime(connection.arr_time)),
+        expected_arrival_time: updated_connection
             .and_then(|c| c.arr_time)
-            .map(|time| model::ExpectedArrivalTimeWrapper { ExpectedArrivalTime: siri_lite::DateTime(time) }),
-        AimedDepartureTime: Some( model::AimedDepartureTimeWrapper{ AimedDepartureTime: siri_lite::DateTime(connection.dep_time)}),
-        ExpectedDepartureTime: updated_connection
+            .map(siri_lite::DateTime),
+        aimed_departure_time: Some(siri_lite::DateTime(connection.dep_time)),
+        expected_departure_time: updated_connection
             .and_then(|c| c.dep_time)
-            .map(|time| model::ExpectedDepartureTimeWrapper { ExpectedDepartureTime: siri_lite::DateTime(time) })
-        };
+            .map(siri_lite::DateTime),
+        prediction_inaccurate: updated_connection
+            .filter(|c| c.estimated)
+            .map(|_| true),
+    }
+}
+
+fn create_estimated_vehicle_journey(
+    data: &Dataset,
+    connection: &Connection,
+) -> model::EstimatedVehicleJourney {
+    let vj = &data.ntm.vehicle_journeys[connection.dated_vj.vj_idx];
+    let line_ref = get_line_ref(connection, &data.ntm).unwrap_or_default();
+    // we consider that the siri's operator in transmodel's company
+    let operator_ref = get_operator_ref(connection, &data.ntm).unwrap_or_default();
 
     model::EstimatedVehicleJourney {
-        LineRef: model::LineRefWrapper{ LineRef: format!("IT:ITC1:Line:busATS:{}", line_ref.clone())}, //TODO: hardcoded prefix
-        DirectionRef: Some(model::DirectionRefWrapper{ DirectionRef: "inbound".to_string()}), //TODO: find value
-        JourneyPatternRef: None, //TODO: find value
-        PublishedLineName: None, //TODO: find value
-        FramedVehicleJourneyRef: model::FramedVehicleJourneyRef {
-            DataFrameRef: Some(model::DataFrameRefWrapper{ DataFrameRef: connection.arr_time.date().to_string() }), 
-            DatedVehicleJourneyRef: Some(model::DatedVehicleJourneyRefWrapper{ DatedVehicleJourneyRef: format!("x")}), //TODO: hardcoded values
+        line_ref: format!("IT:ITC1:Line:busATS:{}", line_ref), //TODO: hardcoded prefix
+        direction_ref: Some("inbound".to_string()),            //TODO: find value
+        journey_pattern_ref: None,                             //TODO: find value
+        published_line_name: None,                             //TODO: find value
+        framed_vehicle_journey_ref: model::FramedVehicleJourneyRef {
+            data_frame_ref: Some(connection.dated_vj.date.to_string()),
+            dated_vehicle_journey_ref: Some(vj.id.clone()),
         },
-        OperatorRef: model::ServiceInfoGroup { 
-            OperatorRef: Some(model::OperatorRefWrapper{ OperatorRef: format!("IT::Operator:02194050486:{}", operator_ref.unwrap_or_default())}) 
-        }, //TODO: hardcoded prefix
-        VehicleRef: None, //TODO: find value
-        EstimatedCalls: call,
+        service_info: model::ServiceInfoGroup {
+            operator_ref: Some(format!("IT::Operator:02194050486:{}", operator_ref)), //TODO: hardcoded prefix
+        },
+        vehicle_ref: None, //TODO: find value
+        estimated_calls: model::EstimatedCalls::default(),
     }
 }
 
@@ -160,36 +178,41 @@ fn is_in_interval(
 }
 
 fn create_estimated_timetable(
-    stop_idx: std::option::Option<Idx<StopPoint>>,
+    stop_points: Option<&IdxSet<StopPoint>>,
     data: &Dataset,
     updated_timetable: &UpdatedTimetable,
     request: &Params,
+    subscription: Option<&Subscription>,
+    now: chrono::DateTime<chrono::Utc>,
 ) -> Vec<model::EstimatedTimetableDelivery> {
     // if we want to datetime in the query, we get the current_time (in the timezone of the dataset)
-    let requested_start_time = request.start_time.as_ref().map(|d| d.0).unwrap_or_else(|| {
-        chrono::Utc::now()
-            .with_timezone(&data.timezone)
-            .naive_local()
-    });
+    let requested_start_time = request
+        .start_time
+        .as_ref()
+        .map(|d| d.0)
+        .unwrap_or_else(|| now.with_timezone(&data.timezone).naive_local());
     let requested_line_ref = request.line_ref.as_deref();
     let requested_operator_ref = request.operator_ref.as_deref();
 
-    let estimated_timetable = data
+    // the calls are grouped by vehicle journey
+    let mut vehicle_journeys: Vec<model::EstimatedVehicleJourney> = vec![];
+    let mut vehicle_journeys_pos: HashMap<&DatedVehicleJourney, usize> = HashMap::new();
+
+    let connections = data
         .timetable
         .connections
         .iter()
         .enumerate()
         .skip_while(|(_, c)| c.dep_time < requested_start_time)
-        .filter(|(_, c)| {
-            stop_idx.is_none() || stop_idx == Some(c.stop_point_idx)
-        })
+        .filter(|(_, c)| stop_points.map_or(true, |s| s.contains(&c.stop_point_idx)))
         // filter on requested lines
         .filter(|(_, c)| {
             requested_line_ref.is_none() || requested_line_ref == get_line_ref(&c, &data.ntm)
         })
         // filter on requested operator
         .filter(|(_, c)| {
-            requested_operator_ref.is_none() || requested_operator_ref == get_operator_ref(&c, &data.ntm)
+            requested_operator_ref.is_none()
+                || requested_operator_ref == get_operator_ref(&c, &data.ntm)
         })
         .filter(|(_, c)| is_in_interval(&c, requested_start_time, &request.preview_interval))
         // filter if has realtime data has expected arrival time or expected departure time
@@ -197,108 +220,138 @@ fn create_estimated_timetable(
             let has_realtime_data = updated_timetable
                 .realtime_connections
                 .get(i)
-                .map(|_c| _c.arr_time.is_some() || _c.dep_time.is_some())
+                .map(|c| c.arr_time.is_some() || c.dep_time.is_some())
                 .unwrap_or(false);
-            if request.only_realtime {
-                has_realtime_data
-            } else {
-                true
-            }
-        })
-        .map(|(idx, c)| {
-            create_estimated_timetable_visit(
-                data,
-                c,
-                match request.data_freshness {
-                    DataFreshness::RealTime => updated_timetable.realtime_connections.get(&idx),
-                    DataFreshness::Scheduled => None,
-                },
-            )
+            !request.only_realtime || has_realtime_data
         })
-        .take(request.maximum_stop_visits as usize)
-        .collect::<Vec<model::EstimatedVehicleJourney>>();
+        .take(request.maximum_stop_visits as usize);
+
+    for (idx, c) in connections {
+        let call = create_estimated_call(
+            data,
+            c,
+            match request.data_freshness {
+                DataFreshness::RealTime => updated_timetable.realtime_connections.get(&idx),
+                DataFreshness::Scheduled => None,
+            },
+        );
+        let pos = *vehicle_journeys_pos
+            .entry(&c.dated_vj)
+            .or_insert_with(|| {
+                vehicle_journeys.push(create_estimated_vehicle_journey(data, c));
+                vehicle_journeys.len() - 1
+            });
+        vehicle_journeys[pos].estimated_calls.estimated_call.push(call);
+    }
+    for vj in &mut vehicle_journeys {
+        vj.estimated_calls.estimated_call.sort_by_key(|c| c.order);
+    }
 
     vec![model::EstimatedTimetableDelivery {
-        ResponseTimestamp: model::ResponseTimeStampWrapper{ResponseTimestamp: chrono::Local::now().to_rfc3339()}, //TODO: is this value correct?
-        RequestMessageRef: None,
-        SubscriberRef: model::SubscriberRefWrapper{ SubscriberRef: "NAP".to_string()}, //TODO: hardcoded value
-        SubscriptionRef: model::SubscriptionRefWrapper{ SubscriptionRef: "0001".to_string()}, //TODO: hardcoded value
-        EstimatedJourneyVersionFrame: model::EstimatedJourneyVersionFrame {
-            RecordedAtTime: model::RecordedAtTimeWrapper{ RecordedAtTime: chrono::Local::now().to_rfc3339()}, //TODO: is this value correct?
-            EstimatedVehicleJourney: estimated_timetable,
-        },
+        common: CommonDelivery::new(now),
+        version: "2.0".to_owned(),
+        subscriber_ref: subscription.map(|s| s.subscriber_ref.clone()),
+        subscription_ref: subscription.map(|s| s.subscription_ref.clone()),
+        estimated_journey_version_frame: vec![model::EstimatedJourneyVersionFrame {
+            recorded_at_time: now.to_rfc3339(),
+            estimated_vehicle_journey: vehicle_journeys,
+        }],
     }]
 }
 
-fn validate_params(request: &mut Params) -> actix_web::Result<()> {
-    // we silently bound the maximum stop visits to 20
-    //request.maximum_stop_visits = std::cmp::min(request.maximum_stop_visits, 20);
-    
+fn validate_params(request: &mut Params, max_stop_visits: u64) -> actix_web::Result<()> {
+    // we silently bound the maximum stop visits to the configured maximum
+    request.maximum_stop_visits = std::cmp::min(request.maximum_stop_visits, max_stop_visits);
+
     Ok(())
 }
 
 fn estimated_timetable(
     mut request: Params,
-    rt_dataset_wrapper: RealTimeDatasetWrapper,
-) -> actix_web::Result<String> {
-    let data = rt_dataset_wrapper.get_base_schedule_dataset()?;
+    rt_dataset: &RealTimeDataset,
+    subscription: Option<&Subscription>,
+    max_stop_visits: u64,
+    now: chrono::DateTime<chrono::Utc>,
+) -> actix_web::Result<SiriResponse> {
+    let data = rt_dataset.base_schedule_dataset.as_ref().as_ref().map_err(|e| {
+        error::ErrorBadGateway(format!(
+            "theoretical dataset temporarily unavailable: {}",
+            e
+        ))
+    })?;
 
-    let updated_timetable = &rt_dataset_wrapper.updated_timetable;
+    let updated_timetable = &rt_dataset.updated_timetable;
 
-    validate_params(&mut request)?;
+    validate_params(&mut request, max_stop_visits)?;
 
     // TODO: hardcoded agency, remove when not necessary
     request.operator_ref = Some("1".to_string());
 
-    // set stop_idx to a default value
-    let mut stop_idx = None;
-    if request.monitoring_ref.as_deref() != None {
-        stop_idx = Some(data
-            .ntm
-            .stop_points
-            .get_idx(request.monitoring_ref.as_deref().unwrap())
-            .ok_or_else(|| {
-                error::ErrorNotFound(format!(
-                    "impossible to find stop: '{:?}'",
-                    &request.monitoring_ref
-                ))
-            })?);
-    }
-
-    let service_delivery = Some(model::ServiceDelivery {
-        ProducerRef: Some(model::ProducerRefWrapper{ ProducerRef: "RAP_Toscana".to_string()}), // TODO: hardcoded value
-        EstimatedTimetableDelivery: create_estimated_timetable(
-            stop_idx,
-            &data,
-            updated_timetable,
-            &request,
-        ),
-        ResponseMessageIdentifier:  Some(model::ResponseMessageIdentifierWrapper{ ResponseMessageIdentifier: "0001".to_string()}),   //TODO: hardcoded prefix
-        ResponseTimestamp: model::ResponseTimeStampWrapper{ ResponseTimestamp: chrono::Utc::now().to_rfc3339()},
-        ..Default::default()
-    });
-
-    let service_delivery_xml = to_string(&service_delivery).unwrap();
-    //TODO: FIX THIS replace
-    let xml_with_root = format!(
-        "<?xml version=\"1.0\" encoding=\"utf-8\"?><Siri xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xmlns=\"http://www.siri.org.uk/siri\" xsi:schemaLocation=\"http://www.siri.org.uk/siri ../xsd/siri.xsd\" version=\"2.1\"><ServiceDelivery>{}</ServiceDelivery></Siri>", service_delivery_xml
-        .replace("<EstimatedCalls>", "<EstimatedCalls><EstimatedCall>")
-        .replace("</EstimatedCalls>", "</EstimatedCall></EstimatedCalls>")
-        .replace("</EstimatedCalls>", "</EstimatedCalls></EstimatedVehicleJourney><EstimatedVehicleJourney>")
-        .replace("<EstimatedVehicleJourney></EstimatedVehicleJourney>", "")
-    );
+    let stop_points = request
+        .monitoring_ref
+        .as_ref()
+        .map(|monitoring_ref| {
+            data.stop_points(monitoring_ref).ok_or_else(|| {
+                error::ErrorNotFound(format!("impossible to find stop: '{}'", monitoring_ref))
+            })
+        })
+        .transpose()?;
 
-    Ok(xml_with_root)
+    Ok(SiriResponse {
+        siri: siri_lite::Siri {
+            service_delivery: Some(model::ServiceDelivery {
+                response_timestamp: now.to_rfc3339(),
+                producer_ref: Some("RAP_Toscana".to_string()), // TODO: hardcoded value
+                response_message_identifier: Some("0001".to_string()), //TODO: hardcoded value
+                estimated_timetable_delivery: create_estimated_timetable(
+                    stop_points.as_ref(),
+                    &data,
+                    updated_timetable,
+                    &request,
+                    subscription,
+                    now,
+                ),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+    })
+}
 
+/// Build the estimated timetable ServiceDelivery (in xml) sent to a subscriber
+pub(crate) fn subscription_estimated_timetable(
+    rt_dataset: &RealTimeDataset,
+    subscription: &Subscription,
+    now: chrono::DateTime<chrono::Utc>,
+) -> actix_web::Result<String> {
+    let response = estimated_timetable(
+        Params::from_subscription(subscription),
+        rt_dataset,
+        Some(subscription),
+        DEFAULT_MAX_STOP_VISITS,
+        now,
+    )?;
+    siri_lite::xml::siri_to_xml(&response.siri).map_err(|e| {
+        error::ErrorInternalServerError(format!(
+            "impossible to serialize siri response: {}",
+            e
+        ))
+    })
 }
 
 pub async fn estimated_timetable_query(
     web::Query(query): web::Query<Params>,
     rt_dataset_wrapper: RealTimeDatasetWrapper,
-) -> HttpResponse {
-    let xml_string = estimated_timetable(query, rt_dataset_wrapper);
-    let xml_string = xml_string.unwrap_or_default();
-    HttpResponse::Ok()
-        .content_type(actix_web::http::header::HeaderValue::from_static("text/plain"))
-        .body(xml_string)
+    clock: web::Data<Clock>,
+    access_control: web::Data<Arc<AccessControl>>,
+    format: ResponseFormat,
+) -> actix_web::Result<HttpResponse> {
+    estimated_timetable(
+        query,
+        &rt_dataset_wrapper,
+        None,
+        access_control.max_stop_visits(),
+        clock.now(),
+    )?
+    .into_http_response(format)
 }
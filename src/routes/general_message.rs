@@ -1,12 +1,16 @@
+use super::gtfs_rt::get_feed_message;
 use super::open_api::make_param;
-use crate::extractors::RealTimeDatasetWrapper;
+use crate::alerts::AlertStore;
+use crate::clock::Clock;
+use crate::extractors::{RealTimeDatasetWrapper, ResponseFormat};
 use crate::siri_lite::{
     general_message as gm, service_delivery::ServiceDelivery, shared::CommonDelivery, Siri,
     SiriResponse,
 };
 use crate::transit_realtime;
 use crate::utils;
-use actix_web::{web, Result};
+use actix_web::{web, HttpResponse, Result};
+use std::sync::Arc;
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
@@ -119,37 +123,26 @@ fn read_info_messages(
 fn general_message(
     request: Params,
     rt_dataset_wrapper: RealTimeDatasetWrapper,
+    alerts: &AlertStore,
+    clock: &Clock,
 ) -> Result<SiriResponse> {
-    unimplemented!()
-    /*
     let timezone = rt_dataset_wrapper.get_base_schedule_dataset()?.timezone;
+    let now = clock.now();
 
     let requested_dt = request
         .request_timestamp
         .map(|d| d.0)
-        .unwrap_or_else(|| chrono::Utc::now().with_timezone(&timezone).naive_local());
+        .unwrap_or_else(|| now.with_timezone(&timezone).naive_local());
     // Note: we decode the gtfs at the query. if needed we can cache this, to parse it once
-    use prost::Message;
-    let feed = rt_dataset_wrapper
-        .gtfs_rt
-        .as_ref()
-        .ok_or_else(|| actix_web::error::ErrorNotFound("no realtime data available"))
-        .map(|rt| rt.data.clone())
-        .and_then(|d| {
-            transit_realtime::FeedMessage::decode(d.as_slice()).map_err(|e| {
-                actix_web::error::ErrorInternalServerError(format!(
-                    "impossible to decode protobuf message: {}",
-                    e
-                ))
-            })
-        })?;
+    let feed = get_feed_message(&rt_dataset_wrapper, alerts, clock)?;
 
     Ok(SiriResponse {
         siri: Siri {
             service_delivery: Some(ServiceDelivery {
+                response_timestamp: now.to_rfc3339(),
                 producer_ref: None, // TODO take the id of the dataset ?
                 general_message_delivery: vec![gm::GeneralMessageDelivery {
-                    common: CommonDelivery::default(),
+                    common: CommonDelivery::new(now),
                     info_messages: read_info_messages(&feed, requested_dt, timezone),
                     info_messages_cancellation: vec![],
                 }],
@@ -158,12 +151,14 @@ fn general_message(
             ..Default::default()
         },
     })
-     */
 }
 
 pub async fn general_message_query(
     web::Query(query): web::Query<Params>,
     realtime_dataset_wrapper: RealTimeDatasetWrapper,
-) -> actix_web::Result<web::Json<SiriResponse>> {
-    Ok(web::Json(general_message(query, realtime_dataset_wrapper)?))
+    alerts: web::Data<Arc<AlertStore>>,
+    clock: web::Data<Clock>,
+    format: ResponseFormat,
+) -> Result<HttpResponse> {
+    general_message(query, realtime_dataset_wrapper, &alerts, &clock)?.into_http_response(format)
 }
@@ -1,40 +1,76 @@
+use crate::alerts::AlertStore;
+use crate::clock::Clock;
 use crate::extractors::RealTimeDatasetWrapper;
 use crate::transit_realtime;
 use actix_web::{error, http::ContentEncoding, web, HttpResponse};
+use std::sync::Arc;
+
+/// The GTFS-RT of the dataset, with the alerts published through the api
+pub(crate) fn get_feed_message(
+    rt_dataset_wrapper: &RealTimeDatasetWrapper,
+    alerts: &AlertStore,
+    clock: &Clock,
+) -> actix_web::Result<transit_realtime::FeedMessage> {
+    use prost::Message;
+    let published_alerts = match rt_dataset_wrapper.get_base_schedule_dataset() {
+        Ok(dataset) => alerts.feed_entities(dataset.timezone),
+        // the alerts datetimes cannot be converted without the dataset's timezone
+        Err(_) => vec![],
+    };
+    let mut feed_message = match (&rt_dataset_wrapper.gtfs_rt, published_alerts.is_empty()) {
+        (Some(rt), _) => {
+            transit_realtime::FeedMessage::decode(rt.data.as_slice()).map_err(|e| {
+                error::ErrorInternalServerError(format!(
+                    "impossible to decode protobuf message: {}",
+                    e
+                ))
+            })?
+        }
+        (None, false) => transit_realtime::FeedMessage {
+            header: transit_realtime::FeedHeader {
+                gtfs_realtime_version: "2.0".to_owned(),
+                incrementality: Some(
+                    transit_realtime::feed_header::Incrementality::FullDataset as i32,
+                ),
+                timestamp: Some(clock.now().timestamp() as u64),
+            },
+            entity: vec![],
+        },
+        (None, true) => return Err(error::ErrorNotFound("no realtime data available")),
+    };
+    feed_message.entity.extend(published_alerts);
+    Ok(feed_message)
+}
 
 pub async fn gtfs_rt_protobuf(
     rt_dataset_wrapper: RealTimeDatasetWrapper,
+    alerts: web::Data<Arc<AlertStore>>,
+    clock: web::Data<Clock>,
 ) -> actix_web::Result<web::HttpResponse> {
     use actix_web::dev::BodyEncoding;
-    rt_dataset_wrapper
-        .gtfs_rt
-        .as_ref()
-        .ok_or_else(|| error::ErrorNotFound("no realtime data available"))
-        .map(|rt| {
-            HttpResponse::Ok()
-                .content_type("application/x-protobuf")
-                .encoding(ContentEncoding::Identity)
-                .body(rt.data.clone())
-        })
+    use prost::Message;
+    let data = match (&rt_dataset_wrapper.gtfs_rt, alerts.is_empty()) {
+        // without published alerts, the GTFS-RT can be given as is
+        (Some(rt), true) => rt.data.clone(),
+        _ => {
+            let feed_message = get_feed_message(&rt_dataset_wrapper, &alerts, &clock)?;
+            let mut data = Vec::new();
+            feed_message.encode(&mut data).map_err(|e| {
+                error::ErrorInternalServerError(format!("impossible to encode protobuf: {}", e))
+            })?;
+            data
+        }
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-protobuf")
+        .encoding(ContentEncoding::Identity)
+        .body(data))
 }
 
 pub async fn gtfs_rt_json(
     rt_dataset_wrapper: RealTimeDatasetWrapper,
+    alerts: web::Data<Arc<AlertStore>>,
+    clock: web::Data<Clock>,
 ) -> actix_web::Result<web::Json<transit_realtime::FeedMessage>> {
-    use prost::Message;
-    rt_dataset_wrapper
-        .gtfs_rt
-        .as_ref()
-        .ok_or_else(|| error::ErrorNotFound("no realtime data available"))
-        .map(|rt| rt.data.clone())
-        .and_then(|d| {
-            transit_realtime::FeedMessage::decode(d.as_slice())
-                .map(web::Json)
-                .map_err(|e| {
-                    error::ErrorInternalServerError(format!(
-                        "impossible to decode protobuf message: {}",
-                        e
-                    ))
-                })
-        })
+    get_feed_message(&rt_dataset_wrapper, &alerts, &clock).map(web::Json)
 }
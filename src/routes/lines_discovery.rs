@@ -0,0 +1,119 @@
+use super::open_api::make_param;
+use super::stoppoints_discovery::bounding_box_matches;
+use crate::clock::Clock;
+use crate::extractors::{DatasetWrapper, ResponseFormat};
+use crate::search::normalize;
+use crate::siri_lite::lines_delivery::{AnnotatedLine, LinesDelivery};
+use crate::siri_lite::shared::CommonDelivery;
+use crate::siri_lite::{Siri, SiriResponse};
+use actix_web::{web, HttpResponse};
+use transit_model::collection::Idx;
+use transit_model::objects::{Line, StopPoint};
+
+fn default_limit() -> usize {
+    20
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Params {
+    /// text searched in the name or the code of the lines
+    q: Option<String>,
+    #[serde(rename = "BoundingBoxStructure.UpperLeft.Longitude")]
+    upper_left_longitude: Option<f64>,
+    #[serde(rename = "BoundingBoxStructure.UpperLeft.Latitude")]
+    upper_left_latitude: Option<f64>,
+    #[serde(rename = "BoundingBoxStructure.LowerRight.Longitude")]
+    lower_right_longitude: Option<f64>,
+    #[serde(rename = "BoundingBoxStructure.LowerRight.Latitude")]
+    lower_right_latitude: Option<f64>,
+    #[serde(default = "default_limit")]
+    limit: usize,
+    #[serde(default)]
+    offset: usize,
+}
+
+impl Params {
+    pub fn openapi_description(spec: &mut openapi::v3_0::Spec) -> Vec<openapi::v3_0::Parameter> {
+        vec![
+            make_param::<String>(spec, "q", false),
+            make_param::<f64>(spec, "BoundingBoxStructure.UpperLeft.Longitude", false),
+            make_param::<f64>(spec, "BoundingBoxStructure.UpperLeft.Latitude", false),
+            make_param::<f64>(spec, "BoundingBoxStructure.LowerRight.Longitude", false),
+            make_param::<f64>(spec, "BoundingBoxStructure.LowerRight.Latitude", false),
+            make_param::<usize>(spec, "limit", false),
+            make_param::<usize>(spec, "offset", false),
+        ]
+    }
+}
+
+/// the query is matched, like the stop points' search, without the case and the diacritics
+fn text_matches(line: &Line, q: &str) -> bool {
+    normalize(&line.name).contains(q)
+        || line
+            .code
+            .as_ref()
+            .map(|c| normalize(c).contains(q))
+            .unwrap_or(false)
+}
+
+pub fn filter(
+    data: &crate::datasets::Dataset,
+    request: Params,
+    now: chrono::DateTime<chrono::Utc>,
+) -> SiriResponse {
+    let model = &data.ntm;
+
+    let q = normalize(request.q.as_deref().unwrap_or_default());
+    let min_lon = request.upper_left_longitude.unwrap_or(-180.);
+    let max_lon = request.lower_right_longitude.unwrap_or(180.);
+    let min_lat = request.lower_right_latitude.unwrap_or(-90.);
+    let max_lat = request.upper_left_latitude.unwrap_or(90.);
+
+    // a line is in the bounding box if one of its stop points is in it
+    let in_bounding_box = |line_idx: Idx<Line>| {
+        model
+            .get_corresponding_from_idx::<_, StopPoint>(line_idx)
+            .into_iter()
+            .any(|sp_idx| {
+                bounding_box_matches(
+                    &model.stop_points[sp_idx].coord,
+                    min_lon,
+                    max_lon,
+                    min_lat,
+                    max_lat,
+                )
+            })
+    };
+
+    let filtered = model
+        .lines
+        .iter()
+        .filter(|(_, l)| text_matches(l, &q))
+        .filter(|(idx, _)| in_bounding_box(*idx))
+        .skip(request.offset)
+        .take(request.limit)
+        // only the returned lines are described, it can be costly
+        .map(|(idx, _)| AnnotatedLine::from(idx, &model))
+        .collect();
+
+    SiriResponse {
+        siri: Siri {
+            lines_delivery: Some(LinesDelivery {
+                common: CommonDelivery::new(now),
+                annotated_line_ref: filtered,
+            }),
+            ..Default::default()
+        },
+    }
+}
+
+pub async fn lines_discovery_query(
+    web::Query(query): web::Query<Params>,
+    dataset_wrapper: DatasetWrapper,
+    clock: web::Data<Clock>,
+    format: ResponseFormat,
+) -> actix_web::Result<HttpResponse> {
+    let dataset = dataset_wrapper.get_dataset()?;
+
+    filter(&dataset, query, clock.now()).into_http_response(format)
+}
@@ -0,0 +1,105 @@
+use crate::actors::{DatasetActor, GetBuildStatus, GetDataset};
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use futures::future::try_join_all;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Address of the DatasetActor of each dataset, by dataset id
+pub struct DatasetActorsById(pub BTreeMap<String, Addr<DatasetActor>>);
+
+/// State of a dataset, as given by the metrics
+struct DatasetState {
+    id: String,
+    available: bool,
+    stale: bool,
+    failed_reloads: u32,
+}
+
+async fn dataset_state(
+    id: &str,
+    actor: &Addr<DatasetActor>,
+) -> Result<DatasetState, actix::MailboxError> {
+    let build_status = actor.send(GetBuildStatus).await?;
+    let dataset = actor.send(GetDataset).await?;
+    Ok(DatasetState {
+        id: id.to_owned(),
+        available: dataset.is_ok(),
+        stale: build_status.reload_failure.is_some(),
+        failed_reloads: build_status.reload_failure.map_or(0, |f| f.failed_attempts),
+    })
+}
+
+/// escape a value of a label, as specified by the prometheus text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_gauge(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    states: &[DatasetState],
+    value: impl Fn(&DatasetState) -> u32,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for state in states {
+        let _ = writeln!(
+            out,
+            "{}{{dataset=\"{}\"}} {}",
+            name,
+            escape_label(&state.id),
+            value(state)
+        );
+    }
+}
+
+/// Metrics of the datasets, in the prometheus text format.
+///
+/// Unlike the status of a dataset, they are given for all the datasets at once,
+/// whether they are available or not
+pub async fn metrics_query(
+    datasets: web::Data<DatasetActorsById>,
+) -> actix_web::Result<HttpResponse> {
+    let states = try_join_all(
+        datasets
+            .0
+            .iter()
+            .map(|(id, actor)| dataset_state(id, actor)),
+    )
+    .await
+    .map_err(|e| {
+        log::error!("error while querying actor for data: {:?}", e);
+        actix_web::error::ErrorInternalServerError("impossible to get data".to_string())
+    })?;
+
+    let mut body = String::new();
+    write_gauge(
+        &mut body,
+        "transpo_rt_dataset_available",
+        "1 if the base schedule of the dataset is served, 0 if it is loading or has been dropped",
+        &states,
+        |s| u32::from(s.available),
+    );
+    write_gauge(
+        &mut body,
+        "transpo_rt_dataset_stale",
+        "1 if the last reloading of the dataset has failed",
+        &states,
+        |s| u32::from(s.stale),
+    );
+    write_gauge(
+        &mut body,
+        "transpo_rt_dataset_failed_reloads",
+        "number of failed reloadings since the dataset is stale",
+        &states,
+        |s| s.failed_reloads,
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
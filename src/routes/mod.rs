@@ -1,29 +1,54 @@
+mod alerts;
 mod api_entry_point;
+mod diagnostics;
 mod exposed_dataset;
 mod general_message;
 mod gtfs_rt;
+mod lines_discovery;
 mod links;
+mod metrics;
 pub(crate) mod open_api;
+mod overrides;
+mod realtime_stream;
 mod siri;
+mod stats;
 mod status;
 mod stop_monitoring;
 mod estimated_timetable;
 mod stoppoints_discovery;
+mod subscription;
+mod usage;
 
+pub use self::alerts::{
+    alerts_query, create_alert_query, delete_alert_query, update_alert_query,
+};
 pub use self::api_entry_point::entry_point;
+pub use self::diagnostics::diagnostics_query;
 pub use self::exposed_dataset::ExposedDataset;
 pub use self::general_message::general_message_query;
 pub use self::gtfs_rt::{gtfs_rt_json, gtfs_rt_protobuf};
+pub use self::lines_discovery::lines_discovery_query;
 pub use self::links::{Link, Links};
+pub use self::metrics::{metrics_query, DatasetActorsById};
 pub use self::open_api::documentation;
+pub use self::overrides::{create_override_query, delete_override_query, overrides_query};
+pub use self::realtime_stream::realtime_stream_query;
 pub use self::siri::siri_endpoint;
+pub use self::stats::stats_query;
 pub use self::status::status_query;
 pub use self::stop_monitoring::stop_monitoring_query;
 pub use self::estimated_timetable::estimated_timetable_query;
 pub use self::stoppoints_discovery::stoppoints_discovery_query;
+pub use self::subscription::subscription_query;
+pub use self::usage::usage_query;
+pub(crate) use self::estimated_timetable::subscription_estimated_timetable;
 
 // export the params/responses for the openapi module
 pub(crate) use self::general_message::Params as GeneralMessageParams;
+pub(crate) use self::lines_discovery::Params as LinesDiscoveryParams;
+pub(crate) use self::realtime_stream::Params as RealtimeStreamParams;
+pub(crate) use self::stats::Params as StatsParams;
+pub(crate) use self::stats::Stats;
 pub(crate) use self::status::Status;
 pub(crate) use self::stop_monitoring::Params as StopMonitoringParams;
 pub(crate) use self::estimated_timetable::Params as EstimatedTimetableParams;
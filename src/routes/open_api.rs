@@ -124,8 +124,12 @@ fn add_path_item_with_undefined_response(
     route: &str,
     description: &str,
     content_type: &str,
+    params: Vec<oa::Parameter>,
 ) {
-    let mut params = vec![];
+    let mut params: Vec<_> = params
+        .into_iter()
+        .map(oa::ObjectOrReference::Object)
+        .collect();
     add_dataset_param(spec, &mut params, route);
     let params = if params.is_empty() {
         None
@@ -168,15 +172,28 @@ fn create_schema() -> oa::Spec {
         "/spec",
         "openapi documentation",
         "application/json",
+        vec![],
     );
 
     add_route!(spec, "/{dataset}" => super::Status, description = "status of a dataset", params = vec![]);
+    add_route!(spec, "/{dataset}/diagnostics" => crate::diagnostics::DiagnosticsReport,
+                description = "quality of the matching of the realtime feeds with the base schedule",
+                params = vec![]);
+    add_route!(spec, "/{dataset}/stats" => super::Stats,
+                description = "punctuality statistics",
+                params = super::StatsParams::openapi_description(&mut spec));
+    add_route!(spec, "/{dataset}/alerts" => crate::alerts::Alert,
+                description = "alerts published through the api",
+                params = vec![], array = true);
     add_route!(spec, "/{dataset}/siri/2.0/stop-monitoring.json" => crate::siri_lite::SiriResponse,
                 description = "siri-lite stop monitoring",
                 params = super::StopMonitoringParams::openapi_description(&mut spec));
     add_route!(spec, "/{dataset}/siri/2.0/stoppoints-discovery.json" => crate::siri_lite::SiriResponse,
                 description = "siri-lite stop discovery",
                 params = super::StopPointsDiscoveryParams::openapi_description(&mut spec));
+    add_route!(spec, "/{dataset}/siri/2.0/lines-discovery.json" => crate::siri_lite::SiriResponse,
+                description = "siri-lite lines discovery",
+                params = super::LinesDiscoveryParams::openapi_description(&mut spec));
     add_route!(spec, "/{dataset}/siri/2.0/general-message.json" => crate::siri_lite::SiriResponse,
                 description = "siri-lite general message",
                 params = super::GeneralMessageParams::openapi_description(&mut spec));
@@ -187,12 +204,22 @@ fn create_schema() -> oa::Spec {
         "/{dataset}/gtfs-rt.json",
         "json of the gtfs-rt",
         "application/json",
+        vec![],
     );
     add_path_item_with_undefined_response(
         &mut spec,
         "/{dataset}/gtfs-rt",
         "raw gtfs-rt (protobuf)",
         "application/x-protobuf",
+        vec![],
+    );
+    let params = super::RealtimeStreamParams::openapi_description(&mut spec);
+    add_path_item_with_undefined_response(
+        &mut spec,
+        "/{dataset}/realtime-stream",
+        "server-sent events stream of the departures of some stop points, lines or trips",
+        "text/event-stream",
+        params,
     );
     spec
 }
@@ -0,0 +1,58 @@
+use crate::extractors::{Admin, DatasetWrapper};
+use crate::overrides::{Override, OverrideError, OverrideStore};
+use actix_web::error::BlockingError;
+use actix_web::{error, web, HttpResponse};
+use std::sync::Arc;
+
+fn override_error(e: BlockingError<OverrideError>) -> actix_web::Error {
+    match e {
+        BlockingError::Error(e @ OverrideError::UnknownOverride(_)) => {
+            error::ErrorNotFound(e.to_string())
+        }
+        e => {
+            log::error!("impossible to change the overrides: {}", e);
+            error::ErrorInternalServerError("impossible to store the override")
+        }
+    }
+}
+
+pub async fn overrides_query(
+    _admin: Admin,
+    store: web::Data<Arc<OverrideStore>>,
+) -> web::Json<Vec<Override>> {
+    web::Json(store.overrides())
+}
+
+pub async fn create_override_query(
+    _admin: Admin,
+    web::Json(o): web::Json<Override>,
+    dataset_wrapper: DatasetWrapper,
+    store: web::Data<Arc<OverrideStore>>,
+) -> actix_web::Result<HttpResponse> {
+    let dataset = dataset_wrapper.get_dataset()?;
+    let errors = o.check(&dataset.ntm);
+    if !errors.is_empty() {
+        return Err(error::ErrorBadRequest(format!(
+            "invalid override: {}",
+            errors.join(", ")
+        )));
+    }
+    let store = store.get_ref().clone();
+    // the overrides are written on disk, it is blocking
+    let o = web::block(move || store.create(o))
+        .await
+        .map_err(override_error)?;
+    Ok(HttpResponse::Created().json(o))
+}
+
+pub async fn delete_override_query(
+    _admin: Admin,
+    override_id: web::Path<String>,
+    store: web::Data<Arc<OverrideStore>>,
+) -> actix_web::Result<HttpResponse> {
+    let store = store.get_ref().clone();
+    web::block(move || store.delete(&override_id))
+        .await
+        .map_err(override_error)?;
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,433 @@
+use super::open_api::make_param;
+use crate::actors::{realtime_channel, DatasetActor, ListenRealtime};
+use crate::clock::Clock;
+use crate::datasets::{Connection, Dataset, RealTimeDataset};
+use crate::siri_lite;
+use crate::utils;
+use actix::Addr;
+use actix_web::{error, web, HttpResponse};
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use std::collections::{BTreeMap, BTreeSet};
+
+fn default_preview_interval() -> chrono::Duration {
+    chrono::Duration::hours(1)
+}
+
+/// the departures further than this are not sent, whatever the `PreviewInterval`
+fn max_preview_interval() -> chrono::Duration {
+    chrono::Duration::days(2)
+}
+
+fn default_heartbeat_interval() -> chrono::Duration {
+    chrono::Duration::seconds(30)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Params {
+    /// Comma separated list of stop_point's id to follow
+    monitoring_ref: Option<String>,
+    /// Comma separated list of line's id to follow
+    line_ref: Option<String>,
+    /// Comma separated list of trip's id to follow
+    dated_vehicle_journey_ref: Option<String>,
+    /// ISO 8601 duration of the period, after the current time, of the departures to send.
+    /// Default is 1 hour, bounded to 2 days
+    preview_interval: Option<utils::Duration>,
+    /// ISO 8601 duration between 2 heartbeat events.
+    /// Default is 30 seconds
+    heartbeat_interval: Option<utils::Duration>,
+}
+
+impl Params {
+    // TODO: generate this via derive macro
+    pub fn openapi_description(spec: &mut openapi::v3_0::Spec) -> Vec<openapi::v3_0::Parameter> {
+        vec![
+            make_param::<String>(spec, "MonitoringRef", false),
+            make_param::<String>(spec, "LineRef", false),
+            make_param::<String>(spec, "DatedVehicleJourneyRef", false),
+            make_param::<utils::Duration>(spec, "PreviewInterval", false),
+            make_param::<utils::Duration>(spec, "HeartbeatInterval", false),
+        ]
+    }
+}
+
+fn split_ids(ids: &Option<String>) -> BTreeSet<String> {
+    ids.as_ref()
+        .map(|ids| {
+            ids.split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The objects a client wants to follow.
+/// A departure is sent if it matches any of them.
+struct Subscription {
+    stop_points: BTreeSet<String>,
+    lines: BTreeSet<String>,
+    vehicle_journeys: BTreeSet<String>,
+    preview_interval: chrono::Duration,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+struct Departure {
+    stop_point_ref: String,
+    line_ref: Option<String>,
+    dated_vehicle_journey_ref: String,
+    data_frame_ref: String,
+    order: u32,
+    aimed_arrival_time: siri_lite::DateTime,
+    aimed_departure_time: siri_lite::DateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected_arrival_time: Option<siri_lite::DateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected_departure_time: Option<siri_lite::DateTime>,
+}
+
+/// identifies a departure between 2 realtime updates:
+/// (dated_vehicle_journey_ref, data_frame_ref, order)
+type DepartureKey = (String, String, u32);
+
+impl Departure {
+    fn key(&self) -> DepartureKey {
+        (
+            self.dated_vehicle_journey_ref.clone(),
+            self.data_frame_ref.clone(),
+            self.order,
+        )
+    }
+}
+
+/// Keep track of the departures sent to a client, to only send the ones that changed
+#[derive(Default)]
+struct DeparturesTracker {
+    sent: Option<BTreeMap<DepartureKey, Departure>>,
+}
+
+impl DeparturesTracker {
+    /// Give the event to send for the current departures, if any.
+    /// The first event is a `snapshot` with all the departures,
+    /// the following ones are `update` events with only the new or changed departures.
+    /// The departures that are no longer listed (because they are passed) do not trigger an event.
+    fn event(&mut self, departures: Vec<Departure>) -> Option<(&'static str, Vec<Departure>)> {
+        let event = match &self.sent {
+            None => Some(("snapshot", departures.clone())),
+            Some(sent) => {
+                let changed: Vec<Departure> = departures
+                    .iter()
+                    .filter(|d| sent.get(&d.key()) != Some(d))
+                    .cloned()
+                    .collect();
+                if changed.is_empty() {
+                    None
+                } else {
+                    Some(("update", changed))
+                }
+            }
+        };
+        self.sent = Some(departures.into_iter().map(|d| (d.key(), d)).collect());
+        event
+    }
+}
+
+fn get_line_ref<'a>(cnx: &Connection, model: &'a transit_model::Model) -> Option<&'a str> {
+    let vj = &model.vehicle_journeys[cnx.dated_vj.vj_idx];
+    model.routes.get(&vj.route_id).map(|r| r.line_id.as_str())
+}
+
+impl Subscription {
+    fn new(request: &Params) -> actix_web::Result<Self> {
+        let subscription = Self {
+            stop_points: split_ids(&request.monitoring_ref),
+            lines: split_ids(&request.line_ref),
+            vehicle_journeys: split_ids(&request.dated_vehicle_journey_ref),
+            preview_interval: request
+                .preview_interval
+                .as_ref()
+                .map(|d| std::cmp::min(**d, max_preview_interval()))
+                .unwrap_or_else(default_preview_interval),
+        };
+        if subscription.stop_points.is_empty()
+            && subscription.lines.is_empty()
+            && subscription.vehicle_journeys.is_empty()
+        {
+            return Err(error::ErrorBadRequest(
+                "at least one MonitoringRef, LineRef or DatedVehicleJourneyRef is needed",
+            ));
+        }
+        Ok(subscription)
+    }
+
+    fn check_ids(&self, data: &Dataset) -> actix_web::Result<()> {
+        if let Some(s) = self
+            .stop_points
+            .iter()
+            .find(|s| data.stop_points(s).is_none())
+        {
+            return Err(error::ErrorNotFound(format!(
+                "impossible to find stop: '{}'",
+                s
+            )));
+        }
+        if let Some(l) = self
+            .lines
+            .iter()
+            .find(|l| data.ntm.lines.get_idx(l).is_none())
+        {
+            return Err(error::ErrorNotFound(format!(
+                "impossible to find line: '{}'",
+                l
+            )));
+        }
+        if let Some(vj) = self
+            .vehicle_journeys
+            .iter()
+            .find(|vj| data.ntm.vehicle_journeys.get_idx(vj).is_none())
+        {
+            return Err(error::ErrorNotFound(format!(
+                "impossible to find trip: '{}'",
+                vj
+            )));
+        }
+        Ok(())
+    }
+
+    fn matches(&self, cnx: &Connection, model: &transit_model::Model) -> bool {
+        let stop_point = &model.stop_points[cnx.stop_point_idx];
+        // a stop area can be followed instead of its stop points
+        self.stop_points.contains(&stop_point.id)
+            || self.stop_points.contains(&stop_point.stop_area_id)
+            || self
+                .vehicle_journeys
+                .contains(&model.vehicle_journeys[cnx.dated_vj.vj_idx].id)
+            || get_line_ref(cnx, model).map_or(false, |l| self.lines.contains(l))
+    }
+
+    /// list the next departures of the followed objects
+    /// None is returned if the base schedule is not available
+    fn departures(
+        &self,
+        rt_dataset: &RealTimeDataset,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Option<Vec<Departure>> {
+        let data = rt_dataset.base_schedule_dataset.as_ref().as_ref().ok()?;
+        let model = &data.ntm;
+        let now = now.with_timezone(&data.timezone).naive_local();
+        let limit = now
+            .checked_add_signed(self.preview_interval)
+            .unwrap_or(chrono::NaiveDateTime::MAX);
+
+        Some(
+            data.timetable
+                .connections
+                .iter()
+                .enumerate()
+                .skip_while(|(_, c)| c.dep_time < now)
+                .take_while(|(_, c)| c.dep_time <= limit)
+                .filter(|(_, c)| self.matches(c, model))
+                .map(|(idx, c)| {
+                    let updated_connection =
+                        rt_dataset.updated_timetable.realtime_connections.get(&idx);
+                    Departure {
+                        stop_point_ref: model.stop_points[c.stop_point_idx].id.clone(),
+                        line_ref: get_line_ref(c, model).map(str::to_owned),
+                        dated_vehicle_journey_ref: model.vehicle_journeys[c.dated_vj.vj_idx]
+                            .id
+                            .clone(),
+                        data_frame_ref: c.dated_vj.date.to_string(),
+                        order: c.sequence,
+                        aimed_arrival_time: siri_lite::DateTime(c.arr_time),
+                        aimed_departure_time: siri_lite::DateTime(c.dep_time),
+                        expected_arrival_time: updated_connection
+                            .and_then(|u| u.arr_time)
+                            .map(siri_lite::DateTime),
+                        expected_departure_time: updated_connection
+                            .and_then(|u| u.dep_time)
+                            .map(siri_lite::DateTime),
+                    }
+                })
+                .collect(),
+        )
+    }
+}
+
+fn make_event(event_type: &str, data: &str) -> Bytes {
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event_type, data))
+}
+
+fn make_departures_event(
+    event_type: &str,
+    departures: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Bytes {
+    make_event(
+        event_type,
+        &format!(
+            r#"{{"ResponseTimestamp":"{}","Departures":{}}}"#,
+            now.to_rfc3339(),
+            departures
+        ),
+    )
+}
+
+/// Stream of the `heartbeat` events, one every `interval`
+fn heartbeats(interval: std::time::Duration, clock: Clock) -> impl futures::Stream<Item = Bytes> {
+    stream::unfold(clock, move |clock| async move {
+        actix_rt::time::delay_for(interval).await;
+        let event = make_event("heartbeat", &clock.now().to_rfc3339());
+        Some((event, clock))
+    })
+}
+
+/// Server-sent events stream of the departures of some stop points, lines or trips.
+///
+/// An initial `snapshot` event is sent on connection, then an `update` event with the
+/// new or changed departures each time the realtime data changes the followed departures.
+/// A `heartbeat` event is sent regularly to keep the connection alive.
+pub async fn realtime_stream_query(
+    web::Query(query): web::Query<Params>,
+    dataset_actor: web::Data<Addr<DatasetActor>>,
+    clock: web::Data<Clock>,
+) -> actix_web::Result<HttpResponse> {
+    let subscription = Subscription::new(&query)?;
+    let clock = clock.get_ref().clone();
+    let heartbeat_interval = query
+        .heartbeat_interval
+        .as_ref()
+        .map(|d| **d)
+        .unwrap_or_else(default_heartbeat_interval)
+        .to_std()
+        .map_err(|_| error::ErrorBadRequest("invalid HeartbeatInterval"))?;
+    if heartbeat_interval.as_secs() == 0 {
+        return Err(error::ErrorBadRequest(
+            "HeartbeatInterval must be at least 1 second",
+        ));
+    }
+
+    let (listener, receiver) = realtime_channel();
+    let current_rt_dataset = dataset_actor
+        .send(ListenRealtime(listener))
+        .await
+        .map_err(|e| {
+            log::error!("error while querying actor for data: {:?}", e);
+            error::ErrorInternalServerError("impossible to get data".to_string())
+        })?;
+    if let Ok(data) = current_rt_dataset.base_schedule_dataset.as_ref() {
+        subscription.check_ids(data)?;
+    }
+
+    let heartbeats = heartbeats(heartbeat_interval, clock.clone());
+    let departures_events = stream::once(futures::future::ready(current_rt_dataset))
+        .chain(receiver)
+        .scan(DeparturesTracker::default(), move |tracker, rt_dataset| {
+            let now = clock.now();
+            let event = subscription
+                .departures(&rt_dataset, now)
+                .and_then(|departures| tracker.event(departures))
+                .and_then(|(event_type, departures)| {
+                    serde_json::to_string(&departures)
+                        .ok()
+                        .map(|departures| make_departures_event(event_type, &departures, now))
+                });
+            futures::future::ready(Some(event))
+        })
+        .filter_map(futures::future::ready);
+
+    let events = stream::select(departures_events, heartbeats)
+        .map(Ok::<_, actix_web::Error>)
+        .boxed_local();
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(events))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::NaiveDateTime;
+
+    fn departure(vj: &str, order: u32, expected_departure: Option<&str>) -> Departure {
+        let dt = |t: &str| siri_lite::DateTime(t.parse::<NaiveDateTime>().unwrap());
+        Departure {
+            stop_point_ref: "SP".to_owned(),
+            line_ref: Some("L".to_owned()),
+            dated_vehicle_journey_ref: vj.to_owned(),
+            data_frame_ref: "2018-12-15".to_owned(),
+            order,
+            aimed_arrival_time: dt("2018-12-15T10:00:00"),
+            aimed_departure_time: dt("2018-12-15T10:00:00"),
+            expected_arrival_time: None,
+            expected_departure_time: expected_departure.map(dt),
+        }
+    }
+
+    #[test]
+    fn initial_snapshot_is_always_sent() {
+        let mut tracker = DeparturesTracker::default();
+        assert_eq!(tracker.event(vec![]), Some(("snapshot", vec![])));
+        // nothing changed, no update
+        assert_eq!(tracker.event(vec![]), None);
+
+        let mut tracker = DeparturesTracker::default();
+        let departures = vec![departure("vj1", 1, None), departure("vj2", 3, None)];
+        assert_eq!(
+            tracker.event(departures.clone()),
+            Some(("snapshot", departures))
+        );
+    }
+
+    #[test]
+    fn only_changed_departures_are_sent() {
+        let mut tracker = DeparturesTracker::default();
+        tracker.event(vec![departure("vj1", 1, None), departure("vj2", 3, None)]);
+
+        // the same departures, no update
+        assert_eq!(
+            tracker.event(vec![departure("vj1", 1, None), departure("vj2", 3, None)]),
+            None
+        );
+        // vj1 is passed, it does not trigger an update
+        assert_eq!(tracker.event(vec![departure("vj2", 3, None)]), None);
+        // vj2 is delayed and vj3 is a new departure
+        let delayed = departure("vj2", 3, Some("2018-12-15T10:05:00"));
+        let new = departure("vj3", 2, None);
+        assert_eq!(
+            tracker.event(vec![delayed.clone(), new.clone()]),
+            Some(("update", vec![delayed, new]))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn heartbeats_are_sent_regularly() {
+        let clock = Clock::simulated("2018-12-15T14:00:00Z".parse().unwrap());
+        let mut heartbeats = Box::pin(heartbeats(std::time::Duration::from_millis(1), clock));
+        for _ in 0..2 {
+            let event = heartbeats.next().await.unwrap();
+            assert_eq!(
+                event,
+                Bytes::from("event: heartbeat\ndata: 2018-12-15T14:00:00+00:00\n\n")
+            );
+        }
+    }
+
+    #[test]
+    fn preview_interval_is_bounded() {
+        let params = Params {
+            monitoring_ref: None,
+            line_ref: Some("L".to_owned()),
+            dated_vehicle_journey_ref: None,
+            preview_interval: Some(serde_json::from_str(r#""P99999999D""#).unwrap()),
+            heartbeat_interval: None,
+        };
+        let subscription = Subscription::new(&params).unwrap();
+        assert_eq!(subscription.preview_interval, max_preview_interval());
+    }
+}
@@ -14,8 +14,10 @@ pub async fn siri_endpoint(
         btreemap! {
             "stop-monitoring" => Link::from_scoped_url(&req, "stop_monitoring_query", dataset_id),
             "stoppoints-discovery" => Link::from_scoped_url(&req, "stoppoints_discovery_query", dataset_id),
+            "lines-discovery" => Link::from_scoped_url(&req, "lines_discovery_query", dataset_id),
             "general-message" => Link::from_scoped_url(&req, "general_message_query", dataset_id),
             "estimated-timetable" => Link::from_scoped_url(&req, "estimated_timetable_query", dataset_id),
+            "subscriptions" => Link::from_scoped_url(&req, "subscription_query", dataset_id),
         }
         .into(),
     ))
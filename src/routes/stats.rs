@@ -0,0 +1,137 @@
+use super::open_api::make_param;
+use crate::clock::Clock;
+use crate::extractors::RealTimeDatasetWrapper;
+use crate::stats::{GroupBy, GroupStats, PunctualityStats, StatsAggregator, StatsStore};
+use actix_web::{error, web};
+use chrono::NaiveDate;
+use openapi_schema::OpenapiSchema;
+
+/// default number of days of the statistics
+const DEFAULT_PERIOD_DAYS: i64 = 7;
+/// maximum number of days of the statistics
+const MAX_PERIOD_DAYS: i64 = 31;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Params {
+    /// first day of the statistics (included), formated as YYYY-MM-DD.
+    /// Default is 7 days before `To`
+    from: Option<NaiveDate>,
+    /// last day of the statistics (included), formated as YYYY-MM-DD.
+    /// Default is today
+    to: Option<NaiveDate>,
+    /// Comma separated list of the groups of the statistics: `line`, `stop` and/or `hour`.
+    /// Default is `line`
+    group_by: Option<String>,
+    /// only the statistics of this line
+    line_ref: Option<String>,
+    /// only the statistics of this stop point
+    monitoring_ref: Option<String>,
+}
+
+impl Params {
+    // TODO: generate this via derive macro
+    pub fn openapi_description(spec: &mut openapi::v3_0::Spec) -> Vec<openapi::v3_0::Parameter> {
+        vec![
+            make_param::<String>(spec, "From", false),
+            make_param::<String>(spec, "To", false),
+            make_param::<String>(spec, "GroupBy", false),
+            make_param::<String>(spec, "LineRef", false),
+            make_param::<String>(spec, "MonitoringRef", false),
+        ]
+    }
+}
+
+#[derive(Serialize, Debug, OpenapiSchema)]
+pub struct Stats {
+    /// first day of the statistics, formated as YYYY-MM-DD
+    from: String,
+    /// last day of the statistics, formated as YYYY-MM-DD
+    to: String,
+    /// statistics of all the connections
+    total: PunctualityStats,
+    groups: Vec<GroupStats>,
+}
+
+fn parse_group_by(group_by: &Option<String>) -> actix_web::Result<Vec<GroupBy>> {
+    match group_by {
+        None => Ok(vec![GroupBy::Line]),
+        Some(groups) => groups
+            .split(',')
+            .map(str::trim)
+            .filter(|g| !g.is_empty())
+            .map(|g| {
+                g.parse()
+                    .map_err(|e| error::ErrorBadRequest(format!("{}", e)))
+            })
+            .collect(),
+    }
+}
+
+pub async fn stats_query(
+    web::Query(query): web::Query<Params>,
+    store: web::Data<Option<StatsStore>>,
+    clock: web::Data<Clock>,
+    rt_dataset_wrapper: RealTimeDatasetWrapper,
+) -> actix_web::Result<web::Json<Stats>> {
+    let store = store
+        .get_ref()
+        .clone()
+        .ok_or_else(|| error::ErrorNotFound("the punctuality statistics are not enabled"))?;
+    // the observations are stored by day in the dataset's timezone
+    let to = query.to.unwrap_or_else(|| {
+        let now = clock.now();
+        match rt_dataset_wrapper.get_base_schedule_dataset() {
+            Ok(data) => now.with_timezone(&data.timezone).date().naive_local(),
+            Err(_) => now.with_timezone(&chrono::Local).date().naive_local(),
+        }
+    });
+    let from = query
+        .from
+        .unwrap_or_else(|| to - chrono::Duration::days(DEFAULT_PERIOD_DAYS));
+    if from > to {
+        return Err(error::ErrorBadRequest("'From' should be before 'To'"));
+    }
+    if to - from > chrono::Duration::days(MAX_PERIOD_DAYS) {
+        return Err(error::ErrorBadRequest(format!(
+            "the period of the statistics cannot be longer than {} days",
+            MAX_PERIOD_DAYS
+        )));
+    }
+    let group_by = parse_group_by(&query.group_by)?;
+
+    // reading the stored observations is blocking
+    // the observations are filtered and aggregated as they are read, not to keep them in memory
+    let line_ref = query.line_ref;
+    let monitoring_ref = query.monitoring_ref;
+    let aggregator = web::block(move || {
+        let mut aggregator = StatsAggregator::new(&group_by);
+        store
+            .for_each(from, to, |o| {
+                let is_selected = line_ref
+                    .as_ref()
+                    .map(|l| o.line_id.as_ref() == Some(l))
+                    .unwrap_or(true)
+                    && monitoring_ref
+                        .as_ref()
+                        .map(|s| &o.stop_id == s)
+                        .unwrap_or(true);
+                if is_selected {
+                    aggregator.add(&o);
+                }
+            })
+            .map(|()| aggregator)
+    })
+    .await
+    .map_err(|e| {
+        log::error!("impossible to read the punctuality statistics: {}", e);
+        error::ErrorInternalServerError("impossible to read the punctuality statistics")
+    })?;
+
+    Ok(web::Json(Stats {
+        from: from.format("%Y-%m-%d").to_string(),
+        to: to.format("%Y-%m-%d").to_string(),
+        total: aggregator.total(),
+        groups: aggregator.groups(),
+    }))
+}
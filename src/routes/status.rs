@@ -1,6 +1,10 @@
+use crate::actors::{BuildProgress, DatasetActor, GetBuildStatus};
+use crate::datasets::{DatasetInfo, ReloadFailure};
 use crate::extractors::DatasetWrapper;
 use crate::routes::{Link, Links};
-use actix_web::{web, HttpRequest};
+use actix::Addr;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
 use maplit::btreemap;
 use openapi_schema::OpenapiSchema;
 
@@ -8,22 +12,52 @@ use openapi_schema::OpenapiSchema;
 pub struct Status {
     #[serde(flatten)]
     dataset: super::ExposedDataset,
-    loaded_at: chrono::DateTime<chrono::Utc>,
+    /// loading datetime of the served dataset, not set if the dataset is not available
+    #[serde(skip_serializing_if = "Option::is_none")]
+    loaded_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// last day with some service in the dataset (formated as YYYY-MM-DD)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    validity_end: Option<String>,
+    /// true if the last reloading of the dataset has failed, the served dataset being the previous one
+    is_stale: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reload_failure: Option<ReloadFailure>,
+    /// progress of the building of the new version of the dataset, if it is being (re)loaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    build_progress: Option<BuildProgress>,
     #[serde(flatten)]
     pub links: Links,
 }
 
+/// Status of a dataset.
+///
+/// If the dataset is not available (it is loading, or it has been dropped), the status is
+/// still given, with the same http status as the other routes of the dataset
 pub async fn status_query(
     req: HttpRequest,
+    dataset_info: web::Data<DatasetInfo>,
+    dataset_actor: web::Data<Addr<DatasetActor>>,
     dataset_wrapper: DatasetWrapper,
-) -> actix_web::Result<web::Json<Status>> {
-    let dataset = dataset_wrapper.get_dataset()?;
+) -> actix_web::Result<HttpResponse> {
+    let build_status = dataset_actor.send(GetBuildStatus).await.map_err(|e| {
+        log::error!("error while querying actor for data: {:?}", e);
+        actix_web::error::ErrorInternalServerError("impossible to get data".to_string())
+    })?;
+    let dataset = dataset_wrapper.get_dataset();
 
-    let dataset_id = &dataset.feed_construction_info.dataset_info.id;
+    let dataset_id = &dataset_info.id;
 
-    Ok(web::Json(Status {
-        dataset: (&dataset.feed_construction_info.dataset_info).into(),
-        loaded_at: dataset.loaded_at,
+    let status = Status {
+        dataset: dataset_info.get_ref().into(),
+        loaded_at: dataset.as_ref().ok().map(|d| d.loaded_at),
+        validity_end: dataset
+            .as_ref()
+            .ok()
+            .and_then(|d| d.validity_end)
+            .map(|d| d.format("%Y-%m-%d").to_string()),
+        is_stale: build_status.reload_failure.is_some(),
+        reload_failure: build_status.reload_failure,
+        build_progress: build_status.build_progress,
         links: btreemap! {
             "gtfs-rt" => Link::from_scoped_url(&req, "gtfs_rt_protobuf", &dataset_id),
             "gtfs-rt.json" => Link::from_scoped_url(&req, "gtfs_rt_json", &dataset_id),
@@ -31,7 +65,24 @@ pub async fn status_query(
             "stoppoints-discovery" => Link::from_scoped_url(&req, "stoppoints_discovery_query", &dataset_id),
             "general-message" => Link::from_scoped_url(&req, "general_message_query", &dataset_id),
             "siri-lite" => Link::from_scoped_url(&req, "siri_endpoint", &dataset_id),
+            "realtime-stream" => Link::from_scoped_url(&req, "realtime_stream_query", &dataset_id),
+            "diagnostics" => Link::from_scoped_url(&req, "diagnostics_query", &dataset_id),
+            "stats" => Link::from_scoped_url(&req, "stats_query", &dataset_id),
+            "alerts" => Link::from_scoped_url(&req, "alerts_query", &dataset_id),
         }
         .into(),
-    }))
+    };
+    let body = serde_json::to_string(&status).map_err(|e| {
+        actix_web::error::ErrorInternalServerError(format!("impossible to serialize status: {}", e))
+    })?;
+    let mut response = match dataset {
+        Ok(_) => HttpResponse::Ok().finish(),
+        // the error response of the dataset (with its `Retry-After` header), but with the status
+        Err(e) => e.as_response_error().error_response(),
+    };
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    Ok(response.set_body(body.into()))
 }
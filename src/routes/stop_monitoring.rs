@@ -1,12 +1,13 @@
 use super::open_api::make_param;
+use crate::clock::Clock;
 use crate::datasets::{Connection, Dataset, RealTimeConnection, UpdatedTimetable};
-use crate::extractors::RealTimeDatasetWrapper;
-use crate::siri_lite::{self, service_delivery as model, SiriResponse};
+use crate::extractors::{RealTimeDatasetWrapper, ResponseFormat};
+use crate::siri_lite::{self, service_delivery as model, shared::CommonDelivery};
 use crate::utils;
-use actix_web::{error, web};
+use actix_web::{error, web, HttpResponse};
 use openapi_schema::OpenapiSchema;
-use transit_model::collection::Idx;
 use transit_model::objects::StopPoint;
+use transit_model::relations::IdxSet;
 
 #[derive(Debug, Deserialize, PartialEq, Eq, OpenapiSchema)]
 enum DataFreshness {
@@ -29,6 +30,7 @@ fn default_stop_visits() -> u8 {
 pub struct Params {
     _requestor_ref: Option<String>,
     /// Id of the stop_point on which we want the next departures
+    /// If it is the id of a stop area, the departures of all its stop points are given
     monitoring_ref: String,
     /// Filter the departures of the given line's id
     line_ref: Option<String>,
@@ -70,11 +72,8 @@ fn create_monitored_stop_visit(
     connection: &Connection,
     updated_connection: Option<&RealTimeConnection>,
 ) -> siri_lite::service_delivery::MonitoredStopVisit {
-    unimplemented!()
-    /*
     let stop = &data.ntm.stop_points[connection.stop_point_idx];
     let vj = &data.ntm.vehicle_journeys[connection.dated_vj.vj_idx];
-    let route = &data.ntm.routes.get(&vj.route_id);
     // we consider that the siri's operator in transmodel's company
     let operator_ref = data
         .ntm
@@ -82,9 +81,6 @@ fn create_monitored_stop_visit(
         .into_iter()
         .next()
         .map(|idx| data.ntm.companies[idx].id.clone());
-    let line_ref = route
-        .map(|r| r.line_id.clone())
-        .unwrap_or_else(|| "".to_owned());
     let update_time = updated_connection
         .map(|c| c.update_time)
         // if we have no realtime data, we consider the update time to be the time of the base schedule loading
@@ -104,19 +100,28 @@ fn create_monitored_stop_visit(
         expected_departure_time: updated_connection
             .and_then(|c| c.dep_time)
             .map(siri_lite::DateTime),
+        prediction_inaccurate: updated_connection
+            .filter(|c| c.estimated)
+            .map(|_| true),
     };
 
     model::MonitoredStopVisit {
         monitoring_ref: stop.id.clone(),
         monitored_vehicle_journey: model::MonitoredVehicleJourney {
+            line_ref: get_line_ref(connection, &data.ntm)
+                .unwrap_or_default()
+                .to_owned(),
+            framed_vehicle_journey_ref: model::FramedVehicleJourneyRef {
+                data_frame_ref: Some(connection.dated_vj.date.to_string()),
+                dated_vehicle_journey_ref: Some(vj.id.clone()),
+            },
             service_info: model::ServiceInfoGroup { operator_ref },
             journey_pattern_ref: None,
+            monitored_call: Some(call),
         },
         recorded_at_time: update_time,
         item_identifier: format!("{}:{}", &stop.id, &vj.id),
     }
-    */
-
 }
 
 fn get_line_ref<'a>(cnx: &Connection, model: &'a transit_model::Model) -> Option<&'a str> {
@@ -139,19 +144,18 @@ fn is_in_interval(
 }
 
 fn create_stop_monitoring(
-    stop_idx: Idx<StopPoint>,
+    stop_points: &IdxSet<StopPoint>,
     data: &Dataset,
     updated_timetable: &UpdatedTimetable,
     request: &Params,
+    now: chrono::DateTime<chrono::Utc>,
 ) -> Vec<model::StopMonitoringDelivery> {
-    unimplemented!()
-    /*
     // if we want to datetime in the query, we get the current_time (in the timezone of the dataset)
-    let requested_start_time = request.start_time.as_ref().map(|d| d.0).unwrap_or_else(|| {
-        chrono::Utc::now()
-            .with_timezone(&data.timezone)
-            .naive_local()
-    });
+    let requested_start_time = request
+        .start_time
+        .as_ref()
+        .map(|d| d.0)
+        .unwrap_or_else(|| now.with_timezone(&data.timezone).naive_local());
     let requested_line_ref = request.line_ref.as_deref();
     let stop_visit = data
         .timetable
@@ -159,7 +163,7 @@ fn create_stop_monitoring(
         .iter()
         .enumerate()
         .skip_while(|(_, c)| c.dep_time < requested_start_time)
-        .filter(|(_, c)| c.stop_point_idx == stop_idx)
+        .filter(|(_, c)| stop_points.contains(&c.stop_point_idx))
         // filter on requested lines
         .filter(|(_, c)| {
             requested_line_ref.is_none() || requested_line_ref == get_line_ref(&c, &data.ntm)
@@ -179,13 +183,11 @@ fn create_stop_monitoring(
         .collect();
 
     vec![model::StopMonitoringDelivery {
+        common: CommonDelivery::new(now),
         version: "2.0".to_owned(),
-        response_time_stamp: chrono::Local::now().to_rfc3339(),
-        request_message_ref: None,
         status: true,
         monitored_stop_visit: stop_visit,
     }]
-    */
 }
 
 fn validate_params(request: &mut Params) -> actix_web::Result<()> {
@@ -197,19 +199,17 @@ fn validate_params(request: &mut Params) -> actix_web::Result<()> {
 fn stop_monitoring(
     mut request: Params,
     rt_dataset_wrapper: RealTimeDatasetWrapper,
+    clock: &Clock,
 ) -> actix_web::Result<siri_lite::SiriResponse> {
-    unimplemented!()
-    /*
+    let now = clock.now();
     let data = rt_dataset_wrapper.get_base_schedule_dataset()?;
 
     let updated_timetable = &rt_dataset_wrapper.updated_timetable;
 
     validate_params(&mut request)?;
 
-    let stop_idx = data
-        .ntm
-        .stop_points
-        .get_idx(&request.monitoring_ref)
+    let stop_points = data
+        .stop_points(&request.monitoring_ref)
         .ok_or_else(|| {
             error::ErrorNotFound(format!(
                 "impossible to find stop: '{}'",
@@ -220,24 +220,27 @@ fn stop_monitoring(
     Ok(siri_lite::SiriResponse {
         siri: siri_lite::Siri {
             service_delivery: Some(model::ServiceDelivery {
+                response_timestamp: now.to_rfc3339(),
                 producer_ref: None, // TODO take the id of the dataset ?
                 stop_monitoring_delivery: create_stop_monitoring(
-                    stop_idx,
+                    &stop_points,
                     &data,
                     updated_timetable,
                     &request,
+                    now,
                 ),
                 ..Default::default()
             }),
             ..Default::default()
         },
     })
-     */
 }
 
 pub async fn stop_monitoring_query(
     web::Query(query): web::Query<Params>,
     rt_dataset_wrapper: RealTimeDatasetWrapper,
-) -> actix_web::Result<web::Json<SiriResponse>> {
-    Ok(web::Json(stop_monitoring(query, rt_dataset_wrapper)?))
+    clock: web::Data<Clock>,
+    format: ResponseFormat,
+) -> actix_web::Result<HttpResponse> {
+    stop_monitoring(query, rt_dataset_wrapper, &clock)?.into_http_response(format)
 }
@@ -1,16 +1,27 @@
 use super::open_api::make_param;
-use crate::extractors::DatasetWrapper;
+use crate::clock::Clock;
+use crate::extractors::{DatasetWrapper, ResponseFormat};
 use crate::siri_lite::shared::CommonDelivery;
-use crate::siri_lite::stop_points_delivery::{AnnotatedStopPoint, StopPointsDelivery};
+use crate::siri_lite::stop_points_delivery::{
+    AnnotatedStopArea, AnnotatedStopPoint, StopPointsDelivery,
+};
 use crate::siri_lite::{Siri, SiriResponse};
-use actix_web::web;
+use actix_web::{web, HttpResponse};
+use std::collections::HashSet;
+use transit_model::collection::Idx;
+use transit_model::objects::{Coord, StopArea, StopPoint};
 
 fn default_limit() -> usize {
     20
 }
 
+fn default_radius() -> f64 {
+    500.
+}
+
 #[derive(Deserialize, Clone)]
 pub struct Params {
+    /// text searched in the name, the id or the code of the stop points
     q: Option<String>,
     #[serde(rename = "BoundingBoxStructure.UpperLeft.Longitude")]
     upper_left_longitude: Option<f64>,
@@ -20,6 +31,18 @@ pub struct Params {
     lower_right_longitude: Option<f64>,
     #[serde(rename = "BoundingBoxStructure.LowerRight.Latitude")]
     lower_right_latitude: Option<f64>,
+    /// Latitude of the center of a proximity query
+    #[serde(rename = "Circle.Center.Latitude")]
+    center_latitude: Option<f64>,
+    /// Longitude of the center of a proximity query
+    #[serde(rename = "Circle.Center.Longitude")]
+    center_longitude: Option<f64>,
+    /// Radius (in meters) of a proximity query
+    #[serde(rename = "Circle.Radius", default = "default_radius")]
+    radius: f64,
+    /// If true, the stop areas of the stop points found are returned, with all their stop points
+    #[serde(default)]
+    stop_areas: bool,
     #[serde(default = "default_limit")]
     limit: usize,
     #[serde(default)]
@@ -34,13 +57,17 @@ impl Params {
             make_param::<f64>(spec, "BoundingBoxStructure.UpperLeft.Latitude", false),
             make_param::<f64>(spec, "BoundingBoxStructure.LowerRight.Longitude", false),
             make_param::<f64>(spec, "BoundingBoxStructure.LowerRight.Latitude", false),
+            make_param::<f64>(spec, "Circle.Center.Latitude", false),
+            make_param::<f64>(spec, "Circle.Center.Longitude", false),
+            make_param::<f64>(spec, "Circle.Radius", false),
+            make_param::<bool>(spec, "stop_areas", false),
             make_param::<usize>(spec, "limit", false),
             make_param::<usize>(spec, "offset", false),
         ]
     }
 }
 
-fn bounding_box_matches(
+pub(crate) fn bounding_box_matches(
     coord: &transit_model::objects::Coord,
     min_lon: f64,
     max_lon: f64,
@@ -50,41 +77,127 @@ fn bounding_box_matches(
     coord.lon >= min_lon && coord.lon <= max_lon && coord.lat >= min_lat && coord.lat <= max_lat
 }
 
-pub fn filter(data: &crate::datasets::Dataset, request: Params) -> SiriResponse {
+pub fn filter(
+    data: &crate::datasets::Dataset,
+    request: Params,
+    now: chrono::DateTime<chrono::Utc>,
+) -> SiriResponse {
     let model = &data.ntm;
 
-    let q = request.q.unwrap_or_default().to_lowercase();
+    let has_bounding_box = request.upper_left_longitude.is_some()
+        || request.upper_left_latitude.is_some()
+        || request.lower_right_longitude.is_some()
+        || request.lower_right_latitude.is_some();
     let min_lon = request.upper_left_longitude.unwrap_or(-180.);
     let max_lon = request.lower_right_longitude.unwrap_or(180.);
     let min_lat = request.lower_right_latitude.unwrap_or(-90.);
     let max_lat = request.upper_left_latitude.unwrap_or(90.);
+    let in_bounding_box = |idx: &Idx<StopPoint>| {
+        bounding_box_matches(
+            &model.stop_points[*idx].coord,
+            min_lon,
+            max_lon,
+            min_lat,
+            max_lat,
+        )
+    };
+
+    let searched: Option<Vec<Idx<StopPoint>>> = match request.q.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(q) => Some(data.stop_points_index.search(q)),
+    };
 
-    let filtered = model
-        .stop_points
-        .iter()
-        .filter(|(_, s)| s.name.to_lowercase().contains(&q))
-        .filter(|(_, s)| bounding_box_matches(&s.coord, min_lon, max_lon, min_lat, max_lat))
-        .map(|(id, _)| AnnotatedStopPoint::from(id, &model))
-        .skip(request.offset)
-        .take(request.limit)
-        .collect();
+    // the stop points with their distance to the center of the query
+    let candidates: Vec<(Idx<StopPoint>, Option<f64>)> =
+        match (request.center_longitude, request.center_latitude, searched) {
+            // proximity query, the stop points are sorted by distance
+            (Some(lon), Some(lat), searched) => {
+                let searched: Option<HashSet<_>> =
+                    searched.map(|s| s.into_iter().collect());
+                data.stop_points_grid
+                    .around(&Coord { lon, lat }, request.radius)
+                    .into_iter()
+                    .filter(|(idx, _)| searched.as_ref().map_or(true, |s| s.contains(idx)))
+                    .filter(|(idx, _)| in_bounding_box(idx))
+                    .map(|(idx, distance)| (idx, Some(distance)))
+                    .collect()
+            }
+            // text query, the stop points are sorted by relevance
+            (_, _, Some(searched)) => searched
+                .into_iter()
+                .filter(in_bounding_box)
+                .map(|idx| (idx, None))
+                .collect(),
+            _ if has_bounding_box => data
+                .stop_points_grid
+                .in_bounding_box(min_lon, max_lon, min_lat, max_lat)
+                .into_iter()
+                .map(|idx| (idx, None))
+                .collect(),
+            _ => model.stop_points.iter().map(|(idx, _)| (idx, None)).collect(),
+        };
+
+    let (annotated_stop_point, annotated_stop_area) = if request.stop_areas {
+        let stop_areas = group_by_stop_area(candidates, model)
+            .into_iter()
+            .skip(request.offset)
+            .take(request.limit)
+            .map(|(idx, distance)| AnnotatedStopArea {
+                distance,
+                ..AnnotatedStopArea::from(idx, &model)
+            })
+            .collect();
+        (vec![], stop_areas)
+    } else {
+        let stop_points = candidates
+            .into_iter()
+            .skip(request.offset)
+            .take(request.limit)
+            .map(|(idx, distance)| AnnotatedStopPoint {
+                distance,
+                ..AnnotatedStopPoint::from(idx, &model)
+            })
+            .collect();
+        (stop_points, vec![])
+    };
 
     SiriResponse {
         siri: Siri {
             stop_points_delivery: Some(StopPointsDelivery {
-                common: CommonDelivery::default(),
-                annotated_stop_point: filtered,
+                common: CommonDelivery::new(now),
+                annotated_stop_point,
+                annotated_stop_area,
             }),
             ..Default::default()
         },
     }
 }
 
+/// the stop areas of the stop points, in the order of their first stop point
+fn group_by_stop_area(
+    stop_points: Vec<(Idx<StopPoint>, Option<f64>)>,
+    model: &transit_model::Model,
+) -> Vec<(Idx<StopArea>, Option<f64>)> {
+    let mut seen = HashSet::new();
+    stop_points
+        .into_iter()
+        .filter_map(|(sp_idx, distance)| {
+            model
+                .stop_areas
+                .get_idx(&model.stop_points[sp_idx].stop_area_id)
+                .map(|sa_idx| (sa_idx, distance))
+        })
+        .filter(|(sa_idx, _)| seen.insert(*sa_idx))
+        .collect()
+}
+
 pub async fn stoppoints_discovery_query(
     web::Query(query): web::Query<Params>,
     dataset_wrapper: DatasetWrapper,
-) -> actix_web::Result<web::Json<SiriResponse>> {
+    clock: web::Data<Clock>,
+    format: ResponseFormat,
+) -> actix_web::Result<HttpResponse> {
     let dataset = dataset_wrapper.get_dataset()?;
 
-    Ok(web::Json(filter(&dataset, query)))
+    filter(&dataset, query, clock.now()).into_http_response(format)
 }
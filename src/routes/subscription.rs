@@ -0,0 +1,180 @@
+use crate::actors::{AddSubscription, Subscription, SubscriptionActor, TerminateSubscriptions};
+use crate::clock::Clock;
+use crate::datasets::Dataset;
+use crate::extractors::{ApiKey, DatasetWrapper};
+use crate::siri_lite::subscription::{
+    self as siri, EstimatedTimetableSubscriptionRequest, ResponseStatus, SiriRequest,
+    SubscriptionRequest, TerminateSubscriptionRequest,
+};
+use actix::Addr;
+use actix_web::{error, web, HttpResponse};
+
+fn check_subscription(
+    et_request: &EstimatedTimetableSubscriptionRequest,
+    data: &Dataset,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<(), String> {
+    if et_request.initial_termination_time < now {
+        return Err("InitialTerminationTime is in the past".to_owned());
+    }
+    if let Some(request) = &et_request.estimated_timetable_request {
+        if let Some(line_ref) = &request.line_ref {
+            data.ntm
+                .lines
+                .get_idx(line_ref)
+                .ok_or_else(|| format!("impossible to find line: '{}'", line_ref))?;
+        }
+        if let Some(monitoring_ref) = &request.monitoring_ref {
+            data.stop_points(monitoring_ref)
+                .ok_or_else(|| format!("impossible to find stop: '{}'", monitoring_ref))?;
+        }
+    }
+    Ok(())
+}
+
+async fn subscribe(
+    request: SubscriptionRequest,
+    api_key: &str,
+    subscription_actor: &Addr<SubscriptionActor>,
+    data: &Dataset,
+    now: chrono::DateTime<chrono::Utc>,
+) -> actix_web::Result<String> {
+    let heartbeat_interval = request
+        .subscription_context
+        .as_ref()
+        .and_then(|c| c.heartbeat_interval.as_ref())
+        .map(|d| **d);
+    let mut statuses = vec![];
+    for et_request in request.estimated_timetable_subscription_request {
+        let subscriber_ref = et_request
+            .subscriber_ref
+            .clone()
+            .unwrap_or_else(|| request.requestor_ref.clone());
+        let mut check = check_subscription(&et_request, data, now);
+        if check.is_ok() {
+            let filter = et_request
+                .estimated_timetable_request
+                .unwrap_or_default();
+            // the actor checks the limits of the subscriptions
+            check = subscription_actor
+                .send(AddSubscription(Subscription {
+                    api_key: api_key.to_owned(),
+                    subscriber_ref: subscriber_ref.clone(),
+                    subscription_ref: et_request.subscription_identifier.clone(),
+                    consumer_address: request.consumer_address.clone(),
+                    initial_termination_time: et_request
+                        .initial_termination_time
+                        .with_timezone(&chrono::Utc),
+                    heartbeat_interval,
+                    line_ref: filter.line_ref,
+                    monitoring_ref: filter.monitoring_ref,
+                    last_notification: now,
+                }))
+                .await
+                .map_err(|e| {
+                    log::error!("error while querying subscription actor: {:?}", e);
+                    error::ErrorInternalServerError("impossible to subscribe".to_string())
+                })?;
+        }
+        statuses.push(ResponseStatus {
+            response_timestamp: now.to_rfc3339(),
+            subscriber_ref,
+            subscription_ref: et_request.subscription_identifier,
+            status: check.is_ok(),
+            error_text: check.err(),
+        });
+    }
+    let dataset_id = &data.feed_construction_info.dataset_info.id;
+    Ok(siri::subscription_response_xml(dataset_id, &statuses, now))
+}
+
+async fn terminate(
+    request: TerminateSubscriptionRequest,
+    api_key: &str,
+    subscription_actor: &Addr<SubscriptionActor>,
+    data: &Dataset,
+    now: chrono::DateTime<chrono::Utc>,
+) -> actix_web::Result<String> {
+    let subscription_refs = if request.all.is_some() {
+        vec![]
+    } else {
+        request.subscription_ref.clone()
+    };
+    let terminated = subscription_actor
+        .send(TerminateSubscriptions {
+            api_key: api_key.to_owned(),
+            subscriber_ref: request.requestor_ref.clone(),
+            subscription_refs,
+        })
+        .await
+        .map_err(|e| {
+            log::error!("error while querying subscription actor: {:?}", e);
+            error::ErrorInternalServerError("impossible to terminate subscription".to_string())
+        })?;
+    let mut statuses: Vec<ResponseStatus> = terminated
+        .iter()
+        .map(|subscription_ref| ResponseStatus {
+            response_timestamp: now.to_rfc3339(),
+            subscriber_ref: request.requestor_ref.clone(),
+            subscription_ref: subscription_ref.clone(),
+            status: true,
+            error_text: None,
+        })
+        .collect();
+    // the unknown subscriptions are also reported
+    statuses.extend(
+        request
+            .subscription_ref
+            .iter()
+            .filter(|s| !terminated.contains(s))
+            .map(|subscription_ref| ResponseStatus {
+                response_timestamp: now.to_rfc3339(),
+                subscriber_ref: request.requestor_ref.clone(),
+                subscription_ref: subscription_ref.clone(),
+                status: false,
+                error_text: Some(format!(
+                    "impossible to find subscription '{}'",
+                    subscription_ref
+                )),
+            }),
+    );
+    let dataset_id = &data.feed_construction_info.dataset_info.id;
+    Ok(siri::terminate_subscription_response_xml(
+        dataset_id, &statuses, now,
+    ))
+}
+
+/// Api to manage the SIRI subscriptions.
+/// It handles `SubscriptionRequest` and `TerminateSubscriptionRequest`, in xml or json.
+/// The consumers are authenticated with an api key,
+/// a consumer can only terminate its own subscriptions
+pub async fn subscription_query(
+    body: String,
+    api_key: ApiKey,
+    subscription_actor: web::Data<Addr<SubscriptionActor>>,
+    dataset_wrapper: DatasetWrapper,
+    clock: web::Data<Clock>,
+) -> actix_web::Result<HttpResponse> {
+    let data = dataset_wrapper.get_dataset()?;
+    let now = clock.now();
+    let request = SiriRequest::from_body(&body).map_err(error::ErrorBadRequest)?;
+
+    let response = match request {
+        SiriRequest {
+            subscription_request: Some(r),
+            ..
+        } => subscribe(r, &api_key.0, &subscription_actor, data, now).await?,
+        SiriRequest {
+            terminate_subscription_request: Some(r),
+            ..
+        } => terminate(r, &api_key.0, &subscription_actor, data, now).await?,
+        _ => {
+            return Err(error::ErrorBadRequest(
+                "no SubscriptionRequest nor TerminateSubscriptionRequest found",
+            ))
+        }
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(response))
+}
@@ -0,0 +1,11 @@
+use crate::access_control::{AccessControl, KeyUsage};
+use crate::extractors::Admin;
+use actix_web::web;
+use std::sync::Arc;
+
+pub async fn usage_query(
+    _admin: Admin,
+    access_control: web::Data<Arc<AccessControl>>,
+) -> web::Json<Vec<KeyUsage>> {
+    web::Json(access_control.usage())
+}
@@ -0,0 +1,427 @@
+//! Reading of the base schedule data, in the different supported formats
+use crate::cache::{self, CachedSource, DataCache};
+use crate::datasets::{DataFormat, DatasetInfo, FeedInfo, IdMapping};
+use anyhow::{anyhow, Context, Error};
+use std::path::{Path, PathBuf};
+
+/// separator between the prefix of a merged feed and the original ids
+pub const PREFIX_SEPARATOR: &str = ":";
+
+/// id of an object of a merged feed
+pub fn prefixed_id(prefix: &str, id: &str) -> String {
+    format!("{}{}{}", prefix, PREFIX_SEPARATOR, id)
+}
+
+/// name of the files of a dataset (either a directory or a zip archive)
+fn file_names(path: &Path) -> Result<Vec<String>, Error> {
+    if path.is_dir() {
+        Ok(std::fs::read_dir(path)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect())
+    } else {
+        let file = std::fs::File::open(path)?;
+        let mut archive = zip::ZipArchive::new(file)?;
+        let mut names = vec![];
+        for i in 0..archive.len() {
+            let name = archive.by_index(i)?.name().to_owned();
+            // we only want the file name, the files can be in a sub directory of the archive
+            names.push(name.rsplit('/').next().unwrap_or_default().to_owned());
+        }
+        Ok(names)
+    }
+}
+
+/// Detect the format of a dataset from its files
+///
+/// A NTFS has a `contributors.txt` file, a GTFS has a `stops.txt` but no `contributors.txt`,
+/// and a NeTEx is made of xml files
+pub fn detect_format(path: &Path) -> Result<DataFormat, Error> {
+    let names = file_names(path)
+        .with_context(|| format!("impossible to read the files of {}", path.display()))?;
+    let has_file = |n: &str| names.iter().any(|name| name == n);
+    if has_file("contributors.txt") {
+        Ok(DataFormat::Ntfs)
+    } else if has_file("stops.txt") {
+        Ok(DataFormat::Gtfs)
+    } else if names.iter().any(|name| name.ends_with(".xml")) {
+        Ok(DataFormat::Netex)
+    } else {
+        Err(anyhow!(
+            "impossible to detect the format of {}, it is not a GTFS, a NTFS nor a NeTEx",
+            path.display()
+        ))
+    }
+}
+
+fn download(url: &str) -> Result<tempfile::NamedTempFile, Error> {
+    let mut response = reqwest::blocking::get(url)
+        .and_then(reqwest::blocking::Response::error_for_status)
+        .with_context(|| format!("impossible to download {}", url))?;
+    let mut file = tempfile::NamedTempFile::new()?;
+    response.copy_to(&mut file)?;
+    Ok(file)
+}
+
+/// the NTFS reader can only read a directory, so the archive is extracted in a temporary directory
+///
+/// The paths inside the archive are kept, and the returned path is the directory holding the data:
+/// the root of the archive, or its only sub directory if all the files are in it
+fn unzip(path: &Path) -> Result<(tempfile::TempDir, PathBuf), Error> {
+    let dir = tempfile::tempdir()?;
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(path)?)?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let out_path = dir.path().join(file.sanitized_name());
+        if file.name().ends_with('/') {
+            std::fs::create_dir_all(&out_path)?;
+            continue;
+        }
+        if let Some(parent) = out_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut out = std::fs::File::create(&out_path)?;
+        std::io::copy(&mut file, &mut out)?;
+    }
+    let entries = std::fs::read_dir(dir.path())?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    let data_dir = match entries.as_slice() {
+        [only_entry] if only_entry.is_dir() => only_entry.clone(),
+        _ => dir.path().to_owned(),
+    };
+    Ok((dir, data_dir))
+}
+
+fn read_local_model(
+    path: &Path,
+    format: DataFormat,
+    prefix: Option<&str>,
+) -> Result<transit_model::Model, Error> {
+    let prefix = prefix.map(str::to_owned);
+    match format {
+        DataFormat::Gtfs => {
+            if path.is_dir() {
+                transit_model::gtfs::read_from_path(path, None::<&str>, prefix)
+            } else {
+                transit_model::gtfs::read_from_zip(path, None::<&str>, prefix)
+            }
+        }
+        DataFormat::Ntfs => {
+            if prefix.is_some() {
+                return Err(anyhow!("the NTFS feeds cannot be merged with other feeds"));
+            }
+            if path.is_dir() {
+                transit_model::ntfs::read(path)
+            } else {
+                let (_dir, data_dir) = unzip(path)?;
+                transit_model::ntfs::read(data_dir)
+            }
+        }
+        DataFormat::Netex => transit_model::netex::read(path, None, prefix),
+    }
+    .map_err(|e| {
+        anyhow!(
+            "impossible to read {:?} {} because {}",
+            format,
+            path.display(),
+            e
+        )
+    })
+}
+
+/// A base schedule source, available locally
+enum LocalSource {
+    Path(PathBuf),
+    /// the remote data are downloaded in a temporary file, removed once the model is read
+    Downloaded(tempfile::NamedTempFile),
+    Cached(CachedSource),
+}
+
+impl LocalSource {
+    fn path(&self) -> &Path {
+        match self {
+            LocalSource::Path(path) => path,
+            LocalSource::Downloaded(file) => file.path(),
+            LocalSource::Cached(cached) => &cached.path,
+        }
+    }
+
+    /// identify the content of the source, to know if it has changed
+    fn version(&self) -> String {
+        match self {
+            LocalSource::Path(path) => {
+                // for the local sources, we consider the modification date
+                let modified = std::fs::metadata(path)
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|m| m.as_nanos())
+                    .unwrap_or_default();
+                cache::stable_hash(format!("{}:{}", path.display(), modified).as_bytes())
+            }
+            // the downloaded sources are not cached, so they are always considered as new
+            LocalSource::Downloaded(file) => {
+                cache::stable_hash(file.path().to_string_lossy().as_bytes())
+            }
+            LocalSource::Cached(cached) => cached.hash.clone(),
+        }
+    }
+}
+
+fn fetch_source(source: &str, cache: Option<&DataCache>) -> Result<LocalSource, Error> {
+    if !source.starts_with("http") {
+        return Ok(LocalSource::Path(PathBuf::from(source)));
+    }
+    match cache {
+        Some(cache) => cache.fetch(source).map(LocalSource::Cached),
+        None => download(source).map(LocalSource::Downloaded),
+    }
+}
+
+/// The base schedule sources of a dataset, available locally
+pub struct Sources {
+    /// the sources, in the same order as the dataset's feeds
+    sources: Vec<LocalSource>,
+    /// identify the sources' content and the dataset's configuration
+    pub version: String,
+}
+
+/// part of the configuration of a dataset used to build its base schedule
+#[derive(Serialize)]
+struct BaseScheduleConfiguration<'a> {
+    gtfs: &'a str,
+    format: &'a Option<DataFormat>,
+    /// prefix, path and format of the feeds
+    feeds: Vec<(&'a str, &'a str, &'a Option<DataFormat>)>,
+    id_mapping: &'a IdMapping,
+}
+
+/// identify the configuration of a dataset, a prepared model is only valid for a given configuration
+///
+/// Only the fields used to build the base schedule are considered, the realtime feeds
+/// or the recording can be changed without building the base schedule again
+pub fn configuration_version(dataset_info: &DatasetInfo) -> String {
+    let configuration = BaseScheduleConfiguration {
+        gtfs: &dataset_info.gtfs,
+        format: &dataset_info.format,
+        feeds: dataset_info
+            .feeds
+            .iter()
+            .map(|f| (f.prefix.as_str(), f.gtfs.as_str(), &f.format))
+            .collect(),
+        id_mapping: &dataset_info.id_mapping,
+    };
+    cache::stable_hash(
+        serde_json::to_string(&configuration)
+            .unwrap_or_default()
+            .as_bytes(),
+    )
+}
+
+/// Get the base schedule sources of a dataset, downloading the remote ones
+/// (or getting them from the cache if they have not changed)
+///
+/// The downloads are blocking, this must only be called from the `DatasetBuilder` pool
+/// (or outside of the actix system), never from an async actor
+pub fn fetch_sources(
+    dataset_info: &DatasetInfo,
+    cache: Option<&DataCache>,
+) -> Result<Sources, Error> {
+    let sources = if dataset_info.feeds.is_empty() {
+        vec![fetch_source(&dataset_info.gtfs, cache)?]
+    } else {
+        dataset_info
+            .feeds
+            .iter()
+            .map(|feed| fetch_source(&feed.gtfs, cache))
+            .collect::<Result<_, _>>()?
+    };
+    let versions: Vec<String> = sources.iter().map(LocalSource::version).collect();
+    let version = format!(
+        "{}:{}",
+        configuration_version(dataset_info),
+        versions.join(",")
+    );
+    Ok(Sources {
+        version: cache::stable_hash(version.as_bytes()),
+        sources,
+    })
+}
+
+/// Read a base schedule source.
+/// If a prefix is given, it is added to all the ids
+fn read_source(
+    name: &str,
+    source: &LocalSource,
+    format: Option<DataFormat>,
+    prefix: Option<&str>,
+) -> Result<transit_model::Model, Error> {
+    let path = source.path();
+    let format = match format {
+        Some(format) => format,
+        None => {
+            let format = detect_format(path)?;
+            log::info!("{} detected as {:?}", name, format);
+            format
+        }
+    };
+    read_local_model(path, format, prefix)
+}
+
+/// Merge several feeds in one model, each feed's ids being prefixed
+fn read_merged_feeds(
+    feeds: &[FeedInfo],
+    sources: &[LocalSource],
+) -> Result<transit_model::Model, Error> {
+    let mut collections = transit_model::model::Collections::default();
+    for (feed, source) in feeds.iter().zip(sources) {
+        log::info!("reading feed {}", &feed.prefix);
+        let model = read_source(&feed.gtfs, source, feed.format, Some(&feed.prefix))?;
+        collections
+            .merge(model.into_collections())
+            .map_err(|e| anyhow!("impossible to merge feed {}: {}", &feed.prefix, e))?;
+    }
+    transit_model::Model::new(collections)
+        .map_err(|e| anyhow!("impossible to create the merged model: {}", e))
+}
+
+/// Read the base schedule of a dataset from its fetched sources
+pub fn read_sources(
+    dataset_info: &DatasetInfo,
+    sources: &Sources,
+) -> Result<transit_model::Model, Error> {
+    if dataset_info.feeds.is_empty() {
+        read_source(
+            &dataset_info.gtfs,
+            &sources.sources[0],
+            dataset_info.format,
+            None,
+        )
+    } else {
+        read_merged_feeds(&dataset_info.feeds, &sources.sources)
+    }
+}
+
+/// Read the base schedule of a dataset
+pub fn read_model(dataset_info: &DatasetInfo) -> Result<transit_model::Model, Error> {
+    read_sources(dataset_info, &fetch_sources(dataset_info, None)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{configuration_version, detect_format};
+    use crate::datasets::{DataFormat, DatasetInfo, FeedInfo};
+
+    fn make_dir(files: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for f in files {
+            std::fs::File::create(dir.path().join(f)).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_format_detection() {
+        let gtfs = make_dir(&["agency.txt", "stops.txt", "stop_times.txt"]);
+        assert_eq!(detect_format(gtfs.path()).unwrap(), DataFormat::Gtfs);
+
+        let ntfs = make_dir(&["contributors.txt", "stops.txt", "stop_times.txt"]);
+        assert_eq!(detect_format(ntfs.path()).unwrap(), DataFormat::Ntfs);
+
+        let netex = make_dir(&["offer.xml", "stops.xml"]);
+        assert_eq!(detect_format(netex.path()).unwrap(), DataFormat::Netex);
+
+        let unknown = make_dir(&["readme.md"]);
+        assert!(detect_format(unknown.path()).is_err());
+
+        assert_eq!(
+            detect_format(std::path::Path::new("fixtures/gtfs.zip")).unwrap(),
+            DataFormat::Gtfs
+        );
+    }
+
+    #[test]
+    fn test_configuration_version() {
+        let dataset_info = DatasetInfo::new_default("fixtures/gtfs.zip", &[]);
+        let version = configuration_version(&dataset_info);
+        // the realtime configuration does not change the base schedule
+        let realtime_changed = DatasetInfo {
+            gtfs_rt_urls: vec!["http://example.com/gtfs-rt".to_owned()],
+            estimate_from_vehicle_positions: true,
+            ..dataset_info.clone()
+        };
+        assert_eq!(configuration_version(&realtime_changed), version);
+        let gtfs_changed = DatasetInfo {
+            gtfs: "fixtures/other_gtfs.zip".to_owned(),
+            ..dataset_info
+        };
+        assert_ne!(configuration_version(&gtfs_changed), version);
+    }
+
+    #[test]
+    fn test_read_gtfs_directory() {
+        let (_dir, data_dir) = super::unzip(std::path::Path::new("fixtures/gtfs.zip")).unwrap();
+        let dataset_info = DatasetInfo::new_default(data_dir.to_str().unwrap(), &[]);
+        let model = super::read_model(&dataset_info).unwrap();
+        let zipped_model =
+            super::read_model(&DatasetInfo::new_default("fixtures/gtfs.zip", &[])).unwrap();
+        assert!(!model.stop_points.is_empty());
+        assert_eq!(model.stop_points.len(), zipped_model.stop_points.len());
+    }
+
+    #[test]
+    fn test_unzip_keeps_the_paths() {
+        use std::io::Write;
+        let archive_dir = tempfile::tempdir().unwrap();
+        let archive_path = archive_dir.path().join("ntfs.zip");
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(&archive_path).unwrap());
+        for (name, content) in &[
+            ("ntfs/stops.txt", "stops"),
+            ("ntfs/old/stops.txt", "old stops"),
+        ] {
+            writer
+                .start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let (_dir, data_dir) = super::unzip(&archive_path).unwrap();
+        // all the files are in the `ntfs` directory, it holds the data
+        assert!(data_dir.ends_with("ntfs"));
+        // the files with the same name in different directories are both kept
+        let read = |path: &str| std::fs::read_to_string(data_dir.join(path)).unwrap();
+        assert_eq!(read("stops.txt"), "stops");
+        assert_eq!(read("old/stops.txt"), "old stops");
+    }
+
+    #[test]
+    fn test_read_merged_feeds() {
+        let feed = |prefix: &str| FeedInfo {
+            prefix: prefix.to_owned(),
+            gtfs: "fixtures/gtfs.zip".to_owned(),
+            format: None,
+            gtfs_rt_urls: vec![format!("http://{}/gtfs-rt", prefix)],
+        };
+        let dataset_info = DatasetInfo {
+            feeds: vec![feed("a"), feed("b")],
+            ..DatasetInfo::new_default("", &[])
+        };
+        let model = super::read_model(&dataset_info).unwrap();
+        let single_model =
+            super::read_model(&DatasetInfo::new_default("fixtures/gtfs.zip", &[])).unwrap();
+        assert_eq!(model.stop_points.len(), 2 * single_model.stop_points.len());
+        for (_, sp) in single_model.stop_points.iter() {
+            assert!(model.stop_points.get(&format!("a:{}", sp.id)).is_some());
+            assert!(model.stop_points.get(&format!("b:{}", sp.id)).is_some());
+        }
+
+        // each GTFS-RT is matched with the prefix of its feed
+        let realtime_feeds = dataset_info.realtime_feeds();
+        assert_eq!(realtime_feeds[0].0, "http://a/gtfs-rt");
+        assert_eq!(realtime_feeds[0].1.prefix.as_deref(), Some("a"));
+        assert_eq!(realtime_feeds[1].1.prefix.as_deref(), Some("b"));
+    }
+}
@@ -0,0 +1,247 @@
+//! In memory search index on the stop points of a dataset
+//!
+//! The names are normalized (unicode decomposition, diacritics removal, lowercase)
+//! and split into tokens. A query matches a stop point if each of its tokens
+//! matches a token of the stop point, either exactly, as a prefix or with a small typo.
+use std::collections::{BTreeMap, HashMap};
+use transit_model::collection::Idx;
+use transit_model::objects::{StopArea, StopPoint};
+use transit_model::Model;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+const EXACT_MATCH_SCORE: u32 = 100;
+const PREFIX_MATCH_SCORE: u32 = 60;
+const TYPO_MATCH_SCORE: u32 = 30;
+/// bonus given when the whole name starts with the query
+const NAME_PREFIX_BONUS: u32 = 20;
+/// score given to the stop points found by their id or one of their codes
+const CODE_MATCH_SCORE: u32 = 1000;
+
+/// Normalize a text: unicode decomposition, removal of the diacritics and lowercase
+pub fn normalize(s: &str) -> String {
+    s.nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Split a normalized text in tokens
+pub fn tokenize(s: &str) -> Vec<String> {
+    s.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Number of typos allowed for a query token, depending of its length
+fn max_typos(token: &str) -> usize {
+    match token.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+/// Levenshtein distance between 2 strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+struct Entry {
+    stop_point_idx: Idx<StopPoint>,
+    normalized_name: String,
+    stop_area_idx: Option<Idx<StopArea>>,
+}
+
+#[derive(Default)]
+pub struct StopPointsIndex {
+    entries: Vec<Entry>,
+    /// for each token, the entries having this token (sorted, without duplicates)
+    tokens: BTreeMap<String, Vec<usize>>,
+    /// stop points by normalized id and codes
+    codes: HashMap<String, Vec<Idx<StopPoint>>>,
+}
+
+impl StopPointsIndex {
+    pub fn new(model: &Model) -> Self {
+        let mut index = Self::default();
+        for (stop_point_idx, stop_point) in model.stop_points.iter() {
+            let entry_id = index.entries.len();
+            let stop_area = model.stop_areas.get(&stop_point.stop_area_id);
+            let normalized_name = normalize(&stop_point.name);
+
+            // the tokens of the stop area's name are also indexed,
+            // to be able to find a stop point by the name of its stop area
+            let mut tokens = tokenize(&normalized_name);
+            if let Some(stop_area) = stop_area {
+                tokens.extend(tokenize(&normalize(&stop_area.name)));
+            }
+            for token in tokens {
+                let entries = index.tokens.entry(token).or_insert_with(Vec::new);
+                if entries.last() != Some(&entry_id) {
+                    entries.push(entry_id);
+                }
+            }
+
+            let codes = std::iter::once(&stop_point.id)
+                .chain(stop_point.codes.iter().map(|(_, code)| code));
+            for code in codes {
+                let stop_points = index.codes.entry(normalize(code)).or_insert_with(Vec::new);
+                if !stop_points.contains(&stop_point_idx) {
+                    stop_points.push(stop_point_idx);
+                }
+            }
+
+            index.entries.push(Entry {
+                stop_point_idx,
+                normalized_name,
+                stop_area_idx: model.stop_areas.get_idx(&stop_point.stop_area_id),
+            });
+        }
+        index
+    }
+
+    /// best score of a query token for each entry
+    fn token_scores(&self, query_token: &str) -> HashMap<usize, u32> {
+        let mut scores = HashMap::new();
+        let mut add = |entries: &Vec<usize>, score: u32| {
+            for e in entries {
+                let s = scores.entry(*e).or_insert(0);
+                *s = std::cmp::max(*s, score);
+            }
+        };
+        for (token, entries) in self
+            .tokens
+            .range(query_token.to_owned()..)
+            .take_while(|(t, _)| t.starts_with(query_token))
+        {
+            let score = if token == query_token {
+                EXACT_MATCH_SCORE
+            } else {
+                PREFIX_MATCH_SCORE
+            };
+            add(entries, score);
+        }
+        let max_typos = max_typos(query_token);
+        if max_typos > 0 {
+            let query_len = query_token.chars().count();
+            for (token, entries) in &self.tokens {
+                let len = token.chars().count();
+                if len + max_typos < query_len || query_len + max_typos < len {
+                    continue;
+                }
+                if edit_distance(query_token, token) <= max_typos {
+                    add(entries, TYPO_MATCH_SCORE);
+                }
+            }
+        }
+        scores
+    }
+
+    /// Search the stop points matching a query, sorted by relevance.
+    ///
+    /// The stop points whose id or code is the query come first,
+    /// then the stop points whose name (or stop area's name) matches all the tokens of the query.
+    /// For the same relevance, the stop points of a same stop area are grouped.
+    pub fn search(&self, query: &str) -> Vec<Idx<StopPoint>> {
+        let normalized_query = normalize(query.trim());
+        let mut scores: HashMap<Idx<StopPoint>, u32> = HashMap::new();
+
+        if let Some(stop_points) = self.codes.get(&normalized_query) {
+            for sp in stop_points {
+                scores.insert(*sp, CODE_MATCH_SCORE);
+            }
+        }
+
+        let query_tokens = tokenize(&normalized_query);
+        let mut entries_scores: Option<HashMap<usize, u32>> = None;
+        for query_token in &query_tokens {
+            let token_scores = self.token_scores(query_token);
+            // all the tokens of the query must match
+            entries_scores = Some(match entries_scores {
+                None => token_scores,
+                Some(previous) => previous
+                    .into_iter()
+                    .filter_map(|(e, s)| token_scores.get(&e).map(|ts| (e, s + ts)))
+                    .collect(),
+            });
+        }
+        for (entry_id, mut score) in entries_scores.unwrap_or_default() {
+            let entry = &self.entries[entry_id];
+            if entry.normalized_name.starts_with(&normalized_query) {
+                score += NAME_PREFIX_BONUS;
+            }
+            let s = scores.entry(entry.stop_point_idx).or_insert(0);
+            *s = std::cmp::max(*s, score);
+        }
+
+        let stop_areas: HashMap<Idx<StopPoint>, Option<Idx<StopArea>>> = self
+            .entries
+            .iter()
+            .filter(|e| scores.contains_key(&e.stop_point_idx))
+            .map(|e| (e.stop_point_idx, e.stop_area_idx))
+            .collect();
+        let mut results: Vec<(Idx<StopPoint>, u32)> = scores.into_iter().collect();
+        results.sort_by(|(sp1, s1), (sp2, s2)| {
+            s2.cmp(s1)
+                .then_with(|| stop_areas.get(sp1).cmp(&stop_areas.get(sp2)))
+                .then_with(|| sp1.cmp(sp2))
+        });
+        results.into_iter().map(|(sp, _)| sp).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{edit_distance, normalize, tokenize, StopPointsIndex};
+    use transit_model_builder::ModelBuilder;
+
+    #[test]
+    fn test_normalization() {
+        assert_eq!(normalize("Gäre-Centrale ÉCOLE"), "gare-centrale ecole");
+        assert_eq!(
+            tokenize(&normalize("Gäre-Centrale ÉCOLE")),
+            vec!["gare", "centrale", "ecole"]
+        );
+        assert_eq!(edit_distance("gare", "gaare"), 1);
+        assert_eq!(edit_distance("centrale", "cnetrale"), 2);
+    }
+
+    #[test]
+    fn test_search() {
+        let model = ModelBuilder::default()
+            .vj("vj1", |vj_builder| {
+                vj_builder
+                    .st("Gäre-Centrale", "10:00:00", "10:01:00")
+                    .st("Mairie", "11:00:00", "11:01:00")
+                    .st("Garenne", "12:00:00", "12:01:00");
+            })
+            .build();
+        let index = StopPointsIndex::new(&model);
+        let names = |query: &str| -> Vec<String> {
+            index
+                .search(query)
+                .into_iter()
+                .map(|idx| model.stop_points[idx].id.clone())
+                .collect()
+        };
+
+        // the exact token match is ranked before the prefix match
+        assert_eq!(names("gare"), vec!["Gäre-Centrale", "Garenne"]);
+        assert_eq!(names("GARE centr"), vec!["Gäre-Centrale"]);
+        // small typo
+        assert_eq!(names("maorie"), vec!["Mairie"]);
+        assert_eq!(names("unknown"), Vec::<String>::new());
+    }
+}
@@ -1,105 +1,367 @@
-use crate::actors::{BaseScheduleReloader, DatasetActor, RealTimeReloader};
+use crate::access_control::AccessControl;
+use crate::actors::{
+    BaseScheduleReloader, BuildDataset, Cancellation, DatasetActor, DatasetBuilder, LoadedDataset,
+    RealTimeReloader, SubscriptionActor, SubscriptionsConfig, UpdateBaseSchedule,
+};
+use crate::alerts::AlertStore;
+use crate::cache::DataCache;
+use crate::clock::Clock;
 use crate::datasets;
 use crate::datasets::{Dataset, DatasetInfo, Datasets, Period};
+use crate::extractors::AdminToken;
+use crate::overrides::OverrideStore;
 use crate::routes::{
-    documentation, entry_point, general_message_query, gtfs_rt_json, gtfs_rt_protobuf,
-    siri_endpoint, status_query, stop_monitoring_query, estimated_timetable_query, stoppoints_discovery_query,
+    alerts_query, create_alert_query, create_override_query, delete_alert_query, delete_override_query, diagnostics_query, documentation, entry_point, general_message_query, gtfs_rt_json, gtfs_rt_protobuf, lines_discovery_query, metrics_query,
+    realtime_stream_query, siri_endpoint, stats_query, status_query, stop_monitoring_query, estimated_timetable_query, stoppoints_discovery_query,
+    overrides_query, subscription_query, update_alert_query, usage_query, DatasetActorsById,
 };
+use crate::recorder::Recorder;
+use crate::replay::Replay;
+use crate::stats::{PunctualityTracker, StatsStore};
 use actix::{Actor, Addr};
 use actix_web::web;
 use std::collections::BTreeMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 
-async fn create_dataset_actors_impl(
+/// Addresses of the actors serving a dataset
+#[derive(Clone)]
+pub struct DatasetActors {
+    pub dataset: Addr<DatasetActor>,
+    pub subscriptions: Addr<SubscriptionActor>,
+    /// used to cancel the building of the dataset and to stop its actors once it is removed
+    pub cancellation: Cancellation,
+    /// current time of the dataset, simulated if the GTFS-RT are replayed
+    pub clock: Clock,
+    pub replay: Option<Arc<Replay>>,
+    /// store of the punctuality statistics, if they are enabled
+    pub stats: Option<StatsStore>,
+    /// alerts published through the api
+    pub alerts: Arc<AlertStore>,
+    /// manual realtime overrides
+    pub overrides: Arc<OverrideStore>,
+}
+
+impl DatasetActors {
+    /// Cancel the building of the dataset, stop its reloadings and its subscriptions.
+    /// To be called when the dataset is removed
+    pub fn cancel(&self) {
+        self.cancellation.cancel();
+    }
+}
+
+/// How the datasets are loaded
+#[derive(Clone)]
+pub struct LoadingOptions {
+    /// maximum number of datasets loaded at the same time
+    pub parallelism: usize,
+    /// a dataset not loaded after this delay is considered as failed
+    pub timeout: std::time::Duration,
+    pub cache: Option<Arc<DataCache>>,
+}
+
+impl Default for LoadingOptions {
+    fn default() -> Self {
+        Self {
+            parallelism: 4,
+            timeout: std::time::Duration::from_secs(10 * 60),
+            cache: None,
+        }
+    }
+}
+
+/// Where the data of the datasets are stored
+#[derive(Clone, Debug, Default)]
+pub struct StorageOptions {
+    /// if given, the punctuality statistics of each dataset are stored in a sub directory
+    pub stats_dir: Option<PathBuf>,
+    /// if given, the alerts published on each dataset are stored in a `<dataset id>.json` file.
+    /// Else they are lost on restart
+    pub alerts_dir: Option<PathBuf>,
+    /// if given, the overrides of each dataset are stored in a `<dataset id>.json` file.
+    /// Else they are lost on restart
+    pub overrides_dir: Option<PathBuf>,
+}
+
+/// Create the actors of a dataset.
+/// The dataset is in a loading state until `load_dataset` is called
+fn create_dataset_actors(
+    dataset_info: &DatasetInfo,
+    subscriptions_config: &SubscriptionsConfig,
+    storage: &StorageOptions,
+) -> DatasetActors {
+    let logger = slog_scope::logger().new(slog::o!("instance" => dataset_info.id.clone()));
+    let loading: Arc<Result<Dataset, anyhow::Error>> =
+        Arc::new(Err(datasets::DatasetLoading.into()));
+    let rt_dataset =
+        datasets::RealTimeDataset::new(loading.clone(), &dataset_info.all_gtfs_rt_urls());
+    let dataset_actors_addr = DatasetActor::new(loading, Arc::new(rt_dataset)).start();
+    let replay = match Replay::new(&dataset_info.all_gtfs_rt_urls()) {
+        Ok(replay) => replay.map(Arc::new),
+        Err(e) => {
+            slog::error!(logger, "impossible to replay the gtfs-rt snapshots: {}", e);
+            None
+        }
+    };
+    let clock = replay
+        .as_ref()
+        .map(|r| r.clock().clone())
+        .unwrap_or_default();
+    let cancellation = Cancellation::default();
+    let subscription_actor = SubscriptionActor::new(
+        &dataset_info.id,
+        dataset_actors_addr.clone(),
+        clock.clone(),
+        subscriptions_config.clone(),
+        cancellation.clone(),
+        logger,
+    )
+    .start();
+
+    DatasetActors {
+        dataset: dataset_actors_addr,
+        subscriptions: subscription_actor,
+        cancellation,
+        clock,
+        replay,
+        stats: storage
+            .stats_dir
+            .as_ref()
+            .map(|dir| StatsStore::new(&dir.join(&dataset_info.id))),
+        alerts: Arc::new(AlertStore::new(
+            storage
+                .alerts_dir
+                .as_ref()
+                .map(|dir| dir.join(format!("{}.json", &dataset_info.id)))
+                .as_deref(),
+        )),
+        overrides: Arc::new(OverrideStore::new(
+            storage
+                .overrides_dir
+                .as_ref()
+                .map(|dir| dir.join(format!("{}.json", &dataset_info.id)))
+                .as_deref(),
+        )),
+    }
+}
+
+async fn load_dataset_impl(
     dataset_info: DatasetInfo,
+    actors: DatasetActors,
     generation_period: &Period,
+    options: &LoadingOptions,
+    builder: &Addr<DatasetBuilder>,
     logger: &slog::Logger,
-) -> (DatasetInfo, Result<Addr<DatasetActor>, anyhow::Error>) {
-    log::info!("creating actors");
-    let dataset = Dataset::try_from_dataset_info(dataset_info.clone(), &generation_period);
-
-    let arc_dataset = Arc::new(dataset);
-    let rt_dataset =
-        datasets::RealTimeDataset::new(arc_dataset.clone(), &dataset_info.gtfs_rt_urls);
-    let dataset_actors = DatasetActor {
-        gtfs: arc_dataset,
-        realtime: Arc::new(rt_dataset),
+) {
+    log::info!("loading dataset");
+    // when replaying some recorded GTFS-RT, the timetable is generated for the replayed period
+    let generation_period = &match &actors.replay {
+        Some(_) => Period {
+            begin: actors.clock.now().with_timezone(&chrono::Local).date().naive_local(),
+            horizon: generation_period.horizon,
+        },
+        None => generation_period.clone(),
     };
-    let dataset_actors_addr = dataset_actors.start();
-    let base_schedule_reloader = BaseScheduleReloader {
-        feed_construction_info: datasets::FeedConstructionInfo {
+    // the building is done in a pool of workers, not to block the server
+    // it has its own cancellation, to stop it on timeout without stopping the later reloadings
+    let building_cancellation = actors.cancellation.child();
+    let (started, building_started) = futures::channel::oneshot::channel();
+    let building = builder.send(BuildDataset {
+        dataset_info: dataset_info.clone(),
+        generation_period: generation_period.clone(),
+        cache: options.cache.clone(),
+        unchanged_version: None,
+        use_cached_dataset: true,
+        dataset_actor: actors.dataset.clone(),
+        cancellation: building_cancellation.clone(),
+        started: Some(started),
+        log: logger.clone(),
+    });
+    // the timeout only starts once a worker has picked up the building,
+    // the waiting in the queue depends on the other datasets
+    // (if the building is never started, the mailbox error is reported below)
+    let _ = building_started.await;
+    let dataset = match actix_rt::time::timeout(options.timeout, building).await {
+        Ok(Ok(Ok(Some(dataset)))) => Ok(dataset),
+        Ok(Ok(Ok(None))) => Err(anyhow::anyhow!("no dataset built")),
+        Ok(Ok(Err(e))) => Err(e),
+        Ok(Err(e)) => Err(anyhow::anyhow!("dataset builder mailbox error: {}", e)),
+        // Note: the current step of the building cannot be interrupted,
+        // but the building stops before the next one and its result is ignored
+        Err(_) => {
+            building_cancellation.cancel();
+            Err(anyhow::anyhow!(
+                "dataset not loaded after {}s",
+                options.timeout.as_secs()
+            ))
+        }
+    };
+    if let Err(e) = &dataset {
+        let msg = format!("impossible to load dataset {}: {}", &dataset_info.id, e);
+        sentry::capture_message(&msg, sentry::Level::Error);
+        log::error!("{}", &msg);
+    }
+    let loaded = dataset
+        .as_ref()
+        .ok()
+        .map(|d| LoadedDataset::new(d, generation_period.begin));
+    actors
+        .dataset
+        .do_send(UpdateBaseSchedule(Arc::new(dataset)));
+
+    let base_schedule_reloader = BaseScheduleReloader::new(
+        datasets::FeedConstructionInfo {
             dataset_info: dataset_info.clone(),
             generation_period: generation_period.clone(),
         },
-        dataset_actor: dataset_actors_addr.clone(),
-        log: logger.clone(),
-    };
+        actors.dataset.clone(),
+        logger.clone(),
+        options.cache.clone(),
+        loaded,
+        builder.clone(),
+        actors.cancellation.clone(),
+        actors.clock.clone(),
+    );
     base_schedule_reloader.start();
     let realtime_reloader = RealTimeReloader {
         dataset_id: dataset_info.id.clone(),
-        gtfs_rt_urls: dataset_info.gtfs_rt_urls.clone(),
-        dataset_actor: dataset_actors_addr.clone(),
+        gtfs_rt_urls: dataset_info.all_gtfs_rt_urls(),
+        clock: actors.clock.clone(),
+        replay: actors.replay.clone(),
+        // the replayed GTFS-RT are already recorded
+        recorder: match (&dataset_info.recording, &actors.replay) {
+            (Some(config), None) => Some(Arc::new(Recorder::new(config))),
+            _ => None,
+        },
+        punctuality: actors
+            .stats
+            .clone()
+            .map(|store| Arc::new(PunctualityTracker::new(store))),
+        overrides: actors.overrides.clone(),
+        alerts: actors.alerts.clone(),
+        cancellation: actors.cancellation.clone(),
+        dataset_actor: actors.dataset.clone(),
         log: logger.clone(),
     };
-    // we fetch a first time the gtfs_rt feeds, for them to be available on startup
-    realtime_reloader.update_realtime_data().await;
+    // we fetch a first time the gtfs_rt feeds, for them to be available as soon as the dataset is loaded
+    // (with the same timeout as the building, not to hold a loading slot on a slow feed)
+    let first_fetch = realtime_reloader.update_realtime_data();
+    if actix_rt::time::timeout(options.timeout, first_fetch)
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "realtime data not loaded after {}s, it will be fetched with the next update",
+            options.timeout.as_secs()
+        );
+    }
     realtime_reloader.start();
-
-    (dataset_info, Ok(dataset_actors_addr))
+    log::info!("dataset loaded");
 }
 
-async fn create_dataset_actors(
+async fn load_dataset(
     dataset_info: DatasetInfo,
+    actors: DatasetActors,
     generation_period: &Period,
-) -> (DatasetInfo, Result<Addr<DatasetActor>, anyhow::Error>) {
+    options: &LoadingOptions,
+    builder: &Addr<DatasetBuilder>,
+) {
     use slog_scope_futures::FutureExt;
     let logger = slog_scope::logger().new(slog::o!("instance" => dataset_info.id.clone()));
-    create_dataset_actors_impl(dataset_info, generation_period, &logger)
+    load_dataset_impl(dataset_info, actors, generation_period, options, builder, &logger)
         .with_logger(&logger)
         .await
 }
 
-pub async fn create_all_actors(
-    datasets: Datasets,
-    generation_period: &Period,
-) -> BTreeMap<DatasetInfo, Addr<DatasetActor>> {
-    let actors = datasets
+/// Create the actors of all the datasets, without loading the datasets.
+/// The server can be started right away, the datasets being in a loading state
+/// until `load_all_datasets` has loaded them
+pub fn create_all_actors(
+    datasets: &Datasets,
+    storage: &StorageOptions,
+) -> BTreeMap<DatasetInfo, DatasetActors> {
+    datasets
         .datasets
-        .into_iter()
-        .map(|d| create_dataset_actors(d, &generation_period));
+        .iter()
+        .map(|d| {
+            (
+                d.clone(),
+                create_dataset_actors(d, &datasets.subscriptions, storage),
+            )
+        })
+        .collect()
+}
 
-    async move {
-        futures::future::join_all(actors)
-            .await
-            .into_iter()
-            .filter_map(|(d, r)| match r {
-                Ok(a) => Some((d, a)),
-                Err(e) => {
-                    // the invalid datasets are filtered
-                    let msg = format!("impossible to create dataset {}: {}", &d.id, e);
-                    sentry::capture_message(&msg, sentry::Level::Error);
-                    log::error!("{}", &msg);
-                    None
-                }
-            })
-            .collect()
-    }
-    .await
+/// Load all the datasets, with at most `options.parallelism` datasets loaded at the same time
+///
+/// The datasets are built (then reloaded) in a pool of `options.parallelism` workers
+pub async fn load_all_datasets(
+    datasets_actors: BTreeMap<DatasetInfo, DatasetActors>,
+    generation_period: Period,
+    options: LoadingOptions,
+) {
+    use futures::StreamExt;
+    let builder = DatasetBuilder::start_pool(options.parallelism);
+    futures::stream::iter(datasets_actors)
+        .for_each_concurrent(options.parallelism, |(dataset_info, actors)| {
+            load_dataset(dataset_info, actors, &generation_period, &options, &builder)
+        })
+        .await;
+    log::info!("all datasets loaded");
 }
 
 fn register_dataset_routes(
     cfg: &mut web::ServiceConfig,
-    datasets_actors: &BTreeMap<DatasetInfo, Addr<DatasetActor>>,
+    datasets_actors: &BTreeMap<DatasetInfo, DatasetActors>,
 ) {
-    for (d, dataset_actor) in datasets_actors {
+    for (d, actors) in datasets_actors {
         cfg.service(
             web::scope(&format!("/{id}", id = &d.id))
-                .data(dataset_actor.clone())
+                .data(d.clone())
+                .data(actors.dataset.clone())
+                .data(actors.subscriptions.clone())
+                .data(actors.clock.clone())
+                .data(actors.stats.clone())
+                .data(actors.alerts.clone())
+                .data(actors.overrides.clone())
                 .service(
                     web::resource("/")
                         .name(&format!("{}/status_query", &d.id))
                         .route(web::get().to(status_query)),
                 )
+                .service(
+                    web::resource("/stats/")
+                        .name(&format!("{}/stats_query", &d.id))
+                        .route(web::get().to(stats_query)),
+                )
+                .service(
+                    web::resource("/alerts/")
+                        .name(&format!("{}/alerts_query", &d.id))
+                        .route(web::get().to(alerts_query))
+                        .route(web::post().to(create_alert_query)),
+                )
+                .service(
+                    web::resource("/alerts/{alert_id}/")
+                        .name(&format!("{}/alert_query", &d.id))
+                        .route(web::put().to(update_alert_query))
+                        .route(web::delete().to(delete_alert_query)),
+                )
+                .service(
+                    web::resource("/overrides/")
+                        .name(&format!("{}/overrides_query", &d.id))
+                        .route(web::get().to(overrides_query))
+                        .route(web::post().to(create_override_query)),
+                )
+                .service(
+                    web::resource("/overrides/{override_id}/")
+                        .name(&format!("{}/override_query", &d.id))
+                        .route(web::delete().to(delete_override_query)),
+                )
+                .service(
+                    web::resource("/diagnostics/")
+                        .name(&format!("{}/diagnostics_query", &d.id))
+                        .route(web::get().to(diagnostics_query)),
+                )
                 .service(
                     web::resource("/gtfs-rt/")
                         .name(&format!("{}/gtfs_rt_protobuf", &d.id))
@@ -110,6 +372,11 @@ fn register_dataset_routes(
                         .name(&format!("{}/gtfs_rt_json", &d.id))
                         .route(web::get().to(gtfs_rt_json)),
                 )
+                .service(
+                    web::resource("/realtime-stream/")
+                        .name(&format!("{}/realtime_stream_query", &d.id))
+                        .route(web::get().to(realtime_stream_query)),
+                )
                 .service(
                     web::resource("/siri/2.0/")
                         .name(&format!("{}/siri_endpoint", &d.id))
@@ -120,20 +387,55 @@ fn register_dataset_routes(
                         .name(&format!("{}/stoppoints_discovery_query", &d.id))
                         .route(web::get().to(stoppoints_discovery_query)),
                 )
+                .service(
+                    web::resource("/siri/2.0/stoppoints-discovery.xml/")
+                        .name(&format!("{}/stoppoints_discovery_query_xml", &d.id))
+                        .route(web::get().to(stoppoints_discovery_query)),
+                )
+                .service(
+                    web::resource("/siri/2.0/lines-discovery.json/")
+                        .name(&format!("{}/lines_discovery_query", &d.id))
+                        .route(web::get().to(lines_discovery_query)),
+                )
+                .service(
+                    web::resource("/siri/2.0/lines-discovery.xml/")
+                        .name(&format!("{}/lines_discovery_query_xml", &d.id))
+                        .route(web::get().to(lines_discovery_query)),
+                )
                 .service(
                     web::resource("/siri/2.0/stop-monitoring.json/")
                         .name(&format!("{}/stop_monitoring_query", &d.id))
                         .route(web::get().to(stop_monitoring_query)),
                 )
+                .service(
+                    web::resource("/siri/2.0/stop-monitoring.xml/")
+                        .name(&format!("{}/stop_monitoring_query_xml", &d.id))
+                        .route(web::get().to(stop_monitoring_query)),
+                )
                 .service(
                     web::resource("/siri/2.0/estimated-timetable.json/")
                         .name(&format!("{}/estimated_timetable_query", &d.id))
                         .route(web::post().to(estimated_timetable_query)),
                 )
+                .service(
+                    web::resource("/siri/2.0/estimated-timetable.xml/")
+                        .name(&format!("{}/estimated_timetable_query_xml", &d.id))
+                        .route(web::post().to(estimated_timetable_query)),
+                )
                 .service(
                     web::resource("/siri/2.0/general-message.json/")
                         .name(&format!("{}/general_message_query", &d.id))
                         .route(web::get().to(general_message_query)),
+                )
+                .service(
+                    web::resource("/siri/2.0/general-message.xml/")
+                        .name(&format!("{}/general_message_query_xml", &d.id))
+                        .route(web::get().to(general_message_query)),
+                )
+                .service(
+                    web::resource("/siri/2.0/subscriptions/")
+                        .name(&format!("{}/subscription_query", &d.id))
+                        .route(web::post().to(subscription_query)),
                 ),
         );
     }
@@ -141,13 +443,35 @@ fn register_dataset_routes(
 
 pub fn init_routes(
     cfg: &mut web::ServiceConfig,
-    datasets_actors: &BTreeMap<DatasetInfo, Addr<DatasetActor>>,
+    datasets_actors: &BTreeMap<DatasetInfo, DatasetActors>,
+    admin_token: &AdminToken,
+    access_control: &Arc<AccessControl>,
 ) {
     let datasets = Datasets {
         datasets: datasets_actors.keys().cloned().collect(),
+        ..Default::default()
     };
+    let dataset_actors = DatasetActorsById(
+        datasets_actors
+            .iter()
+            .map(|(d, a)| (d.id.clone(), a.dataset.clone()))
+            .collect(),
+    );
     cfg.data(datasets)
+        .data(dataset_actors)
+        .data(admin_token.clone())
+        .data(access_control.clone())
         .service(documentation)
-        .service(entry_point);
+        .service(entry_point)
+        .service(
+            web::resource("/usage/")
+                .name("usage_query")
+                .route(web::get().to(usage_query)),
+        )
+        .service(
+            web::resource("/metrics/")
+                .name("metrics_query")
+                .route(web::get().to(metrics_query)),
+        );
     register_dataset_routes(cfg, datasets_actors);
 }
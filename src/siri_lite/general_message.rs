@@ -93,6 +93,8 @@ pub struct InfoMessageCancellation {
 pub struct GeneralMessageDelivery {
     #[serde(flatten)]
     pub common: crate::siri_lite::shared::CommonDelivery,
+    #[serde(rename = "InfoMessage")]
     pub info_messages: Vec<InfoMessage>,
-    pub info_messages_cancellation: Vec<InfoMessage>,
+    #[serde(rename = "InfoMessageCancellation")]
+    pub info_messages_cancellation: Vec<InfoMessageCancellation>,
 }
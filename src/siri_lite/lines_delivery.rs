@@ -0,0 +1,107 @@
+use crate::siri_lite::shared::format_colour;
+use crate::siri_lite::stop_points_delivery::Location;
+use openapi_schema::OpenapiSchema;
+use transit_model::collection::Idx;
+use transit_model::objects::{Company, Line, PhysicalMode, Route, StopPoint};
+use transit_model::Model;
+
+#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct Destination {
+    /// Id of the stop area at the end of the line
+    pub destination_ref: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub place_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct ServedStopPoint {
+    pub stop_point_ref: String,
+    pub stop_name: String,
+    pub location: Location,
+}
+
+#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct AnnotatedLine {
+    /// Id of the line, to be used as `LineRef` in the other apis
+    pub line_ref: String,
+    pub line_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_code: Option<String>,
+    /// hexadecimal colour of the line (like `FF0000`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_colour: Option<String>,
+    /// Physical modes of the line's vehicle journeys
+    pub mode: Vec<String>,
+    /// Id of the operators of the line's vehicle journeys
+    pub operator_ref: Vec<String>,
+    pub destination: Vec<Destination>,
+    pub stop_point: Vec<ServedStopPoint>,
+}
+
+#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct LinesDelivery {
+    #[serde(flatten)]
+    pub common: crate::siri_lite::shared::CommonDelivery,
+    pub annotated_line_ref: Vec<AnnotatedLine>,
+}
+
+impl AnnotatedLine {
+    pub fn from(line_idx: Idx<Line>, model: &Model) -> Self {
+        let line = &model.lines[line_idx];
+
+        let mode = model
+            .get_corresponding_from_idx::<_, PhysicalMode>(line_idx)
+            .into_iter()
+            .map(|idx| model.physical_modes[idx].name.clone())
+            .collect();
+        let operator_ref = model
+            .get_corresponding_from_idx::<_, Company>(line_idx)
+            .into_iter()
+            .map(|idx| model.companies[idx].id.clone())
+            .collect();
+
+        let mut destination: Vec<Destination> = vec![];
+        for route_idx in model.get_corresponding_from_idx::<_, Route>(line_idx) {
+            if let Some(destination_id) = &model.routes[route_idx].destination_id {
+                if destination.iter().any(|d| &d.destination_ref == destination_id) {
+                    continue;
+                }
+                destination.push(Destination {
+                    destination_ref: destination_id.clone(),
+                    place_name: model.stop_areas.get(destination_id).map(|sa| sa.name.clone()),
+                });
+            }
+        }
+
+        let stop_point = model
+            .get_corresponding_from_idx::<_, StopPoint>(line_idx)
+            .into_iter()
+            .map(|idx| {
+                let sp = &model.stop_points[idx];
+                ServedStopPoint {
+                    stop_point_ref: sp.id.clone(),
+                    stop_name: sp.name.clone(),
+                    location: Location {
+                        longitude: sp.coord.lon,
+                        latitude: sp.coord.lat,
+                    },
+                }
+            })
+            .collect();
+
+        Self {
+            line_ref: line.id.clone(),
+            line_name: line.name.clone(),
+            line_code: line.code.clone(),
+            line_colour: line.color.as_ref().map(format_colour),
+            mode,
+            operator_ref,
+            destination,
+            stop_point,
+        }
+    }
+}
@@ -1,8 +1,14 @@
 pub mod general_message;
+pub mod lines_delivery;
 pub mod service_delivery;
 pub mod shared;
 pub mod stop_points_delivery;
+pub mod subscription;
+pub mod xml;
 
+use crate::extractors::ResponseFormat;
+use actix_web::HttpResponse;
+use lines_delivery::LinesDelivery;
 use openapi_schema::OpenapiSchema;
 use service_delivery::ServiceDelivery;
 use stop_points_delivery::StopPointsDelivery;
@@ -15,6 +21,8 @@ pub struct Siri {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stop_points_delivery: Option<StopPointsDelivery>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub lines_delivery: Option<LinesDelivery>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub service_delivery: Option<ServiceDelivery>,
 }
 
@@ -23,3 +31,21 @@ pub struct Siri {
 pub struct SiriResponse {
     pub siri: Siri,
 }
+
+impl SiriResponse {
+    /// create the http response in the format asked by the client
+    pub fn into_http_response(self, format: ResponseFormat) -> actix_web::Result<HttpResponse> {
+        match format {
+            ResponseFormat::Json => Ok(HttpResponse::Ok().json(self)),
+            ResponseFormat::Xml => {
+                let xml = xml::siri_to_xml(&self.siri).map_err(|e| {
+                    actix_web::error::ErrorInternalServerError(format!(
+                        "impossible to serialize siri response: {}",
+                        e
+                    ))
+                })?;
+                Ok(HttpResponse::Ok().content_type(xml::CONTENT_TYPE).body(xml))
+            }
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use crate::siri_lite::general_message::GeneralMessageDelivery;
+use crate::siri_lite::shared::CommonDelivery;
 use crate::siri_lite::DateTime;
-use serde::{Serialize, Deserialize};
 use openapi_schema::OpenapiSchema;
 
 #[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
@@ -19,113 +19,40 @@ pub enum ArrivalStatus {
 #[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
 #[serde(rename_all = "PascalCase")]
 pub struct MonitoredCall {
-    #[serde(flatten)]
-    pub Order: OrderWrapper,
-    
-    #[serde(flatten)]
-    pub StopPointName: StopPointNameWrapper,
-    
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub VehicleAtStop: Option<VehicleAtStopWrapper>,
-    
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub DestinationDisplay: Option<DestinationDisplayWrapper>,
-    
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub AimedArrivalTime: Option<AimedArrivalTimeWrapper>,
-    
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub AimedDepartureTime: Option<AimedDepartureTimeWrapper>,
-    
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub ExpectedArrivalTime: Option<ExpectedArrivalTimeWrapper>,
-    
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub ExpectedDepartureTime: Option<ExpectedDepartureTimeWrapper>,
-    
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub ArrivalStatus: Option<ArrivalStatusWrapper>,
-}
-
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
-#[serde(rename_all = "PascalCase")]
-pub struct EstimatedVehicleJourney {
-    #[serde(flatten)]
-    pub LineRef: LineRefWrapper,
-    
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub DirectionRef: Option<DirectionRefWrapper>,
-    
+    pub order: u16,
+    pub stop_point_name: String,
+    /// true if the vehicle is at the stop
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub JourneyPatternRef: Option<JourneyPatternRefWrapper>,
-    
+    pub vehicle_at_stop: Option<bool>,
+    /// true if the realtime times are estimated from the position of the vehicle
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub PublishedLineName: Option<PublishedLineNameWrapper>,
-
-    pub FramedVehicleJourneyRef: FramedVehicleJourneyRef,
-    
-    #[serde(flatten)]
-    pub OperatorRef: ServiceInfoGroup,
-    
+    pub prediction_inaccurate: Option<bool>,
+    /// name of the destination of the vehicle
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub VehicleRef: Option<VehicleRefWrapper>,
-    
-    pub EstimatedCalls: EstimatedCall,
-}
-
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
-#[serde(rename_all = "PascalCase")]
-pub struct EstimatedCall {
-    #[serde(flatten)]
-    pub StopPointRef: StopPointRefWrapper,
-    
+    pub destination_display: Option<String>,
+    /// Scheduled arrival time
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub VisitNumber: Option<VisitNumberWrapper>,
-    
-    #[serde(flatten)]
-    pub Order: OrderWrapper,
-    
-    #[serde(flatten)]
-    pub StopPointName: StopPointNameWrapper,
-    
+    pub aimed_arrival_time: Option<DateTime>,
+    /// Realtime arrival time
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub AimedArrivalTime: Option<AimedArrivalTimeWrapper>,
-
+    pub expected_arrival_time: Option<DateTime>,
+    /// Status of the arrival
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub ExpectedArrivalTime: Option<ExpectedArrivalTimeWrapper>,
-    
+    pub arrival_status: Option<ArrivalStatus>,
+    /// Scheduled departure time
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub AimedDepartureTime: Option<AimedDepartureTimeWrapper>,
-    
+    pub aimed_departure_time: Option<DateTime>,
+    /// Realtime departure time
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub ExpectedDepartureTime: Option<ExpectedDepartureTimeWrapper>,
+    pub expected_departure_time: Option<DateTime>,
 }
 
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
+#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
 #[serde(rename_all = "PascalCase")]
 pub struct ServiceInfoGroup {
     /// Id of the operator
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub OperatorRef: Option<OperatorRefWrapper>,
+    pub operator_ref: Option<String>,
     /* TODO find the right documentation for the type of this
     /// Specific features available in the vehicle
     #[serde(skip_serializing_if = "Vec::is_empty")]
@@ -135,278 +62,145 @@ pub struct ServiceInfoGroup {
 
 #[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
 #[serde(rename_all = "PascalCase")]
-pub struct MonitoredVehicleJourney {
-    #[serde(flatten)]
-    pub ServiceInfo: ServiceInfoGroup,
-    
+pub struct FramedVehicleJourneyRef {
+    /// Date of the vehicle journey
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub JourneyPatternRef: Option<JourneyPatternRefWrapper>,
+    pub data_frame_ref: Option<String>,
+    /// Id of the vehicle journey
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dated_vehicle_journey_ref: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
 #[serde(rename_all = "PascalCase")]
-pub struct MonitoredStopVisit {
-    #[serde(flatten)]
-    pub MonitoringRef: MonitoringRefWrapper,
-    
-    #[serde(flatten)]
-    pub RecordedAtTime: RecordedAtTimeWrapper,
-    
+pub struct MonitoredVehicleJourney {
+    /// Id of the line
+    pub line_ref: String,
+    pub framed_vehicle_journey_ref: FramedVehicleJourneyRef,
+    /// Id of the journey pattern
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub journey_pattern_ref: Option<String>,
     #[serde(flatten)]
-    pub ItemIdentifier: ItemIdentifierWrapper,
-    
-    pub MonitoredVehicleJourney: MonitoredVehicleJourney,
+    pub service_info: ServiceInfoGroup,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub monitored_call: Option<MonitoredCall>,
 }
 
 #[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
 #[serde(rename_all = "PascalCase")]
-pub struct EstimatedTimetableVisit {
+pub struct MonitoredStopVisit {
+    /// Datetime of the information update
+    pub recorded_at_time: chrono::DateTime<chrono::Utc>,
     /// Id of the couple Stop / VehicleJourney
-    //pub item_identifier: String,
-    pub MonitoredVehicleJourney: EstimatedVehicleJourney,
+    pub item_identifier: String,
+    /// Id of the stop point
+    pub monitoring_ref: String,
+    pub monitored_vehicle_journey: MonitoredVehicleJourney,
 }
 
 #[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
 #[serde(rename_all = "PascalCase")]
 pub struct StopMonitoringDelivery {
     #[serde(flatten)]
-    pub Version: VersionWrapper,
-    
-    #[serde(flatten)]
-    pub ResponseTimestamp: ResponseTimeStampWrapper,
-    
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub RequestMessageRef: Option<RequestMessageRefWrapper>,
-    
-    #[serde(flatten)]
-    pub Status: StatusWrapper,
-    
-    pub MonitoredStopVisit: Vec<MonitoredStopVisit>,
+    pub common: CommonDelivery,
+    /// Version of the siri's response
+    pub version: String,
+    /// Status of the response, true if the response has been correctly treated, false otherwise
+    pub status: bool,
+    pub monitored_stop_visit: Vec<MonitoredStopVisit>,
 }
 
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
+#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
 #[serde(rename_all = "PascalCase")]
-pub struct EstimatedTimetableDelivery {
-     #[serde(flatten)]
-    pub ResponseTimestamp: ResponseTimeStampWrapper,
-    
+pub struct EstimatedCall {
+    /// Id of the stop point
+    pub stop_point_ref: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub RequestMessageRef: Option<RequestMessageRefWrapper>, // Note: this is mandatory for idf profil
-
-    #[serde(flatten)]
-    pub SubscriberRef: SubscriberRefWrapper,
-
-    #[serde(flatten)]
-    pub SubscriptionRef: SubscriptionRefWrapper,
-
-    pub EstimatedJourneyVersionFrame: EstimatedJourneyVersionFrame,
+    pub visit_number: Option<u16>,
+    pub order: u16,
+    pub stop_point_name: String,
+    /// true if the realtime times are estimated from the position of the vehicle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prediction_inaccurate: Option<bool>,
+    /// Scheduled arrival time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aimed_arrival_time: Option<DateTime>,
+    /// Realtime arrival time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_arrival_time: Option<DateTime>,
+    /// Scheduled departure time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aimed_departure_time: Option<DateTime>,
+    /// Realtime departure time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_departure_time: Option<DateTime>,
 }
 
 #[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
 #[serde(rename_all = "PascalCase")]
-pub struct EstimatedJourneyVersionFrame {
-    #[serde(flatten)]
-    pub RecordedAtTime: RecordedAtTimeWrapper,
-    
-    pub EstimatedVehicleJourney: Vec<EstimatedVehicleJourney>,
+pub struct EstimatedCalls {
+    pub estimated_call: Vec<EstimatedCall>,
 }
 
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
+#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
 #[serde(rename_all = "PascalCase")]
-pub struct ServiceDelivery {
-    #[serde(flatten)]
-   pub ResponseTimestamp: ResponseTimeStampWrapper,
-    
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub ProducerRef: Option<ProducerRefWrapper>,
-    
+pub struct EstimatedVehicleJourney {
+    /// Id of the line
+    pub line_ref: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub Address: Option<AddressWrapper>,
-    
+    pub direction_ref: Option<String>,
+    pub framed_vehicle_journey_ref: FramedVehicleJourneyRef,
+    /// Id of the journey pattern
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub ResponseMessageIdentifier: Option<ResponseMessageIdentifierWrapper>,
-    
-    #[serde(skip_serializing_if = "Vec::is_empty", default)]
-    pub StopMonitoringDelivery: Vec<StopMonitoringDelivery>,
-    
-    #[serde(skip_serializing_if = "Vec::is_empty", default)]
-    pub EstimatedTimetableDelivery: Vec<EstimatedTimetableDelivery>,
-    
-    #[serde(skip_serializing_if = "Vec::is_empty", default)]
-    pub GeneralMessageDelivery: Vec<GeneralMessageDelivery>,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
-#[serde(rename_all = "PascalCase")]
-pub struct FramedVehicleJourneyRef {
+    pub journey_pattern_ref: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub published_line_name: Option<String>,
     #[serde(flatten)]
-    pub DataFrameRef: Option<DataFrameRefWrapper>,
-    
+    pub service_info: ServiceInfoGroup,
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(flatten)]
-    pub DatedVehicleJourneyRef: Option<DatedVehicleJourneyRefWrapper>,
-}
-
-// Wrappers for all fields
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct DataFrameRefWrapper {
-    pub DataFrameRef: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct DatedVehicleJourneyRefWrapper {
-    pub DatedVehicleJourneyRef: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct ResponseTimeStampWrapper {
-    pub ResponseTimestamp: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct RequestMessageRefWrapper {
-    pub RequestMessageRef: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct SubscriberRefWrapper {
-    pub SubscriberRef: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct SubscriptionRefWrapper {
-    pub SubscriptionRef: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct OrderWrapper {
-    pub Order: u16,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct StopPointNameWrapper {
-    pub StopPointName: String,
+    pub vehicle_ref: Option<String>,
+    pub estimated_calls: EstimatedCalls,
 }
 
 #[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct VehicleAtStopWrapper {
-    pub VehicleAtStop: bool,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct DestinationDisplayWrapper {
-    pub DestinationDisplay: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
-pub struct AimedArrivalTimeWrapper {
-    pub AimedArrivalTime: DateTime,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
-pub struct AimedDepartureTimeWrapper {
-    pub AimedDepartureTime: DateTime,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
-pub struct ExpectedArrivalTimeWrapper {
-    pub ExpectedArrivalTime: DateTime,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
-pub struct ExpectedDepartureTimeWrapper {
-    pub ExpectedDepartureTime: DateTime,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
-pub struct ArrivalStatusWrapper {
-    pub ArrivalStatus: ArrivalStatus,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct LineRefWrapper {
-    pub LineRef: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct DirectionRefWrapper {
-    pub DirectionRef: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct JourneyPatternRefWrapper {
-    pub JourneyPatternRef: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct PublishedLineNameWrapper {
-    pub PublishedLineName: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct VehicleRefWrapper {
-    pub VeichleRef: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct StopPointRefWrapper {
-    pub StopPointRef: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct VisitNumberWrapper {
-    pub VisitNumber: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct OperatorRefWrapper {
-    pub OperatorRef: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct MonitoringRefWrapper {
-    pub MonitoringRef: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct RecordedAtTimeWrapper {
-    pub RecordedAtTime: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct ItemIdentifierWrapper {
-    pub ItemIdentifier: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct VersionWrapper {
-    pub Version: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct StatusWrapper {
-    pub Status: bool,
+#[serde(rename_all = "PascalCase")]
+pub struct EstimatedJourneyVersionFrame {
+    pub recorded_at_time: String,
+    pub estimated_vehicle_journey: Vec<EstimatedVehicleJourney>,
 }
 
 #[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct ProducerRefWrapper {
-    pub ProducerRef: String,
+#[serde(rename_all = "PascalCase")]
+pub struct EstimatedTimetableDelivery {
+    #[serde(flatten)]
+    pub common: CommonDelivery,
+    /// Version of the siri's response
+    pub version: String,
+    /// Id of the subscriber, only set for the deliveries sent to the subscribers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscriber_ref: Option<String>,
+    /// Id of the subscription, only set for the deliveries sent to the subscribers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscription_ref: Option<String>,
+    pub estimated_journey_version_frame: Vec<EstimatedJourneyVersionFrame>,
 }
 
 #[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct AddressWrapper {
-    pub Address: String,
+#[serde(rename_all = "PascalCase")]
+pub struct ServiceDelivery {
+    pub response_timestamp: String,
+    /// Id of the producer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub producer_ref: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// Id of the response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_message_identifier: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub stop_monitoring_delivery: Vec<StopMonitoringDelivery>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub estimated_timetable_delivery: Vec<EstimatedTimetableDelivery>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub general_message_delivery: Vec<GeneralMessageDelivery>,
 }
-
-#[derive(Debug, Serialize, Deserialize, OpenapiSchema, Default)]
-pub struct ResponseMessageIdentifierWrapper {
-    pub ResponseMessageIdentifier: String,
-}
\ No newline at end of file
@@ -1,6 +1,6 @@
 use openapi_schema::OpenapiSchema;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct DateTime(pub chrono::NaiveDateTime);
 
 impl std::string::ToString for DateTime {
@@ -50,18 +50,31 @@ impl OpenapiSchema for DateTime {
 #[derive(Serialize, Deserialize, OpenapiSchema, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct CommonDelivery {
-    pub response_time_stamp: String,
+    pub response_timestamp: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     /// Id of the query
-    pub request_message_ref: Option<String>
+    pub request_message_ref: Option<String>,
 }
 
-impl Default for CommonDelivery {
-    fn default() -> Self {
+impl CommonDelivery {
+    /// delivery answered at `now`, the current time of the dataset
+    pub fn new(now: chrono::DateTime<chrono::Utc>) -> Self {
         CommonDelivery {
-            response_time_stamp: chrono::Utc::now().to_rfc3339(),
+            response_timestamp: now.to_rfc3339(),
             // error_condition: None,
             request_message_ref: None,
         }
     }
 }
+
+/// Note: it uses the system time, the routes use `CommonDelivery::new` with the clock of their dataset
+impl Default for CommonDelivery {
+    fn default() -> Self {
+        Self::new(chrono::Utc::now())
+    }
+}
+
+/// hexadecimal representation of a colour (like `FF0000`)
+pub fn format_colour(colour: &transit_model::objects::Rgb) -> String {
+    format!("{:02X}{:02X}{:02X}", colour.red, colour.green, colour.blue)
+}
@@ -1,15 +1,33 @@
+use crate::siri_lite::shared::format_colour;
 use openapi_schema::OpenapiSchema;
 use transit_model::collection::Idx;
-use transit_model::objects::StopPoint;
+use transit_model::objects::{Availability, PhysicalMode, StopArea, StopPoint};
 use transit_model::Model;
 
 #[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
 #[serde(rename_all = "PascalCase")]
 pub struct Line {
     pub line_ref: String,
+    pub line_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_code: Option<String>,
+    /// hexadecimal colour of the line (like `FF0000`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_colour: Option<String>,
+    /// Physical modes of the line's vehicle journeys
+    pub mode: Vec<String>,
 }
 
+/// The stop area containing a stop point
 #[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct ParentStopArea {
+    pub stop_area_ref: String,
+    pub stop_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
+#[serde(rename_all = "PascalCase")]
 pub struct Location {
     pub longitude: f64,
     pub latitude: f64,
@@ -20,8 +38,35 @@ pub struct Location {
 pub struct AnnotatedStopPoint {
     pub stop_point_ref: String,
     pub stop_name: String,
+    /// Code of the stop, as displayed to the travellers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_code: Option<String>,
     pub lines: Vec<Line>,
     pub location: Location,
+    /// true if the stop is accessible by wheelchair, false if it is not,
+    /// and missing if the information is not available
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wheelchair_accessible: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_stop_area: Option<ParentStopArea>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fare_zone: Option<String>,
+    /// Distance in meters to the center of a proximity query
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance: Option<f64>,
+}
+
+/// A stop area (a parent station) with all its stop points
+#[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct AnnotatedStopArea {
+    pub stop_area_ref: String,
+    pub stop_name: String,
+    pub location: Location,
+    /// Distance in meters to the center of a proximity query
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance: Option<f64>,
+    pub annotated_stop_point: Vec<AnnotatedStopPoint>,
 }
 
 #[derive(Debug, Serialize, Deserialize, OpenapiSchema)]
@@ -30,28 +75,97 @@ pub struct StopPointsDelivery {
     #[serde(flatten)]
     pub common: crate::siri_lite::shared::CommonDelivery,
     pub annotated_stop_point: Vec<AnnotatedStopPoint>,
+    /// Only given if the stop areas are requested
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub annotated_stop_area: Vec<AnnotatedStopArea>,
+}
+
+/// the key of the gtfs stop_code in the stop points' codes
+const GTFS_STOP_CODE: &str = "gtfs_stop_code";
+
+impl Line {
+    pub fn from(line_idx: Idx<transit_model::objects::Line>, model: &Model) -> Self {
+        let line = &model.lines[line_idx];
+        Self {
+            line_ref: line.id.clone(),
+            line_name: line.name.clone(),
+            line_code: line.code.clone(),
+            line_colour: line.color.as_ref().map(format_colour),
+            mode: model
+                .get_corresponding_from_idx::<_, PhysicalMode>(line_idx)
+                .into_iter()
+                .map(|idx| model.physical_modes[idx].name.clone())
+                .collect(),
+        }
+    }
 }
 
 impl AnnotatedStopPoint {
     pub fn from(stop_point_idx: Idx<StopPoint>, model: &Model) -> Self {
+        // the lines are unique since the relation gives a set of lines
         let lines = model
-            .get_corresponding_from_idx(stop_point_idx)
+            .get_corresponding_from_idx::<_, transit_model::objects::Line>(stop_point_idx)
             .into_iter()
-            .map(|route_id| Line {
-                line_ref: model.routes[route_id].id.clone(),
-            })
+            .map(|line_idx| Line::from(line_idx, model))
             .collect();
 
         let sp = &model.stop_points[stop_point_idx];
 
+        let wheelchair_accessible = sp
+            .equipment_id
+            .as_ref()
+            .and_then(|id| model.equipments.get(id))
+            .and_then(|e| match e.wheelchair_boarding {
+                Availability::Available => Some(true),
+                Availability::NotAvailable => Some(false),
+                Availability::InformationNotAvailable => None,
+            });
+
         Self {
             stop_point_ref: sp.id.clone(),
             stop_name: sp.name.clone(),
+            stop_code: sp
+                .codes
+                .iter()
+                .find(|(key, _)| key == GTFS_STOP_CODE)
+                .map(|(_, code)| code.clone()),
             lines,
             location: Location {
                 longitude: sp.coord.lon,
                 latitude: sp.coord.lat,
             },
+            wheelchair_accessible,
+            parent_stop_area: model
+                .stop_areas
+                .get(&sp.stop_area_id)
+                .map(|sa| ParentStopArea {
+                    stop_area_ref: sa.id.clone(),
+                    stop_name: sa.name.clone(),
+                }),
+            fare_zone: sp.fare_zone_id.clone(),
+            distance: None,
+        }
+    }
+}
+
+impl AnnotatedStopArea {
+    pub fn from(stop_area_idx: Idx<StopArea>, model: &Model) -> Self {
+        let sa = &model.stop_areas[stop_area_idx];
+        let annotated_stop_point = model
+            .get_corresponding_from_idx::<_, StopPoint>(stop_area_idx)
+            .into_iter()
+            .map(|sp_idx| AnnotatedStopPoint::from(sp_idx, model))
+            .collect();
+
+        Self {
+            stop_area_ref: sa.id.clone(),
+            stop_name: sa.name.clone(),
+            location: Location {
+                longitude: sa.coord.lon,
+                latitude: sa.coord.lat,
+            },
+            distance: None,
+            annotated_stop_point,
         }
     }
 }
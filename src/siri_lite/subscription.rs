@@ -0,0 +1,252 @@
+use crate::siri_lite::xml::xml_escape;
+use crate::utils;
+
+/// Request sent by a consumer to subscribe to some data.
+/// The matching ServiceDelivery will be POSTed to its ConsumerAddress.
+///
+/// Note: for the moment only the EstimatedTimetable subscriptions are handled
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SubscriptionRequest {
+    pub requestor_ref: String,
+    /// url where the ServiceDelivery and the heartbeats will be sent
+    pub consumer_address: String,
+    #[serde(default)]
+    pub subscription_context: Option<SubscriptionContext>,
+    #[serde(default)]
+    pub estimated_timetable_subscription_request: Vec<EstimatedTimetableSubscriptionRequest>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SubscriptionContext {
+    /// ISO 8601 duration between 2 heartbeat notifications
+    pub heartbeat_interval: Option<utils::Duration>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EstimatedTimetableSubscriptionRequest {
+    pub subscriber_ref: Option<String>,
+    pub subscription_identifier: String,
+    /// end of the subscription
+    pub initial_termination_time: chrono::DateTime<chrono::FixedOffset>,
+    #[serde(default)]
+    pub estimated_timetable_request: Option<EstimatedTimetableRequest>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct EstimatedTimetableRequest {
+    pub line_ref: Option<String>,
+    pub monitoring_ref: Option<String>,
+}
+
+/// Request sent by a consumer to stop some of its subscriptions
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TerminateSubscriptionRequest {
+    pub requestor_ref: String,
+    /// Terminate all the subscriptions of the requestor
+    #[serde(default)]
+    pub all: Option<String>,
+    #[serde(default)]
+    pub subscription_ref: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct SiriRequest {
+    pub subscription_request: Option<SubscriptionRequest>,
+    pub terminate_subscription_request: Option<TerminateSubscriptionRequest>,
+}
+
+/// The json requests are wrapped in a `Siri` object,
+/// whereas for the xml the `Siri` object is the root element
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SiriJsonRequest {
+    pub siri: SiriRequest,
+}
+
+impl SiriRequest {
+    /// read a siri request, either in json or in xml
+    pub fn from_body(body: &str) -> Result<Self, anyhow::Error> {
+        if body.trim_start().starts_with('{') {
+            serde_json::from_str::<SiriJsonRequest>(body)
+                .map(|r| r.siri)
+                .map_err(|e| anyhow::anyhow!("invalid json siri request: {}", e))
+        } else {
+            quick_xml::de::from_str(body)
+                .map_err(|e| anyhow::anyhow!("invalid xml siri request: {}", e))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ResponseStatus {
+    pub response_timestamp: String,
+    pub subscriber_ref: String,
+    pub subscription_ref: String,
+    pub status: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_text: Option<String>,
+}
+
+impl ResponseStatus {
+    fn to_xml(&self) -> String {
+        format!(
+            "<ResponseStatus><ResponseTimestamp>{}</ResponseTimestamp><SubscriberRef>{}</SubscriberRef><SubscriptionRef>{}</SubscriptionRef><Status>{}</Status>{}</ResponseStatus>",
+            self.response_timestamp,
+            xml_escape(&self.subscriber_ref),
+            xml_escape(&self.subscription_ref),
+            self.status,
+            self.error_text
+                .as_ref()
+                .map(|e| format!(
+                    "<ErrorCondition><OtherError><ErrorText>{}</ErrorText></OtherError></ErrorCondition>",
+                    xml_escape(e)
+                ))
+                .unwrap_or_default()
+        )
+    }
+}
+
+pub const XML_HEADER: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?><Siri xmlns=\"http://www.siri.org.uk/siri\" version=\"2.0\">";
+pub const XML_FOOTER: &str = "</Siri>";
+
+fn responses_to_xml(
+    node: &str,
+    responder_ref: &str,
+    statuses: &[ResponseStatus],
+    now: chrono::DateTime<chrono::Utc>,
+) -> String {
+    format!(
+        "{}<{node}><ResponseTimestamp>{}</ResponseTimestamp><ResponderRef>{}</ResponderRef>{}</{node}>{}",
+        XML_HEADER,
+        now.to_rfc3339(),
+        xml_escape(responder_ref),
+        statuses.iter().map(ResponseStatus::to_xml).collect::<String>(),
+        XML_FOOTER,
+        node = node,
+    )
+}
+
+pub fn subscription_response_xml(
+    responder_ref: &str,
+    statuses: &[ResponseStatus],
+    now: chrono::DateTime<chrono::Utc>,
+) -> String {
+    responses_to_xml("SubscriptionResponse", responder_ref, statuses, now)
+}
+
+pub fn terminate_subscription_response_xml(
+    responder_ref: &str,
+    statuses: &[ResponseStatus],
+    now: chrono::DateTime<chrono::Utc>,
+) -> String {
+    responses_to_xml(
+        "TerminateSubscriptionResponse",
+        responder_ref,
+        statuses,
+        now,
+    )
+}
+
+pub fn heartbeat_notification_xml(
+    producer_ref: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> String {
+    format!(
+        "{}<HeartbeatNotification><RequestTimestamp>{}</RequestTimestamp><ProducerRef>{}</ProducerRef><Status>true</Status></HeartbeatNotification>{}",
+        XML_HEADER,
+        now.to_rfc3339(),
+        xml_escape(producer_ref),
+        XML_FOOTER,
+    )
+}
+
+pub fn subscription_terminated_notification_xml(
+    producer_ref: &str,
+    subscriber_ref: &str,
+    subscription_ref: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> String {
+    format!(
+        "{}<SubscriptionTerminatedNotification><ResponseTimestamp>{}</ResponseTimestamp><ProducerRef>{}</ProducerRef><SubscriberRef>{}</SubscriberRef><SubscriptionRef>{}</SubscriptionRef></SubscriptionTerminatedNotification>{}",
+        XML_HEADER,
+        now.to_rfc3339(),
+        xml_escape(producer_ref),
+        xml_escape(subscriber_ref),
+        xml_escape(subscription_ref),
+        XML_FOOTER,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::SiriRequest;
+
+    #[test]
+    fn read_xml_subscription_request() {
+        let request = SiriRequest::from_body(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+            <Siri xmlns="http://www.siri.org.uk/siri" version="2.0">
+                <SubscriptionRequest>
+                    <RequestorRef>consumer</RequestorRef>
+                    <ConsumerAddress>http://localhost/siri</ConsumerAddress>
+                    <SubscriptionContext>
+                        <HeartbeatInterval>PT1M</HeartbeatInterval>
+                    </SubscriptionContext>
+                    <EstimatedTimetableSubscriptionRequest>
+                        <SubscriberRef>consumer</SubscriberRef>
+                        <SubscriptionIdentifier>sub1</SubscriptionIdentifier>
+                        <InitialTerminationTime>2030-01-01T00:00:00+00:00</InitialTerminationTime>
+                        <EstimatedTimetableRequest>
+                            <LineRef>l1</LineRef>
+                        </EstimatedTimetableRequest>
+                    </EstimatedTimetableSubscriptionRequest>
+                </SubscriptionRequest>
+            </Siri>"#,
+        )
+        .unwrap();
+        let subscription_request = request.subscription_request.unwrap();
+        assert_eq!(subscription_request.requestor_ref, "consumer");
+        assert_eq!(
+            subscription_request.consumer_address,
+            "http://localhost/siri"
+        );
+        assert_eq!(
+            subscription_request
+                .subscription_context
+                .and_then(|c| c.heartbeat_interval)
+                .map(|d| d.num_seconds()),
+            Some(60)
+        );
+        let et_request = &subscription_request.estimated_timetable_subscription_request[0];
+        assert_eq!(et_request.subscription_identifier, "sub1");
+        assert_eq!(
+            et_request
+                .estimated_timetable_request
+                .as_ref()
+                .and_then(|r| r.line_ref.as_deref()),
+            Some("l1")
+        );
+    }
+
+    #[test]
+    fn read_json_terminate_subscription_request() {
+        let request = SiriRequest::from_body(
+            r#"{"Siri": {"TerminateSubscriptionRequest": {
+                "RequestorRef": "consumer",
+                "SubscriptionRef": ["sub1", "sub2"]
+            }}}"#,
+        )
+        .unwrap();
+        let terminate_request = request.terminate_subscription_request.unwrap();
+        assert_eq!(terminate_request.requestor_ref, "consumer");
+        assert_eq!(terminate_request.subscription_ref, vec!["sub1", "sub2"]);
+        assert!(terminate_request.all.is_none());
+    }
+}
@@ -0,0 +1,682 @@
+//! Xml serialization of the siri-lite model
+//!
+//! The siri-lite json and the siri xml share the same structure:
+//! each field of an object is an xml element, and each element of an array is
+//! an xml element named after the array's field.
+//! So we serialize the model in a tree of xml nodes (keeping the order of the fields,
+//! as it matters in the siri xsd), and write this tree as xml.
+//! The only exception is the `Version` of the deliveries, which is an xml attribute.
+use anyhow::{anyhow, Error};
+use serde::ser::{self, Serialize};
+
+pub const CONTENT_TYPE: &str = "application/xml";
+
+const SIRI_ATTRIBUTES: &str =
+    r#"xmlns="http://www.siri.org.uk/siri" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" version="2.0""#;
+
+/// fields written as an attribute of their element, with the name of the attribute
+const XML_ATTRIBUTES: &[(&str, &str)] = &[("Version", "version")];
+
+fn attribute_name(field_name: &str) -> Option<&'static str> {
+    XML_ATTRIBUTES
+        .iter()
+        .find(|(field, _)| *field == field_name)
+        .map(|(_, attribute)| *attribute)
+}
+
+pub fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Serialized value, before being written as xml
+enum Node {
+    Empty,
+    Text(String),
+    List(Vec<Node>),
+    Element(Vec<(String, Node)>),
+}
+
+#[derive(Debug)]
+struct SerializationError(String);
+
+impl std::fmt::Display for SerializationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SerializationError {}
+
+impl ser::Error for SerializationError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        SerializationError(msg.to_string())
+    }
+}
+
+type NodeResult = Result<Node, SerializationError>;
+
+/// Serde serializer building the `Node` tree of a value
+struct NodeSerializer;
+
+fn text<T: ToString>(v: T) -> NodeResult {
+    Ok(Node::Text(v.to_string()))
+}
+
+/// a variant of an enum holding some data is written as an element named after the variant
+fn variant(name: &str, node: Node) -> Node {
+    Node::Element(vec![(name.to_owned(), node)])
+}
+
+impl ser::Serializer for NodeSerializer {
+    type Ok = Node;
+    type Error = SerializationError;
+    type SerializeSeq = ListSerializer;
+    type SerializeTuple = ListSerializer;
+    type SerializeTupleStruct = ListSerializer;
+    type SerializeTupleVariant = ListSerializer;
+    type SerializeMap = ElementSerializer;
+    type SerializeStruct = ElementSerializer;
+    type SerializeStructVariant = ElementSerializer;
+
+    fn serialize_bool(self, v: bool) -> NodeResult {
+        text(v)
+    }
+    fn serialize_i8(self, v: i8) -> NodeResult {
+        text(v)
+    }
+    fn serialize_i16(self, v: i16) -> NodeResult {
+        text(v)
+    }
+    fn serialize_i32(self, v: i32) -> NodeResult {
+        text(v)
+    }
+    fn serialize_i64(self, v: i64) -> NodeResult {
+        text(v)
+    }
+    fn serialize_u8(self, v: u8) -> NodeResult {
+        text(v)
+    }
+    fn serialize_u16(self, v: u16) -> NodeResult {
+        text(v)
+    }
+    fn serialize_u32(self, v: u32) -> NodeResult {
+        text(v)
+    }
+    fn serialize_u64(self, v: u64) -> NodeResult {
+        text(v)
+    }
+    fn serialize_f32(self, v: f32) -> NodeResult {
+        text(v)
+    }
+    fn serialize_f64(self, v: f64) -> NodeResult {
+        text(v)
+    }
+    fn serialize_char(self, v: char) -> NodeResult {
+        text(v)
+    }
+    fn serialize_str(self, v: &str) -> NodeResult {
+        text(v)
+    }
+    fn serialize_bytes(self, _v: &[u8]) -> NodeResult {
+        Err(ser::Error::custom("bytes cannot be serialized in xml"))
+    }
+    fn serialize_none(self) -> NodeResult {
+        Ok(Node::Empty)
+    }
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> NodeResult {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> NodeResult {
+        Ok(Node::Empty)
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> NodeResult {
+        Ok(Node::Empty)
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> NodeResult {
+        text(variant)
+    }
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> NodeResult {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        name: &'static str,
+        value: &T,
+    ) -> NodeResult {
+        Ok(variant(name, value.serialize(self)?))
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<ListSerializer, SerializationError> {
+        Ok(ListSerializer::new(None, len.unwrap_or_default()))
+    }
+    fn serialize_tuple(self, len: usize) -> Result<ListSerializer, SerializationError> {
+        Ok(ListSerializer::new(None, len))
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer, SerializationError> {
+        Ok(ListSerializer::new(None, len))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        name: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer, SerializationError> {
+        Ok(ListSerializer::new(Some(name), len))
+    }
+    fn serialize_map(self, len: Option<usize>) -> Result<ElementSerializer, SerializationError> {
+        Ok(ElementSerializer::new(None, len.unwrap_or_default()))
+    }
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<ElementSerializer, SerializationError> {
+        Ok(ElementSerializer::new(None, len))
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        name: &'static str,
+        len: usize,
+    ) -> Result<ElementSerializer, SerializationError> {
+        Ok(ElementSerializer::new(Some(name), len))
+    }
+}
+
+struct ListSerializer {
+    variant: Option<&'static str>,
+    nodes: Vec<Node>,
+}
+
+impl ListSerializer {
+    fn new(variant: Option<&'static str>, len: usize) -> Self {
+        Self {
+            variant,
+            nodes: Vec::with_capacity(len),
+        }
+    }
+
+    fn push<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerializationError> {
+        self.nodes.push(value.serialize(NodeSerializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> NodeResult {
+        let list = Node::List(self.nodes);
+        Ok(match self.variant {
+            Some(name) => variant(name, list),
+            None => list,
+        })
+    }
+}
+
+impl ser::SerializeSeq for ListSerializer {
+    type Ok = Node;
+    type Error = SerializationError;
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        self.push(value)
+    }
+    fn end(self) -> NodeResult {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for ListSerializer {
+    type Ok = Node;
+    type Error = SerializationError;
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        self.push(value)
+    }
+    fn end(self) -> NodeResult {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer {
+    type Ok = Node;
+    type Error = SerializationError;
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        self.push(value)
+    }
+    fn end(self) -> NodeResult {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for ListSerializer {
+    type Ok = Node;
+    type Error = SerializationError;
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        self.push(value)
+    }
+    fn end(self) -> NodeResult {
+        self.finish()
+    }
+}
+
+struct ElementSerializer {
+    variant: Option<&'static str>,
+    fields: Vec<(String, Node)>,
+    next_key: Option<String>,
+}
+
+impl ElementSerializer {
+    fn new(variant: Option<&'static str>, len: usize) -> Self {
+        Self {
+            variant,
+            fields: Vec::with_capacity(len),
+            next_key: None,
+        }
+    }
+
+    fn push<T: ?Sized + Serialize>(
+        &mut self,
+        key: String,
+        value: &T,
+    ) -> Result<(), SerializationError> {
+        self.fields.push((key, value.serialize(NodeSerializer)?));
+        Ok(())
+    }
+
+    fn finish(self) -> NodeResult {
+        let element = Node::Element(self.fields);
+        Ok(match self.variant {
+            Some(name) => variant(name, element),
+            None => element,
+        })
+    }
+}
+
+impl ser::SerializeMap for ElementSerializer {
+    type Ok = Node;
+    type Error = SerializationError;
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Self::Error> {
+        match key.serialize(NodeSerializer)? {
+            Node::Text(key) => {
+                self.next_key = Some(key);
+                Ok(())
+            }
+            _ => Err(ser::Error::custom("the xml element names must be strings")),
+        }
+    }
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| ser::Error::custom("value serialized before its key"))?;
+        self.push(key, value)
+    }
+    fn end(self) -> NodeResult {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for ElementSerializer {
+    type Ok = Node;
+    type Error = SerializationError;
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        self.push(key.to_owned(), value)
+    }
+    fn end(self) -> NodeResult {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for ElementSerializer {
+    type Ok = Node;
+    type Error = SerializationError;
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        self.push(key.to_owned(), value)
+    }
+    fn end(self) -> NodeResult {
+        self.finish()
+    }
+}
+
+fn write_element(out: &mut String, name: &str, node: &Node, attributes: Option<&str>) {
+    match node {
+        Node::Empty => {}
+        Node::List(nodes) => {
+            for n in nodes {
+                write_element(out, name, n, attributes);
+            }
+        }
+        Node::Element(fields) => {
+            out.push('<');
+            out.push_str(name);
+            if let Some(attributes) = attributes {
+                out.push(' ');
+                out.push_str(attributes);
+            }
+            for (field_name, field_node) in fields {
+                if let (Some(attribute), Node::Text(value)) =
+                    (attribute_name(field_name), field_node)
+                {
+                    out.push_str(&format!(r#" {}="{}""#, attribute, xml_escape(value)));
+                }
+            }
+            out.push('>');
+            for (field_name, field_node) in fields {
+                // the fields starting with '_' are not siri fields (like the hal links)
+                if field_name.starts_with('_') || attribute_name(field_name).is_some() {
+                    continue;
+                }
+                write_element(out, field_name, field_node, None);
+            }
+            out.push_str("</");
+            out.push_str(name);
+            out.push('>');
+        }
+        Node::Text(s) => write_text_element(out, name, &xml_escape(s)),
+    }
+}
+
+fn write_text_element(out: &mut String, name: &str, text: &str) {
+    out.push('<');
+    out.push_str(name);
+    out.push('>');
+    out.push_str(text);
+    out.push_str("</");
+    out.push_str(name);
+    out.push('>');
+}
+
+/// Serialize the content of a `Siri` object as a siri 2.0 xml document
+pub fn siri_to_xml<T: Serialize>(siri: &T) -> Result<String, Error> {
+    let node = siri.serialize(NodeSerializer)?;
+    if !matches!(node, Node::Element(_)) {
+        return Err(anyhow!("a siri document can only be created from an object"));
+    }
+    let mut out = r#"<?xml version="1.0" encoding="utf-8"?>"#.to_owned();
+    write_element(&mut out, "Siri", &node, Some(SIRI_ATTRIBUTES));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::siri_to_xml;
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Call {
+        order: u16,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expected_departure_time: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Calls {
+        estimated_call: Vec<Call>,
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Siri {
+        stop_point_name: String,
+        line_ref: Vec<String>,
+        estimated_calls: Calls,
+    }
+
+    #[test]
+    fn siri_xml_serialization() {
+        let xml = siri_to_xml(&Siri {
+            stop_point_name: "Gare & Centre".to_owned(),
+            line_ref: vec!["l1".to_owned(), "l2".to_owned()],
+            estimated_calls: Calls {
+                estimated_call: vec![
+                    Call {
+                        order: 1,
+                        expected_departure_time: Some("2019-02-06T10:00:00".to_owned()),
+                    },
+                    Call {
+                        order: 2,
+                        expected_departure_time: None,
+                    },
+                ],
+            },
+        })
+        .unwrap();
+        assert_eq!(
+            xml,
+            r#"<?xml version="1.0" encoding="utf-8"?><Siri xmlns="http://www.siri.org.uk/siri" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" version="2.0"><StopPointName>Gare &amp; Centre</StopPointName><LineRef>l1</LineRef><LineRef>l2</LineRef><EstimatedCalls><EstimatedCall><Order>1</Order><ExpectedDepartureTime>2019-02-06T10:00:00</ExpectedDepartureTime></EstimatedCall><EstimatedCall><Order>2</Order></EstimatedCall></EstimatedCalls></Siri>"#
+        );
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Common {
+        response_timestamp: String,
+    }
+
+    #[derive(Serialize)]
+    enum Status {
+        #[serde(rename = "onTime")]
+        OnTime,
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Delivery {
+        version: String,
+        #[serde(flatten)]
+        common: Common,
+        arrival_status: Status,
+    }
+
+    #[test]
+    fn xml_fields_keep_the_model_order() {
+        let xml = siri_to_xml(&Delivery {
+            version: "2.0".to_owned(),
+            common: Common {
+                response_timestamp: "2019-02-06T10:00:00Z".to_owned(),
+            },
+            arrival_status: Status::OnTime,
+        })
+        .unwrap();
+        assert!(xml.ends_with(
+            r#"version="2.0"><ResponseTimestamp>2019-02-06T10:00:00Z</ResponseTimestamp><ArrivalStatus>onTime</ArrivalStatus></Siri>"#
+        ));
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Deliveries {
+        stop_monitoring_delivery: Vec<Delivery>,
+    }
+
+    #[test]
+    fn version_is_an_attribute_of_the_deliveries() {
+        let xml = siri_to_xml(&Deliveries {
+            stop_monitoring_delivery: vec![Delivery {
+                version: "2.0".to_owned(),
+                common: Common {
+                    response_timestamp: "2019-02-06T10:00:00Z".to_owned(),
+                },
+                arrival_status: Status::OnTime,
+            }],
+        })
+        .unwrap();
+        assert!(xml.ends_with(
+            r#"<StopMonitoringDelivery version="2.0"><ResponseTimestamp>2019-02-06T10:00:00Z</ResponseTimestamp><ArrivalStatus>onTime</ArrivalStatus></StopMonitoringDelivery></Siri>"#
+        ));
+    }
+
+    /// golden test of the deliveries, with the elements in the order of the siri 2.0 xsd
+    #[test]
+    fn siri_deliveries_follow_the_xsd_order() {
+        use crate::siri_lite::service_delivery::*;
+        use crate::siri_lite::shared::CommonDelivery;
+        use crate::siri_lite::{DateTime, Siri};
+
+        let now = "2019-02-06T10:00:00Z".parse().unwrap();
+        let dt = |t: &str| DateTime(t.parse().unwrap());
+        let framed_vj = || FramedVehicleJourneyRef {
+            data_frame_ref: Some("2019-02-06".to_owned()),
+            dated_vehicle_journey_ref: Some("vj1".to_owned()),
+        };
+        let service_info = || ServiceInfoGroup {
+            operator_ref: Some("op1".to_owned()),
+        };
+        let siri = Siri {
+            service_delivery: Some(ServiceDelivery {
+                response_timestamp: "2019-02-06T10:00:00+00:00".to_owned(),
+                stop_monitoring_delivery: vec![StopMonitoringDelivery {
+                    common: CommonDelivery::new(now),
+                    version: "2.0".to_owned(),
+                    status: true,
+                    monitored_stop_visit: vec![MonitoredStopVisit {
+                        recorded_at_time: now,
+                        item_identifier: "item1".to_owned(),
+                        monitoring_ref: "sp1".to_owned(),
+                        monitored_vehicle_journey: MonitoredVehicleJourney {
+                            line_ref: "l1".to_owned(),
+                            framed_vehicle_journey_ref: framed_vj(),
+                            journey_pattern_ref: Some("jp1".to_owned()),
+                            service_info: service_info(),
+                            monitored_call: Some(MonitoredCall {
+                                order: 2,
+                                stop_point_name: "Gare".to_owned(),
+                                vehicle_at_stop: Some(false),
+                                prediction_inaccurate: Some(true),
+                                destination_display: Some("Centre".to_owned()),
+                                aimed_arrival_time: Some(dt("2019-02-06T10:05:00")),
+                                expected_arrival_time: Some(dt("2019-02-06T10:07:00")),
+                                arrival_status: Some(ArrivalStatus::Delayed),
+                                aimed_departure_time: Some(dt("2019-02-06T10:06:00")),
+                                expected_departure_time: Some(dt("2019-02-06T10:08:00")),
+                            }),
+                        },
+                    }],
+                }],
+                estimated_timetable_delivery: vec![EstimatedTimetableDelivery {
+                    common: CommonDelivery::new(now),
+                    version: "2.0".to_owned(),
+                    subscriber_ref: None,
+                    subscription_ref: None,
+                    estimated_journey_version_frame: vec![EstimatedJourneyVersionFrame {
+                        recorded_at_time: "2019-02-06T10:00:00+00:00".to_owned(),
+                        estimated_vehicle_journey: vec![EstimatedVehicleJourney {
+                            line_ref: "l1".to_owned(),
+                            direction_ref: None,
+                            framed_vehicle_journey_ref: framed_vj(),
+                            journey_pattern_ref: Some("jp1".to_owned()),
+                            published_line_name: Some("Line 1".to_owned()),
+                            service_info: service_info(),
+                            vehicle_ref: None,
+                            estimated_calls: EstimatedCalls {
+                                estimated_call: vec![EstimatedCall {
+                                    stop_point_ref: "sp1".to_owned(),
+                                    visit_number: None,
+                                    order: 2,
+                                    stop_point_name: "Gare".to_owned(),
+                                    prediction_inaccurate: Some(true),
+                                    aimed_arrival_time: Some(dt("2019-02-06T10:05:00")),
+                                    expected_arrival_time: Some(dt("2019-02-06T10:07:00")),
+                                    aimed_departure_time: Some(dt("2019-02-06T10:06:00")),
+                                    expected_departure_time: Some(dt("2019-02-06T10:08:00")),
+                                }],
+                            },
+                        }],
+                    }],
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let expected = r#"<?xml version="1.0" encoding="utf-8"?>
+            <Siri xmlns="http://www.siri.org.uk/siri" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" version="2.0">
+            <ServiceDelivery>
+            <ResponseTimestamp>2019-02-06T10:00:00+00:00</ResponseTimestamp>
+            <StopMonitoringDelivery version="2.0">
+            <ResponseTimestamp>2019-02-06T10:00:00+00:00</ResponseTimestamp>
+            <Status>true</Status>
+            <MonitoredStopVisit>
+            <RecordedAtTime>2019-02-06T10:00:00Z</RecordedAtTime>
+            <ItemIdentifier>item1</ItemIdentifier>
+            <MonitoringRef>sp1</MonitoringRef>
+            <MonitoredVehicleJourney>
+            <LineRef>l1</LineRef>
+            <FramedVehicleJourneyRef>
+            <DataFrameRef>2019-02-06</DataFrameRef>
+            <DatedVehicleJourneyRef>vj1</DatedVehicleJourneyRef>
+            </FramedVehicleJourneyRef>
+            <JourneyPatternRef>jp1</JourneyPatternRef>
+            <OperatorRef>op1</OperatorRef>
+            <MonitoredCall>
+            <Order>2</Order>
+            <StopPointName>Gare</StopPointName>
+            <VehicleAtStop>false</VehicleAtStop>
+            <PredictionInaccurate>true</PredictionInaccurate>
+            <DestinationDisplay>Centre</DestinationDisplay>
+            <AimedArrivalTime>2019-02-06T10:05:00</AimedArrivalTime>
+            <ExpectedArrivalTime>2019-02-06T10:07:00</ExpectedArrivalTime>
+            <ArrivalStatus>delayed</ArrivalStatus>
+            <AimedDepartureTime>2019-02-06T10:06:00</AimedDepartureTime>
+            <ExpectedDepartureTime>2019-02-06T10:08:00</ExpectedDepartureTime>
+            </MonitoredCall>
+            </MonitoredVehicleJourney>
+            </MonitoredStopVisit>
+            </StopMonitoringDelivery>
+            <EstimatedTimetableDelivery version="2.0">
+            <ResponseTimestamp>2019-02-06T10:00:00+00:00</ResponseTimestamp>
+            <EstimatedJourneyVersionFrame>
+            <RecordedAtTime>2019-02-06T10:00:00+00:00</RecordedAtTime>
+            <EstimatedVehicleJourney>
+            <LineRef>l1</LineRef>
+            <FramedVehicleJourneyRef>
+            <DataFrameRef>2019-02-06</DataFrameRef>
+            <DatedVehicleJourneyRef>vj1</DatedVehicleJourneyRef>
+            </FramedVehicleJourneyRef>
+            <JourneyPatternRef>jp1</JourneyPatternRef>
+            <PublishedLineName>Line 1</PublishedLineName>
+            <OperatorRef>op1</OperatorRef>
+            <EstimatedCalls>
+            <EstimatedCall>
+            <StopPointRef>sp1</StopPointRef>
+            <Order>2</Order>
+            <StopPointName>Gare</StopPointName>
+            <PredictionInaccurate>true</PredictionInaccurate>
+            <AimedArrivalTime>2019-02-06T10:05:00</AimedArrivalTime>
+            <ExpectedArrivalTime>2019-02-06T10:07:00</ExpectedArrivalTime>
+            <AimedDepartureTime>2019-02-06T10:06:00</AimedDepartureTime>
+            <ExpectedDepartureTime>2019-02-06T10:08:00</ExpectedDepartureTime>
+            </EstimatedCall>
+            </EstimatedCalls>
+            </EstimatedVehicleJourney>
+            </EstimatedJourneyVersionFrame>
+            </EstimatedTimetableDelivery>
+            </ServiceDelivery>
+            </Siri>"#;
+        let expected: String = expected.lines().map(str::trim).collect();
+        assert_eq!(siri_to_xml(&siri).unwrap(), expected);
+    }
+}
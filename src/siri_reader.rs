@@ -0,0 +1,618 @@
+//! Reading of the SIRI EstimatedTimetable and StopMonitoring xml feeds, as realtime sources
+//!
+//! The SIRI feeds are converted to the same `ModelUpdate` as the GTFS-RT.
+//! The `DatedVehicleJourneyRef` and `StopPointRef` are matched with the base schedule
+//! after the source's `IdStripping`, then with the dataset's `IdMapping`.
+//!
+//! Only the fields needed to update the timetable are read, all the other fields are ignored.
+use crate::datasets::{DatedVehicleJourney, IdStripping};
+use crate::diagnostics::IssueKind;
+use crate::model_update::{IdResolver, ModelUpdate, StopTimeUpdate, TripUpdate};
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, Utc};
+use log::debug;
+use transit_model::collection::Idx;
+use transit_model::objects::{StopTime, VehicleJourney};
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct Siri {
+    #[serde(default)]
+    pub service_delivery: Option<ServiceDelivery>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct ServiceDelivery {
+    #[serde(default)]
+    pub response_timestamp: Option<String>,
+    #[serde(default)]
+    pub estimated_timetable_delivery: Vec<EstimatedTimetableDelivery>,
+    #[serde(default)]
+    pub stop_monitoring_delivery: Vec<StopMonitoringDelivery>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct EstimatedTimetableDelivery {
+    #[serde(default)]
+    pub estimated_journey_version_frame: Vec<EstimatedJourneyVersionFrame>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct EstimatedJourneyVersionFrame {
+    #[serde(default)]
+    pub recorded_at_time: Option<String>,
+    #[serde(default)]
+    pub estimated_vehicle_journey: Vec<EstimatedVehicleJourney>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct FramedVehicleJourneyRef {
+    #[serde(default)]
+    pub data_frame_ref: Option<String>,
+    #[serde(default)]
+    pub dated_vehicle_journey_ref: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct EstimatedVehicleJourney {
+    #[serde(default)]
+    pub framed_vehicle_journey_ref: Option<FramedVehicleJourneyRef>,
+    /// used by some producers instead of the `FramedVehicleJourneyRef`
+    #[serde(default)]
+    pub dated_vehicle_journey_ref: Option<String>,
+    #[serde(default)]
+    pub recorded_at_time: Option<String>,
+    #[serde(default)]
+    pub recorded_calls: Option<RecordedCalls>,
+    #[serde(default)]
+    pub estimated_calls: Option<EstimatedCalls>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct RecordedCalls {
+    #[serde(default)]
+    pub recorded_call: Vec<Call>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct EstimatedCalls {
+    #[serde(default)]
+    pub estimated_call: Vec<Call>,
+}
+
+/// A recorded, estimated or monitored call
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct Call {
+    #[serde(default)]
+    pub stop_point_ref: Option<String>,
+    #[serde(default)]
+    pub order: Option<u32>,
+    #[serde(default)]
+    pub expected_arrival_time: Option<String>,
+    #[serde(default)]
+    pub expected_departure_time: Option<String>,
+    #[serde(default)]
+    pub actual_arrival_time: Option<String>,
+    #[serde(default)]
+    pub actual_departure_time: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct StopMonitoringDelivery {
+    #[serde(default)]
+    pub monitored_stop_visit: Vec<MonitoredStopVisit>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct MonitoredStopVisit {
+    #[serde(default)]
+    pub recorded_at_time: Option<String>,
+    #[serde(default)]
+    pub monitoring_ref: Option<String>,
+    #[serde(default)]
+    pub monitored_vehicle_journey: Option<MonitoredVehicleJourney>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct MonitoredVehicleJourney {
+    #[serde(default)]
+    pub framed_vehicle_journey_ref: Option<FramedVehicleJourneyRef>,
+    #[serde(default)]
+    pub dated_vehicle_journey_ref: Option<String>,
+    #[serde(default)]
+    pub monitored_call: Option<Call>,
+}
+
+/// read a SIRI xml document
+pub fn read_siri(data: &[u8]) -> Result<Siri, Error> {
+    let xml = std::str::from_utf8(data).map_err(|e| anyhow!("invalid siri xml: {}", e))?;
+    quick_xml::de::from_str(xml).map_err(|e| anyhow!("invalid siri xml: {}", e))
+}
+
+/// A SIRI datetime, converted to the local time of the dataset if it has an offset
+fn parse_datetime(datetime: &str, timezone: chrono_tz::Tz) -> Option<NaiveDateTime> {
+    let datetime = datetime.trim();
+    DateTime::<FixedOffset>::parse_from_rfc3339(datetime)
+        .map(|dt| dt.with_timezone(&timezone).naive_local())
+        .or_else(|_| NaiveDateTime::parse_from_str(datetime, "%Y-%m-%dT%H:%M:%S%.f"))
+        .ok()
+}
+
+fn parse_utc_datetime(datetime: &Option<String>) -> Option<DateTime<Utc>> {
+    datetime
+        .as_ref()
+        .and_then(|dt| DateTime::<FixedOffset>::parse_from_rfc3339(dt.trim()).ok())
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
+/// The `DataFrameRef` is usually the date of the vehicle journey
+fn parse_data_frame(data_frame: &str) -> Option<NaiveDate> {
+    let data_frame = data_frame.trim();
+    NaiveDate::parse_from_str(data_frame, "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(data_frame, "%Y%m%d"))
+        .ok()
+}
+
+/// Converts the SIRI feeds to a `ModelUpdate`
+struct SiriConverter<'a> {
+    model: &'a transit_model::Model,
+    id_resolver: &'a IdResolver,
+    id_stripping: &'a IdStripping,
+    timezone: chrono_tz::Tz,
+    now: DateTime<Utc>,
+    model_update: ModelUpdate,
+}
+
+fn stop_time_update(
+    stop_time: &StopTime,
+    call: &Call,
+    timezone: chrono_tz::Tz,
+) -> (u32, StopTimeUpdate) {
+    // the recorded calls only have the actual times
+    let time = |expected: &Option<String>, actual: &Option<String>| {
+        expected
+            .as_ref()
+            .or_else(|| actual.as_ref())
+            .and_then(|t| parse_datetime(t, timezone))
+    };
+    (
+        stop_time.sequence,
+        StopTimeUpdate {
+            stop_point_idx: Some(stop_time.stop_point_idx),
+            updated_arrival: time(&call.expected_arrival_time, &call.actual_arrival_time),
+            updated_departure: time(&call.expected_departure_time, &call.actual_departure_time),
+        },
+    )
+}
+
+impl<'a> SiriConverter<'a> {
+    fn dated_vj(
+        &mut self,
+        framed_ref: &Option<FramedVehicleJourneyRef>,
+        vj_ref: &Option<String>,
+    ) -> Option<(DatedVehicleJourney, String)> {
+        let vj_ref = framed_ref
+            .as_ref()
+            .and_then(|f| f.dated_vehicle_journey_ref.as_ref())
+            .or_else(|| vj_ref.as_ref())?;
+        let vj_idx = match self
+            .id_resolver
+            .vehicle_journey(self.model, self.id_stripping.strip(vj_ref))
+        {
+            Some(vj_idx) => vj_idx,
+            None => {
+                self.model_update.matching_report.add_issue(
+                    IssueKind::UnknownTrip,
+                    vj_ref,
+                    format!("impossible to find vehicle journey {}", vj_ref),
+                );
+                return None;
+            }
+        };
+        let date = framed_ref
+            .as_ref()
+            .and_then(|f| f.data_frame_ref.as_ref())
+            .and_then(|d| parse_data_frame(d))
+            .unwrap_or_else(|| self.now.with_timezone(&self.timezone).date().naive_local());
+        Some((DatedVehicleJourney { vj_idx, date }, vj_ref.clone()))
+    }
+
+    /// Find the stop time of a call, to create its `StopTimeUpdate`.
+    ///
+    /// The call is matched on its stop point, its `Order` (the position of the stop, starting at 1)
+    /// being used for the vehicle journeys going several times by the same stop
+    fn call_update(
+        &mut self,
+        vj_idx: Idx<VehicleJourney>,
+        call: &Call,
+        entity_id: &str,
+    ) -> Option<(u32, StopTimeUpdate)> {
+        let vj = &self.model.vehicle_journeys[vj_idx];
+        let by_order = call
+            .order
+            .and_then(|order| order.checked_sub(1))
+            .and_then(|pos| vj.stop_times.get(pos as usize));
+        let stop_ref = match &call.stop_point_ref {
+            Some(stop_ref) => stop_ref,
+            None => return by_order.map(|st| stop_time_update(st, call, self.timezone)),
+        };
+        let stop_idx = match self
+            .id_resolver
+            .stop_point(self.model, self.id_stripping.strip(stop_ref))
+        {
+            Some(stop_idx) => stop_idx,
+            None => {
+                self.model_update.matching_report.add_issue(
+                    IssueKind::UnknownStop,
+                    entity_id,
+                    format!("impossible to find stop {} for vj {}", stop_ref, vj.id),
+                );
+                return None;
+            }
+        };
+        let stop_time = by_order
+            .filter(|st| st.stop_point_idx == stop_idx)
+            .or_else(|| {
+                vj.stop_times
+                    .iter()
+                    .find(|st| st.stop_point_idx == stop_idx)
+            });
+        match stop_time {
+            Some(st) => Some(stop_time_update(st, call, self.timezone)),
+            None => {
+                self.model_update.matching_report.add_issue(
+                    IssueKind::StopMismatch,
+                    entity_id,
+                    format!("vj {} does not stop at {}", vj.id, stop_ref),
+                );
+                None
+            }
+        }
+    }
+
+    /// add the calls of a vehicle journey to its trip update
+    fn add_calls<'c>(
+        &mut self,
+        dated_vj: DatedVehicleJourney,
+        entity_id: String,
+        update_dt: DateTime<Utc>,
+        calls: impl Iterator<Item = &'c Call>,
+    ) {
+        let mut stop_time_updates = vec![];
+        for call in calls {
+            if let Some((sequence, stu)) = self.call_update(dated_vj.vj_idx, call, &entity_id) {
+                // the calls without realtime time are not updated
+                if stu.updated_arrival.is_some() || stu.updated_departure.is_some() {
+                    stop_time_updates.push((sequence, stu));
+                }
+            }
+        }
+        let trip_update = self
+            .model_update
+            .trips
+            .entry(dated_vj)
+            .or_insert_with(|| TripUpdate {
+                stop_time_update_by_sequence: Default::default(),
+                update_dt,
+                entity_id,
+                estimated: false,
+            });
+        trip_update.update_dt = trip_update.update_dt.max(update_dt);
+        trip_update
+            .stop_time_update_by_sequence
+            .extend(stop_time_updates);
+    }
+
+    fn add_service_delivery(&mut self, delivery: &ServiceDelivery) {
+        let response_dt = parse_utc_datetime(&delivery.response_timestamp).unwrap_or(self.now);
+        for frame in delivery
+            .estimated_timetable_delivery
+            .iter()
+            .flat_map(|d| &d.estimated_journey_version_frame)
+        {
+            let frame_dt = parse_utc_datetime(&frame.recorded_at_time).unwrap_or(response_dt);
+            for journey in &frame.estimated_vehicle_journey {
+                self.model_update.matching_report.nb_trip_updates += 1;
+                let (dated_vj, entity_id) = match self.dated_vj(
+                    &journey.framed_vehicle_journey_ref,
+                    &journey.dated_vehicle_journey_ref,
+                ) {
+                    Some(dated_vj) => dated_vj,
+                    None => continue,
+                };
+                self.model_update.matching_report.nb_matched_trip_updates += 1;
+                let update_dt = parse_utc_datetime(&journey.recorded_at_time).unwrap_or(frame_dt);
+                let recorded_calls = journey.recorded_calls.iter().flat_map(|c| &c.recorded_call);
+                let estimated_calls = journey
+                    .estimated_calls
+                    .iter()
+                    .flat_map(|c| &c.estimated_call);
+                self.add_calls(
+                    dated_vj,
+                    entity_id,
+                    update_dt,
+                    recorded_calls.chain(estimated_calls),
+                );
+            }
+        }
+
+        for visit in delivery
+            .stop_monitoring_delivery
+            .iter()
+            .flat_map(|d| &d.monitored_stop_visit)
+        {
+            let journey = match &visit.monitored_vehicle_journey {
+                Some(journey) => journey,
+                None => continue,
+            };
+            self.model_update.matching_report.nb_trip_updates += 1;
+            let (dated_vj, entity_id) = match self.dated_vj(
+                &journey.framed_vehicle_journey_ref,
+                &journey.dated_vehicle_journey_ref,
+            ) {
+                Some(dated_vj) => dated_vj,
+                None => continue,
+            };
+            self.model_update.matching_report.nb_matched_trip_updates += 1;
+            let update_dt = parse_utc_datetime(&visit.recorded_at_time).unwrap_or(response_dt);
+            let call = journey.monitored_call.as_ref().map(|call| Call {
+                // the monitored call is at the monitored stop if it does not give its stop
+                stop_point_ref: call
+                    .stop_point_ref
+                    .clone()
+                    .or_else(|| visit.monitoring_ref.clone()),
+                order: call.order,
+                expected_arrival_time: call.expected_arrival_time.clone(),
+                expected_departure_time: call.expected_departure_time.clone(),
+                actual_arrival_time: call.actual_arrival_time.clone(),
+                actual_departure_time: call.actual_departure_time.clone(),
+            });
+            self.add_calls(dated_vj, entity_id, update_dt, call.iter());
+        }
+    }
+}
+
+/// Create a `ModelUpdate` from some SIRI EstimatedTimetable or StopMonitoring documents.
+///
+/// `now` is used for the vehicle journeys without date
+pub fn get_model_update(
+    model: &transit_model::Model,
+    id_resolver: &IdResolver,
+    id_stripping: &IdStripping,
+    siris: &[Siri],
+    timezone: chrono_tz::Tz,
+    now: DateTime<Utc>,
+) -> ModelUpdate {
+    let mut converter = SiriConverter {
+        model,
+        id_resolver,
+        id_stripping,
+        timezone,
+        now,
+        model_update: ModelUpdate::default(),
+    };
+    for delivery in siris.iter().filter_map(|s| s.service_delivery.as_ref()) {
+        converter.add_service_delivery(delivery);
+    }
+    debug!(
+        "{} trip updates read from the siri feeds",
+        converter.model_update.trips.len()
+    );
+    converter.model_update
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transit_model_builder::ModelBuilder;
+
+    fn ndt(d: &str) -> NaiveDateTime {
+        d.parse().unwrap()
+    }
+
+    fn make_model() -> transit_model::Model {
+        ModelBuilder::default()
+            .vj("vj1", |vj_builder| {
+                vj_builder
+                    .st("A", "10:00:00", "10:01:00")
+                    .st("B", "11:00:00", "11:01:00")
+                    .st("C", "12:00:00", "12:01:00");
+            })
+            .build()
+    }
+
+    fn stripping() -> IdStripping {
+        IdStripping {
+            prefix: Some("TEST:".to_owned()),
+            suffix: Some(":LOC".to_owned()),
+            separator: Some(":".to_owned()),
+        }
+    }
+
+    fn update_of<'a>(
+        model_update: &'a ModelUpdate,
+        model: &transit_model::Model,
+        stop: &str,
+    ) -> &'a StopTimeUpdate {
+        model_update
+            .trips
+            .values()
+            .flat_map(|tu| tu.stop_time_update_by_sequence.values())
+            .find(|stu| stu.stop_point_idx == model.stop_points.get_idx(stop))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_id_stripping() {
+        let stripping = stripping();
+        assert_eq!(stripping.strip("TEST:ServiceJourney:vj1:LOC"), "vj1");
+        assert_eq!(stripping.strip("vj1"), "vj1");
+        assert_eq!(IdStripping::default().strip(" a:b "), "a:b");
+    }
+
+    #[test]
+    fn test_estimated_timetable() {
+        let model = make_model();
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<Siri xmlns="http://www.siri.org.uk/siri" version="2.0">
+  <ServiceDelivery>
+    <ResponseTimestamp>2018-12-15T10:30:00Z</ResponseTimestamp>
+    <EstimatedTimetableDelivery version="2.0">
+      <EstimatedJourneyVersionFrame>
+        <RecordedAtTime>2018-12-15T10:29:00Z</RecordedAtTime>
+        <EstimatedVehicleJourney>
+          <LineRef>TEST:Line:l1:LOC</LineRef>
+          <FramedVehicleJourneyRef>
+            <DataFrameRef>2018-12-15</DataFrameRef>
+            <DatedVehicleJourneyRef>TEST:ServiceJourney:vj1:LOC</DatedVehicleJourneyRef>
+          </FramedVehicleJourneyRef>
+          <RecordedCalls>
+            <RecordedCall>
+              <StopPointRef>TEST:StopPoint:A:LOC</StopPointRef>
+              <Order>1</Order>
+              <ActualDepartureTime>2018-12-15T10:02:00Z</ActualDepartureTime>
+            </RecordedCall>
+          </RecordedCalls>
+          <EstimatedCalls>
+            <EstimatedCall>
+              <StopPointRef>TEST:StopPoint:B:LOC</StopPointRef>
+              <Order>2</Order>
+              <AimedArrivalTime>2018-12-15T11:00:00Z</AimedArrivalTime>
+              <ExpectedArrivalTime>2018-12-15T12:05:00+01:00</ExpectedArrivalTime>
+              <ExpectedDepartureTime>2018-12-15T11:06:00</ExpectedDepartureTime>
+            </EstimatedCall>
+            <EstimatedCall>
+              <StopPointRef>TEST:StopPoint:unknown:LOC</StopPointRef>
+              <Order>3</Order>
+              <ExpectedArrivalTime>2018-12-15T12:05:00Z</ExpectedArrivalTime>
+            </EstimatedCall>
+          </EstimatedCalls>
+        </EstimatedVehicleJourney>
+        <EstimatedVehicleJourney>
+          <FramedVehicleJourneyRef>
+            <DataFrameRef>2018-12-15</DataFrameRef>
+            <DatedVehicleJourneyRef>TEST:ServiceJourney:unknown:LOC</DatedVehicleJourneyRef>
+          </FramedVehicleJourneyRef>
+        </EstimatedVehicleJourney>
+      </EstimatedJourneyVersionFrame>
+    </EstimatedTimetableDelivery>
+  </ServiceDelivery>
+</Siri>"#;
+        let siri = read_siri(xml.as_bytes()).unwrap();
+        let model_update = get_model_update(
+            &model,
+            &IdResolver::default(),
+            &stripping(),
+            &[siri],
+            chrono_tz::UTC,
+            Utc::now(),
+        );
+
+        assert_eq!(model_update.trips.len(), 1);
+        let dated_vj = DatedVehicleJourney {
+            vj_idx: model.vehicle_journeys.get_idx("vj1").unwrap(),
+            date: NaiveDate::from_ymd(2018, 12, 15),
+        };
+        let trip_update = &model_update.trips[&dated_vj];
+        assert!(!trip_update.estimated);
+        assert_eq!(
+            trip_update.update_dt.to_rfc3339(),
+            "2018-12-15T10:29:00+00:00"
+        );
+        assert_eq!(trip_update.stop_time_update_by_sequence.len(), 2);
+        assert_eq!(
+            update_of(&model_update, &model, "A").updated_departure,
+            Some(ndt("2018-12-15T10:02:00"))
+        );
+        let b = update_of(&model_update, &model, "B");
+        assert_eq!(b.updated_arrival, Some(ndt("2018-12-15T11:05:00")));
+        assert_eq!(b.updated_departure, Some(ndt("2018-12-15T11:06:00")));
+
+        let report = &model_update.matching_report;
+        assert_eq!(report.nb_trip_updates, 2);
+        assert_eq!(report.nb_matched_trip_updates, 1);
+        let kinds: Vec<_> = report.issues.iter().map(|i| i.kind).collect();
+        assert_eq!(kinds, vec![IssueKind::UnknownStop, IssueKind::UnknownTrip]);
+    }
+
+    #[test]
+    fn test_stop_monitoring() {
+        let model = make_model();
+        let xml = r#"<Siri>
+  <ServiceDelivery>
+    <ResponseTimestamp>2018-12-15T10:30:00Z</ResponseTimestamp>
+    <StopMonitoringDelivery>
+      <MonitoredStopVisit>
+        <RecordedAtTime>2018-12-15T10:28:00Z</RecordedAtTime>
+        <MonitoringRef>TEST:StopPoint:B:LOC</MonitoringRef>
+        <MonitoredVehicleJourney>
+          <FramedVehicleJourneyRef>
+            <DataFrameRef>2018-12-15</DataFrameRef>
+            <DatedVehicleJourneyRef>TEST:ServiceJourney:vj1:LOC</DatedVehicleJourneyRef>
+          </FramedVehicleJourneyRef>
+          <MonitoredCall>
+            <ExpectedArrivalTime>2018-12-15T11:03:00Z</ExpectedArrivalTime>
+            <ExpectedDepartureTime>2018-12-15T11:04:00Z</ExpectedDepartureTime>
+          </MonitoredCall>
+        </MonitoredVehicleJourney>
+      </MonitoredStopVisit>
+      <MonitoredStopVisit>
+        <MonitoringRef>TEST:StopPoint:C:LOC</MonitoringRef>
+        <MonitoredVehicleJourney>
+          <FramedVehicleJourneyRef>
+            <DataFrameRef>2018-12-15</DataFrameRef>
+            <DatedVehicleJourneyRef>TEST:ServiceJourney:vj1:LOC</DatedVehicleJourneyRef>
+          </FramedVehicleJourneyRef>
+          <MonitoredCall>
+            <StopPointRef>TEST:StopPoint:C:LOC</StopPointRef>
+            <ExpectedArrivalTime>2018-12-15T12:03:00Z</ExpectedArrivalTime>
+          </MonitoredCall>
+        </MonitoredVehicleJourney>
+      </MonitoredStopVisit>
+    </StopMonitoringDelivery>
+  </ServiceDelivery>
+</Siri>"#;
+        let siri = read_siri(xml.as_bytes()).unwrap();
+        let model_update = get_model_update(
+            &model,
+            &IdResolver::default(),
+            &stripping(),
+            &[siri],
+            chrono_tz::UTC,
+            Utc::now(),
+        );
+
+        // the visits of the same vehicle journey are merged in one trip update
+        assert_eq!(model_update.trips.len(), 1);
+        let trip_update = model_update.trips.values().next().unwrap();
+        assert_eq!(trip_update.stop_time_update_by_sequence.len(), 2);
+        assert_eq!(
+            trip_update.update_dt.to_rfc3339(),
+            "2018-12-15T10:30:00+00:00"
+        );
+        let b = update_of(&model_update, &model, "B");
+        assert_eq!(b.updated_arrival, Some(ndt("2018-12-15T11:03:00")));
+        assert_eq!(b.updated_departure, Some(ndt("2018-12-15T11:04:00")));
+        assert_eq!(
+            update_of(&model_update, &model, "C").updated_arrival,
+            Some(ndt("2018-12-15T12:03:00"))
+        );
+        assert!(model_update.matching_report.issues.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_siri() {
+        assert!(read_siri(b"not some xml <").is_err());
+    }
+}
@@ -0,0 +1,198 @@
+//! Spatial index on the stop points of a dataset
+//!
+//! The stop points are put in a regular grid of `CELL_SIZE` degrees,
+//! so the bounding box and proximity queries only look at the stop points
+//! of the cells covering the queried area.
+use std::collections::HashMap;
+use transit_model::collection::Idx;
+use transit_model::objects::{Coord, StopPoint};
+use transit_model::Model;
+
+/// size of a cell in degrees (about 1km in latitude)
+const CELL_SIZE: f64 = 0.01;
+/// above this number of cells, it is cheaper to scan all the stop points
+const MAX_CELLS_BY_QUERY: i64 = 10_000;
+const EARTH_RADIUS: f64 = 6_371_000.;
+pub(crate) const METERS_BY_DEGREE: f64 = 111_320.;
+
+type Cell = (i32, i32);
+
+fn cell(lon: f64, lat: f64) -> Cell {
+    ((lon / CELL_SIZE).floor() as i32, (lat / CELL_SIZE).floor() as i32)
+}
+
+/// distance in meters between 2 coordinates (haversine formula)
+pub fn distance(from: &Coord, to: &Coord) -> f64 {
+    let phi1 = from.lat.to_radians();
+    let phi2 = to.lat.to_radians();
+    let delta_phi = (to.lat - from.lat).to_radians();
+    let delta_lambda = (to.lon - from.lon).to_radians();
+    let a = (delta_phi / 2.).sin().powi(2)
+        + phi1.cos() * phi2.cos() * (delta_lambda / 2.).sin().powi(2);
+    2. * EARTH_RADIUS * a.sqrt().asin()
+}
+
+#[derive(Default)]
+pub struct StopPointsGrid {
+    cells: HashMap<Cell, Vec<(Idx<StopPoint>, Coord)>>,
+}
+
+impl StopPointsGrid {
+    pub fn new(model: &Model) -> Self {
+        Self::from_coords(model.stop_points.iter().map(|(idx, sp)| {
+            (
+                idx,
+                Coord {
+                    lon: sp.coord.lon,
+                    lat: sp.coord.lat,
+                },
+            )
+        }))
+    }
+
+    fn from_coords(coords: impl Iterator<Item = (Idx<StopPoint>, Coord)>) -> Self {
+        let mut grid = Self::default();
+        for (idx, coord) in coords {
+            grid.cells
+                .entry(cell(coord.lon, coord.lat))
+                .or_insert_with(Vec::new)
+                .push((idx, coord));
+        }
+        grid
+    }
+
+    fn candidates<'a>(
+        &'a self,
+        min_lon: f64,
+        max_lon: f64,
+        min_lat: f64,
+        max_lat: f64,
+    ) -> Box<dyn Iterator<Item = &'a (Idx<StopPoint>, Coord)> + 'a> {
+        let (min_x, min_y) = cell(min_lon, min_lat);
+        let (max_x, max_y) = cell(max_lon, max_lat);
+        let nb_cells = (i64::from(max_x) - i64::from(min_x) + 1)
+            * (i64::from(max_y) - i64::from(min_y) + 1);
+        if nb_cells > MAX_CELLS_BY_QUERY || nb_cells as usize > self.cells.len() {
+            Box::new(self.cells.values().flatten())
+        } else {
+            Box::new(
+                (min_x..=max_x)
+                    .flat_map(move |x| (min_y..=max_y).map(move |y| (x, y)))
+                    .filter_map(move |c| self.cells.get(&c))
+                    .flatten(),
+            )
+        }
+    }
+
+    /// stop points inside a bounding box
+    pub fn in_bounding_box(
+        &self,
+        min_lon: f64,
+        max_lon: f64,
+        min_lat: f64,
+        max_lat: f64,
+    ) -> Vec<Idx<StopPoint>> {
+        let mut stop_points: Vec<_> = self
+            .candidates(min_lon, max_lon, min_lat, max_lat)
+            .filter(|(_, c)| c.lon >= min_lon && c.lon <= max_lon)
+            .filter(|(_, c)| c.lat >= min_lat && c.lat <= max_lat)
+            .map(|(idx, _)| *idx)
+            .collect();
+        // to have a stable order between the queries
+        stop_points.sort();
+        stop_points
+    }
+
+    /// stop points at less than `radius` meters from `center`, with their distance, sorted by distance
+    pub fn around(&self, center: &Coord, radius: f64) -> Vec<(Idx<StopPoint>, f64)> {
+        let delta_lat = radius / METERS_BY_DEGREE;
+        // near the poles, the longitude delta is unbounded
+        let delta_lon = radius / (METERS_BY_DEGREE * center.lat.to_radians().cos().max(0.01));
+        let mut stop_points: Vec<_> = self
+            .candidates(
+                center.lon - delta_lon,
+                center.lon + delta_lon,
+                center.lat - delta_lat,
+                center.lat + delta_lat,
+            )
+            .map(|(idx, c)| (*idx, distance(center, c)))
+            .filter(|(_, d)| *d <= radius)
+            .collect();
+        stop_points.sort_by(|(idx1, d1), (idx2, d2)| {
+            d1.partial_cmp(d2)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| idx1.cmp(idx2))
+        });
+        stop_points
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{distance, StopPointsGrid};
+    use transit_model::objects::Coord;
+    use transit_model_builder::ModelBuilder;
+
+    #[test]
+    fn test_distance() {
+        let paris = Coord {
+            lon: 2.3522,
+            lat: 48.8566,
+        };
+        let lyon = Coord {
+            lon: 4.8357,
+            lat: 45.7640,
+        };
+        let d = distance(&paris, &lyon);
+        assert!((d - 392_000.).abs() < 2_000., "distance: {}", d);
+    }
+
+    #[test]
+    fn test_grid_queries() {
+        let model = ModelBuilder::default()
+            .vj("vj1", |vj_builder| {
+                vj_builder
+                    .st("center", "10:00:00", "10:01:00")
+                    .st("close", "11:00:00", "11:01:00")
+                    .st("other_cell", "12:00:00", "12:01:00")
+                    .st("far", "13:00:00", "13:01:00");
+            })
+            .build();
+        let coords = vec![
+            ("center", 2.35, 48.85),
+            ("close", 2.351, 48.851),
+            ("other_cell", 2.356, 48.861),
+            ("far", 2.45, 48.95),
+        ];
+        let grid = StopPointsGrid::from_coords(coords.into_iter().map(|(id, lon, lat)| {
+            (
+                model.stop_points.get_idx(id).unwrap(),
+                Coord { lon, lat },
+            )
+        }));
+        let ids = |idxs: Vec<_>| -> Vec<String> {
+            idxs.into_iter()
+                .map(|idx| model.stop_points[idx].id.clone())
+                .collect()
+        };
+
+        let around = grid.around(
+            &Coord {
+                lon: 2.35,
+                lat: 48.85,
+            },
+            1_500.,
+        );
+        assert_eq!(
+            ids(around.iter().map(|(idx, _)| *idx).collect()),
+            vec!["center", "close", "other_cell"]
+        );
+        assert_eq!(around[0].1, 0.);
+
+        let mut in_bbox = ids(grid.in_bounding_box(2.3505, 2.36, 48.8505, 48.87));
+        in_bbox.sort();
+        assert_eq!(in_bbox, vec!["close", "other_cell"]);
+        // a huge bounding box scans all the stop points
+        assert_eq!(grid.in_bounding_box(-180., 180., -90., 90.).len(), 4);
+    }
+}
@@ -0,0 +1,639 @@
+//! Historical punctuality statistics
+//!
+//! The `UpdatedTimetable` only holds the current realtime data, so the last known realtime times
+//! of each connection are tracked, and once the connection has passed, they are stored as an `Observation`
+//! (one json line by observation, in one file by day).
+//! The statistics are then computed from the stored observations.
+use crate::datasets::{Dataset, RealTimeConnection, RealTimeDataset, ScheduleRelationship};
+use anyhow::{Context, Error};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Timelike, Utc};
+use openapi_schema::OpenapiSchema;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// a connection is considered as passed (and its realtime data final) this long after its scheduled departure
+const FINALIZATION_DELAY_MINUTES: i64 = 60;
+/// a connection is on time if its delay is between those bounds
+const MAX_ADVANCE_ON_TIME_SECONDS: i64 = 60;
+const MAX_DELAY_ON_TIME_SECONDS: i64 = 5 * 60;
+/// bounds (in seconds) of the delay distribution's buckets
+const DELAY_BUCKETS: [i64; 6] = [-60, 60, 3 * 60, 5 * 60, 10 * 60, 20 * 60];
+
+/// Final state of a passed connection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Observation {
+    pub line_id: Option<String>,
+    pub stop_id: String,
+    pub vehicle_journey_id: String,
+    pub date: NaiveDate,
+    /// scheduled departure, in the dataset's timezone
+    pub scheduled: NaiveDateTime,
+    /// delay of the last known realtime departure (or arrival, compared to the scheduled arrival),
+    /// if there were some realtime data
+    pub delay_seconds: Option<i64>,
+    pub cancelled: bool,
+}
+
+/// Observations of a dataset, stored in one file by day
+#[derive(Clone, Debug)]
+pub struct StatsStore {
+    directory: PathBuf,
+}
+
+impl StatsStore {
+    pub fn new(directory: &Path) -> Self {
+        Self {
+            directory: directory.to_owned(),
+        }
+    }
+
+    fn day_file(&self, date: NaiveDate) -> PathBuf {
+        self.directory
+            .join(format!("{}.jsonl", date.format("%Y-%m-%d")))
+    }
+
+    pub fn append(&self, observations: &[Observation]) -> Result<(), Error> {
+        std::fs::create_dir_all(&self.directory).with_context(|| {
+            format!(
+                "impossible to create stats directory {}",
+                self.directory.display()
+            )
+        })?;
+        let mut by_date = BTreeMap::<NaiveDate, Vec<&Observation>>::new();
+        for o in observations {
+            by_date.entry(o.date).or_default().push(o);
+        }
+        for (date, observations) in by_date {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.day_file(date))?;
+            let mut writer = std::io::BufWriter::new(file);
+            for o in observations {
+                serde_json::to_writer(&mut writer, o)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// read the observations of the trips between `from` and `to` (included)
+    pub fn read(&self, from: NaiveDate, to: NaiveDate) -> Result<Vec<Observation>, Error> {
+        let mut observations = vec![];
+        self.for_each(from, to, |o| observations.push(o))?;
+        Ok(observations)
+    }
+
+    /// call `f` on each observation of the trips between `from` and `to` (included),
+    /// as they are read, without keeping them in memory
+    pub fn for_each(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+        mut f: impl FnMut(Observation),
+    ) -> Result<(), Error> {
+        let mut date = from;
+        while date <= to {
+            let path = self.day_file(date);
+            if path.exists() {
+                let file = std::io::BufReader::new(std::fs::File::open(&path)?);
+                for line in file.lines() {
+                    let line = line?;
+                    match serde_json::from_str(&line) {
+                        Ok(o) => f(o),
+                        Err(e) => log::warn!("invalid observation in {}: {}", path.display(), e),
+                    }
+                }
+            }
+            date = date.succ();
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct TrackerState {
+    /// load datetime of the base schedule of the tracked connections,
+    /// the connections indexes are not valid anymore if the base schedule is reloaded
+    base_schedule_loaded_at: Option<DateTime<Utc>>,
+    /// the connections scheduled before are already stored
+    watermark: Option<NaiveDateTime>,
+    /// last known realtime data of the connections not yet passed
+    last_known: HashMap<usize, RealTimeConnection>,
+}
+
+/// Keep the last known realtime data of the connections, and store them once the connections have passed
+pub struct PunctualityTracker {
+    store: StatsStore,
+    state: Mutex<TrackerState>,
+}
+
+fn observation(
+    dataset: &Dataset,
+    idx: usize,
+    realtime: Option<&RealTimeConnection>,
+) -> Observation {
+    let connection = &dataset.timetable.connections[idx];
+    let vj = &dataset.ntm.vehicle_journeys[connection.dated_vj.vj_idx];
+    let delay_seconds = realtime.and_then(|rt| {
+        rt.dep_time
+            .map(|dt| dt - connection.dep_time)
+            .or_else(|| rt.arr_time.map(|dt| dt - connection.arr_time))
+            .map(|delay| delay.num_seconds())
+    });
+    Observation {
+        line_id: dataset
+            .ntm
+            .routes
+            .get(&vj.route_id)
+            .map(|r| r.line_id.clone()),
+        stop_id: dataset.ntm.stop_points[connection.stop_point_idx]
+            .id
+            .clone(),
+        vehicle_journey_id: vj.id.clone(),
+        date: connection.dated_vj.date,
+        scheduled: connection.dep_time,
+        delay_seconds,
+        cancelled: realtime
+            .map(|rt| matches!(rt.schedule_relationship, ScheduleRelationship::Skipped))
+            .unwrap_or(false),
+    }
+}
+
+impl PunctualityTracker {
+    pub fn new(store: StatsStore) -> Self {
+        Self {
+            store,
+            state: Mutex::new(TrackerState::default()),
+        }
+    }
+
+    pub fn store(&self) -> &StatsStore {
+        &self.store
+    }
+
+    /// Track the realtime data of a new `RealTimeDataset`, and return the observations of the connections passed at `now`.
+    /// The observations are not stored, as writing them is blocking (cf. `StatsStore::append`).
+    ///
+    /// Note: on the first call, the connections already passed are not returned
+    pub fn track(&self, rt_dataset: &RealTimeDataset, now: DateTime<Utc>) -> Vec<Observation> {
+        let dataset = match rt_dataset.base_schedule_dataset.as_ref() {
+            Ok(dataset) => dataset,
+            Err(_) => return vec![],
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.base_schedule_loaded_at != Some(dataset.loaded_at) {
+            state.base_schedule_loaded_at = Some(dataset.loaded_at);
+            state.last_known.clear();
+        }
+        for (idx, rt) in &rt_dataset.updated_timetable.realtime_connections {
+            state.last_known.insert(*idx, rt.clone());
+        }
+
+        let limit = now.with_timezone(&dataset.timezone).naive_local()
+            - chrono::Duration::minutes(FINALIZATION_DELAY_MINUTES);
+        let watermark = match state.watermark {
+            Some(watermark) => watermark,
+            None => {
+                state.watermark = Some(limit);
+                return vec![];
+            }
+        };
+        let passed: Vec<usize> = dataset
+            .timetable
+            .connections
+            .iter()
+            .enumerate()
+            .skip_while(|(_, c)| c.dep_time <= watermark)
+            .take_while(|(_, c)| c.dep_time <= limit)
+            .map(|(idx, _)| idx)
+            .collect();
+        let observations: Vec<Observation> = passed
+            .iter()
+            .map(|idx| observation(dataset, *idx, state.last_known.get(idx)))
+            .collect();
+        for idx in &passed {
+            state.last_known.remove(idx);
+        }
+        state.watermark = Some(limit.max(watermark));
+        observations
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, OpenapiSchema)]
+pub struct DelayBucket {
+    /// lower bound (included) of the delays of the bucket, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_seconds: Option<i64>,
+    /// upper bound (excluded) of the delays of the bucket, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_seconds: Option<i64>,
+    pub count: usize,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, OpenapiSchema)]
+pub struct PunctualityStats {
+    pub nb_connections: usize,
+    /// number of connections with some realtime data
+    pub nb_observed_connections: usize,
+    /// percentage of the observed connections with a delay between -1 and 5 minutes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_time_percentage: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub average_delay_seconds: Option<f64>,
+    pub delay_distribution: Vec<DelayBucket>,
+    /// ratio of the cancelled connections
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cancellation_rate: Option<f64>,
+}
+
+/// Counters of some observations, from which their statistics are computed
+/// (the observations are added one by one, without being kept)
+#[derive(Debug, Clone, Default)]
+struct Accumulator {
+    nb_connections: usize,
+    nb_cancelled: usize,
+    nb_observed: usize,
+    nb_on_time: usize,
+    sum_delays: i64,
+    /// number of delays in each bucket, the first and the last buckets being unbounded
+    bucket_counts: [usize; DELAY_BUCKETS.len() + 1],
+}
+
+impl Accumulator {
+    fn add(&mut self, o: &Observation) {
+        self.nb_connections += 1;
+        if o.cancelled {
+            self.nb_cancelled += 1;
+        } else if let Some(delay) = o.delay_seconds {
+            self.nb_observed += 1;
+            self.sum_delays += delay;
+            if (-MAX_ADVANCE_ON_TIME_SECONDS..=MAX_DELAY_ON_TIME_SECONDS).contains(&delay) {
+                self.nb_on_time += 1;
+            }
+            let bucket = DELAY_BUCKETS.iter().filter(|b| delay >= **b).count();
+            self.bucket_counts[bucket] += 1;
+        }
+    }
+
+    fn stats(&self) -> PunctualityStats {
+        let ratio = |n: usize, total: usize| {
+            if total == 0 {
+                None
+            } else {
+                Some(n as f64 / total as f64)
+            }
+        };
+        let bounds: Vec<Option<i64>> = std::iter::once(None)
+            .chain(DELAY_BUCKETS.iter().copied().map(Some))
+            .chain(std::iter::once(None))
+            .collect();
+        let delay_distribution = bounds
+            .windows(2)
+            .zip(self.bucket_counts.iter())
+            .map(|(w, count)| DelayBucket {
+                min_seconds: w[0],
+                max_seconds: w[1],
+                count: *count,
+            })
+            .collect();
+
+        PunctualityStats {
+            nb_connections: self.nb_connections,
+            nb_observed_connections: self.nb_observed,
+            on_time_percentage: ratio(self.nb_on_time, self.nb_observed).map(|r| r * 100.),
+            average_delay_seconds: if self.nb_observed == 0 {
+                None
+            } else {
+                Some(self.sum_delays as f64 / self.nb_observed as f64)
+            },
+            delay_distribution,
+            cancellation_rate: ratio(self.nb_cancelled, self.nb_connections),
+        }
+    }
+}
+
+impl PunctualityStats {
+    pub fn new<'a>(observations: impl Iterator<Item = &'a Observation>) -> Self {
+        let mut accumulator = Accumulator::default();
+        for o in observations {
+            accumulator.add(o);
+        }
+        accumulator.stats()
+    }
+}
+
+/// How the observations are grouped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupBy {
+    Line,
+    Stop,
+    Hour,
+}
+
+impl std::str::FromStr for GroupBy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "line" => Ok(GroupBy::Line),
+            "stop" => Ok(GroupBy::Stop),
+            "hour" => Ok(GroupBy::Hour),
+            _ => Err(anyhow::anyhow!(
+                "invalid group '{}', it should be 'line', 'stop' or 'hour'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, OpenapiSchema)]
+pub struct GroupKey {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_ref: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_point_ref: Option<String>,
+    /// hour of the scheduled departure, in the dataset's timezone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hour: Option<u32>,
+}
+
+#[derive(Serialize, Debug, Clone, OpenapiSchema)]
+pub struct GroupStats {
+    #[serde(flatten)]
+    pub key: GroupKey,
+    #[serde(flatten)]
+    pub stats: PunctualityStats,
+}
+
+/// Statistics of some observations, for all of them and by group,
+/// computed while the observations are added
+pub struct StatsAggregator {
+    group_by: Vec<GroupBy>,
+    total: Accumulator,
+    groups: BTreeMap<GroupKey, Accumulator>,
+}
+
+impl StatsAggregator {
+    pub fn new(group_by: &[GroupBy]) -> Self {
+        Self {
+            group_by: group_by.to_vec(),
+            total: Accumulator::default(),
+            groups: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, o: &Observation) {
+        let mut key = GroupKey::default();
+        for g in &self.group_by {
+            match g {
+                GroupBy::Line => key.line_ref = Some(o.line_id.clone().unwrap_or_default()),
+                GroupBy::Stop => key.stop_point_ref = Some(o.stop_id.clone()),
+                GroupBy::Hour => key.hour = Some(o.scheduled.hour()),
+            }
+        }
+        self.total.add(o);
+        self.groups.entry(key).or_default().add(o);
+    }
+
+    /// statistics of all the observations
+    pub fn total(&self) -> PunctualityStats {
+        self.total.stats()
+    }
+
+    /// statistics of each group, ordered by group
+    pub fn groups(&self) -> Vec<GroupStats> {
+        self.groups
+            .iter()
+            .map(|(key, accumulator)| GroupStats {
+                key: key.clone(),
+                stats: accumulator.stats(),
+            })
+            .collect()
+    }
+}
+
+/// Compute the statistics of the observations, grouped by `group_by`
+pub fn group_stats(observations: &[Observation], group_by: &[GroupBy]) -> Vec<GroupStats> {
+    let mut aggregator = StatsAggregator::new(group_by);
+    for o in observations {
+        aggregator.add(o);
+    }
+    aggregator.groups()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasets::{DatasetInfo, Period, UpdatedTimetable};
+    use chrono::TimeZone;
+    use std::sync::Arc;
+
+    fn observation(line: &str, stop: &str, scheduled: &str, delay: Option<i64>) -> Observation {
+        let scheduled: NaiveDateTime = scheduled.parse().unwrap();
+        Observation {
+            line_id: Some(line.to_owned()),
+            stop_id: stop.to_owned(),
+            vehicle_journey_id: "vj".to_owned(),
+            date: scheduled.date(),
+            scheduled,
+            delay_seconds: delay,
+            cancelled: false,
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut cancelled = observation("l1", "A", "2020-03-10T08:00:00", None);
+        cancelled.cancelled = true;
+        let observations = vec![
+            observation("l1", "A", "2020-03-10T08:00:00", Some(30)),
+            observation("l1", "B", "2020-03-10T08:10:00", Some(600)),
+            observation("l1", "C", "2020-03-10T09:10:00", Some(-120)),
+            observation("l1", "D", "2020-03-10T09:20:00", None),
+            cancelled,
+            observation("l2", "A", "2020-03-10T09:00:00", Some(0)),
+        ];
+
+        let stats = group_stats(&observations, &[GroupBy::Line]);
+        assert_eq!(stats.len(), 2);
+        let l1 = &stats[0].stats;
+        assert_eq!(stats[0].key.line_ref.as_deref(), Some("l1"));
+        assert_eq!(l1.nb_connections, 5);
+        assert_eq!(l1.nb_observed_connections, 3);
+        assert!((l1.on_time_percentage.unwrap() - 100. / 3.).abs() < 1e-6);
+        assert_eq!(l1.average_delay_seconds, Some(170.));
+        assert_eq!(l1.cancellation_rate, Some(0.2));
+        let counts: Vec<usize> = l1.delay_distribution.iter().map(|b| b.count).collect();
+        assert_eq!(counts, vec![1, 1, 0, 0, 0, 1, 0]);
+
+        // the total is computed along the groups
+        let mut aggregator = StatsAggregator::new(&[GroupBy::Line]);
+        for o in &observations {
+            aggregator.add(o);
+        }
+        assert_eq!(
+            aggregator.total(),
+            PunctualityStats::new(observations.iter())
+        );
+        assert_eq!(aggregator.total().nb_connections, 6);
+        let counts: Vec<usize> = aggregator
+            .total()
+            .delay_distribution
+            .iter()
+            .map(|b| b.count)
+            .collect();
+        assert_eq!(counts, vec![1, 2, 0, 0, 0, 1, 0]);
+
+        let stats = group_stats(&observations, &[GroupBy::Line, GroupBy::Hour]);
+        let keys: Vec<_> = stats
+            .iter()
+            .map(|s| (s.key.line_ref.clone().unwrap(), s.key.hour.unwrap()))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                ("l1".to_owned(), 8),
+                ("l1".to_owned(), 9),
+                ("l2".to_owned(), 9)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StatsStore::new(dir.path());
+        let first_day = observation("l1", "A", "2020-03-10T23:00:00", Some(30));
+        let second_day = observation("l1", "B", "2020-03-11T01:00:00", Some(60));
+        store
+            .append(&[first_day.clone(), second_day.clone()])
+            .unwrap();
+
+        let d = |s: &str| s.parse::<NaiveDate>().unwrap();
+        assert_eq!(
+            store.read(d("2020-03-10"), d("2020-03-10")).unwrap(),
+            vec![first_day.clone()]
+        );
+        assert_eq!(
+            store.read(d("2020-03-09"), d("2020-03-12")).unwrap(),
+            vec![first_day, second_day]
+        );
+    }
+
+    fn realtime_connection(dep_time: Option<&str>, arr_time: Option<&str>) -> RealTimeConnection {
+        RealTimeConnection {
+            dep_time: dep_time.map(|t| t.parse().unwrap()),
+            arr_time: arr_time.map(|t| t.parse().unwrap()),
+            schedule_relationship: ScheduleRelationship::Scheduled,
+            update_time: Utc::now(),
+            estimated: false,
+        }
+    }
+
+    // realtime dataset with some realtime connections, given by trip and stop sequence
+    fn rt_dataset(
+        dataset: &Arc<Result<Dataset, Error>>,
+        updates: Vec<(&str, u32, RealTimeConnection)>,
+    ) -> RealTimeDataset {
+        let data = dataset.as_ref().as_ref().unwrap();
+        let mut updated_timetable = UpdatedTimetable::default();
+        for (trip, sequence, rt) in updates {
+            let vj_idx = data.ntm.vehicle_journeys.get_idx(trip).unwrap();
+            let idx = data
+                .timetable
+                .connections
+                .iter()
+                .position(|c| c.dated_vj.vj_idx == vj_idx && c.sequence == sequence)
+                .unwrap();
+            updated_timetable.realtime_connections.insert(idx, rt);
+        }
+        RealTimeDataset {
+            updated_timetable,
+            ..RealTimeDataset::new(dataset.clone(), &[])
+        }
+    }
+
+    #[test]
+    fn test_track() {
+        let period = Period {
+            begin: NaiveDate::from_ymd(2018, 12, 15),
+            horizon: chrono::Duration::days(1),
+        };
+        let dataset = Arc::new(Dataset::try_from_dataset_info(
+            DatasetInfo::new_default("fixtures/gtfs.zip", &[]),
+            &period,
+            None,
+        ));
+        let dir = tempfile::tempdir().unwrap();
+        let tracker = PunctualityTracker::new(StatsStore::new(dir.path()));
+
+        // the dataset is in the America/Los_Angeles timezone (UTC-8 in december)
+        // the first call only sets the watermark, at 04:00 local time
+        let first = rt_dataset(&dataset, vec![]);
+        assert!(tracker
+            .track(&first, Utc.ymd(2018, 12, 15).and_hms(13, 0, 0))
+            .is_empty());
+
+        // at 07:30, the connections up to 06:30 are passed
+        let second = rt_dataset(
+            &dataset,
+            vec![
+                (
+                    "STBA",
+                    1,
+                    realtime_connection(Some("2018-12-15T06:05:00"), None),
+                ),
+                (
+                    "AB1",
+                    2,
+                    realtime_connection(None, Some("2018-12-15T08:12:00")),
+                ),
+            ],
+        );
+        let second_observations = tracker.track(&second, Utc.ymd(2018, 12, 15).and_hms(15, 30, 0));
+        // at 09:30, the connections up to 08:30 are passed, the AB1 update is still known
+        let third = rt_dataset(&dataset, vec![]);
+        let third_observations = tracker.track(&third, Utc.ymd(2018, 12, 15).and_hms(17, 30, 0));
+
+        let data = dataset.as_ref().as_ref().unwrap();
+        let local = |h, m| NaiveDate::from_ymd(2018, 12, 15).and_hms(h, m, 0);
+        let expected: Vec<NaiveDateTime> = data
+            .timetable
+            .connections
+            .iter()
+            .map(|c| c.dep_time)
+            .filter(|dt| *dt > local(4, 0) && *dt <= local(8, 30))
+            .collect();
+        let observations: Vec<&Observation> = second_observations
+            .iter()
+            .chain(third_observations.iter())
+            .collect();
+        // each passed connection is counted once
+        assert_eq!(observations.len(), expected.len());
+        let mut keys: Vec<_> = observations
+            .iter()
+            .map(|o| (o.vehicle_journey_id.clone(), o.stop_id.clone(), o.date))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), observations.len());
+        assert!(second_observations
+            .iter()
+            .all(|o| o.scheduled <= local(6, 30)));
+
+        let delay = |trip: &str, stop: &str| {
+            observations
+                .iter()
+                .find(|o| o.vehicle_journey_id == trip && o.stop_id == stop)
+                .unwrap()
+                .delay_seconds
+        };
+        assert_eq!(delay("STBA", "STAGECOACH"), Some(300));
+        assert_eq!(delay("STBA", "BEATTY_AIRPORT"), None);
+        // the arrival is compared to the scheduled arrival (08:10), not to the departure (08:15)
+        assert_eq!(delay("AB1", "BULLFROG"), Some(120));
+    }
+}
@@ -0,0 +1,94 @@
+use crate::alerts::AlertStore;
+use crate::clock::Clock;
+use crate::extractors::AdminToken;
+use crate::routes::{create_alert_query, delete_alert_query, gtfs_rt_json, update_alert_query};
+use crate::tests::test_utils::fixture_dataset_actor;
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+fn stop_alert(header: &str, stop_id: &str) -> Value {
+    json!({
+        "informed_entities": [{"stop_id": stop_id}],
+        "header_text": [{"text": header, "language": "en"}],
+    })
+}
+
+#[actix_rt::test]
+async fn alerts_administration() {
+    let mut app = test::init_service(
+        App::new()
+            .data(fixture_dataset_actor())
+            .data(Clock::default())
+            .data(AdminToken(Some("secret".to_owned())))
+            .data(Arc::new(AlertStore::new(None)))
+            .route("/alerts/", web::post().to(create_alert_query))
+            .route("/alerts/{alert_id}/", web::put().to(update_alert_query))
+            .route("/alerts/{alert_id}/", web::delete().to(delete_alert_query))
+            .route("/gtfs-rt.json", web::get().to(gtfs_rt_json)),
+    )
+    .await;
+    let admin = |request: test::TestRequest| request.header("Authorization", "Bearer secret");
+
+    // the alerts can only be published with the admin token
+    let request = test::TestRequest::post()
+        .uri("/alerts/")
+        .set_json(&stop_alert("strike", "STAGECOACH"))
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // the informed entities should be in the base schedule
+    let request = admin(test::TestRequest::post())
+        .uri("/alerts/")
+        .set_json(&stop_alert("strike", "unknown"))
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let request = admin(test::TestRequest::post())
+        .uri("/alerts/")
+        .set_json(&stop_alert("strike", "STAGECOACH"))
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created: Value = test::read_body_json(response).await;
+    assert_eq!(created["id"], "alert:1");
+
+    let request = admin(test::TestRequest::put())
+        .uri("/alerts/alert:1/")
+        .set_json(&stop_alert("strike ended", "STAGECOACH"))
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // the alert is added to the GTFS-RT of the dataset
+    let request = test::TestRequest::get().uri("/gtfs-rt.json").to_request();
+    let gtfs_rt: Value = test::read_response_json(&mut app, request).await;
+    let entities = gtfs_rt["entity"].as_array().unwrap();
+    assert_eq!(entities.len(), 1);
+    assert_eq!(entities[0]["id"], "alert:1");
+    let alert = &entities[0]["alert"];
+    assert_eq!(
+        alert["headerText"]["translation"][0]["text"],
+        "strike ended"
+    );
+    assert_eq!(alert["informedEntity"][0]["stopId"], "STAGECOACH");
+
+    let request = admin(test::TestRequest::delete())
+        .uri("/alerts/alert:1/")
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let request = admin(test::TestRequest::delete())
+        .uri("/alerts/alert:1/")
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // without realtime data nor alerts, there is no GTFS-RT
+    let request = test::TestRequest::get().uri("/gtfs-rt.json").to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,71 @@
+use crate::clock::Clock;
+use crate::routes::lines_discovery_query;
+use crate::tests::test_utils::fixture_dataset_actor;
+use actix_web::{test, web, App};
+use serde_json::Value;
+
+fn line_refs(response: &Value) -> Vec<&str> {
+    response["Siri"]["LinesDelivery"]["AnnotatedLineRef"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|l| l["LineRef"].as_str().unwrap())
+        .collect()
+}
+
+async fn query(uri: &str) -> Value {
+    let mut app = test::init_service(
+        App::new()
+            .data(fixture_dataset_actor())
+            .data(Clock::default())
+            .route(
+                "/lines-discovery.json",
+                web::get().to(lines_discovery_query),
+            ),
+    )
+    .await;
+    let request = test::TestRequest::get().uri(uri).to_request();
+    test::read_response_json(&mut app, request).await
+}
+
+#[actix_rt::test]
+async fn lines_discovery_text_search() {
+    let response = query("/lines-discovery.json?q=airport").await;
+    let mut lines = line_refs(&response);
+    lines.sort();
+    assert_eq!(lines, vec!["AAMV", "AB", "STBA"]);
+
+    // the search does not depend on the case or on the diacritics
+    let response = query("/lines-discovery.json?q=A%C3%8FRPORT").await;
+    assert_eq!(line_refs(&response).len(), 3);
+
+    // the lines can also be found by their code
+    let response = query("/lines-discovery.json?q=40").await;
+    assert_eq!(line_refs(&response), vec!["CITY"]);
+}
+
+#[actix_rt::test]
+async fn lines_discovery_bounding_box() {
+    // only the Amargosa Valley stop is in this bounding box
+    let response = query(
+        "/lines-discovery.json?BoundingBoxStructure.UpperLeft.Longitude=-116.5\
+         &BoundingBoxStructure.UpperLeft.Latitude=36.7\
+         &BoundingBoxStructure.LowerRight.Longitude=-116.3\
+         &BoundingBoxStructure.LowerRight.Latitude=36.6",
+    )
+    .await;
+    assert_eq!(line_refs(&response), vec!["AAMV"]);
+}
+
+#[actix_rt::test]
+async fn lines_discovery_pagination() {
+    let all_lines = query("/lines-discovery.json").await;
+    let all_lines = line_refs(&all_lines);
+    assert_eq!(all_lines.len(), 5);
+
+    let page = query("/lines-discovery.json?limit=2&offset=1").await;
+    assert_eq!(line_refs(&page), all_lines[1..3].to_vec());
+
+    let page = query("/lines-discovery.json?limit=2&offset=4").await;
+    assert_eq!(line_refs(&page), all_lines[4..].to_vec());
+}
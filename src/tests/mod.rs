@@ -1,2 +1,9 @@
+mod alerts_test;
+mod lines_discovery_test;
+mod replay_test;
+mod siri_test;
+mod status_test;
+mod stop_areas_test;
 mod test_utils;
 mod update_model_test;
+mod validation_test;
@@ -0,0 +1,125 @@
+use crate::actors::{Cancellation, DatasetActor, RealTimeReloader};
+use crate::alerts::AlertStore;
+use crate::overrides::OverrideStore;
+use crate::replay::{Replay, FILE_SCHEME};
+use crate::routes::stop_monitoring_query;
+use crate::tests::test_utils::{create_feed_message, make_stu, trip_update};
+use crate::transit_realtime as tr;
+use actix_web::{test, web, App};
+use prost::Message;
+use serde_json::Value;
+use std::sync::Arc;
+
+/// GTFS-RT delaying the departure of the trip AB1 from BEATTY_AIRPORT (at 08:00 in the base schedule)
+fn delayed_departure(departure: &str) -> Vec<u8> {
+    let feed_message = create_feed_message(&[trip_update(
+        "AB1",
+        tr::TripUpdate {
+            trip: tr::TripDescriptor {
+                trip_id: Some("AB1".to_owned()),
+                start_date: Some("20181215".to_owned()),
+                ..Default::default()
+            },
+            stop_time_update: vec![make_stu(
+                "BEATTY_AIRPORT",
+                1,
+                Some(departure),
+                Some(departure),
+            )],
+            ..Default::default()
+        },
+    )]);
+    let mut data = vec![];
+    feed_message.encode(&mut data).unwrap();
+    data
+}
+
+async fn stop_monitoring(
+    dataset_actor: &actix::Addr<DatasetActor>,
+    replay: &Replay,
+) -> (String, String) {
+    let mut app = test::init_service(
+        App::new()
+            .data(dataset_actor.clone())
+            .data(replay.clock().clone())
+            .route(
+                "/stop-monitoring.json",
+                web::get().to(stop_monitoring_query),
+            ),
+    )
+    .await;
+    // without StartTime, the departures are given from the current time of the dataset
+    let request = test::TestRequest::get()
+        .uri("/stop-monitoring.json?MonitoringRef=BEATTY_AIRPORT")
+        .to_request();
+    let response: Value = test::read_response_json(&mut app, request).await;
+    let delivery = &response["Siri"]["ServiceDelivery"];
+    let visit = &delivery["StopMonitoringDelivery"][0]["MonitoredStopVisit"][0];
+    let journey = &visit["MonitoredVehicleJourney"];
+    assert_eq!(
+        journey["FramedVehicleJourneyRef"]["DatedVehicleJourneyRef"],
+        "AB1"
+    );
+    (
+        delivery["ResponseTimestamp"].as_str().unwrap().to_owned(),
+        journey["MonitoredCall"]["ExpectedDepartureTime"]
+            .as_str()
+            .unwrap()
+            .to_owned(),
+    )
+}
+
+#[actix_rt::test]
+async fn replay_recorded_day() {
+    // 2 snapshots recorded during the morning (the dataset is in America/Los_Angeles)
+    let snapshots = tempfile::tempdir().unwrap();
+    for (name, departure) in &[
+        ("20181215T150000Z.pb", "2018-12-15T08:05:00-08:00"),
+        ("20181215T153000Z.pb", "2018-12-15T08:10:00-08:00"),
+    ] {
+        std::fs::write(snapshots.path().join(name), delayed_departure(departure)).unwrap();
+    }
+    let url = format!("{}{}", FILE_SCHEME, snapshots.path().display());
+    let replay = Arc::new(Replay::new(&[url.clone()]).unwrap().unwrap());
+
+    let dataset_actor = crate::tests::test_utils::fixture_dataset_actor();
+    let reloader = RealTimeReloader {
+        gtfs_rt_urls: vec![url],
+        dataset_id: "replay".to_owned(),
+        clock: replay.clock().clone(),
+        replay: Some(replay.clone()),
+        recorder: None,
+        punctuality: None,
+        overrides: Arc::new(OverrideStore::new(None)),
+        alerts: Arc::new(AlertStore::new(None)),
+        cancellation: Cancellation::default(),
+        dataset_actor: dataset_actor.clone(),
+        log: slog::Logger::root(slog::Discard, slog::o!()),
+    };
+
+    // the stop monitoring is given at the time of each snapshot, with its delays
+    reloader.update_realtime_data().await;
+    assert_eq!(
+        stop_monitoring(&dataset_actor, &replay).await,
+        (
+            "2018-12-15T15:00:00+00:00".to_owned(),
+            "2018-12-15T08:05:00".to_owned()
+        )
+    );
+
+    reloader.update_realtime_data().await;
+    assert_eq!(
+        stop_monitoring(&dataset_actor, &replay).await,
+        (
+            "2018-12-15T15:30:00+00:00".to_owned(),
+            "2018-12-15T08:10:00".to_owned()
+        )
+    );
+
+    // once all the snapshots have been replayed, the last one is still served
+    reloader.update_realtime_data().await;
+    assert_eq!(
+        stop_monitoring(&dataset_actor, &replay).await.1,
+        "2018-12-15T08:10:00"
+    );
+}
@@ -0,0 +1,164 @@
+use crate::actors::{Cancellation, DatasetActor, GetRealtimeDataset, RealTimeReloader};
+use crate::clock::Clock;
+use crate::datasets::{DatasetInfo, RealtimeFormat, RealtimeSource};
+use crate::alerts::AlertStore;
+use crate::overrides::OverrideStore;
+use crate::tests::test_utils::{create_feed_message, dataset_info_actor, make_stu, trip_update};
+use crate::transit_realtime as tr;
+use chrono::TimeZone;
+use prost::Message;
+use std::sync::Arc;
+
+/// SIRI EstimatedTimetable delaying the departure of the trip STBA from STAGECOACH (at 06:00 in the base schedule)
+const STBA_DELAYED: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<Siri xmlns="http://www.siri.org.uk/siri" version="2.0">
+  <ServiceDelivery>
+    <ResponseTimestamp>2018-12-15T13:00:00Z</ResponseTimestamp>
+    <EstimatedTimetableDelivery version="2.0">
+      <EstimatedJourneyVersionFrame>
+        <RecordedAtTime>2018-12-15T13:00:00Z</RecordedAtTime>
+        <EstimatedVehicleJourney>
+          <FramedVehicleJourneyRef>
+            <DataFrameRef>2018-12-15</DataFrameRef>
+            <DatedVehicleJourneyRef>STBA</DatedVehicleJourneyRef>
+          </FramedVehicleJourneyRef>
+          <EstimatedCalls>
+            <EstimatedCall>
+              <StopPointRef>STAGECOACH</StopPointRef>
+              <Order>1</Order>
+              <ExpectedDepartureTime>2018-12-15T06:05:00-08:00</ExpectedDepartureTime>
+            </EstimatedCall>
+          </EstimatedCalls>
+        </EstimatedVehicleJourney>
+      </EstimatedJourneyVersionFrame>
+    </EstimatedTimetableDelivery>
+  </ServiceDelivery>
+</Siri>"#;
+
+fn reloader(
+    dataset_info: &DatasetInfo,
+    dataset_actor: &actix::Addr<DatasetActor>,
+) -> RealTimeReloader {
+    RealTimeReloader {
+        gtfs_rt_urls: dataset_info.all_gtfs_rt_urls(),
+        dataset_id: dataset_info.id.clone(),
+        // the dataset is in America/Los_Angeles, it is 05:00 there
+        clock: Clock::simulated(chrono::Utc.ymd(2018, 12, 15).and_hms(13, 0, 0)),
+        replay: None,
+        recorder: None,
+        punctuality: None,
+        overrides: Arc::new(OverrideStore::new(None)),
+        alerts: Arc::new(AlertStore::new(None)),
+        cancellation: Cancellation::default(),
+        dataset_actor: dataset_actor.clone(),
+        log: slog::Logger::root(slog::Discard, slog::o!()),
+    }
+}
+
+#[actix_rt::test]
+async fn siri_only_dataset() {
+    let _siri = mockito::mock("GET", "/siri_only/siri-et")
+        .with_status(200)
+        .with_body(STBA_DELAYED)
+        .create();
+    let dataset_info = DatasetInfo {
+        realtime_sources: vec![RealtimeSource {
+            url: format!("{}/siri_only/siri-et", mockito::server_url()),
+            format: RealtimeFormat::SiriEt,
+            id_stripping: Default::default(),
+        }],
+        ..DatasetInfo::new_default("fixtures/gtfs.zip", &[])
+    };
+    let dataset_actor = dataset_info_actor(dataset_info.clone());
+
+    reloader(&dataset_info, &dataset_actor)
+        .update_realtime_data()
+        .await;
+
+    let rt_dataset = dataset_actor.send(GetRealtimeDataset).await.unwrap();
+    let data = rt_dataset.base_schedule_dataset.as_ref().as_ref().unwrap();
+    let stba = data.ntm.vehicle_journeys.get_idx("STBA").unwrap();
+    let delayed: Vec<_> = data
+        .timetable
+        .connections
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| c.dated_vj.vj_idx == stba && c.sequence == 1)
+        .filter_map(|(idx, _)| rt_dataset.updated_timetable.realtime_connections.get(&idx))
+        .collect();
+    assert_eq!(delayed.len(), 1);
+    assert_eq!(
+        delayed[0].dep_time,
+        Some("2018-12-15T06:05:00".parse().unwrap())
+    );
+    // the GTFS-RT is generated from the SIRI feed
+    assert!(rt_dataset.gtfs_rt.is_some());
+}
+
+#[actix_rt::test]
+async fn gtfs_rt_and_siri_dataset() {
+    // the GTFS-RT delays AB1, with some fields not kept in the realtime timetable
+    let ab1_trip_update = trip_update(
+        "ab1",
+        tr::TripUpdate {
+            trip: tr::TripDescriptor {
+                trip_id: Some("AB1".to_owned()),
+                start_date: Some("20181215".to_owned()),
+                ..Default::default()
+            },
+            vehicle: Some(tr::VehicleDescriptor {
+                id: Some("bus 1".to_owned()),
+                ..Default::default()
+            }),
+            stop_time_update: vec![make_stu(
+                "BEATTY_AIRPORT",
+                1,
+                Some("2018-12-15T08:03:00-08:00"),
+                Some("2018-12-15T08:03:00-08:00"),
+            )],
+            ..Default::default()
+        },
+    );
+    let mut gtfs_rt = vec![];
+    create_feed_message(&[ab1_trip_update.clone()])
+        .encode(&mut gtfs_rt)
+        .unwrap();
+    let _gtfs_rt = mockito::mock("GET", "/mixed/gtfs-rt")
+        .with_status(200)
+        .with_body(gtfs_rt)
+        .create();
+    let _siri = mockito::mock("GET", "/mixed/siri-et")
+        .with_status(200)
+        .with_body(STBA_DELAYED)
+        .create();
+    let dataset_info = DatasetInfo {
+        realtime_sources: vec![RealtimeSource {
+            url: format!("{}/mixed/siri-et", mockito::server_url()),
+            format: RealtimeFormat::SiriEt,
+            id_stripping: Default::default(),
+        }],
+        ..DatasetInfo::new_default(
+            "fixtures/gtfs.zip",
+            &[format!("{}/mixed/gtfs-rt", mockito::server_url())],
+        )
+    };
+    let dataset_actor = dataset_info_actor(dataset_info.clone());
+
+    reloader(&dataset_info, &dataset_actor)
+        .update_realtime_data()
+        .await;
+
+    let rt_dataset = dataset_actor.send(GetRealtimeDataset).await.unwrap();
+    let feed_message =
+        tr::FeedMessage::decode(rt_dataset.gtfs_rt.as_ref().unwrap().data.as_slice()).unwrap();
+    let ids: Vec<_> = feed_message.entity.iter().map(|e| e.id.as_str()).collect();
+    // the SIRI trip update is generated, the GTFS-RT one is given as is
+    assert_eq!(ids, vec!["STBA:20181215", "ab1"]);
+    assert_eq!(feed_message.entity[1], ab1_trip_update);
+    let stba = feed_message.entity[0].trip_update.as_ref().unwrap();
+    assert_eq!(stba.trip.trip_id(), "STBA");
+    assert_eq!(
+        stba.stop_time_update[0].departure.as_ref().unwrap().delay,
+        Some(300)
+    );
+}
@@ -0,0 +1,77 @@
+use crate::access_control::{AccessControl, AccessControlConfig};
+use crate::actors::{BuildProgress, BuildStep, MarkStale, ReportBuildProgress};
+use crate::datasets::{DatasetInfo, Datasets, ReloadFailure};
+use crate::extractors::AdminToken;
+use crate::server::{create_all_actors, init_routes, StorageOptions};
+use actix_web::http::StatusCode;
+use actix_web::{test, App};
+use serde_json::Value;
+use std::sync::Arc;
+
+#[actix_rt::test]
+async fn status_of_a_loading_dataset() {
+    let datasets = Datasets {
+        datasets: vec![DatasetInfo::new_default("fixtures/gtfs.zip", &[])],
+        ..Default::default()
+    };
+    let actors = create_all_actors(&datasets, &StorageOptions::default());
+    let access_control = Arc::new(AccessControl::new(&AccessControlConfig::default()).unwrap());
+    let dataset_actor = actors.values().next().unwrap().dataset.clone();
+    dataset_actor
+        .send(ReportBuildProgress(Some(BuildProgress {
+            step: BuildStep::FetchingSources,
+            since: chrono::Utc::now(),
+        })))
+        .await
+        .unwrap();
+    let mut app = test::init_service(
+        App::new()
+            .configure(|cfg| init_routes(cfg, &actors, &AdminToken::default(), &access_control)),
+    )
+    .await;
+
+    // the dataset is not available yet, but its progress is given
+    let request = test::TestRequest::get().uri("/default/").to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let status: Value = test::read_body_json(response).await;
+    assert_eq!(status["id"], "default");
+    assert_eq!(status["build_progress"]["step"], "fetching_sources");
+    assert_eq!(status["is_stale"], false);
+    assert!(status.get("loaded_at").is_none());
+}
+
+#[actix_rt::test]
+async fn metrics_of_the_datasets() {
+    let datasets = Datasets {
+        datasets: vec![DatasetInfo::new_default("fixtures/gtfs.zip", &[])],
+        ..Default::default()
+    };
+    let actors = create_all_actors(&datasets, &StorageOptions::default());
+    let access_control = Arc::new(AccessControl::new(&AccessControlConfig::default()).unwrap());
+    let dataset_actor = actors.values().next().unwrap().dataset.clone();
+    dataset_actor
+        .send(MarkStale(ReloadFailure {
+            stale_since: chrono::Utc::now(),
+            failed_attempts: 2,
+            last_error: "impossible to download the gtfs".to_owned(),
+            next_attempt: chrono::Utc::now(),
+        }))
+        .await
+        .unwrap();
+    let mut app = test::init_service(
+        App::new()
+            .configure(|cfg| init_routes(cfg, &actors, &AdminToken::default(), &access_control)),
+    )
+    .await;
+
+    // the stale state is given even if the dataset is not available
+    let request = test::TestRequest::get().uri("/metrics/").to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = test::read_body(response).await;
+    let metrics = std::str::from_utf8(&body).unwrap();
+    assert!(metrics.contains("transpo_rt_dataset_available{dataset=\"default\"} 0\n"));
+    assert!(metrics.contains("transpo_rt_dataset_stale{dataset=\"default\"} 1\n"));
+    assert!(metrics.contains("transpo_rt_dataset_failed_reloads{dataset=\"default\"} 2\n"));
+}
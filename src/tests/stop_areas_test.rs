@@ -0,0 +1,186 @@
+use crate::clock::Clock;
+use crate::routes::{stop_monitoring_query, stoppoints_discovery_query};
+use crate::tests::test_utils::dataset_actor;
+use actix_web::{test, web, App};
+use serde_json::Value;
+
+/// a GTFS with a station made of 2 platforms, and a stop without parent station
+fn station_gtfs() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    let files = [
+        (
+            "agency.txt",
+            "agency_id,agency_name,agency_url,agency_timezone\n\
+             A,Agency,http://example.com,Europe/Paris\n",
+        ),
+        (
+            "stops.txt",
+            "stop_id,stop_name,stop_code,stop_lat,stop_lon,zone_id,location_type,parent_station,wheelchair_boarding\n\
+             STATION,Central Station,,48.85,2.35,,1,,\n\
+             P1,Central Station platform 1,C1,48.8501,2.3501,Z1,0,STATION,1\n\
+             P2,Central Station platform 2,C2,48.8502,2.3502,Z1,0,STATION,2\n\
+             ALONE,Lonely stop,,48.86,2.36,Z2,0,,\n",
+        ),
+        (
+            "routes.txt",
+            "route_id,agency_id,route_short_name,route_long_name,route_type,route_color\n\
+             R1,A,1,Line one,3,FF0000\n\
+             R2,A,2,Line two,3,\n",
+        ),
+        (
+            "trips.txt",
+            "route_id,service_id,trip_id\n\
+             R1,S,T1\n\
+             R2,S,T2\n",
+        ),
+        (
+            "stop_times.txt",
+            "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+             T1,10:00:00,10:00:00,P1,1\n\
+             T1,10:10:00,10:10:00,ALONE,2\n\
+             T2,10:05:00,10:05:00,P2,1\n\
+             T2,10:15:00,10:15:00,ALONE,2\n",
+        ),
+        (
+            "calendar.txt",
+            "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n\
+             S,1,1,1,1,1,1,1,20180101,20201231\n",
+        ),
+    ];
+    for (name, content) in files.iter() {
+        std::fs::write(dir.path().join(name), content).unwrap();
+    }
+    dir
+}
+
+async fn query(gtfs: &tempfile::TempDir, uri: &str) -> Value {
+    let mut app = test::init_service(
+        App::new()
+            .data(dataset_actor(gtfs.path().to_str().unwrap()))
+            .data(Clock::default())
+            .route(
+                "/stop-monitoring.json",
+                web::get().to(stop_monitoring_query),
+            )
+            .route(
+                "/stoppoints-discovery.json",
+                web::get().to(stoppoints_discovery_query),
+            ),
+    )
+    .await;
+    let request = test::TestRequest::get().uri(uri).to_request();
+    test::read_response_json(&mut app, request).await
+}
+
+fn monitored_stops(response: &Value) -> Vec<&str> {
+    response["Siri"]["ServiceDelivery"]["StopMonitoringDelivery"][0]["MonitoredStopVisit"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|v| v["MonitoringRef"].as_str().unwrap())
+        .collect()
+}
+
+fn refs<'a>(objects: &'a Value, field: &str) -> Vec<&'a str> {
+    objects
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|o| o[field].as_str().unwrap())
+        .collect()
+}
+
+#[actix_rt::test]
+async fn stop_monitoring_of_a_stop_area() {
+    let gtfs = station_gtfs();
+    // the departures of all the stop points of the stop area are given
+    let response = query(
+        &gtfs,
+        "/stop-monitoring.json?MonitoringRef=STATION&StartTime=2018-12-15T09:00:00&MaximumStopVisits=10",
+    )
+    .await;
+    assert_eq!(monitored_stops(&response), vec!["P1", "P2"]);
+
+    // a stop point id still only gives the departures of this stop point
+    let response = query(
+        &gtfs,
+        "/stop-monitoring.json?MonitoringRef=P2&StartTime=2018-12-15T09:00:00&MaximumStopVisits=10",
+    )
+    .await;
+    assert_eq!(monitored_stops(&response), vec!["P2"]);
+}
+
+#[actix_rt::test]
+async fn stoppoints_discovery_of_stop_areas() {
+    let gtfs = station_gtfs();
+    let response = query(&gtfs, "/stoppoints-discovery.json?q=central").await;
+    let delivery = &response["Siri"]["StopPointsDelivery"];
+    let mut stop_points = refs(&delivery["AnnotatedStopPoint"], "StopPointRef");
+    stop_points.sort();
+    assert_eq!(stop_points, vec!["P1", "P2"]);
+    assert!(delivery.get("AnnotatedStopArea").is_none());
+
+    // with stop_areas, the stop area of the stop points found is given, with all its stop points
+    let response = query(
+        &gtfs,
+        "/stoppoints-discovery.json?q=platform%201&stop_areas=true",
+    )
+    .await;
+    let delivery = &response["Siri"]["StopPointsDelivery"];
+    assert_eq!(delivery["AnnotatedStopPoint"], Value::Array(vec![]));
+    let stop_areas = &delivery["AnnotatedStopArea"];
+    assert_eq!(refs(stop_areas, "StopAreaRef"), vec!["STATION"]);
+    assert_eq!(stop_areas[0]["StopName"], "Central Station");
+    let mut stop_points = refs(&stop_areas[0]["AnnotatedStopPoint"], "StopPointRef");
+    stop_points.sort();
+    assert_eq!(stop_points, vec!["P1", "P2"]);
+}
+
+fn find<'a>(objects: &'a Value, field: &str, id: &str) -> &'a Value {
+    objects
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|o| o[field] == id)
+        .unwrap()
+}
+
+#[actix_rt::test]
+async fn stoppoints_discovery_details() {
+    let gtfs = station_gtfs();
+    let response = query(&gtfs, "/stoppoints-discovery.json").await;
+    let stop_points = &response["Siri"]["StopPointsDelivery"]["AnnotatedStopPoint"];
+
+    let p1 = find(stop_points, "StopPointRef", "P1");
+    assert_eq!(p1["StopName"], "Central Station platform 1");
+    assert_eq!(p1["StopCode"], "C1");
+    assert_eq!(p1["FareZone"], "Z1");
+    assert_eq!(p1["WheelchairAccessible"], true);
+    assert_eq!(
+        p1["ParentStopArea"],
+        serde_json::json!({"StopAreaRef": "STATION", "StopName": "Central Station"})
+    );
+    assert_eq!(
+        p1["Lines"],
+        serde_json::json!([{
+            "LineRef": "R1",
+            "LineName": "Line one",
+            "LineCode": "1",
+            "LineColour": "FF0000",
+            "Mode": ["Bus"],
+        }])
+    );
+
+    let p2 = find(stop_points, "StopPointRef", "P2");
+    assert_eq!(p2["WheelchairAccessible"], false);
+
+    // each line serving the stop is given once, with its details
+    let alone = find(stop_points, "StopPointRef", "ALONE");
+    assert_eq!(refs(&alone["Lines"], "LineRef"), vec!["R1", "R2"]);
+    assert_eq!(alone["FareZone"], "Z2");
+    // without information, the accessibility is not given
+    assert!(alone.get("WheelchairAccessible").is_none());
+    assert!(alone.get("StopCode").is_none());
+    // a stop without parent station is in a stop area created for it
+    assert!(alone["ParentStopArea"]["StopAreaRef"].is_string());
+}
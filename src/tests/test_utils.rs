@@ -51,3 +51,30 @@ pub fn create_feed_message(
         entity: entities.to_vec(),
     }
 }
+
+/// DatasetActor serving the fixture gtfs, loaded for the 2018-12-15
+pub fn fixture_dataset_actor() -> actix::Addr<crate::actors::DatasetActor> {
+    dataset_actor("fixtures/gtfs.zip")
+}
+
+/// DatasetActor serving a base schedule, loaded for the 2018-12-15
+pub fn dataset_actor(path: &str) -> actix::Addr<crate::actors::DatasetActor> {
+    dataset_info_actor(crate::datasets::DatasetInfo::new_default(path, &[]))
+}
+
+/// DatasetActor serving a dataset, loaded for the 2018-12-15
+pub fn dataset_info_actor(
+    dataset_info: crate::datasets::DatasetInfo,
+) -> actix::Addr<crate::actors::DatasetActor> {
+    use crate::datasets::{Dataset, Period, RealTimeDataset};
+    use actix::Actor;
+    use std::sync::Arc;
+
+    let period = Period {
+        begin: chrono::NaiveDate::from_ymd(2018, 12, 15),
+        horizon: chrono::Duration::days(1),
+    };
+    let dataset = Arc::new(Dataset::try_from_dataset_info(dataset_info, &period, None));
+    let rt_dataset = Arc::new(RealTimeDataset::new(dataset.clone(), &[]));
+    crate::actors::DatasetActor::new(dataset, rt_dataset).start()
+}
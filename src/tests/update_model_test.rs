@@ -1,3 +1,4 @@
+use crate::diagnostics::IssueKind;
 use crate::model_update;
 use crate::tests::test_utils::{create_feed_message, make_stu, trip_update};
 use crate::transit_realtime as tr;
@@ -55,7 +56,13 @@ fn read_simple_gtfs_rt() {
     let model = simple_dataset();
     let gtfs_rt = create_simple_gtfs_rt();
 
-    let model_update = model_update::get_model_update(&model, &[gtfs_rt], chrono_tz::UTC).unwrap();
+    let model_update = model_update::get_model_update(
+        &model,
+        &model_update::IdResolver::default(),
+        &[gtfs_rt],
+        chrono_tz::UTC,
+        chrono::Utc::now(),
+    ).unwrap();
 
     assert_eq!(model_update.trips.len(), 1);
 
@@ -143,7 +150,13 @@ fn feed_on_unknown_stop_and_trip() {
         ),
     ]);
 
-    let model_update = model_update::get_model_update(&model, &[gtfs_rt], chrono_tz::UTC).unwrap();
+    let model_update = model_update::get_model_update(
+        &model,
+        &model_update::IdResolver::default(),
+        &[gtfs_rt],
+        chrono_tz::UTC,
+        chrono::Utc::now(),
+    ).unwrap();
 
     // we should have only 1 trip_update on the 2 from the feed, because one of them is invalid (on an invalid vj)
     assert_eq!(model_update.trips.len(), 1);
@@ -174,4 +187,15 @@ fn feed_on_unknown_stop_and_trip() {
             updated_departure: None,
         }
     );
+    // the unknown trip and stop are reported in the diagnostics
+    let report = &model_update.matching_report;
+    assert_eq!(report.nb_trip_updates, 2);
+    assert_eq!(report.nb_matched_trip_updates, 1);
+    let issues: Vec<_> = report
+        .issues
+        .iter()
+        .map(|i| (i.kind, i.entity_id.as_str()))
+        .collect();
+    assert!(issues.contains(&(IssueKind::UnknownTrip, "invalid_trip_message")));
+    assert!(issues.contains(&(IssueKind::UnknownStop, "delay_trip_A")));
 }
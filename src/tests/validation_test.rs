@@ -0,0 +1,70 @@
+use crate::datasets::Period;
+use crate::diagnostics::IssueKind;
+use crate::tests::test_utils::{create_feed_message, make_stu, trip_update};
+use crate::transit_realtime as tr;
+use crate::validation::{validate, Thresholds};
+use prost::Message;
+
+fn trip(trip_id: &str, stop_time_update: Vec<tr::trip_update::StopTimeUpdate>) -> tr::TripUpdate {
+    tr::TripUpdate {
+        trip: tr::TripDescriptor {
+            trip_id: Some(trip_id.to_owned()),
+            start_date: Some("20181215".to_owned()),
+            ..Default::default()
+        },
+        stop_time_update,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn validate_gtfs_rt_on_fixture() {
+    // the shuttle is delayed, the other trip is not in the base schedule
+    let feed_message = create_feed_message(&[
+        trip_update(
+            "delayed_shuttle",
+            trip(
+                "STBA",
+                vec![make_stu(
+                    "BEATTY_AIRPORT",
+                    2,
+                    Some("2018-12-15T06:25:00-08:00"),
+                    Some("2018-12-15T06:25:00-08:00"),
+                )],
+            ),
+        ),
+        trip_update("unknown", trip("UNKNOWN_TRIP", vec![])),
+    ]);
+    let dir = tempfile::tempdir().unwrap();
+    let gtfs_rt = dir.path().join("gtfs-rt.pb");
+    let mut data = vec![];
+    feed_message.encode(&mut data).unwrap();
+    std::fs::write(&gtfs_rt, data).unwrap();
+
+    let period = Period {
+        begin: chrono::NaiveDate::from_ymd(2018, 12, 15),
+        horizon: chrono::Duration::days(1),
+    };
+    let now = chrono::DateTime::parse_from_rfc3339("2018-12-15T06:00:00-08:00")
+        .unwrap()
+        .with_timezone(&chrono::Utc);
+    let report = validate(
+        "fixtures/gtfs.zip",
+        &[gtfs_rt.to_str().unwrap().to_owned()],
+        &period,
+        now,
+        &Thresholds::default(),
+    )
+    .unwrap();
+
+    assert_eq!(report.nb_trip_updates, 2);
+    assert_eq!(report.nb_matched_trip_updates, 1);
+    assert_eq!(report.nb_unmatched_trip_updates, 1);
+    assert_eq!(report.nb_inconsistencies, 0);
+    assert_eq!(report.nb_updated_connections, 1);
+    assert_eq!(report.issues.len(), 1);
+    assert_eq!(report.issues[0].kind, IssueKind::UnknownTrip);
+    // half of the trip updates are not matched, above the default 10%
+    assert!(!report.is_valid());
+    assert!(report.to_string().contains("unknown_trip: 1"));
+}
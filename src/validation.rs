@@ -0,0 +1,222 @@
+//! Offline validation of a base schedule and some GTFS-RT
+//!
+//! The GTFS-RT are applied on the base schedule like they would be by the server,
+//! and the matching issues are reported, to check a dataset before adding it to the configuration.
+use crate::actors::apply_rt_update;
+use crate::datasets::{Dataset, DatasetInfo, Period};
+use crate::diagnostics::{summarize_issues, IssueSummary, MatchingReport};
+use crate::transit_realtime;
+use anyhow::{anyhow, Context, Error};
+use std::sync::Arc;
+
+/// Thresholds above which the validation fails
+#[derive(Debug, Clone)]
+pub struct Thresholds {
+    /// maximum ratio (between 0 and 1) of trip updates not matching any trip of the base schedule
+    pub max_unmatched_ratio: f64,
+    /// maximum number of inconsistencies (unknown stops, stop mismatches, invalid dates...)
+    /// in the matched trip updates
+    pub max_inconsistencies: usize,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            max_unmatched_ratio: 0.1,
+            max_inconsistencies: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ValidationReport {
+    pub nb_trip_updates: usize,
+    pub nb_matched_trip_updates: usize,
+    pub nb_unmatched_trip_updates: usize,
+    pub nb_inconsistencies: usize,
+    /// number of connections of the timetable updated by the GTFS-RT
+    pub nb_updated_connections: usize,
+    pub issues: Vec<IssueSummary>,
+    /// the thresholds exceeded, the validation fails if not empty
+    pub errors: Vec<String>,
+}
+
+impl ValidationReport {
+    pub fn new(
+        report: &MatchingReport,
+        nb_updated_connections: usize,
+        thresholds: &Thresholds,
+    ) -> Self {
+        let nb_unmatched_trip_updates = report.nb_trip_updates - report.nb_matched_trip_updates;
+        let nb_inconsistencies = report
+            .issues
+            .iter()
+            .filter(|i| !i.kind.is_matching_failure())
+            .count();
+        let mut errors = vec![];
+        if report.nb_trip_updates > 0 {
+            let unmatched_ratio = nb_unmatched_trip_updates as f64 / report.nb_trip_updates as f64;
+            if unmatched_ratio > thresholds.max_unmatched_ratio {
+                errors.push(format!(
+                    "{:.1}% of the trip updates are not matched, the maximum is {:.1}%",
+                    unmatched_ratio * 100.,
+                    thresholds.max_unmatched_ratio * 100.
+                ));
+            }
+        }
+        if nb_inconsistencies > thresholds.max_inconsistencies {
+            errors.push(format!(
+                "{} inconsistencies found, the maximum is {}",
+                nb_inconsistencies, thresholds.max_inconsistencies
+            ));
+        }
+        Self {
+            nb_trip_updates: report.nb_trip_updates,
+            nb_matched_trip_updates: report.nb_matched_trip_updates,
+            nb_unmatched_trip_updates,
+            nb_inconsistencies,
+            nb_updated_connections,
+            issues: summarize_issues(report.issues.iter()),
+            errors,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl std::fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "trip updates:           {}", self.nb_trip_updates)?;
+        writeln!(
+            f,
+            "  matched:              {}",
+            self.nb_matched_trip_updates
+        )?;
+        writeln!(
+            f,
+            "  unmatched:            {}",
+            self.nb_unmatched_trip_updates
+        )?;
+        writeln!(f, "inconsistencies:        {}", self.nb_inconsistencies)?;
+        writeln!(f, "updated connections:    {}", self.nb_updated_connections)?;
+        for issue in &self.issues {
+            writeln!(f)?;
+            writeln!(f, "{}: {}", issue.kind, issue.count)?;
+            for sample in &issue.samples {
+                writeln!(f, "  - entity {}: {}", sample.entity_id, sample.message)?;
+            }
+        }
+        writeln!(f)?;
+        if self.is_valid() {
+            writeln!(f, "validation OK")
+        } else {
+            for error in &self.errors {
+                writeln!(f, "validation failed: {}", error)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// read a GTFS-RT from a file or an url
+fn read_feed_message(path: &str) -> Result<transit_realtime::FeedMessage, Error> {
+    use prost::Message;
+    let data = if path.starts_with("http") {
+        reqwest::blocking::get(path)
+            .and_then(reqwest::blocking::Response::error_for_status)
+            .and_then(|r| r.bytes())
+            .with_context(|| format!("impossible to fetch gtfs-rt {}", path))?
+            .to_vec()
+    } else {
+        std::fs::read(path).with_context(|| format!("impossible to read gtfs-rt {}", path))?
+    };
+    transit_realtime::FeedMessage::decode(data.as_slice())
+        .map_err(|e| anyhow!("impossible to decode gtfs-rt {}: {}", path, e))
+}
+
+/// Load the base schedule and apply the GTFS-RT on it.
+///
+/// The GTFS-RT are matched with the base schedule on the `generation_period`,
+/// `now` being used for the trips without date
+pub fn validate(
+    gtfs: &str,
+    gtfs_rts: &[String],
+    generation_period: &Period,
+    now: chrono::DateTime<chrono::Utc>,
+    thresholds: &Thresholds,
+) -> Result<ValidationReport, Error> {
+    let log = slog_scope::logger();
+    let dataset_info = DatasetInfo::new_default(gtfs, gtfs_rts);
+    let dataset = Dataset::try_from_dataset_info(dataset_info, generation_period, None)?;
+    let feed_messages = gtfs_rts
+        .iter()
+        .map(|path| read_feed_message(path).map(|feed| (path.clone(), feed)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let (updated_timetable, report) =
+        apply_rt_update(&Arc::new(Ok(dataset)), &feed_messages, &[], &[], now, &log)?;
+    Ok(ValidationReport::new(
+        &report,
+        updated_timetable.realtime_connections.len(),
+        thresholds,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::IssueKind;
+
+    fn report(nb_trip_updates: usize, issues: &[IssueKind]) -> MatchingReport {
+        let mut report = MatchingReport {
+            nb_trip_updates,
+            nb_matched_trip_updates: nb_trip_updates
+                - issues.iter().filter(|k| k.is_matching_failure()).count(),
+            ..Default::default()
+        };
+        for (i, kind) in issues.iter().enumerate() {
+            report.add_issue(*kind, &format!("e{}", i), "an issue".to_owned());
+        }
+        report
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let thresholds = Thresholds {
+            max_unmatched_ratio: 0.2,
+            max_inconsistencies: 1,
+        };
+        let valid = ValidationReport::new(
+            &report(10, &[IssueKind::UnknownTrip, IssueKind::StopMismatch]),
+            12,
+            &thresholds,
+        );
+        assert_eq!(valid.nb_unmatched_trip_updates, 1);
+        assert_eq!(valid.nb_inconsistencies, 1);
+        assert!(valid.is_valid());
+
+        let too_many_unmatched = ValidationReport::new(
+            &report(
+                10,
+                &[
+                    IssueKind::UnknownTrip,
+                    IssueKind::AmbiguousMatch,
+                    IssueKind::InvalidTrip,
+                ],
+            ),
+            12,
+            &thresholds,
+        );
+        assert_eq!(too_many_unmatched.errors.len(), 1);
+
+        let too_many_inconsistencies = ValidationReport::new(
+            &report(10, &[IssueKind::UnknownStop, IssueKind::DateOutOfPeriod]),
+            12,
+            &thresholds,
+        );
+        assert_eq!(too_many_inconsistencies.nb_unmatched_trip_updates, 0);
+        assert!(!too_many_inconsistencies.is_valid());
+    }
+}